@@ -0,0 +1,15 @@
+// 複数のシェーダで共有するカメラのuniform
+// Rust側のuniform::camera_layoutとレイアウトを合わせること
+layout(std140) uniform Camera{
+    mat4 views[2];
+    mat4 projections[2];
+};
+
+#ifdef MULTIVIEW
+// 描画中のビュー(目)の番号はOVR_multiview2が与える
+#define VIEW_INDEX int(gl_ViewID_OVR)
+#else
+// 描画中のビュー(目)の番号
+uniform int view_index;
+#define VIEW_INDEX view_index
+#endif
@@ -1,15 +1,20 @@
 #version 300 es
 
+#ifdef MULTIVIEW
+// 両目を1回の描画で処理する。#extensionは他の宣言より前に置く必要がある
+#extension GL_OVR_multiview2 : require
+layout(num_views = 2) in;
+#endif
+
 in vec3 vertex_position;
 in vec4 color;
 
 uniform mat4 model;
-uniform mat4 view;
-uniform mat4 projection;
+#include "common.glsl"
 
 out vec4 v_color;
 
 void main() {
     v_color = color;
-    gl_Position = projection * view * model * vec4(vertex_position, 1.0);
+    gl_Position = projections[VIEW_INDEX] * views[VIEW_INDEX] * model * vec4(vertex_position, 1.0);
 }
@@ -0,0 +1,254 @@
+use gl_matrix::common::Mat4;
+use gl_matrix::mat4;
+
+// ヘッドセット無しのプレビュー用カメラ
+// マウスとキーボードの入力をCameraInputにまとめて渡すので、web-sysには依存しない
+// 向きはyaw(Y軸回り、左が正)とpitch(上が正)で持ち、yaw = pitch = 0 で-Zを向く
+
+// 真上・真下を向くとyawが決まらなくなるので、少し手前で止める
+const MAX_PITCH: f32 = 89.0 * std::f32::consts::PI / 180.0;
+const MIN_DISTANCE: f32 = 0.1;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum CameraMode{
+    // 視点そのものを動かす
+    Fly,
+    // 注視点の周りを回る
+    Orbit,
+}
+
+// 1フレーム分の入力
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct CameraInput{
+    // x: 右, y: 上, z: 前。それぞれ -1.0..=1.0
+    pub movement: [f32; 3],
+    // マウスの移動量(ピクセル)。x: 右, y: 下
+    pub look: [f32; 2],
+    // ホイールの回転量。正で遠ざかる
+    pub zoom: f32,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct PreviewCamera{
+    pub mode: CameraMode,
+    // Flyでの視点
+    pub position: [f32; 3],
+    // Orbitでの注視点と距離
+    pub target: [f32; 3],
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    // 移動の速さ(メートル/秒)
+    pub move_speed: f32,
+    // 1ピクセルあたりの回転(ラジアン)
+    pub look_speed: f32,
+    // ホイール1単位あたりの距離の倍率
+    pub zoom_speed: f32,
+}
+
+impl Default for PreviewCamera{
+    // 原点を2メートル手前から眺める
+    fn default()->Self{
+        PreviewCamera{
+            mode: CameraMode::Orbit,
+            position: [0.0, 0.0, 2.0],
+            target: [0.0, 0.0, 0.0],
+            distance: 2.0,
+            yaw: 0.0,
+            pitch: 0.0,
+            move_speed: 1.5,
+            look_speed: 0.005,
+            zoom_speed: 0.001,
+        }
+    }
+}
+
+impl PreviewCamera{
+    pub fn new()->Self{
+        PreviewCamera::default()
+    }
+
+    pub fn forward(&self)->[f32; 3]{
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        [-sin_yaw * cos_pitch, sin_pitch, -cos_yaw * cos_pitch]
+    }
+
+    pub fn right(&self)->[f32; 3]{
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        [cos_yaw, 0.0, -sin_yaw]
+    }
+
+    pub fn up(&self)->[f32; 3]{
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        [sin_yaw * sin_pitch, cos_pitch, cos_yaw * sin_pitch]
+    }
+
+    // 実際の視点の位置
+    pub fn eye(&self)->[f32; 3]{
+        match self.mode{
+            CameraMode::Fly=>self.position,
+            CameraMode::Orbit=>add(self.target, scale(self.forward(), -self.distance)),
+        }
+    }
+
+    // 見た目が変わらないようにモードを切り替える
+    pub fn toggle_mode(&mut self){
+        match self.mode{
+            CameraMode::Fly=>{
+                self.target = add(self.position, scale(self.forward(), self.distance));
+                self.mode = CameraMode::Orbit;
+            },
+            CameraMode::Orbit=>{
+                self.position = self.eye();
+                self.mode = CameraMode::Fly;
+            },
+        }
+    }
+
+    // delta_timeは秒
+    pub fn update(&mut self, input: &CameraInput, delta_time: f32){
+        self.yaw -= input.look[0] * self.look_speed;
+        self.pitch = (self.pitch - input.look[1] * self.look_speed).clamp(-MAX_PITCH, MAX_PITCH);
+
+        let step = self.move_speed * delta_time;
+        match self.mode{
+            CameraMode::Fly=>{
+                // 上下移動は向きに関係なくワールドのY軸に沿う
+                let mut offset = add(scale(self.right(), input.movement[0]), scale(self.forward(), input.movement[2]));
+                offset[1] += input.movement[1];
+                self.position = add(self.position, scale(offset, step));
+                self.position = add(self.position, scale(self.forward(), -input.zoom * self.zoom_speed * self.distance));
+            },
+            CameraMode::Orbit=>{
+                // 左右・上下は注視点を画面に平行に動かし、前後は注視点に近づく。離れているほど速く動かす
+                let speed = step * self.distance.max(1.0);
+                let offset = add(scale(self.right(), input.movement[0]), scale(self.up(), input.movement[1]));
+                self.target = add(self.target, scale(offset, speed));
+                self.distance = (self.distance - input.movement[2] * speed).max(MIN_DISTANCE);
+                self.distance = (self.distance * (1.0 + input.zoom * self.zoom_speed)).max(MIN_DISTANCE);
+            },
+        }
+    }
+
+    // カメラの姿勢の逆行列
+    pub fn view_matrix(&self)->Mat4{
+        let right = self.right();
+        let up = self.up();
+        let back = scale(self.forward(), -1.0);
+        let eye = self.eye();
+        [
+            right[0], up[0], back[0], 0.0,
+            right[1], up[1], back[1], 0.0,
+            right[2], up[2], back[2], 0.0,
+            -dot(right, eye), -dot(up, eye), -dot(back, eye), 1.0,
+        ]
+    }
+}
+
+// 縦の画角(ラジアン)とアスペクト比から透視投影行列を作る
+pub fn projection_matrix(fovy: f32, aspect: f32, near: f32, far: f32)->Mat4{
+    let mut projection = mat4::create();
+    mat4::perspective(&mut projection, fovy, aspect.max(f32::EPSILON), near, Some(far));
+    projection
+}
+
+fn add(a: [f32; 3], b: [f32; 3])->[f32; 3]{
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn scale(a: [f32; 3], s: f32)->[f32; 3]{
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3])->f32{
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn assert_near(actual: [f32; 3], expected: [f32; 3]){
+        for (a, e) in actual.iter().zip(expected.iter()){
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn clamps_pitch_and_turns_yaw(){
+        let mut camera = PreviewCamera::new();
+        camera.update(&CameraInput{look: [0.0, -10000.0], ..Default::default()}, 0.0);
+        assert_eq!(camera.pitch, MAX_PITCH);
+        camera.update(&CameraInput{look: [0.0, 10000.0], ..Default::default()}, 0.0);
+        assert_eq!(camera.pitch, -MAX_PITCH);
+
+        // マウスを右に動かすと右を向く(yawは左が正)
+        let mut camera = PreviewCamera::new();
+        camera.update(&CameraInput{look: [100.0, 0.0], ..Default::default()}, 0.0);
+        assert!((camera.yaw + 0.5).abs() < 1e-6);
+        assert!(camera.forward()[0] > 0.0);
+    }
+
+    #[test]
+    fn fly_moves_in_camera_space(){
+        let mut camera = PreviewCamera{mode: CameraMode::Fly, position: [0.0; 3], yaw: std::f32::consts::FRAC_PI_2, move_speed: 1.0, ..Default::default()};
+        // 左を向いているので、前は-X、右は-Z
+        camera.update(&CameraInput{movement: [0.0, 0.0, 1.0], ..Default::default()}, 1.0);
+        assert_near(camera.position, [-1.0, 0.0, 0.0]);
+        camera.update(&CameraInput{movement: [1.0, 0.0, 0.0], ..Default::default()}, 1.0);
+        assert_near(camera.position, [-1.0, 0.0, -1.0]);
+
+        // 下を向いていても上下移動はワールドのY軸
+        camera.pitch = -1.0;
+        camera.update(&CameraInput{movement: [0.0, 1.0, 0.0], ..Default::default()}, 0.5);
+        assert_near(camera.position, [-1.0, 0.5, -1.0]);
+    }
+
+    #[test]
+    fn orbit_eye_is_behind_target(){
+        let camera = PreviewCamera{target: [1.0, 2.0, 3.0], distance: 2.0, yaw: std::f32::consts::FRAC_PI_2, ..Default::default()};
+        assert_near(camera.eye(), [3.0, 2.0, 3.0]);
+
+        // 上から見下ろすと視点は注視点より上にある
+        let camera = PreviewCamera{target: [0.0; 3], distance: 2.0, pitch: -std::f32::consts::FRAC_PI_6, ..Default::default()};
+        assert_near(camera.eye(), [0.0, 1.0, 3.0f32.sqrt()]);
+
+        // ビュー行列は視点を原点に、注視点を-Z方向に写す
+        let view = camera.view_matrix();
+        let mut target = [0.0; 3];
+        gl_matrix::vec3::transform_mat4(&mut target, &camera.target, &view);
+        assert_near(target, [0.0, 0.0, -2.0]);
+    }
+
+    #[test]
+    fn orbit_forward_dollies_toward_target(){
+        let mut camera = PreviewCamera{distance: 4.0, move_speed: 1.0, ..Default::default()};
+        camera.update(&CameraInput{movement: [0.0, 0.0, 1.0], ..Default::default()}, 0.25);
+        assert_eq!(camera.target, [0.0; 3]);
+        assert!((camera.distance - 3.0).abs() < 1e-6);
+
+        camera.update(&CameraInput{movement: [0.0, 0.0, 1.0], ..Default::default()}, 100.0);
+        assert_eq!(camera.distance, MIN_DISTANCE);
+
+        // 左右・上下は注視点を動かす
+        let mut camera = PreviewCamera{distance: 1.0, move_speed: 1.0, ..Default::default()};
+        camera.update(&CameraInput{movement: [1.0, 1.0, 0.0], ..Default::default()}, 1.0);
+        assert_near(camera.target, [1.0, 1.0, 0.0]);
+        assert_eq!(camera.distance, 1.0);
+    }
+
+    #[test]
+    fn toggle_mode_keeps_the_eye(){
+        let mut camera = PreviewCamera{target: [0.5, 1.0, 0.0], distance: 3.0, yaw: 0.3, pitch: -0.2, ..Default::default()};
+        let eye = camera.eye();
+        camera.toggle_mode();
+        assert_eq!(camera.mode, CameraMode::Fly);
+        assert_near(camera.eye(), eye);
+        camera.toggle_mode();
+        assert_eq!(camera.mode, CameraMode::Orbit);
+        assert_near(camera.eye(), eye);
+        assert_near(camera.target, [0.5, 1.0, 0.0]);
+    }
+}
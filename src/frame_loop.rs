@@ -0,0 +1,382 @@
+use crate::input::{Handedness,InputEvent,InputEventSubscribers,InputSourceState,Pose};
+use crate::gamepad::{Controllers,Deadzones};
+use crate::hand::{GestureThresholds,Hands,HAND_TRACKING_FEATURE};
+use crate::locomotion::{Locomotion,LocomotionInput,LocomotionSettings,WalkableArea};
+use crate::session::{EnabledFeatures,SessionMode};
+use crate::lifecycle::Visibility;
+use crate::platform::XrFrameInput;
+use crate::platform::simulated::{SimulatedFrame,SimulatedFrameInput,SimulatedSession,SimulatedStep};
+
+// アニメーションループの1フレーム分の処理のうち、ブラウザに依存しない部分
+// WebXRのループとシミュレーターのループの両方から同じ順番で呼ぶ
+
+// フレームの時刻(ミリ秒)から、前のフレームからの経過時間(秒)を求める
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct FrameClock{
+    last_time: Option<f64>,
+    // フレームが長く止まったときに移動量が跳ねないようにする(秒)
+    pub max_delta_time: f32,
+}
+
+impl FrameClock{
+    pub fn new(max_delta_time: f32)->Self{
+        FrameClock{last_time: None, max_delta_time}
+    }
+
+    // 最初のフレームでは0
+    pub fn tick(&mut self, time: f64)->f32{
+        let delta_time = self.last_time.map(|last_time| ((time - last_time) / 1000.0) as f32).unwrap_or(0.0).min(self.max_delta_time);
+        self.last_time = Some(time);
+        delta_time
+    }
+}
+
+// コントローラー・手・移動の状態
+pub struct Interaction{
+    pub controllers: Controllers,
+    // hand-trackingが許可されなかった場合は手の関節が届かないので、ジェスチャーの判定もしない
+    pub hands: Option<Hands>,
+    // inlineセッションでは画面の中を見ているだけなので、立ち位置は動かさない
+    pub locomotion: Option<Locomotion>,
+}
+
+impl Interaction{
+    pub fn new(mode: SessionMode, features: &EnabledFeatures, walkable: Vec<WalkableArea>)->Self{
+        Interaction{
+            controllers: Controllers::new(Deadzones::default()),
+            hands: features.contains(HAND_TRACKING_FEATURE).then(|| Hands::new(GestureThresholds::default())),
+            locomotion: mode.is_immersive().then(|| Locomotion::new(LocomotionSettings::default(), walkable)),
+        }
+    }
+
+    // 入力ソースの姿勢を読んだ後に呼ぶ
+    pub fn update_input(&mut self, sources: &[InputSourceState]){
+        self.controllers.update(sources);
+        if let Some(hands) = self.hands.as_mut(){
+            hands.update(sources);
+        }
+    }
+
+    // 左スティックで移動、右スティックで回転とテレポート
+    // 立ち位置が変わったら、参照空間に渡す新しいオフセット(位置, クォータニオン)を返す
+    // 呼び出し側はオフセットを反映してから、入力ソースの姿勢を読み直す
+    pub fn update_locomotion(&mut self, sources: &[InputSourceState], head: Option<Pose>, delta_time: f32)->Option<([f32; 3], [f32; 4])>{
+        let locomotion = self.locomotion.as_mut()?;
+        let head = head?;
+        let aim = sources.iter()
+            .find(|source| source.handedness == Handedness::Right)
+            .and_then(|source| source.target_ray_pose)
+            .map(|pose| (pose.position, pose.forward()));
+        let locomotion_input = LocomotionInput{
+            move_stick: self.controllers.left.thumbstick,
+            turn_stick: self.controllers.right.thumbstick,
+            head_position: head.position,
+            head_forward: head.forward(),
+            aim,
+        };
+        if !locomotion.update(&locomotion_input, delta_time){
+            return None;
+        }
+        Some(locomotion.rig.origin_offset())
+    }
+
+    // 参照空間がリセットされたら立ち位置を新しい原点に戻し、参照空間に渡すオフセットを返す
+    pub fn reset_locomotion(&mut self)->Option<([f32; 3], [f32; 4])>{
+        let locomotion = self.locomotion.as_mut()?;
+        locomotion.reset();
+        Some(locomotion.rig.origin_offset())
+    }
+}
+
+// 1フレーム分の処理の結果
+#[derive(Debug,Clone)]
+pub struct FrameStep{
+    // 前のフレームからの経過時間(秒)
+    pub delta_time: f32,
+    // 購読者に配ったselect/squeeze。visible-blurredの間は空
+    pub events: Vec<InputEvent>,
+}
+
+// WebXRとシミュレーターのアニメーションループが、フレームごとにstepを呼ぶ
+pub struct FrameLoop{
+    pub clock: FrameClock,
+    pub interaction: Interaction,
+    pub input_events: InputEventSubscribers,
+}
+
+impl FrameLoop{
+    pub fn new(clock: FrameClock, interaction: Interaction, input_events: InputEventSubscribers)->Self{
+        FrameLoop{clock, interaction, input_events}
+    }
+
+    // 入力を読み、移動してから、前のフレームの後に届いたイベントを購読者に配る
+    // visible-blurredの間は入力が届かないので、入力の処理を止めて前の状態のまま描画させ、その間に届いたイベントは捨てる
+    pub fn step(&mut self, input: &mut impl XrFrameInput, time: f64, visibility: Visibility)->FrameStep{
+        let delta_time = self.clock.tick(time);
+        let events = input.take_events();
+        if !visibility.should_simulate(){
+            return FrameStep{delta_time, events: Vec::new()};
+        }
+        input.update_input();
+        self.interaction.update_input(input.input_sources());
+        // 参照空間が動いたら、入力の姿勢も新しい空間で取り直す
+        if self.interaction.locomotion.is_some(){
+            let offset = self.interaction.update_locomotion(input.input_sources(), input.viewer_pose(), delta_time);
+            if let Some((position, orientation)) = offset{
+                if input.set_origin_offset(position, orientation){
+                    input.update_input();
+                }
+            }
+        }
+        for event in events.iter(){
+            self.input_events.dispatch(event);
+        }
+        FrameStep{delta_time, events}
+    }
+
+    // 参照空間がリセットされたら、移動した分を捨てて新しい原点に立つ
+    pub fn reset(&mut self, input: &mut impl XrFrameInput){
+        if let Some((position, orientation)) = self.interaction.reset_locomotion(){
+            input.set_origin_offset(position, orientation);
+        }
+    }
+}
+
+// シミュレーターのセッションを、WebXRのループと同じFrameLoopで進める
+// フレームが届くたびにstepしてからrenderに渡す
+// セッションが終わるか、max_refreshes回リフレッシュしたら戻り、描いたフレームの数を返す
+pub fn run_simulated_session(session: &mut SimulatedSession, frame_loop: &mut FrameLoop, max_refreshes: usize, mut render: impl FnMut(&SimulatedFrame, &FrameLoop, &FrameStep))->usize{
+    session.request_animation_frame();
+    let mut frames = 0;
+    for _ in 0..max_refreshes{
+        let mut frame = match session.advance(){
+            SimulatedStep::Frame(frame)=>frame,
+            SimulatedStep::Skipped=>continue,
+            SimulatedStep::Ended=>break,
+        };
+        let (time, visibility) = (frame.time, session.visibility());
+        let step = frame_loop.step(&mut SimulatedFrameInput{session, frame: &mut frame}, time, visibility);
+        render(&frame, frame_loop, &step);
+        frames += 1;
+        session.request_animation_frame();
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::gamepad::GamepadSnapshot;
+    use crate::input::{InputEventKind,TargetRayMode};
+    use crate::platform::simulated::{ScriptEvent,SessionScript,SimulatedDevice,SimulatedInputSource,Timeline};
+
+    fn pose(position: [f32; 3])->Pose{
+        Pose{position, ..Pose::default()}
+    }
+
+    // 100Hzなので1周期は10ミリ秒
+    fn session(script: SessionScript)->SimulatedSession{
+        let device = SimulatedDevice{eye_width: 64, eye_height: 64, refresh_rate: 100.0, ..Default::default()};
+        SimulatedSession::new(device, SessionMode::ImmersiveVr, script)
+    }
+
+    fn frame_loop()->FrameLoop{
+        let interaction = Interaction::new(SessionMode::ImmersiveVr, &EnabledFeatures::new([]), vec![WalkableArea::floor(20.0)]);
+        FrameLoop::new(FrameClock::new(0.1), interaction, InputEventSubscribers::new())
+    }
+
+    fn yaw(frame_loop: &FrameLoop)->f32{
+        frame_loop.interaction.locomotion.as_ref().unwrap().rig.yaw
+    }
+
+    // 入力ソースと頭は決まった位置のまま、イベントだけを届けるXrFrameInput
+    #[derive(Default)]
+    struct FixedInput{
+        sources: Vec<InputSourceState>,
+        events: Vec<InputEvent>,
+        updates: usize,
+        offsets: Vec<[f32; 3]>,
+    }
+
+    impl XrFrameInput for FixedInput{
+        fn update_input(&mut self){
+            self.updates += 1;
+        }
+
+        fn input_sources(&self)->&[InputSourceState]{
+            &self.sources
+        }
+
+        fn viewer_pose(&self)->Option<Pose>{
+            Some(pose([0.0, 1.6, 0.0]))
+        }
+
+        fn set_origin_offset(&mut self, position: [f32; 3], _orientation: [f32; 4])->bool{
+            self.offsets.push(position);
+            true
+        }
+
+        fn take_events(&mut self)->Vec<InputEvent>{
+            std::mem::take(&mut self.events)
+        }
+    }
+
+    fn select()->InputEvent{
+        InputEvent{kind: InputEventKind::Select, handedness: Handedness::Right, target_ray_pose: None, source: None}
+    }
+
+    fn controller(handedness: Handedness, gamepad: Timeline<GamepadSnapshot>)->SimulatedInputSource{
+        SimulatedInputSource::controller(handedness, Timeline::constant(pose([0.2, 1.0, -0.3]))).with_gamepad(gamepad)
+    }
+
+    #[test]
+    fn clock_measures_seconds_and_clamps_gaps(){
+        let mut clock = FrameClock::new(0.1);
+        assert_eq!(clock.tick(1000.0), 0.0);
+        assert!((clock.tick(1020.0) - 0.02).abs() < 1e-6);
+        assert_eq!(clock.tick(3000.0), 0.1);
+    }
+
+    #[test]
+    fn runs_until_max_refreshes_or_end(){
+        let script = SessionScript::new(Timeline::constant(pose([0.0, 1.6, 0.0]))).with_event(30.0, ScriptEvent::DropFrames(2));
+        let mut times = Vec::new();
+        let frames = run_simulated_session(&mut session(script.clone()), &mut frame_loop(), 8, |frame, _, step| times.push((frame.time, step.delta_time)));
+        // 落としたリフレッシュも数えるので、8回のうち描くのは6フレーム
+        assert_eq!(frames, 6);
+        let expected = [(0.0, 0.0), (10.0, 0.01), (20.0, 0.01), (50.0, 0.03), (60.0, 0.01), (70.0, 0.01)];
+        for ((time, delta_time), (expected_time, expected_delta)) in times.iter().zip(expected.iter()){
+            assert_eq!(time, expected_time);
+            assert!((delta_time - expected_delta).abs() < 1e-6);
+        }
+
+        let mut session = session(script.with_event(25.0, ScriptEvent::End));
+        let frames = run_simulated_session(&mut session, &mut frame_loop(), 1000, |_, _, _|{});
+        assert_eq!(frames, 3);
+        assert!(session.is_ended());
+    }
+
+    #[test]
+    fn scripted_sticks_drive_locomotion(){
+        let idle = GamepadSnapshot::xr_standard();
+        // 右スティックを100ミリ秒から倒して200ミリ秒で戻す。左スティックは300ミリ秒から前に倒したまま
+        let right = Timeline::constant(idle.clone())
+            .with_key(100.0, idle.clone().with_thumbstick([1.0, 0.0]))
+            .with_key(200.0, idle.clone());
+        let left = Timeline::constant(idle.clone()).with_key(300.0, idle.with_thumbstick([0.0, -1.0]));
+        let script = SessionScript::new(Timeline::constant(pose([0.0, 1.6, 0.0])))
+            .with_source(controller(Handedness::Right, right))
+            .with_source(controller(Handedness::Left, left));
+        let mut frame_loop = frame_loop();
+        let mut yaws = Vec::new();
+        run_simulated_session(&mut session(script), &mut frame_loop, 40, |frame, frame_loop, _|{
+            yaws.push((frame.time, yaw(frame_loop)));
+        });
+        // 倒している間に1回だけスナップ回転する
+        let angle = -30.0_f32.to_radians();
+        assert!(yaws.iter().filter(|(time, _)| *time < 100.0).all(|(_, yaw)| *yaw == 0.0));
+        assert!(yaws.iter().filter(|(time, _)| *time >= 100.0).all(|(_, yaw)| (*yaw - angle).abs() < 1e-6));
+
+        // 右に30度回っているので、前に進むとワールドでは-Zから右に30度の向きに進む
+        let interaction = &frame_loop.interaction;
+        let rig = interaction.locomotion.as_ref().unwrap().rig;
+        let speed = LocomotionSettings::default().move_speed;
+        let distance = (rig.position[0] * rig.position[0] + rig.position[2] * rig.position[2]).sqrt();
+        // 300..=390ミリ秒の10フレーム、0.01秒ずつ進む
+        assert!((distance - speed * 0.1).abs() < 1e-3, "moved {}", distance);
+        assert!((rig.position[0] / distance - 0.5).abs() < 1e-3);
+        assert!(rig.position[2] < 0.0);
+        assert_eq!(interaction.controllers.left.thumbstick, [0.0, -1.0]);
+    }
+
+    #[test]
+    fn blurred_and_hidden_sessions_pause_interaction(){
+        let idle = GamepadSnapshot::xr_standard();
+        let right = Timeline::constant(idle.clone()).with_key(100.0, idle.with_thumbstick([1.0, 0.0]));
+        let select = ScriptEvent::Input{handedness: Handedness::Right, kind: InputEventKind::Select};
+        let script = SessionScript::new(Timeline::constant(pose([0.0, 1.6, 0.0])))
+            .with_source(controller(Handedness::Right, right))
+            .with_event(50.0, ScriptEvent::Visibility(Visibility::VisibleBlurred))
+            .with_event(60.0, select)
+            .with_event(150.0, ScriptEvent::Visibility(Visibility::Hidden))
+            .with_event(200.0, ScriptEvent::Visibility(Visibility::Visible))
+            .with_event(220.0, select)
+            .with_event(300.0, ScriptEvent::End);
+        let mut session = session(script);
+        let mut frame_loop = frame_loop();
+        let mut subscriber = frame_loop.input_events.subscribe();
+        let mut frames = Vec::new();
+        let count = run_simulated_session(&mut session, &mut frame_loop, 1000, |frame, frame_loop, step|{
+            frames.push((frame.time, step.events.len(), yaw(frame_loop)));
+        });
+        // hiddenの150..200ミリ秒はフレームが届かない
+        assert_eq!(count, 25);
+        assert!(frames.iter().all(|(time, _, _)| *time < 150.0 || *time >= 200.0));
+        // visible-blurredの間はスティックを倒していても回らず、visibleに戻ってから回る
+        let angle = -30.0_f32.to_radians();
+        assert!(frames.iter().filter(|(time, _, _)| *time < 200.0).all(|(_, _, yaw)| *yaw == 0.0));
+        assert!((frames.iter().find(|(time, _, _)| *time == 200.0).unwrap().2 - angle).abs() < 1e-6);
+        // visibleのときに起きたselectだけが届く
+        let events: Vec<f64> = frames.iter().filter(|(_, events, _)| *events > 0).map(|(time, _, _)| *time).collect();
+        assert_eq!(events, vec![220.0]);
+        // 購読者にも同じselectだけが届く
+        let received = events_received(&mut subscriber);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].kind, InputEventKind::Select);
+        assert!(session.is_ended());
+    }
+
+    fn events_received(events: &mut futures::channel::mpsc::UnboundedReceiver<InputEvent>)->Vec<InputEvent>{
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv(){
+            received.push(event);
+        }
+        received
+    }
+
+    #[test]
+    fn step_dispatches_events_only_while_visible(){
+        let mut frame_loop = frame_loop();
+        let mut events = frame_loop.input_events.subscribe();
+        let mut input = FixedInput{events: vec![select()], ..Default::default()};
+        let step = frame_loop.step(&mut input, 0.0, Visibility::Visible);
+        assert_eq!(step.events.len(), 1);
+        assert_eq!(input.updates, 1);
+        assert_eq!(events_received(&mut events).len(), 1);
+
+        // visible-blurredの間に届いたイベントは捨て、入力も読まない
+        input.events = vec![select(), select()];
+        let step = frame_loop.step(&mut input, 10.0, Visibility::VisibleBlurred);
+        assert!(step.events.is_empty());
+        assert!(input.events.is_empty());
+        assert_eq!(input.updates, 1);
+        assert!(events_received(&mut events).is_empty());
+        assert!((step.delta_time - 0.01).abs() < 1e-6);
+    }
+
+    #[test]
+    fn step_rereads_input_after_moving_and_reset_returns_to_origin(){
+        let idle = GamepadSnapshot::xr_standard();
+        let right = InputSourceState{
+            handedness: Handedness::Right,
+            target_ray_mode: TargetRayMode::TrackedPointer,
+            profiles: Vec::new(),
+            grip_pose: Some(pose([0.2, 1.0, -0.3])),
+            target_ray_pose: Some(pose([0.2, 1.0, -0.3])),
+            gamepad: Some(idle.with_thumbstick([1.0, 0.0])),
+            hand: None,
+            source: None,
+        };
+        let mut frame_loop = frame_loop();
+        let mut input = FixedInput{sources: vec![right], ..Default::default()};
+        frame_loop.step(&mut input, 0.0, Visibility::Visible);
+        // 回転したので参照空間を動かし、姿勢を読み直す
+        assert_eq!(input.offsets.len(), 1);
+        assert_eq!(input.updates, 2);
+        assert!(yaw(&frame_loop) != 0.0);
+
+        frame_loop.reset(&mut input);
+        assert_eq!(yaw(&frame_loop), 0.0);
+        assert_eq!(input.offsets.last(), Some(&[0.0, 0.0, 0.0]));
+    }
+}
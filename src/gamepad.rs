@@ -0,0 +1,379 @@
+use wasm_bindgen::JsCast;
+use web_sys::*;
+use crate::input::{Handedness,InputSourceState};
+
+// XrInputSource.gamepadの状態を、xr-standardのマッピングに従って名前付きのボタンにする
+// https://www.w3.org/TR/webxr-gamepads-module-1/#xr-standard-gamepad-mapping
+// 判定はGamepadSnapshotだけを使うので、ブラウザ無しでも組み立てたスナップショットで確かめられる
+
+pub const XR_STANDARD_MAPPING: &str = "xr-standard";
+
+// ボタン1つ分の生の値
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct ButtonSnapshot{
+    pub pressed: bool,
+    pub touched: bool,
+    pub value: f32,
+}
+
+// あるフレームでのGamepadの値
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct GamepadSnapshot{
+    pub mapping: String,
+    pub buttons: Vec<ButtonSnapshot>,
+    pub axes: Vec<f32>,
+}
+
+impl GamepadSnapshot{
+    pub fn from_gamepad(gamepad: &Gamepad)->Self{
+        // web-sysのGamepadMappingTypeには"xr-standard"が無いので、文字列のまま読む
+        let mapping = js_sys::Reflect::get(gamepad, &"mapping".into()).ok().and_then(|mapping| mapping.as_string()).unwrap_or_default();
+        let buttons = gamepad.buttons().iter()
+            .map(|button|{
+                let button = button.unchecked_into::<GamepadButton>();
+                ButtonSnapshot{
+                    pressed: button.pressed(),
+                    touched: button.touched(),
+                    value: button.value() as f32,
+                }
+            })
+            .collect();
+        let axes = gamepad.axes().iter().map(|axis| axis.as_f64().unwrap_or(0.0) as f32).collect();
+        GamepadSnapshot{mapping, buttons, axes}
+    }
+
+    // 何も触っていないxr-standardのゲームパッド。シミュレーターの台本で使う
+    pub fn xr_standard()->Self{
+        GamepadSnapshot{
+            mapping: XR_STANDARD_MAPPING.to_string(),
+            buttons: vec![ButtonSnapshot::default(); ControllerButton::ALL.len()],
+            axes: vec![0.0; 4],
+        }
+    }
+
+    pub fn with_button(mut self, button: ControllerButton, pressed: bool)->Self{
+        if let Some(snapshot) = self.buttons.get_mut(button.index()){
+            *snapshot = ButtonSnapshot{pressed, touched: pressed, value: if pressed{ 1.0 } else{ 0.0 }};
+        }
+        self
+    }
+
+    pub fn with_thumbstick(mut self, axes: [f32; 2])->Self{
+        for (index, value) in THUMBSTICK_AXES.into_iter().zip(axes){
+            if let Some(axis) = self.axes.get_mut(index){
+                *axis = value;
+            }
+        }
+        self
+    }
+
+    pub fn is_xr_standard(&self)->bool{
+        self.mapping == XR_STANDARD_MAPPING
+    }
+}
+
+// xr-standardのボタン。値はbuttonsの添字
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum ControllerButton{
+    Trigger = 0,
+    Squeeze = 1,
+    Touchpad = 2,
+    Thumbstick = 3,
+    // A / X
+    Primary = 4,
+    // B / Y
+    Secondary = 5,
+}
+
+impl ControllerButton{
+    pub const ALL: [ControllerButton; 6] = [
+        ControllerButton::Trigger,
+        ControllerButton::Squeeze,
+        ControllerButton::Touchpad,
+        ControllerButton::Thumbstick,
+        ControllerButton::Primary,
+        ControllerButton::Secondary,
+    ];
+
+    pub fn index(&self)->usize{
+        *self as usize
+    }
+}
+
+// xr-standardの軸の添字
+const TOUCHPAD_AXES: [usize; 2] = [0, 1];
+const THUMBSTICK_AXES: [usize; 2] = [2, 3];
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Deadzones{
+    // スティック・タッチパッドの半径方向のデッドゾーン
+    pub stick: f32,
+    // トリガー・グリップのアナログ値のデッドゾーン
+    pub trigger: f32,
+}
+
+impl Default for Deadzones{
+    fn default()->Self{
+        Deadzones{
+            stick: 0.15,
+            trigger: 0.05,
+        }
+    }
+}
+
+// デッドゾーン以下を0にし、残りを0.0..=1.0に引き伸ばす
+pub fn apply_deadzone(value: f32, deadzone: f32)->f32{
+    let magnitude = value.abs();
+    if magnitude <= deadzone || deadzone >= 1.0{
+        return 0.0;
+    }
+    value.signum() * ((magnitude - deadzone) / (1.0 - deadzone)).min(1.0)
+}
+
+// 2軸をまとめて扱う。軸ごとに切ると斜め方向が引っかかるため
+pub fn apply_radial_deadzone(axes: [f32; 2], deadzone: f32)->[f32; 2]{
+    let magnitude = (axes[0] * axes[0] + axes[1] * axes[1]).sqrt();
+    if magnitude <= deadzone || deadzone >= 1.0{
+        return [0.0, 0.0];
+    }
+    let scaled = ((magnitude - deadzone) / (1.0 - deadzone)).min(1.0);
+    [axes[0] / magnitude * scaled, axes[1] / magnitude * scaled]
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct ButtonState{
+    pub pressed: bool,
+    pub touched: bool,
+    // デッドゾーン適用後のアナログ値
+    pub value: f32,
+    // このフレームで押された・離された
+    pub just_pressed: bool,
+    pub just_released: bool,
+}
+
+impl ButtonState{
+    fn next(&self, snapshot: ButtonSnapshot, deadzone: f32)->Self{
+        ButtonState{
+            pressed: snapshot.pressed,
+            touched: snapshot.touched,
+            value: apply_deadzone(snapshot.value, deadzone),
+            just_pressed: snapshot.pressed && !self.pressed,
+            just_released: !snapshot.pressed && self.pressed,
+        }
+    }
+}
+
+// 片手のコントローラーの状態
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct ControllerState{
+    pub connected: bool,
+    buttons: [ButtonState; 6],
+    // x: 右が正, y: 手前(下)が正。xr-standardの向きのまま
+    pub thumbstick: [f32; 2],
+    pub touchpad: [f32; 2],
+}
+
+impl ControllerState{
+    // 前のフレームの状態と比べて押した・離したを判定する
+    // スナップショットが無いフレームは切断として扱い、押されていたボタンは離されたことになる
+    pub fn update(&mut self, snapshot: Option<&GamepadSnapshot>, deadzones: &Deadzones){
+        let snapshot = snapshot.filter(|snapshot| snapshot.is_xr_standard());
+        let empty = GamepadSnapshot::default();
+        let values = snapshot.unwrap_or(&empty);
+        for button in ControllerButton::ALL{
+            let raw = values.buttons.get(button.index()).copied().unwrap_or_default();
+            let deadzone = match button{
+                ControllerButton::Trigger | ControllerButton::Squeeze=>deadzones.trigger,
+                _=>0.0,
+            };
+            self.buttons[button.index()] = self.buttons[button.index()].next(raw, deadzone);
+        }
+        let axes = |indices: [usize; 2]| [
+            values.axes.get(indices[0]).copied().unwrap_or(0.0),
+            values.axes.get(indices[1]).copied().unwrap_or(0.0),
+        ];
+        self.thumbstick = apply_radial_deadzone(axes(THUMBSTICK_AXES), deadzones.stick);
+        self.touchpad = apply_radial_deadzone(axes(TOUCHPAD_AXES), deadzones.stick);
+        self.connected = snapshot.is_some();
+    }
+
+    pub fn button(&self, button: ControllerButton)->&ButtonState{
+        &self.buttons[button.index()]
+    }
+
+    pub fn pressed(&self, button: ControllerButton)->bool{
+        self.button(button).pressed
+    }
+
+    pub fn just_pressed(&self, button: ControllerButton)->bool{
+        self.button(button).just_pressed
+    }
+
+    pub fn just_released(&self, button: ControllerButton)->bool{
+        self.button(button).just_released
+    }
+
+    pub fn value(&self, button: ControllerButton)->f32{
+        self.button(button).value
+    }
+}
+
+// 両手のコントローラー
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Controllers{
+    pub left: ControllerState,
+    pub right: ControllerState,
+    pub deadzones: Deadzones,
+}
+
+impl Controllers{
+    pub fn new(deadzones: Deadzones)->Self{
+        Controllers{
+            deadzones,
+            ..Controllers::default()
+        }
+    }
+
+    pub fn hand(&self, handedness: Handedness)->Option<&ControllerState>{
+        match handedness{
+            Handedness::Left=>Some(&self.left),
+            Handedness::Right=>Some(&self.right),
+            Handedness::None=>None,
+        }
+    }
+
+    // アニメーションループで入力ソースを更新した後に呼ぶ
+    pub fn update(&mut self, sources: &[InputSourceState]){
+        let snapshot = |handedness: Handedness| sources.iter()
+            .find(|source| source.handedness == handedness)
+            .and_then(|source| source.gamepad.as_ref());
+        self.left.update(snapshot(Handedness::Left), &self.deadzones);
+        self.right.update(snapshot(Handedness::Right), &self.deadzones);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::input::TargetRayMode;
+
+    fn assert_close(actual: [f32; 2], expected: [f32; 2]){
+        assert!((actual[0] - expected[0]).abs() < 1e-5 && (actual[1] - expected[1]).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+    }
+
+    fn source(handedness: Handedness, gamepad: Option<GamepadSnapshot>)->InputSourceState{
+        InputSourceState{
+            handedness,
+            target_ray_mode: TargetRayMode::TrackedPointer,
+            profiles: Vec::new(),
+            grip_pose: None,
+            target_ray_pose: None,
+            gamepad,
+            hand: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn detects_pressed_and_released_edges(){
+        let deadzones = Deadzones::default();
+        let mut state = ControllerState::default();
+        let released = GamepadSnapshot::xr_standard();
+        let pressed = GamepadSnapshot::xr_standard().with_button(ControllerButton::Trigger, true);
+
+        state.update(Some(&released), &deadzones);
+        assert!(state.connected);
+        assert!(!state.pressed(ControllerButton::Trigger));
+
+        state.update(Some(&pressed), &deadzones);
+        assert!(state.pressed(ControllerButton::Trigger));
+        assert!(state.just_pressed(ControllerButton::Trigger));
+        assert_eq!(state.value(ControllerButton::Trigger), 1.0);
+        // 押し続けている間は立ち上がりにならない
+        state.update(Some(&pressed), &deadzones);
+        assert!(state.pressed(ControllerButton::Trigger));
+        assert!(!state.just_pressed(ControllerButton::Trigger));
+
+        state.update(Some(&released), &deadzones);
+        assert!(state.just_released(ControllerButton::Trigger));
+        state.update(Some(&released), &deadzones);
+        assert!(!state.just_released(ControllerButton::Trigger));
+        assert!(!state.button(ControllerButton::Squeeze).just_pressed);
+    }
+
+    #[test]
+    fn disconnect_releases_buttons(){
+        let deadzones = Deadzones::default();
+        let mut state = ControllerState::default();
+        state.update(Some(&GamepadSnapshot::xr_standard().with_button(ControllerButton::Primary, true).with_thumbstick([1.0, 0.0])), &deadzones);
+        state.update(None, &deadzones);
+        assert!(!state.connected);
+        assert!(state.just_released(ControllerButton::Primary));
+        assert_eq!(state.thumbstick, [0.0, 0.0]);
+
+        // xr-standard以外のマッピングは読まない
+        let generic = GamepadSnapshot{mapping: String::new(), ..GamepadSnapshot::xr_standard().with_button(ControllerButton::Primary, true)};
+        state.update(Some(&generic), &deadzones);
+        assert!(!state.connected);
+        assert!(!state.pressed(ControllerButton::Primary));
+    }
+
+    #[test]
+    fn rescales_stick_outside_deadzone(){
+        assert_eq!(apply_radial_deadzone([0.1, 0.1], 0.15), [0.0, 0.0]);
+        assert_close(apply_radial_deadzone([1.0, 0.0], 0.15), [1.0, 0.0]);
+        // 0.15から1.0を0.0から1.0に引き伸ばす
+        assert_close(apply_radial_deadzone([0.0, -0.575], 0.15), [0.0, -0.5]);
+        // 斜め方向も向きを保ったまま大きさだけ変わる
+        let diagonal = 0.575 / 2.0f32.sqrt();
+        assert_close(apply_radial_deadzone([diagonal, diagonal], 0.15), [0.5 / 2.0f32.sqrt(), 0.5 / 2.0f32.sqrt()]);
+        // 1を超える入力は1に収める
+        assert_close(apply_radial_deadzone([1.0, 1.0], 0.15), [1.0 / 2.0f32.sqrt(), 1.0 / 2.0f32.sqrt()]);
+        assert_eq!(apply_radial_deadzone([1.0, 0.0], 1.0), [0.0, 0.0]);
+
+        assert_eq!(apply_deadzone(0.04, 0.05), 0.0);
+        assert!((apply_deadzone(-0.525, 0.05) + 0.5).abs() < 1e-5);
+
+        let mut state = ControllerState::default();
+        state.update(Some(&GamepadSnapshot::xr_standard().with_thumbstick([0.0, -0.575])), &Deadzones::default());
+        assert_close(state.thumbstick, [0.0, -0.5]);
+        assert_eq!(state.touchpad, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn tolerates_missing_buttons_and_axes(){
+        // ボタン2つ・軸2つだけのxr-standard(タッチパッドもスティックも無いコントローラー)
+        let snapshot = GamepadSnapshot{
+            mapping: XR_STANDARD_MAPPING.to_string(),
+            buttons: vec![ButtonSnapshot{pressed: true, touched: true, value: 0.03}, ButtonSnapshot{pressed: false, touched: false, value: 0.5}],
+            axes: vec![0.9, 0.0],
+        };
+        let mut state = ControllerState::default();
+        state.update(Some(&snapshot), &Deadzones::default());
+        assert!(state.connected);
+        assert!(state.pressed(ControllerButton::Trigger));
+        // トリガーのデッドゾーン以下のアナログ値は0
+        assert_eq!(state.value(ControllerButton::Trigger), 0.0);
+        assert!(state.value(ControllerButton::Squeeze) > 0.4);
+        assert!(!state.pressed(ControllerButton::Secondary));
+        assert_eq!(state.thumbstick, [0.0, 0.0]);
+        assert!(state.touchpad[0] > 0.8);
+    }
+
+    #[test]
+    fn controllers_follow_handedness(){
+        let mut controllers = Controllers::new(Deadzones::default());
+        let sources = vec![
+            source(Handedness::Right, Some(GamepadSnapshot::xr_standard().with_button(ControllerButton::Squeeze, true))),
+            source(Handedness::Left, None),
+            source(Handedness::None, Some(GamepadSnapshot::xr_standard().with_button(ControllerButton::Trigger, true))),
+        ];
+        controllers.update(&sources);
+        assert!(controllers.right.connected);
+        assert!(controllers.right.just_pressed(ControllerButton::Squeeze));
+        assert!(!controllers.left.connected);
+        assert!(!controllers.right.pressed(ControllerButton::Trigger));
+        assert!(controllers.hand(Handedness::None).is_none());
+        assert_eq!(controllers.hand(Handedness::Right), Some(&controllers.right));
+    }
+}
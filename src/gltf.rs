@@ -0,0 +1,874 @@
+use std::collections::HashMap;
+use gl_matrix::common::*;
+use gl_matrix::{mat4,quat};
+use serde::Deserialize;
+use crate::scene::{Scene,Node,NodeId,Transform,MeshHandle};
+use crate::mesh::{VertexLayout,VertexAttribute};
+
+// glTF 2.0 (.gltf / .glb) のパーサ
+// web-sysには依存せず、fetchしたバイト列からメッシュ・ノード・マテリアルを取り出す
+
+const GLB_MAGIC: u32 = 0x4654_6C67; // "glTF"
+const GLB_CHUNK_JSON: u32 = 0x4E4F_534A; // "JSON"
+const GLB_CHUNK_BIN: u32 = 0x004E_4942; // "BIN\0"
+
+const COMPONENT_BYTE: u32 = 5120;
+const COMPONENT_UNSIGNED_BYTE: u32 = 5121;
+const COMPONENT_SHORT: u32 = 5122;
+const COMPONENT_UNSIGNED_SHORT: u32 = 5123;
+const COMPONENT_UNSIGNED_INT: u32 = 5125;
+const COMPONENT_FLOAT: u32 = 5126;
+
+const MODE_TRIANGLES: u32 = 4;
+
+// bufferViewの無いアクセサをゼロ埋めするときの要素数の上限
+const MAX_ZERO_FILLED_COMPONENTS: usize = 1 << 24;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum GltfError{
+    InvalidGlb(String),
+    Json(String),
+    // 外部ファイルのバッファが渡されなかった
+    MissingBuffer(String),
+    InvalidAccessor{accessor: usize, reason: String},
+    Unsupported(String),
+    // u16のインデックスに収まらない
+    TooManyVertices(usize),
+}
+
+impl std::fmt::Display for GltfError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            GltfError::InvalidGlb(reason)=>write!(f, "invalid GLB: {}", reason),
+            GltfError::Json(reason)=>write!(f, "invalid glTF JSON: {}", reason),
+            GltfError::MissingBuffer(uri)=>write!(f, "buffer `{}` was not loaded", uri),
+            GltfError::InvalidAccessor{accessor, reason}=>write!(f, "accessor {}: {}", accessor, reason),
+            GltfError::Unsupported(feature)=>write!(f, "unsupported glTF feature: {}", feature),
+            GltfError::TooManyVertices(count)=>write!(f, "{} vertices do not fit in 16-bit indices", count),
+        }
+    }
+}
+
+impl std::error::Error for GltfError{}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+#[serde(rename_all = "camelCase")]
+struct Root{
+    scene: Option<usize>,
+    #[serde(default)]
+    scenes: Vec<RawScene>,
+    #[serde(default)]
+    nodes: Vec<RawNode>,
+    #[serde(default)]
+    meshes: Vec<RawMesh>,
+    #[serde(default)]
+    materials: Vec<RawMaterial>,
+    #[serde(default)]
+    accessors: Vec<RawAccessor>,
+    #[serde(default)]
+    buffer_views: Vec<RawBufferView>,
+    #[serde(default)]
+    buffers: Vec<RawBuffer>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+struct RawScene{
+    name: Option<String>,
+    #[serde(default)]
+    nodes: Vec<usize>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+struct RawNode{
+    name: Option<String>,
+    mesh: Option<usize>,
+    #[serde(default)]
+    children: Vec<usize>,
+    matrix: Option<[f32;16]>,
+    translation: Option<[f32;3]>,
+    rotation: Option<[f32;4]>,
+    scale: Option<[f32;3]>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+struct RawMesh{
+    name: Option<String>,
+    primitives: Vec<RawPrimitive>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+struct RawPrimitive{
+    attributes: HashMap<String,usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    mode: Option<u32>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+#[serde(rename_all = "camelCase")]
+struct RawMaterial{
+    name: Option<String>,
+    pbr_metallic_roughness: Option<RawPbr>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+#[serde(rename_all = "camelCase")]
+struct RawPbr{
+    base_color_factor: Option<[f32;4]>,
+    metallic_factor: Option<f32>,
+    roughness_factor: Option<f32>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+#[serde(rename_all = "camelCase")]
+struct RawAccessor{
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    #[serde(default)]
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    accessor_type: String,
+    sparse: Option<serde_json::Value>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+#[serde(rename_all = "camelCase")]
+struct RawBufferView{
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>,
+}
+
+#[derive(Debug,Clone,Deserialize,Default)]
+#[serde(rename_all = "camelCase")]
+struct RawBuffer{
+    uri: Option<String>,
+    byte_length: usize,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct GltfNode{
+    pub name: String,
+    pub transform: Transform,
+    pub mesh: Option<usize>,
+    pub children: Vec<usize>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct GltfMesh{
+    pub name: String,
+    pub primitives: Vec<GltfPrimitive>,
+}
+
+// 三角形リスト1つ分の頂点データ
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct GltfPrimitive{
+    pub positions: Vec<[f32;3]>,
+    pub normals: Option<Vec<[f32;3]>>,
+    pub tangents: Option<Vec<[f32;4]>>,
+    pub uvs: Option<Vec<[f32;2]>>,
+    pub colors: Option<Vec<[f32;4]>>,
+    pub joints: Option<Vec<[f32;4]>>,
+    pub weights: Option<Vec<[f32;4]>>,
+    pub indices: Option<Vec<u32>>,
+    pub material: Option<usize>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct GltfMaterial{
+    pub name: String,
+    pub base_color_factor: [f32;4],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+}
+
+impl Default for GltfMaterial{
+    fn default()->Self{
+        GltfMaterial{
+            name: String::new(),
+            base_color_factor: [1.0,1.0,1.0,1.0],
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct GltfScene{
+    pub name: String,
+    pub nodes: Vec<usize>,
+}
+
+// 読み込みが完了したglTFアセット
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct GltfAsset{
+    pub nodes: Vec<GltfNode>,
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<GltfMaterial>,
+    pub scenes: Vec<GltfScene>,
+    pub default_scene: Option<usize>,
+}
+
+// JSONとGLBのバイナリチャンクを保持した、バッファ解決前の状態
+#[derive(Debug,Clone)]
+pub struct GltfDocument{
+    root: Root,
+    bin: Option<Vec<u8>>,
+}
+
+impl GltfDocument{
+    // 先頭のマジックナンバーを見て、GLBかJSONかを判定する
+    pub fn from_slice(bytes: &[u8])->Result<Self,GltfError>{
+        if bytes.len() >= 4 && read_u32(bytes, 0) == GLB_MAGIC{
+            Self::from_glb(bytes)
+        }
+        else{
+            let text = std::str::from_utf8(bytes).map_err(|error| GltfError::Json(error.to_string()))?;
+            Self::from_json(text)
+        }
+    }
+
+    pub fn from_json(text: &str)->Result<Self,GltfError>{
+        let root = serde_json::from_str::<Root>(text).map_err(|error| GltfError::Json(error.to_string()))?;
+        Ok(GltfDocument{root, bin: None})
+    }
+
+    pub fn from_glb(bytes: &[u8])->Result<Self,GltfError>{
+        if bytes.len() < 12{
+            return Err(GltfError::InvalidGlb("header is too short".to_string()));
+        }
+        if read_u32(bytes, 0) != GLB_MAGIC{
+            return Err(GltfError::InvalidGlb("magic is not `glTF`".to_string()));
+        }
+        let version = read_u32(bytes, 4);
+        if version != 2{
+            return Err(GltfError::InvalidGlb(format!("version {} is not supported", version)));
+        }
+        let length = (read_u32(bytes, 8) as usize).min(bytes.len());
+
+        let mut json = None;
+        let mut bin = None;
+        let mut offset = 12;
+        while offset + 8 <= length{
+            let chunk_length = read_u32(bytes, offset) as usize;
+            let chunk_type = read_u32(bytes, offset + 4);
+            let start = offset + 8;
+            // 32bitの長さをそのまま足すと桁あふれするので、checked_addで確かめる
+            let Some(end) = start.checked_add(chunk_length).filter(|end| *end <= length) else{
+                return Err(GltfError::InvalidGlb("chunk exceeds file length".to_string()));
+            };
+            match chunk_type{
+                GLB_CHUNK_JSON=>json = Some(&bytes[start..end]),
+                GLB_CHUNK_BIN=>bin = Some(bytes[start..end].to_vec()),
+                // 未知のチャンクは仕様通り読み飛ばす
+                _=>{},
+            }
+            // チャンクは4バイト境界に揃えられている
+            let Some(next) = end.checked_add((4 - chunk_length % 4) % 4) else{
+                return Err(GltfError::InvalidGlb("chunk padding exceeds file length".to_string()));
+            };
+            offset = next;
+        }
+
+        let Some(json) = json else{
+            return Err(GltfError::InvalidGlb("JSON chunk is missing".to_string()));
+        };
+        let text = std::str::from_utf8(json).map_err(|error| GltfError::Json(error.to_string()))?;
+        let mut document = Self::from_json(text)?;
+        document.bin = bin;
+        Ok(document)
+    }
+
+    // 別途fetchが必要なバッファのURI(data URIとGLBのBINチャンクは除く)
+    pub fn external_uris(&self)->Vec<String>{
+        self.root.buffers.iter()
+            .filter_map(|buffer| buffer.uri.clone())
+            .filter(|uri| !uri.starts_with("data:"))
+            .collect()
+    }
+
+    // fetch済みの外部バッファを使ってアクセサを解決し、アセットを組み立てる
+    pub fn load(&self, external_buffers: &HashMap<String,Vec<u8>>)->Result<GltfAsset,GltfError>{
+        let buffers = self.resolve_buffers(external_buffers)?;
+        let root = &self.root;
+
+        let nodes = root.nodes.iter().enumerate().map(|(index,node)| GltfNode{
+            name: node.name.clone().unwrap_or_else(|| format!("node_{}", index)),
+            transform: node_transform(node),
+            mesh: node.mesh,
+            children: node.children.clone(),
+        }).collect::<Vec<_>>();
+
+        for node in nodes.iter(){
+            if let Some(mesh) = node.mesh{
+                if mesh >= root.meshes.len(){
+                    return Err(GltfError::Json(format!("node `{}` references missing mesh {}", node.name, mesh)));
+                }
+            }
+            if let Some(child) = node.children.iter().find(|child| **child >= nodes.len()){
+                return Err(GltfError::Json(format!("node `{}` references missing child {}", node.name, child)));
+            }
+        }
+
+        let mut meshes = Vec::with_capacity(root.meshes.len());
+        for (index,mesh) in root.meshes.iter().enumerate(){
+            let mut primitives = Vec::with_capacity(mesh.primitives.len());
+            for primitive in mesh.primitives.iter(){
+                primitives.push(self.load_primitive(primitive, &buffers)?);
+            }
+            meshes.push(GltfMesh{
+                name: mesh.name.clone().unwrap_or_else(|| format!("mesh_{}", index)),
+                primitives,
+            });
+        }
+
+        let materials = root.materials.iter().enumerate().map(|(index,material)|{
+            let pbr = material.pbr_metallic_roughness.clone().unwrap_or_default();
+            let default = GltfMaterial::default();
+            GltfMaterial{
+                name: material.name.clone().unwrap_or_else(|| format!("material_{}", index)),
+                base_color_factor: pbr.base_color_factor.unwrap_or(default.base_color_factor),
+                metallic_factor: pbr.metallic_factor.unwrap_or(default.metallic_factor),
+                roughness_factor: pbr.roughness_factor.unwrap_or(default.roughness_factor),
+            }
+        }).collect();
+
+        let scenes = root.scenes.iter().enumerate().map(|(index,scene)| GltfScene{
+            name: scene.name.clone().unwrap_or_else(|| format!("scene_{}", index)),
+            nodes: scene.nodes.clone(),
+        }).collect();
+
+        Ok(GltfAsset{
+            nodes,
+            meshes,
+            materials,
+            scenes,
+            default_scene: root.scene,
+        })
+    }
+
+    fn resolve_buffers(&self, external_buffers: &HashMap<String,Vec<u8>>)->Result<Vec<Vec<u8>>,GltfError>{
+        let mut buffers = Vec::with_capacity(self.root.buffers.len());
+        for (index,buffer) in self.root.buffers.iter().enumerate(){
+            let data = match &buffer.uri{
+                Some(uri) if uri.starts_with("data:")=>decode_data_uri(uri)?,
+                Some(uri)=>external_buffers.get(uri).cloned().ok_or_else(|| GltfError::MissingBuffer(uri.clone()))?,
+                // uriが無いのはGLBのBINチャンクを指す最初のバッファだけ
+                None if index == 0=>self.bin.clone().ok_or_else(|| GltfError::MissingBuffer("GLB BIN chunk".to_string()))?,
+                None=>return Err(GltfError::MissingBuffer(format!("buffer {}", index))),
+            };
+            if data.len() < buffer.byte_length{
+                return Err(GltfError::MissingBuffer(format!("buffer {} is shorter than byteLength", index)));
+            }
+            buffers.push(data);
+        }
+        Ok(buffers)
+    }
+
+    fn load_primitive(&self, primitive: &RawPrimitive, buffers: &[Vec<u8>])->Result<GltfPrimitive,GltfError>{
+        let mode = primitive.mode.unwrap_or(MODE_TRIANGLES);
+        if mode != MODE_TRIANGLES{
+            return Err(GltfError::Unsupported(format!("primitive mode {}", mode)));
+        }
+        let Some(position) = primitive.attributes.get("POSITION") else{
+            return Err(GltfError::Unsupported("primitive without POSITION".to_string()));
+        };
+
+        let attribute = |name: &str, components: usize|->Result<Option<Vec<f32>>,GltfError>{
+            match primitive.attributes.get(name){
+                Some(accessor)=>Ok(Some(self.read_accessor(*accessor, components, buffers)?)),
+                None=>Ok(None),
+            }
+        };
+
+        let positions = chunk::<3>(self.read_accessor(*position, 3, buffers)?);
+        let vertex_count = positions.len();
+        let primitive = GltfPrimitive{
+            positions,
+            normals: attribute("NORMAL", 3)?.map(chunk::<3>),
+            tangents: attribute("TANGENT", 4)?.map(chunk::<4>),
+            uvs: attribute("TEXCOORD_0", 2)?.map(chunk::<2>),
+            colors: self.read_colors(primitive, buffers)?,
+            joints: attribute("JOINTS_0", 4)?.map(chunk::<4>),
+            weights: attribute("WEIGHTS_0", 4)?.map(chunk::<4>),
+            indices: match primitive.indices{
+                Some(accessor)=>Some(self.read_indices(accessor, buffers)?),
+                None=>None,
+            },
+            material: primitive.material,
+        };
+
+        if let Some(index) = primitive.indices.iter().flatten().find(|index| **index as usize >= vertex_count){
+            return Err(GltfError::Json(format!("index {} is out of range for {} vertices", index, vertex_count)));
+        }
+        Ok(primitive)
+    }
+
+    // COLOR_0はVEC3の場合もあるので、アルファを1.0で補う
+    fn read_colors(&self, primitive: &RawPrimitive, buffers: &[Vec<u8>])->Result<Option<Vec<[f32;4]>>,GltfError>{
+        let Some(accessor) = primitive.attributes.get("COLOR_0") else{
+            return Ok(None);
+        };
+        let components = self.root.accessors.get(*accessor).map(|raw| component_count(&raw.accessor_type)).unwrap_or(None);
+        match components{
+            Some(3)=>Ok(Some(chunk::<3>(self.read_accessor(*accessor, 3, buffers)?).into_iter().map(|[r,g,b]| [r,g,b,1.0]).collect())),
+            _=>Ok(Some(chunk::<4>(self.read_accessor(*accessor, 4, buffers)?))),
+        }
+    }
+
+    fn read_indices(&self, accessor: usize, buffers: &[Vec<u8>])->Result<Vec<u32>,GltfError>{
+        let raw = self.accessor(accessor)?;
+        match raw.component_type{
+            COMPONENT_UNSIGNED_BYTE | COMPONENT_UNSIGNED_SHORT | COMPONENT_UNSIGNED_INT=>{},
+            other=>return Err(GltfError::InvalidAccessor{accessor, reason: format!("component type {} cannot be used for indices", other)}),
+        }
+        self.read_components(accessor, 1, buffers, |bytes, component_type| match component_type{
+            COMPONENT_UNSIGNED_BYTE=>bytes[0] as u32,
+            COMPONENT_UNSIGNED_SHORT=>u16::from_le_bytes([bytes[0],bytes[1]]) as u32,
+            _=>read_u32(bytes, 0),
+        })
+    }
+
+    // アクセサを読み、正規化を考慮したf32の列にする
+    fn read_accessor(&self, accessor: usize, components: usize, buffers: &[Vec<u8>])->Result<Vec<f32>,GltfError>{
+        let normalized = self.accessor(accessor)?.normalized;
+        self.read_components(accessor, components, buffers, |bytes, component_type| match component_type{
+            COMPONENT_BYTE=>{
+                let value = bytes[0] as i8 as f32;
+                if normalized{(value / 127.0).max(-1.0)} else{value}
+            },
+            COMPONENT_UNSIGNED_BYTE=>{
+                let value = bytes[0] as f32;
+                if normalized{value / 255.0} else{value}
+            },
+            COMPONENT_SHORT=>{
+                let value = i16::from_le_bytes([bytes[0],bytes[1]]) as f32;
+                if normalized{(value / 32767.0).max(-1.0)} else{value}
+            },
+            COMPONENT_UNSIGNED_SHORT=>{
+                let value = u16::from_le_bytes([bytes[0],bytes[1]]) as f32;
+                if normalized{value / 65535.0} else{value}
+            },
+            COMPONENT_UNSIGNED_INT=>read_u32(bytes, 0) as f32,
+            _=>f32::from_le_bytes([bytes[0],bytes[1],bytes[2],bytes[3]]),
+        })
+    }
+
+    fn read_components<T>(&self, accessor: usize, components: usize, buffers: &[Vec<u8>], convert: impl Fn(&[u8],u32)->T)->Result<Vec<T>,GltfError>{
+        let raw = self.accessor(accessor)?;
+        let invalid = |reason: String| GltfError::InvalidAccessor{accessor, reason};
+
+        if raw.sparse.is_some(){
+            return Err(GltfError::Unsupported("sparse accessors".to_string()));
+        }
+        let Some(expected) = component_count(&raw.accessor_type) else{
+            return Err(invalid(format!("unknown type `{}`", raw.accessor_type)));
+        };
+        if expected != components{
+            return Err(invalid(format!("expected {} components but type is `{}`", components, raw.accessor_type)));
+        }
+        let Some(component_size) = component_size(raw.component_type) else{
+            return Err(invalid(format!("unknown component type {}", raw.component_type)));
+        };
+        let Some(view_index) = raw.buffer_view else{
+            // bufferViewが無いアクセサはゼロで埋める
+            // 巨大なcountで際限なく確保しないよう、上限を超えたら拒否する
+            let Some(len) = raw.count.checked_mul(components).filter(|len| *len <= MAX_ZERO_FILLED_COMPONENTS) else{
+                return Err(invalid(format!("count {} is too large", raw.count)));
+            };
+            return Ok((0..len).map(|_| convert(&[0;4], raw.component_type)).collect());
+        };
+        let Some(view) = self.root.buffer_views.get(view_index) else{
+            return Err(invalid(format!("missing bufferView {}", view_index)));
+        };
+        let Some(buffer) = buffers.get(view.buffer) else{
+            return Err(invalid(format!("missing buffer {}", view.buffer)));
+        };
+
+        let element_size = component_size * components;
+        let stride = view.byte_stride.unwrap_or(element_size);
+        if stride < element_size{
+            return Err(invalid(format!("byteStride {} is smaller than element size {}", stride, element_size)));
+        }
+        if raw.count == 0{
+            return Ok(Vec::new());
+        }
+        // countやbyteOffsetが大きすぎるファイルでも、桁あふれせずにエラーにする
+        let required = stride.checked_mul(raw.count - 1)
+            .and_then(|size| size.checked_add(element_size))
+            .and_then(|size| size.checked_add(raw.byte_offset));
+        let view_end = view.byte_offset.checked_add(view.byte_length);
+        match (required, view_end){
+            (Some(required), Some(view_end)) if required <= view.byte_length && view_end <= buffer.len()=>{},
+            _=>return Err(invalid("data exceeds bufferView".to_string())),
+        }
+
+        let data = &buffer[view.byte_offset + raw.byte_offset..view.byte_offset + view.byte_length];
+        let mut values = Vec::with_capacity(raw.count * components);
+        for element in 0..raw.count{
+            let start = element * stride;
+            for component in 0..components{
+                let offset = start + component * component_size;
+                values.push(convert(&data[offset..offset + component_size], raw.component_type));
+            }
+        }
+        Ok(values)
+    }
+
+    fn accessor(&self, accessor: usize)->Result<&RawAccessor,GltfError>{
+        self.root.accessors.get(accessor).ok_or(GltfError::InvalidAccessor{accessor, reason: "does not exist".to_string()})
+    }
+}
+
+impl GltfPrimitive{
+    // インデックスが無い場合は頂点順に並べたものを返す
+    pub fn indices_u16(&self)->Result<Vec<u16>,GltfError>{
+        if self.positions.len() > u16::MAX as usize + 1{
+            return Err(GltfError::TooManyVertices(self.positions.len()));
+        }
+        Ok(match &self.indices{
+            Some(indices)=>indices.iter().map(|index| *index as u16).collect(),
+            None=>(0..self.positions.len() as u16).collect(),
+        })
+    }
+
+    // プリミティブが持っている属性から作る頂点レイアウト
+    // 頂点色はマテリアルの色を焼き込むので常に含める
+    pub fn vertex_layout(&self)->VertexLayout{
+        let mut attributes = vec![VertexAttribute::Position];
+        if self.normals.is_some(){
+            attributes.push(VertexAttribute::Normal);
+        }
+        if self.uvs.is_some(){
+            attributes.push(VertexAttribute::Uv);
+        }
+        attributes.push(VertexAttribute::Color);
+        if self.tangents.is_some(){
+            attributes.push(VertexAttribute::Tangent);
+        }
+        if self.joints.is_some() && self.weights.is_some(){
+            attributes.push(VertexAttribute::Joints);
+            attributes.push(VertexAttribute::Weights);
+        }
+        VertexLayout::new(&attributes).expect("attributes are unique and non-empty")
+    }
+
+    // レイアウトに従って頂点データをインターリーブする。頂点色にはマテリアルの色を掛ける
+    // レイアウトにあってプリミティブに無い属性は0で埋める
+    pub fn interleave(&self, layout: &VertexLayout, base_color: [f32;4])->Vec<f32>{
+        let mut vertices = Vec::with_capacity(self.positions.len() * layout.floats_per_vertex());
+        for index in 0..self.positions.len(){
+            for element in layout.elements(){
+                let components = element.attribute.components() as usize;
+                let value: Option<&[f32]> = match element.attribute{
+                    VertexAttribute::Position=>Some(&self.positions[index]),
+                    VertexAttribute::Normal=>self.normals.as_ref().and_then(|values| values.get(index)).map(|value| value.as_slice()),
+                    VertexAttribute::Uv=>self.uvs.as_ref().and_then(|values| values.get(index)).map(|value| value.as_slice()),
+                    VertexAttribute::Tangent=>self.tangents.as_ref().and_then(|values| values.get(index)).map(|value| value.as_slice()),
+                    VertexAttribute::Joints=>self.joints.as_ref().and_then(|values| values.get(index)).map(|value| value.as_slice()),
+                    VertexAttribute::Weights=>self.weights.as_ref().and_then(|values| values.get(index)).map(|value| value.as_slice()),
+                    VertexAttribute::Color=>{
+                        let color = self.colors.as_ref().and_then(|colors| colors.get(index)).copied().unwrap_or([1.0,1.0,1.0,1.0]);
+                        vertices.extend((0..4).map(|channel| color[channel] * base_color[channel]));
+                        continue;
+                    },
+                };
+                match value{
+                    Some(value)=>vertices.extend_from_slice(value),
+                    None=>vertices.extend(std::iter::repeat_n(0.0, components)),
+                }
+            }
+        }
+        vertices
+    }
+}
+
+impl GltfAsset{
+    pub fn material(&self, primitive: &GltfPrimitive)->GltfMaterial{
+        primitive.material.and_then(|material| self.materials.get(material)).cloned().unwrap_or_default()
+    }
+
+    // ルートノード。シーンが無い場合は親を持たないノードを使う
+    pub fn root_nodes(&self)->Vec<usize>{
+        let scene = self.default_scene.or(if self.scenes.is_empty(){None} else{Some(0)});
+        if let Some(scene) = scene.and_then(|scene| self.scenes.get(scene)){
+            return scene.nodes.clone();
+        }
+        let mut has_parent = vec![false; self.nodes.len()];
+        for node in self.nodes.iter(){
+            for child in node.children.iter(){
+                has_parent[*child] = true;
+            }
+        }
+        (0..self.nodes.len()).filter(|index| !has_parent[*index]).collect()
+    }
+
+    // ノード階層をシーングラフに追加する
+    // primitive_meshes[メッシュ番号][プリミティブ番号] はアップロード済みのメッシュを指す
+    pub fn instantiate(&self, scene: &mut Scene, parent: Option<NodeId>, primitive_meshes: &[Vec<MeshHandle>])->Vec<NodeId>{
+        let mut roots = Vec::new();
+        for root in self.root_nodes(){
+            if let Some(id) = self.instantiate_node(root, scene, parent, primitive_meshes, 0){
+                roots.push(id);
+            }
+        }
+        roots
+    }
+
+    fn instantiate_node(&self, index: usize, scene: &mut Scene, parent: Option<NodeId>, primitive_meshes: &[Vec<MeshHandle>], depth: usize)->Option<NodeId>{
+        // 壊れたファイルで無限に再帰しないようにする
+        if depth > self.nodes.len(){
+            return None;
+        }
+        let gltf_node = self.nodes.get(index)?;
+        let handles = gltf_node.mesh.and_then(|mesh| primitive_meshes.get(mesh)).map(|handles| handles.as_slice()).unwrap_or(&[]);
+
+        let mut node = Node::new(&gltf_node.name).with_transform(gltf_node.transform);
+        if let Some(first) = handles.first(){
+            node = node.with_mesh(*first);
+        }
+        let id = scene.add_node(parent, node).ok()?;
+
+        // 2つ目以降のプリミティブは同じ位置の子ノードとして追加する
+        for (primitive,handle) in handles.iter().enumerate().skip(1){
+            let name = format!("{}_primitive_{}", gltf_node.name, primitive);
+            let _ = scene.add_node(Some(id), Node::new(&name).with_mesh(*handle));
+        }
+        for child in gltf_node.children.iter(){
+            self.instantiate_node(*child, scene, Some(id), primitive_meshes, depth + 1);
+        }
+        Some(id)
+    }
+}
+
+fn node_transform(node: &RawNode)->Transform{
+    if let Some(matrix) = node.matrix{
+        let mut translation = [0.0;3];
+        let mut rotation = [0.0,0.0,0.0,1.0];
+        let mut scale = [1.0;3];
+        mat4::get_translation(&mut translation, &matrix);
+        mat4::get_scaling(&mut scale, &matrix);
+        // mat4::get_rotationは非一様スケールで誤差が出るので、列ごとにスケールを除いてから変換する
+        let column = |index: usize, scale: f32| if scale == 0.0{[0.0;3]} else{[matrix[index*4]/scale, matrix[index*4+1]/scale, matrix[index*4+2]/scale]};
+        let [c0,c1,c2] = [column(0,scale[0]), column(1,scale[1]), column(2,scale[2])];
+        let rotation_matrix: Mat3 = [c0[0],c0[1],c0[2], c1[0],c1[1],c1[2], c2[0],c2[1],c2[2]];
+        quat::from_mat3(&mut rotation, &rotation_matrix);
+        let rotation_clone = rotation;
+        quat::normalize(&mut rotation, &rotation_clone);
+        return Transform{translation, rotation, scale};
+    }
+    let default = Transform::default();
+    Transform{
+        translation: node.translation.unwrap_or(default.translation),
+        rotation: node.rotation.unwrap_or(default.rotation),
+        scale: node.scale.unwrap_or(default.scale),
+    }
+}
+
+fn component_count(accessor_type: &str)->Option<usize>{
+    match accessor_type{
+        "SCALAR"=>Some(1),
+        "VEC2"=>Some(2),
+        "VEC3"=>Some(3),
+        "VEC4"=>Some(4),
+        "MAT2"=>Some(4),
+        "MAT3"=>Some(9),
+        "MAT4"=>Some(16),
+        _=>None,
+    }
+}
+
+fn component_size(component_type: u32)->Option<usize>{
+    match component_type{
+        COMPONENT_BYTE | COMPONENT_UNSIGNED_BYTE=>Some(1),
+        COMPONENT_SHORT | COMPONENT_UNSIGNED_SHORT=>Some(2),
+        COMPONENT_UNSIGNED_INT | COMPONENT_FLOAT=>Some(4),
+        _=>None,
+    }
+}
+
+fn chunk<const N: usize>(values: Vec<f32>)->Vec<[f32;N]>{
+    values.chunks_exact(N).map(|chunk| chunk.try_into().unwrap()).collect()
+}
+
+fn read_u32(bytes: &[u8], offset: usize)->u32{
+    u32::from_le_bytes([bytes[offset],bytes[offset+1],bytes[offset+2],bytes[offset+3]])
+}
+
+// data:application/octet-stream;base64,... 形式のURIをデコードする
+fn decode_data_uri(uri: &str)->Result<Vec<u8>,GltfError>{
+    let Some((header,data)) = uri.split_once(',') else{
+        return Err(GltfError::Json("malformed data URI".to_string()));
+    };
+    if !header.ends_with(";base64"){
+        return Err(GltfError::Unsupported("data URI without base64 encoding".to_string()));
+    }
+    decode_base64(data).ok_or_else(|| GltfError::Json("invalid base64 in data URI".to_string()))
+}
+
+fn decode_base64(data: &str)->Option<Vec<u8>>{
+    let mut bytes = Vec::with_capacity(data.len() * 3 / 4);
+    let mut accumulator: u32 = 0;
+    let mut bits = 0;
+    for c in data.bytes().filter(|c| !c.is_ascii_whitespace()){
+        let value = match c{
+            b'A'..=b'Z'=>c - b'A',
+            b'a'..=b'z'=>c - b'a' + 26,
+            b'0'..=b'9'=>c - b'0' + 52,
+            b'+' | b'-'=>62,
+            b'/' | b'_'=>63,
+            b'='=>break,
+            _=>return None,
+        };
+        accumulator = (accumulator << 6) | value as u32;
+        bits += 6;
+        if bits >= 8{
+            bits -= 8;
+            bytes.push((accumulator >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const PYRAMID: &[u8] = include_bytes!("../assets/models/pyramid.glb");
+
+    // 3頂点のPOSITION(36バイト)と、u16のインデックス3つ(6バイト+詰め物2バイト)
+    const TRIANGLE_BUFFER: &str = "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAABAAIAAAA=";
+
+    fn triangle_json(position_count: &str)->String{
+        format!(r#"{{
+            "asset": {{"version": "2.0"}},
+            "nodes": [{{"name": "triangle", "mesh": 0, "translation": [0, 1, 0]}}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}, "indices": 1}}]}}],
+            "accessors": [
+                {{"bufferView": 0, "componentType": 5126, "count": {}, "type": "VEC3"}},
+                {{"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}}
+            ],
+            "bufferViews": [
+                {{"buffer": 0, "byteOffset": 0, "byteLength": 36}},
+                {{"buffer": 0, "byteOffset": 36, "byteLength": 6}}
+            ],
+            "buffers": [{{"uri": "{}", "byteLength": 44}}]
+        }}"#, position_count, TRIANGLE_BUFFER)
+    }
+
+    fn load(bytes: &[u8])->Result<GltfAsset,GltfError>{
+        GltfDocument::from_slice(bytes)?.load(&HashMap::new())
+    }
+
+    #[test]
+    fn loads_sample_glb(){
+        let document = GltfDocument::from_slice(PYRAMID).unwrap();
+        assert!(document.external_uris().is_empty());
+        let asset = document.load(&HashMap::new()).unwrap();
+
+        assert_eq!(asset.meshes.len(), 2);
+        let colored = &asset.meshes[0].primitives[0];
+        assert_eq!(colored.positions.len(), 5);
+        assert_eq!(colored.positions.iter().map(|position| position[1]).fold(0.0, f32::max), 1.0);
+        assert_eq!(colored.colors.as_ref().map(|colors| colors.len()), Some(5));
+        assert_eq!(colored.indices.as_ref().map(|indices| indices.len()), Some(18));
+        assert_eq!(colored.indices_u16().unwrap().len(), 18);
+        assert_eq!(colored.material, None);
+
+        let tinted = &asset.meshes[1].primitives[0];
+        let material = asset.material(tinted);
+        assert_eq!(material.name, "orange");
+        assert_eq!(material.base_color_factor, [1.0,0.5,0.1,1.0]);
+        assert_eq!(material.metallic_factor, 0.0);
+
+        assert_eq!(asset.default_scene, Some(0));
+        assert_eq!(asset.root_nodes(), vec![0]);
+        assert_eq!(asset.nodes[0].transform.translation, [0.0,0.0,-1.5]);
+        assert_eq!(asset.nodes[0].children, vec![1]);
+        // matrixで指定したノードはT・R・Sに分解される
+        let top = asset.nodes[1].transform;
+        assert_eq!(top.translation, [0.0,2.0,0.0]);
+        assert_eq!(top.scale, [0.5,0.5,0.5]);
+        assert!((top.rotation[0].abs() - 1.0).abs() < 1e-5, "{:?}", top.rotation);
+
+        let mut scene = Scene::new();
+        let roots = asset.instantiate(&mut scene, None, &[vec![MeshHandle(7)], vec![MeshHandle(8)]]);
+        assert_eq!(roots.len(), 1);
+        assert_eq!(scene.draw_items().iter().map(|item| item.mesh).collect::<Vec<_>>(), vec![MeshHandle(7), MeshHandle(8)]);
+    }
+
+    #[test]
+    fn loads_json_with_data_uri(){
+        let document = GltfDocument::from_slice(triangle_json("3").as_bytes()).unwrap();
+        assert!(document.external_uris().is_empty());
+        let asset = document.load(&HashMap::new()).unwrap();
+        let primitive = &asset.meshes[0].primitives[0];
+        assert_eq!(primitive.positions, vec![[0.0,0.0,0.0],[1.0,0.0,0.0],[0.0,1.0,0.0]]);
+        assert_eq!(primitive.indices, Some(vec![0,1,2]));
+        // シーンが無いので親を持たないノードがルートになる
+        assert_eq!(asset.root_nodes(), vec![0]);
+        assert_eq!(asset.nodes[0].transform.translation, [0.0,1.0,0.0]);
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_version(){
+        let mut bytes = PYRAMID.to_vec();
+        bytes[3] = b'X';
+        assert_eq!(GltfDocument::from_glb(&bytes).unwrap_err(), GltfError::InvalidGlb("magic is not `glTF`".to_string()));
+        // マジックが違えばJSONとして読もうとする
+        assert!(matches!(GltfDocument::from_slice(&bytes), Err(GltfError::Json(_))));
+
+        let mut bytes = PYRAMID.to_vec();
+        bytes[4] = 1;
+        assert!(matches!(GltfDocument::from_glb(&bytes), Err(GltfError::InvalidGlb(reason)) if reason.contains("version 1")));
+        assert!(matches!(GltfDocument::from_glb(&PYRAMID[..8]), Err(GltfError::InvalidGlb(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_chunks(){
+        assert_eq!(GltfDocument::from_slice(&PYRAMID[..100]).unwrap_err(), GltfError::InvalidGlb("chunk exceeds file length".to_string()));
+        // JSONチャンクは残っていても、BINチャンクが途中で切れている
+        let json_length = read_u32(PYRAMID, 12) as usize;
+        let truncated = &PYRAMID[..20 + json_length + 8 + 16];
+        assert_eq!(GltfDocument::from_slice(truncated).unwrap_err(), GltfError::InvalidGlb("chunk exceeds file length".to_string()));
+        // BINチャンクが無ければバッファが足りない
+        let without_bin = &PYRAMID[..20 + json_length];
+        assert!(matches!(load(without_bin), Err(GltfError::MissingBuffer(_))));
+    }
+
+    #[test]
+    fn rejects_overflowing_chunk_length(){
+        let mut bytes = Vec::new();
+        for word in [GLB_MAGIC, 2, 28, 0xFFFF_FFF8, GLB_CHUNK_JSON]{
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(b"{}  ");
+        assert_eq!(GltfDocument::from_glb(&bytes).unwrap_err(), GltfError::InvalidGlb("chunk exceeds file length".to_string()));
+    }
+
+    #[test]
+    fn rejects_out_of_range_accessors(){
+        assert!(matches!(load(triangle_json("4").as_bytes()), Err(GltfError::InvalidAccessor{accessor: 0, reason}) if reason == "data exceeds bufferView"));
+        // 桁あふれするcountでもパニックせずにエラーにする
+        let huge = usize::MAX.to_string();
+        assert!(matches!(load(triangle_json(&huge).as_bytes()), Err(GltfError::InvalidAccessor{accessor: 0, ..})));
+        let huge = (usize::MAX / 12 + 2).to_string();
+        assert!(matches!(load(triangle_json(&huge).as_bytes()), Err(GltfError::InvalidAccessor{accessor: 0, ..})));
+
+        // bufferViewの無いアクセサはゼロで埋めるが、巨大なcountは確保せずに拒否する
+        let zeros = |count: &str| triangle_json(count).replacen(r#""bufferView": 0, "#, "", 1);
+        let asset = load(zeros("3").as_bytes()).unwrap();
+        assert_eq!(asset.meshes[0].primitives[0].positions, vec![[0.0;3];3]);
+        assert!(matches!(load(zeros("100000000").as_bytes()), Err(GltfError::InvalidAccessor{accessor: 0, reason}) if reason.contains("too large")));
+
+        let missing = triangle_json("3").replace(r#""POSITION": 0"#, r#""POSITION": 5"#);
+        assert_eq!(load(missing.as_bytes()).unwrap_err(), GltfError::InvalidAccessor{accessor: 5, reason: "does not exist".to_string()});
+        let bad_index = triangle_json("2");
+        assert!(matches!(load(bad_index.as_bytes()), Err(GltfError::Json(reason)) if reason.contains("out of range")));
+    }
+}
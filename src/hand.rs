@@ -0,0 +1,443 @@
+use web_sys::*;
+use crate::input::{Handedness,InputSourceState,Pose};
+
+// ハンドトラッキング
+// XRHandの25個の関節の姿勢を読み、ピンチ・グー・指差しを判定する
+// 判定はHandSkeletonだけを使うので、記録した関節データでもそのまま確かめられる
+
+// セッション作成時に要求する機能名
+pub const HAND_TRACKING_FEATURE: &str = "hand-tracking";
+
+pub const JOINT_COUNT: usize = 25;
+
+// WebXR Hand Input の関節。並び順は仕様と同じ
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum HandJoint{
+    Wrist,
+    ThumbMetacarpal,
+    ThumbPhalanxProximal,
+    ThumbPhalanxDistal,
+    ThumbTip,
+    IndexFingerMetacarpal,
+    IndexFingerPhalanxProximal,
+    IndexFingerPhalanxIntermediate,
+    IndexFingerPhalanxDistal,
+    IndexFingerTip,
+    MiddleFingerMetacarpal,
+    MiddleFingerPhalanxProximal,
+    MiddleFingerPhalanxIntermediate,
+    MiddleFingerPhalanxDistal,
+    MiddleFingerTip,
+    RingFingerMetacarpal,
+    RingFingerPhalanxProximal,
+    RingFingerPhalanxIntermediate,
+    RingFingerPhalanxDistal,
+    RingFingerTip,
+    PinkyFingerMetacarpal,
+    PinkyFingerPhalanxProximal,
+    PinkyFingerPhalanxIntermediate,
+    PinkyFingerPhalanxDistal,
+    PinkyFingerTip,
+}
+
+impl HandJoint{
+    pub const ALL: [HandJoint; JOINT_COUNT] = [
+        HandJoint::Wrist,
+        HandJoint::ThumbMetacarpal,
+        HandJoint::ThumbPhalanxProximal,
+        HandJoint::ThumbPhalanxDistal,
+        HandJoint::ThumbTip,
+        HandJoint::IndexFingerMetacarpal,
+        HandJoint::IndexFingerPhalanxProximal,
+        HandJoint::IndexFingerPhalanxIntermediate,
+        HandJoint::IndexFingerPhalanxDistal,
+        HandJoint::IndexFingerTip,
+        HandJoint::MiddleFingerMetacarpal,
+        HandJoint::MiddleFingerPhalanxProximal,
+        HandJoint::MiddleFingerPhalanxIntermediate,
+        HandJoint::MiddleFingerPhalanxDistal,
+        HandJoint::MiddleFingerTip,
+        HandJoint::RingFingerMetacarpal,
+        HandJoint::RingFingerPhalanxProximal,
+        HandJoint::RingFingerPhalanxIntermediate,
+        HandJoint::RingFingerPhalanxDistal,
+        HandJoint::RingFingerTip,
+        HandJoint::PinkyFingerMetacarpal,
+        HandJoint::PinkyFingerPhalanxProximal,
+        HandJoint::PinkyFingerPhalanxIntermediate,
+        HandJoint::PinkyFingerPhalanxDistal,
+        HandJoint::PinkyFingerTip,
+    ];
+
+    pub fn index(&self)->usize{
+        *self as usize
+    }
+
+    pub fn to_xr(&self)->XrHandJoint{
+        match self{
+            HandJoint::Wrist=>XrHandJoint::Wrist,
+            HandJoint::ThumbMetacarpal=>XrHandJoint::ThumbMetacarpal,
+            HandJoint::ThumbPhalanxProximal=>XrHandJoint::ThumbPhalanxProximal,
+            HandJoint::ThumbPhalanxDistal=>XrHandJoint::ThumbPhalanxDistal,
+            HandJoint::ThumbTip=>XrHandJoint::ThumbTip,
+            HandJoint::IndexFingerMetacarpal=>XrHandJoint::IndexFingerMetacarpal,
+            HandJoint::IndexFingerPhalanxProximal=>XrHandJoint::IndexFingerPhalanxProximal,
+            HandJoint::IndexFingerPhalanxIntermediate=>XrHandJoint::IndexFingerPhalanxIntermediate,
+            HandJoint::IndexFingerPhalanxDistal=>XrHandJoint::IndexFingerPhalanxDistal,
+            HandJoint::IndexFingerTip=>XrHandJoint::IndexFingerTip,
+            HandJoint::MiddleFingerMetacarpal=>XrHandJoint::MiddleFingerMetacarpal,
+            HandJoint::MiddleFingerPhalanxProximal=>XrHandJoint::MiddleFingerPhalanxProximal,
+            HandJoint::MiddleFingerPhalanxIntermediate=>XrHandJoint::MiddleFingerPhalanxIntermediate,
+            HandJoint::MiddleFingerPhalanxDistal=>XrHandJoint::MiddleFingerPhalanxDistal,
+            HandJoint::MiddleFingerTip=>XrHandJoint::MiddleFingerTip,
+            HandJoint::RingFingerMetacarpal=>XrHandJoint::RingFingerMetacarpal,
+            HandJoint::RingFingerPhalanxProximal=>XrHandJoint::RingFingerPhalanxProximal,
+            HandJoint::RingFingerPhalanxIntermediate=>XrHandJoint::RingFingerPhalanxIntermediate,
+            HandJoint::RingFingerPhalanxDistal=>XrHandJoint::RingFingerPhalanxDistal,
+            HandJoint::RingFingerTip=>XrHandJoint::RingFingerTip,
+            HandJoint::PinkyFingerMetacarpal=>XrHandJoint::PinkyFingerMetacarpal,
+            HandJoint::PinkyFingerPhalanxProximal=>XrHandJoint::PinkyFingerPhalanxProximal,
+            HandJoint::PinkyFingerPhalanxIntermediate=>XrHandJoint::PinkyFingerPhalanxIntermediate,
+            HandJoint::PinkyFingerPhalanxDistal=>XrHandJoint::PinkyFingerPhalanxDistal,
+            HandJoint::PinkyFingerTip=>XrHandJoint::PinkyFingerTip,
+        }
+    }
+}
+
+// 親指以外の4本の指
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Finger{
+    Index,
+    Middle,
+    Ring,
+    Pinky,
+}
+
+impl Finger{
+    pub const ALL: [Finger; 4] = [Finger::Index, Finger::Middle, Finger::Ring, Finger::Pinky];
+
+    // 付け根から指先までの関節
+    pub fn joints(&self)->[HandJoint; 5]{
+        match self{
+            Finger::Index=>[HandJoint::IndexFingerMetacarpal, HandJoint::IndexFingerPhalanxProximal, HandJoint::IndexFingerPhalanxIntermediate, HandJoint::IndexFingerPhalanxDistal, HandJoint::IndexFingerTip],
+            Finger::Middle=>[HandJoint::MiddleFingerMetacarpal, HandJoint::MiddleFingerPhalanxProximal, HandJoint::MiddleFingerPhalanxIntermediate, HandJoint::MiddleFingerPhalanxDistal, HandJoint::MiddleFingerTip],
+            Finger::Ring=>[HandJoint::RingFingerMetacarpal, HandJoint::RingFingerPhalanxProximal, HandJoint::RingFingerPhalanxIntermediate, HandJoint::RingFingerPhalanxDistal, HandJoint::RingFingerTip],
+            Finger::Pinky=>[HandJoint::PinkyFingerMetacarpal, HandJoint::PinkyFingerPhalanxProximal, HandJoint::PinkyFingerPhalanxIntermediate, HandJoint::PinkyFingerPhalanxDistal, HandJoint::PinkyFingerTip],
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct JointPose{
+    pub pose: Pose,
+    // 関節の半径(メートル)
+    pub radius: f32,
+}
+
+// 片手の全ての関節の姿勢。1つでも追跡できていない関節があるフレームでは作らない
+#[derive(Debug,Clone,PartialEq)]
+pub struct HandSkeleton{
+    pub handedness: Handedness,
+    joints: [JointPose; JOINT_COUNT],
+}
+
+impl HandSkeleton{
+    // jointsはHandJoint::ALLの順に並べる
+    pub fn new(handedness: Handedness, joints: [JointPose; JOINT_COUNT])->Self{
+        HandSkeleton{handedness, joints}
+    }
+
+    pub fn from_xr(handedness: Handedness, hand: &XrHand, frame: &XrFrame, reference_space: &XrReferenceSpace)->Option<Self>{
+        let mut joints = Vec::with_capacity(JOINT_COUNT);
+        for joint in HandJoint::ALL{
+            let joint_pose = frame.get_joint_pose(&hand.get(joint.to_xr()), reference_space)?;
+            joints.push(JointPose{
+                pose: Pose::from_transform(&joint_pose.transform()),
+                radius: joint_pose.radius(),
+            });
+        }
+        Some(HandSkeleton::new(handedness, joints.try_into().ok()?))
+    }
+
+    pub fn joint(&self, joint: HandJoint)->&JointPose{
+        &self.joints[joint.index()]
+    }
+
+    pub fn joints(&self)->&[JointPose; JOINT_COUNT]{
+        &self.joints
+    }
+
+    pub fn position(&self, joint: HandJoint)->[f32; 3]{
+        self.joint(joint).pose.position
+    }
+
+    // 2つの関節の表面どうしの距離
+    pub fn gap(&self, a: HandJoint, b: HandJoint)->f32{
+        let distance = distance(self.position(a), self.position(b));
+        (distance - self.joint(a).radius - self.joint(b).radius).max(0.0)
+    }
+
+    // 指の伸び具合。手首から指先までの直線距離と、骨に沿った長さの比
+    // まっすぐ伸ばすと1に近く、握り込むと小さくなる
+    pub fn extension(&self, finger: Finger)->f32{
+        let joints = finger.joints();
+        let mut along_bones = distance(self.position(HandJoint::Wrist), self.position(joints[0]));
+        for pair in joints.windows(2){
+            along_bones += distance(self.position(pair[0]), self.position(pair[1]));
+        }
+        if along_bones <= f32::EPSILON{
+            return 0.0;
+        }
+        distance(self.position(HandJoint::Wrist), self.position(joints[4])) / along_bones
+    }
+}
+
+fn distance(a: [f32; 3], b: [f32; 3])->f32{
+    let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt()
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct GestureThresholds{
+    // 親指と人差し指の指先の隙間がこれ以下でピンチ開始(メートル)
+    pub pinch_start: f32,
+    // 一度ピンチしたら、これを超えるまで離したことにしない
+    pub pinch_end: f32,
+    // extensionがこれ以上なら伸ばしている
+    pub extended: f32,
+    // extensionがこれ以下なら曲げている
+    pub curled: f32,
+}
+
+impl Default for GestureThresholds{
+    fn default()->Self{
+        GestureThresholds{
+            pinch_start: 0.01,
+            pinch_end: 0.03,
+            extended: 0.85,
+            curled: 0.65,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct HandGestures{
+    pub pinch: bool,
+    // 4本の指を全て握っている
+    pub grab: bool,
+    // 人差し指だけを伸ばしている
+    pub point: bool,
+    // 0.0(離れている)から1.0(くっついている)
+    pub pinch_strength: f32,
+}
+
+// ジェスチャーの判定。ピンチは前のフレームの状態を使ってばたつきを抑える
+pub fn classify(skeleton: &HandSkeleton, thresholds: &GestureThresholds, was_pinching: bool)->HandGestures{
+    let gap = skeleton.gap(HandJoint::ThumbTip, HandJoint::IndexFingerTip);
+    let pinch = if was_pinching{gap <= thresholds.pinch_end} else{gap <= thresholds.pinch_start};
+    let pinch_strength = 1.0 - ((gap - thresholds.pinch_start) / (thresholds.pinch_end - thresholds.pinch_start)).clamp(0.0, 1.0);
+
+    let curled = |finger: Finger| skeleton.extension(finger) <= thresholds.curled;
+    let extended = |finger: Finger| skeleton.extension(finger) >= thresholds.extended;
+    let grab = !pinch && Finger::ALL.iter().all(|finger| curled(*finger));
+    let point = !pinch && extended(Finger::Index) && [Finger::Middle, Finger::Ring, Finger::Pinky].iter().all(|finger| curled(*finger));
+
+    HandGestures{pinch, grab, point, pinch_strength}
+}
+
+// 片手のジェスチャーの状態
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct HandState{
+    pub skeleton: Option<HandSkeleton>,
+    pub gestures: HandGestures,
+    // このフレームでピンチを始めた・やめた
+    pub pinch_started: bool,
+    pub pinch_ended: bool,
+}
+
+impl HandState{
+    // 手を見失ったフレームはジェスチャーを全て解除する
+    pub fn update(&mut self, skeleton: Option<&HandSkeleton>, thresholds: &GestureThresholds){
+        let was_pinching = self.gestures.pinch;
+        self.gestures = match skeleton{
+            Some(skeleton)=>classify(skeleton, thresholds, was_pinching),
+            None=>HandGestures::default(),
+        };
+        self.skeleton = skeleton.cloned();
+        self.pinch_started = self.gestures.pinch && !was_pinching;
+        self.pinch_ended = !self.gestures.pinch && was_pinching;
+    }
+}
+
+// 両手
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Hands{
+    pub left: HandState,
+    pub right: HandState,
+    pub thresholds: GestureThresholds,
+}
+
+impl Hands{
+    pub fn new(thresholds: GestureThresholds)->Self{
+        Hands{
+            thresholds,
+            ..Hands::default()
+        }
+    }
+
+    pub fn hand(&self, handedness: Handedness)->Option<&HandState>{
+        match handedness{
+            Handedness::Left=>Some(&self.left),
+            Handedness::Right=>Some(&self.right),
+            Handedness::None=>None,
+        }
+    }
+
+    // アニメーションループで入力ソースを更新した後に呼ぶ
+    pub fn update(&mut self, sources: &[InputSourceState]){
+        let skeleton = |handedness: Handedness| sources.iter()
+            .find(|source| source.handedness == handedness)
+            .and_then(|source| source.hand.as_ref());
+        self.left.update(skeleton(Handedness::Left), &self.thresholds);
+        self.right.update(skeleton(Handedness::Right), &self.thresholds);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::input::TargetRayMode;
+
+    const TIP_RADIUS: f32 = 0.008;
+    const JOINT_RADIUS: f32 = 0.01;
+    // 曲げない・握り込む関節1つあたりの角度(度)
+    const STRAIGHT: f32 = 0.0;
+    const CURLED: f32 = 80.0;
+    // 人差し指の指先から離した親指の指先
+    const THUMB_AWAY: [f32; 3] = [0.07, 0.0, -0.06];
+
+    // 右手を手のひらを下にして前(-Z)へ伸ばした関節の並び
+    // 指ごとに、付け根の関節から先の3つの関節をbend度ずつ手のひら側(-Y)へ曲げる
+    fn fixture(bends: [f32; 4], thumb_tip: [f32; 3])->HandSkeleton{
+        let mut positions = [[0.0f32; 3]; JOINT_COUNT];
+        positions[HandJoint::ThumbMetacarpal.index()] = [0.02, 0.0, -0.01];
+        positions[HandJoint::ThumbPhalanxProximal.index()] = [0.04, 0.0, -0.03];
+        positions[HandJoint::ThumbPhalanxDistal.index()] = [0.05, 0.0, -0.05];
+        positions[HandJoint::ThumbTip.index()] = thumb_tip;
+        for (finger, (x, bend)) in Finger::ALL.iter().zip([0.02, 0.0, -0.02, -0.04].into_iter().zip(bends)){
+            let joints = finger.joints();
+            positions[joints[0].index()] = [x * 0.5, 0.0, -0.01];
+            let mut position = [x, 0.0, -0.08];
+            positions[joints[1].index()] = position;
+            let mut angle = 0.0f32;
+            for (joint, length) in joints[2..].iter().zip([0.04, 0.025, 0.02]){
+                angle += bend.to_radians();
+                position = [position[0], position[1] - length * angle.sin(), position[2] - length * angle.cos()];
+                positions[joint.index()] = position;
+            }
+        }
+        let joints = std::array::from_fn(|index|{
+            let tip = [HandJoint::ThumbTip, HandJoint::IndexFingerTip, HandJoint::MiddleFingerTip, HandJoint::RingFingerTip, HandJoint::PinkyFingerTip]
+                .iter().any(|joint| joint.index() == index);
+            JointPose{
+                pose: Pose{position: positions[index], ..Pose::default()},
+                radius: if tip{TIP_RADIUS} else{JOINT_RADIUS},
+            }
+        });
+        HandSkeleton::new(Handedness::Right, joints)
+    }
+
+    // 親指の指先を、人差し指の指先から表面どうしの隙間がgapになる位置に置く
+    fn pinch_fixture(gap: f32)->HandSkeleton{
+        let open = fixture([STRAIGHT; 4], THUMB_AWAY);
+        let index_tip = open.position(HandJoint::IndexFingerTip);
+        fixture([STRAIGHT; 4], [index_tip[0] + gap + 2.0 * TIP_RADIUS, index_tip[1], index_tip[2]])
+    }
+
+    fn source(hand: Option<HandSkeleton>)->InputSourceState{
+        InputSourceState{
+            handedness: Handedness::Right,
+            target_ray_mode: TargetRayMode::TrackedPointer,
+            profiles: Vec::new(),
+            grip_pose: None,
+            target_ray_pose: None,
+            gamepad: None,
+            hand,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn measures_finger_extension(){
+        let thresholds = GestureThresholds::default();
+        let open = fixture([STRAIGHT; 4], THUMB_AWAY);
+        let fist = fixture([CURLED; 4], THUMB_AWAY);
+        for finger in Finger::ALL{
+            assert!(open.extension(finger) >= thresholds.extended, "{:?} {}", finger, open.extension(finger));
+            assert!(fist.extension(finger) <= thresholds.curled, "{:?} {}", finger, fist.extension(finger));
+        }
+        // 半分だけ曲げた指は、伸ばしても曲げてもいない
+        let half = fixture([40.0; 4], THUMB_AWAY).extension(Finger::Middle);
+        assert!(half > thresholds.curled && half < thresholds.extended, "{}", half);
+        assert!((pinch_fixture(0.005).gap(HandJoint::ThumbTip, HandJoint::IndexFingerTip) - 0.005).abs() < 1e-6);
+    }
+
+    #[test]
+    fn classifies_open_hand(){
+        let gestures = classify(&fixture([STRAIGHT; 4], THUMB_AWAY), &GestureThresholds::default(), false);
+        assert_eq!(gestures, HandGestures{pinch: false, grab: false, point: false, pinch_strength: 0.0});
+    }
+
+    #[test]
+    fn classifies_grab_and_point(){
+        let thresholds = GestureThresholds::default();
+        let grab = classify(&fixture([CURLED; 4], THUMB_AWAY), &thresholds, false);
+        assert!(grab.grab && !grab.point && !grab.pinch);
+
+        let point = classify(&fixture([STRAIGHT, CURLED, CURLED, CURLED], THUMB_AWAY), &thresholds, false);
+        assert!(point.point && !point.grab && !point.pinch);
+        // 中指も伸ばしていたら指差しではない
+        let two_fingers = classify(&fixture([STRAIGHT, STRAIGHT, CURLED, CURLED], THUMB_AWAY), &thresholds, false);
+        assert!(!two_fingers.point && !two_fingers.grab);
+    }
+
+    #[test]
+    fn pinch_uses_hysteresis(){
+        let thresholds = GestureThresholds::default();
+        // pinch_start以下で始まる
+        let touching = classify(&pinch_fixture(0.005), &thresholds, false);
+        assert!(touching.pinch);
+        assert_eq!(touching.pinch_strength, 1.0);
+        // ピンチ中は他のジェスチャーにならない
+        assert!(!touching.grab && !touching.point);
+
+        // pinch_startとpinch_endの間では、前のフレームの状態を保つ
+        let between = pinch_fixture(0.02);
+        assert!(!classify(&between, &thresholds, false).pinch);
+        assert!(classify(&between, &thresholds, true).pinch);
+        assert!((classify(&between, &thresholds, false).pinch_strength - 0.5).abs() < 1e-3);
+        // pinch_endを超えたら離す
+        assert!(!classify(&pinch_fixture(0.035), &thresholds, true).pinch);
+        assert!(classify(&pinch_fixture(0.029), &thresholds, true).pinch);
+        assert!(!classify(&pinch_fixture(0.011), &thresholds, false).pinch);
+    }
+
+    #[test]
+    fn hand_state_reports_pinch_edges(){
+        let mut hands = Hands::new(GestureThresholds::default());
+        let frames = [pinch_fixture(0.04), pinch_fixture(0.005), pinch_fixture(0.02), pinch_fixture(0.04)];
+        let mut edges = Vec::new();
+        for skeleton in frames{
+            hands.update(&[source(Some(skeleton))]);
+            edges.push((hands.right.gestures.pinch, hands.right.pinch_started, hands.right.pinch_ended));
+        }
+        assert_eq!(edges, vec![(false, false, false), (true, true, false), (true, false, false), (false, false, true)]);
+
+        // 手を見失ったら離したことになる
+        hands.update(&[source(Some(pinch_fixture(0.005)))]);
+        hands.update(&[source(None)]);
+        assert!(hands.right.pinch_ended);
+        assert!(hands.right.skeleton.is_none());
+        assert_eq!(hands.left, HandState::default());
+    }
+}
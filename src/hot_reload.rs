@@ -0,0 +1,160 @@
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+#[cfg(target_arch = "wasm32")]
+use futures::channel::mpsc;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash,Hasher};
+#[cfg(target_arch = "wasm32")]
+use crate::load_shader_with_query;
+use crate::preprocess::PreprocessedSource;
+
+// 開発用のシェーダのホットリロード
+// shader/*.glslを定期的にfetchし、内容が変わったときだけアニメーションループに送る
+
+// ポーリング間隔(ミリ秒)
+pub const POLL_INTERVAL_MS: i32 = 1000;
+
+// ページのURLにこのクエリが含まれているときだけ有効にする
+pub const HOT_RELOAD_QUERY: &str = "hot-reload";
+
+// 再コンパイル対象のシェーダソース一式(#include展開済み)
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ShaderSources{
+    pub vertex: PreprocessedSource,
+    pub fragment: PreprocessedSource,
+}
+
+// 前回読み込んだソースのハッシュを覚えておき、変化したかを判定する
+#[derive(Debug,Clone,Default)]
+pub struct ChangeDetector{
+    last_hash: Option<u64>,
+}
+
+impl ChangeDetector{
+    pub fn new()->Self{
+        ChangeDetector::default()
+    }
+
+    // 基準と内容が違うか。基準がまだ無いときは変化なしとする
+    pub fn changed(&self, sources: &ShaderSources)->bool{
+        self.last_hash.is_some_and(|last_hash| last_hash != hash_sources(sources))
+    }
+
+    // 読み込んだソースを次の比較の基準にする
+    pub fn accept(&mut self, sources: &ShaderSources){
+        self.last_hash = Some(hash_sources(sources));
+    }
+}
+
+fn hash_sources(sources: &ShaderSources)->u64{
+    let mut hasher = DefaultHasher::new();
+    sources.vertex.code.hash(&mut hasher);
+    sources.fragment.code.hash(&mut hasher);
+    hasher.finish()
+}
+
+// ページのURLでホットリロードが有効にされているか
+pub fn hot_reload_enabled(window: &Window)->bool{
+    let Ok(search) = window.location().search() else{
+        return false;
+    };
+    search.trim_start_matches('?').split('&').any(|parameter| parameter.split('=').next() == Some(HOT_RELOAD_QUERY))
+}
+
+// ポーリングを開始し、変更されたシェーダを受け取るReceiverを返す
+// compiledは今のプログラムのソースで、これと違う内容が読み込まれたときだけ送る
+// インクルードされたファイルの変更も、展開後のソースの差分として検出される
+#[cfg(target_arch = "wasm32")]
+pub fn watch_shaders(window: &Window, vertex_path: &str, fragment_path: &str, compiled: &ShaderSources)->mpsc::Receiver<ShaderSources>{
+    let (mut sources_tx, sources_rx) = mpsc::channel::<ShaderSources>(1);
+    let window = window.clone();
+    let vertex_path = vertex_path.to_string();
+    let fragment_path = fragment_path.to_string();
+    // 最初のポーリングまでに編集された場合も検出できるように、コンパイルしたソースを基準にする
+    let mut detector = ChangeDetector::new();
+    detector.accept(compiled);
+
+    wasm_bindgen_futures::spawn_local(async move{
+        console::log_1(&"Shader hot reload is enabled".into());
+        loop{
+            // セッションが終わってReceiverが破棄されたら、fetchせずに止める
+            if sources_tx.is_closed(){
+                return;
+            }
+            // ブラウザのキャッシュを避けるために毎回クエリを変える
+            let cache_buster = format!("?t={}", js_sys::Date::now());
+            let vertex = load_shader_with_query(window.clone(), &vertex_path, &cache_buster).await;
+            let fragment = load_shader_with_query(window.clone(), &fragment_path, &cache_buster).await;
+            if let (Ok(vertex), Ok(fragment)) = (vertex, fragment){
+                let sources = ShaderSources{vertex, fragment};
+                if detector.changed(&sources){
+                    console::log_1(&"Shader change detected".into());
+                    match sources_tx.try_send(sources.clone()){
+                        Ok(())=>detector.accept(&sources),
+                        // セッションが終わってReceiverが破棄された
+                        Err(error) if error.is_disconnected()=>return,
+                        // 前の変更がまだ受け取られていないので、次のポーリングで送り直す
+                        Err(_)=>{},
+                    }
+                }
+            }
+
+            if sleep(&window, POLL_INTERVAL_MS).await.is_err(){
+                console::log_1(&"[Error] Could not schedule shader polling".into());
+                return;
+            }
+        }
+    });
+    sources_rx
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn sleep(window: &Window, milliseconds: i32)->Result<(),JsValue>{
+    let promise = js_sys::Promise::new(&mut |resolve, _reject|{
+        let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, milliseconds);
+    });
+    wasm_bindgen_futures::JsFuture::from(promise).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::collections::HashMap;
+    use crate::preprocess;
+
+    fn preprocessed(code: &str)->PreprocessedSource{
+        let files = HashMap::from([("shader.glsl".to_string(), code.to_string())]);
+        preprocess::resolve_includes("shader.glsl", &files).unwrap()
+    }
+
+    fn sources(fragment: &str)->ShaderSources{
+        ShaderSources{vertex: preprocessed("void main(){}"), fragment: preprocessed(fragment)}
+    }
+
+    #[test]
+    fn first_poll_without_baseline_is_not_a_change(){
+        let detector = ChangeDetector::new();
+        assert!(!detector.changed(&sources("a")));
+    }
+
+    #[test]
+    fn unchanged_poll_is_not_a_change(){
+        let mut detector = ChangeDetector::new();
+        detector.accept(&sources("a"));
+        assert!(!detector.changed(&sources("a")));
+    }
+
+    #[test]
+    fn changed_poll_is_detected_until_accepted(){
+        let mut detector = ChangeDetector::new();
+        detector.accept(&sources("a"));
+        assert!(detector.changed(&sources("b")));
+        // 送れなかった変更は、次のポーリングでも変化として扱う
+        assert!(detector.changed(&sources("b")));
+        detector.accept(&sources("b"));
+        assert!(!detector.changed(&sources("b")));
+        assert!(detector.changed(&sources("a")));
+    }
+}
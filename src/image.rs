@@ -0,0 +1,489 @@
+// RGBA8の画像と、PNGの読み書き・比較
+// ソフトウェアラスタライザの出力を参照画像と比べるために使う。web-sysには依存しない
+// PNGは8bitのRGB・RGBA(インターレース無し)だけを読み、書き出しは無圧縮のdeflateで行う
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+// 無圧縮のdeflateブロックに入る最大のバイト数
+const STORED_BLOCK_SIZE: usize = 65535;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ImageError{
+    InvalidPng(String),
+    // 8bitのRGB・RGBA以外の形式
+    Unsupported(String),
+    SizeMismatch{expected: (u32, u32), actual: (u32, u32)},
+}
+
+impl std::fmt::Display for ImageError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            ImageError::InvalidPng(reason)=>write!(f, "invalid PNG: {}", reason),
+            ImageError::Unsupported(reason)=>write!(f, "unsupported PNG: {}", reason),
+            ImageError::SizeMismatch{expected, actual}=>write!(f, "image is {}x{}, expected {}x{}", actual.0, actual.1, expected.0, expected.1),
+        }
+    }
+}
+
+impl std::error::Error for ImageError{}
+
+// 左上が原点で、行ごとに並べたピクセル
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Image{
+    width: u32,
+    height: u32,
+    pixels: Vec<[u8; 4]>,
+}
+
+impl Image{
+    pub fn new(width: u32, height: u32, color: [u8; 4])->Self{
+        Image{width, height, pixels: vec![color; width as usize * height as usize]}
+    }
+
+    pub fn width(&self)->u32{
+        self.width
+    }
+
+    pub fn height(&self)->u32{
+        self.height
+    }
+
+    pub fn pixels(&self)->&[[u8; 4]]{
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: u32, y: u32)->Option<[u8; 4]>{
+        if x >= self.width || y >= self.height{
+            return None;
+        }
+        Some(self.pixels[(y * self.width + x) as usize])
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: [u8; 4]){
+        if x < self.width && y < self.height{
+            self.pixels[(y * self.width + x) as usize] = color;
+        }
+    }
+
+    pub fn to_png(&self)->Vec<u8>{
+        // 各行の先頭にフィルタの種類(0: なし)を置く
+        let mut raw = Vec::with_capacity((self.width as usize * 4 + 1) * self.height as usize);
+        for row in self.pixels.chunks(self.width.max(1) as usize){
+            raw.push(0);
+            raw.extend(row.iter().flatten());
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        // ビット深度8、RGBA、圧縮・フィルタ・インターレースは既定
+        header.extend([8, 6, 0, 0, 0]);
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &header);
+        write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    pub fn from_png(bytes: &[u8])->Result<Self,ImageError>{
+        if !bytes.starts_with(&PNG_SIGNATURE){
+            return Err(ImageError::InvalidPng("missing signature".to_string()));
+        }
+        let mut position = PNG_SIGNATURE.len();
+        let mut header = None;
+        let mut data = Vec::new();
+        while position + 8 <= bytes.len(){
+            let length = u32::from_be_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+            let kind = &bytes[position + 4..position + 8];
+            let Some(body) = bytes.get(position + 8..position + 8 + length) else{
+                return Err(ImageError::InvalidPng("truncated chunk".to_string()));
+            };
+            match kind{
+                b"IHDR"=>header = Some(body.to_vec()),
+                b"IDAT"=>data.extend_from_slice(body),
+                b"IEND"=>break,
+                _=>{},
+            }
+            // CRCは読み飛ばす
+            position += 12 + length;
+        }
+        let Some(header) = header.filter(|header| header.len() == 13) else{
+            return Err(ImageError::InvalidPng("missing IHDR".to_string()));
+        };
+        let width = u32::from_be_bytes(header[0..4].try_into().unwrap());
+        let height = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let channels = match (header[8], header[9]){
+            (8, 6)=>4,
+            (8, 2)=>3,
+            (depth, color_type)=>return Err(ImageError::Unsupported(format!("bit depth {} with color type {}", depth, color_type))),
+        };
+        if header[12] != 0{
+            return Err(ImageError::Unsupported("interlaced image".to_string()));
+        }
+
+        let raw = zlib_decompress(&data)?;
+        let stride = width as usize * channels;
+        if raw.len() < (stride + 1) * height as usize{
+            return Err(ImageError::InvalidPng("image data is too short".to_string()));
+        }
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+        let mut previous = vec![0u8; stride];
+        for row in raw.chunks(stride + 1).take(height as usize){
+            let current = unfilter(row[0], &row[1..], &previous, channels)?;
+            for pixel in current.chunks(channels){
+                pixels.push([pixel[0], pixel[1], pixel[2], if channels == 4{ pixel[3] } else{ 255 }]);
+            }
+            previous = current;
+        }
+        Ok(Image{width, height, pixels})
+    }
+
+    // チャンネルごとの差がtoleranceを超えたピクセルを数える
+    pub fn compare(&self, other: &Image, tolerance: u8)->Result<ImageDiff,ImageError>{
+        if (self.width, self.height) != (other.width, other.height){
+            return Err(ImageError::SizeMismatch{expected: (self.width, self.height), actual: (other.width, other.height)});
+        }
+        let mut diff = ImageDiff::default();
+        for (expected, actual) in self.pixels.iter().zip(other.pixels.iter()){
+            let difference = expected.iter().zip(actual.iter()).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0);
+            diff.max_difference = diff.max_difference.max(difference);
+            if difference > tolerance{
+                diff.differing_pixels += 1;
+            }
+        }
+        Ok(diff)
+    }
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct ImageDiff{
+    pub differing_pixels: usize,
+    // 全ピクセル・全チャンネルでの最大の差
+    pub max_difference: u8,
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]){
+    png.extend((body.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(body);
+    let mut crc_input = kind.to_vec();
+    crc_input.extend(body);
+    png.extend(crc32(&crc_input).to_be_bytes());
+}
+
+fn crc32(bytes: &[u8])->u32{
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes{
+        crc ^= *byte as u32;
+        for _ in 0..8{
+            crc = if crc & 1 != 0{ (crc >> 1) ^ 0xedb8_8320 } else{ crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8])->u32{
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes{
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// 無圧縮のブロックだけでzlibのストリームを作る
+fn zlib_stored(data: &[u8])->Vec<u8>{
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none(){
+        stream.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next(){
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        stream.push(u8::from(last));
+        stream.extend(length.to_le_bytes());
+        stream.extend((!length).to_le_bytes());
+        stream.extend(block);
+    }
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+// PNGのフィルタを戻す
+fn unfilter(filter: u8, row: &[u8], previous: &[u8], channels: usize)->Result<Vec<u8>,ImageError>{
+    let mut current = row.to_vec();
+    for index in 0..current.len(){
+        let left = if index >= channels{ current[index - channels] } else{ 0 };
+        let up = previous[index];
+        let up_left = if index >= channels{ previous[index - channels] } else{ 0 };
+        let predictor = match filter{
+            0=>0,
+            1=>left,
+            2=>up,
+            3=>((left as u16 + up as u16) / 2) as u8,
+            4=>paeth(left, up, up_left),
+            _=>return Err(ImageError::InvalidPng(format!("unknown filter {}", filter))),
+        };
+        current[index] = current[index].wrapping_add(predictor);
+    }
+    Ok(current)
+}
+
+fn paeth(left: u8, up: u8, up_left: u8)->u8{
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (to_left, to_up, to_up_left) = ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+    if to_left <= to_up && to_left <= to_up_left{
+        left
+    }
+    else if to_up <= to_up_left{
+        up
+    }
+    else{
+        up_left
+    }
+}
+
+// deflate (RFC 1951) の展開
+// 画像編集ソフトで保存し直した参照画像も読めるように、固定・動的ハフマンにも対応する
+
+struct BitReader<'a>{
+    bytes: &'a [u8],
+    position: usize,
+    bit: u8,
+}
+
+impl BitReader<'_>{
+    fn bits(&mut self, count: u8)->Result<u32,ImageError>{
+        let mut value = 0;
+        for index in 0..count{
+            let Some(byte) = self.bytes.get(self.position) else{
+                return Err(ImageError::InvalidPng("compressed data is truncated".to_string()));
+            };
+            value |= (((byte >> self.bit) & 1) as u32) << index;
+            self.bit += 1;
+            if self.bit == 8{
+                self.bit = 0;
+                self.position += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self){
+        if self.bit != 0{
+            self.bit = 0;
+            self.position += 1;
+        }
+    }
+}
+
+// 符号長から作るハフマン符号。長さごとの個数と、符号順に並べた記号
+struct Huffman{
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman{
+    fn new(lengths: &[u8])->Self{
+        let mut counts = [0u16; 16];
+        for length in lengths{
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..16{
+            offsets[length] = offsets[length - 1] + counts[length - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate(){
+            if *length != 0{
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        Huffman{counts, symbols}
+    }
+
+    fn decode(&self, reader: &mut BitReader)->Result<u16,ImageError>{
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16{
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count{
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(ImageError::InvalidPng("invalid Huffman code".to_string()))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// 動的ハフマンで、符号長の符号長が並ぶ順番
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn zlib_decompress(data: &[u8])->Result<Vec<u8>,ImageError>{
+    if data.len() < 2 || data[0] & 0x0f != 8{
+        return Err(ImageError::InvalidPng("not a zlib stream".to_string()));
+    }
+    let mut reader = BitReader{bytes: &data[2..], position: 0, bit: 0};
+    let mut output = Vec::new();
+    loop{
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)?{
+            0=>{
+                reader.align();
+                let Some(header) = reader.bytes.get(reader.position..reader.position + 4) else{
+                    return Err(ImageError::InvalidPng("stored block is truncated".to_string()));
+                };
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let start = reader.position + 4;
+                let Some(block) = reader.bytes.get(start..start + length) else{
+                    return Err(ImageError::InvalidPng("stored block is truncated".to_string()));
+                };
+                output.extend_from_slice(block);
+                reader.position = start + length;
+            },
+            1=>{
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                inflate_block(&mut reader, &mut output, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            },
+            2=>{
+                let (literals, distances) = read_dynamic_tables(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            },
+            _=>return Err(ImageError::InvalidPng("invalid block type".to_string())),
+        }
+        if last{
+            return Ok(output);
+        }
+    }
+}
+
+fn read_dynamic_tables(reader: &mut BitReader)->Result<(Huffman, Huffman),ImageError>{
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(code_length_count){
+        code_lengths[*index] = reader.bits(3)? as u8;
+    }
+    let code_length_huffman = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count{
+        let symbol = code_length_huffman.decode(reader)?;
+        let (value, repeat) = match symbol{
+            0..=15=>(symbol as u8, 1),
+            16=>{
+                let Some(previous) = lengths.last().copied() else{
+                    return Err(ImageError::InvalidPng("repeat without a previous length".to_string()));
+                };
+                (previous, 3 + reader.bits(2)? as usize)
+            },
+            17=>(0, 3 + reader.bits(3)? as usize),
+            _=>(0, 11 + reader.bits(7)? as usize),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat));
+    }
+    if lengths.len() != literal_count + distance_count{
+        return Err(ImageError::InvalidPng("code lengths overflow".to_string()));
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman)->Result<(),ImageError>{
+    loop{
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256{
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256{
+            return Ok(());
+        }
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len(){
+            return Err(ImageError::InvalidPng("invalid length symbol".to_string()));
+        }
+        let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])? as usize;
+        let index = distances.decode(reader)? as usize;
+        if index >= DISTANCE_BASE.len(){
+            return Err(ImageError::InvalidPng("invalid distance symbol".to_string()));
+        }
+        let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index])? as usize;
+        if distance > output.len(){
+            return Err(ImageError::InvalidPng("distance is too far back".to_string()));
+        }
+        let start = output.len() - distance;
+        for offset in 0..length{
+            output.push(output[start + offset]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn gradient(width: u32, height: u32)->Image{
+        let mut image = Image::new(width, height, [0, 0, 0, 255]);
+        for y in 0..height{
+            for x in 0..width{
+                image.set_pixel(x, y, [(x * 7) as u8, (y * 11) as u8, (x ^ y) as u8, !(x as u8)]);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn png_round_trip_keeps_pixels(){
+        let image = gradient(37, 21);
+        let decoded = Image::from_png(&image.to_png()).unwrap();
+        assert_eq!(decoded, image);
+        // 1行が保存ブロックの上限を超える画像も往復できる
+        let wide = gradient(20000, 1);
+        assert_eq!(Image::from_png(&wide.to_png()).unwrap(), wide);
+    }
+
+    #[test]
+    fn decodes_compressed_and_filtered_png(){
+        // zlibで圧縮し、行ごとにSub・Up・Paethのフィルタをかけた4×3のRGB画像
+        let image = Image::from_png(include_bytes!("../tests/golden/filtered_rgb_4x3.png")).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 3));
+        for y in 0..3{
+            for x in 0..4{
+                assert_eq!(image.pixel(x, y), Some([(x * 60) as u8, (y * 100) as u8, ((x + y) * 30) as u8, 255]));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_png(){
+        let png = gradient(4, 4).to_png();
+        assert!(matches!(Image::from_png(&png[1..]), Err(ImageError::InvalidPng(_))));
+        assert!(matches!(Image::from_png(&png[..40]), Err(ImageError::InvalidPng(_))));
+        assert!(matches!(Image::from_png(&PNG_SIGNATURE), Err(ImageError::InvalidPng(_))));
+    }
+
+    #[test]
+    fn compare_counts_pixels_over_tolerance(){
+        let expected = gradient(8, 8);
+        let mut actual = expected.clone();
+        actual.set_pixel(1, 1, [10, 11, 0, 254]);
+        actual.set_pixel(2, 2, [14, 22, 0, 253]);
+        assert_eq!(expected.compare(&actual, 2).unwrap(), ImageDiff{differing_pixels: 1, max_difference: 3});
+        assert_eq!(expected.compare(&actual, 3).unwrap(), ImageDiff{differing_pixels: 0, max_difference: 3});
+        assert_eq!(expected.compare(&gradient(8, 4), 0), Err(ImageError::SizeMismatch{expected: (8, 8), actual: (8, 4)}));
+    }
+}
@@ -0,0 +1,303 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::*;
+use futures::channel::mpsc;
+use gl_matrix::common::Mat4;
+use gl_matrix::{mat4,quat,vec3};
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::gamepad::GamepadSnapshot;
+use crate::hand::HandSkeleton;
+
+// XRの入力ソース(コントローラー・手・視線)
+// 姿勢はフレームごとにXrFrameから読み、select/squeezeはイベントとしてチャンネルで受け取る
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Handedness{
+    None,
+    Left,
+    Right,
+}
+
+impl Handedness{
+    pub fn from_xr(handedness: XrHandedness)->Self{
+        match handedness{
+            XrHandedness::Left=>Handedness::Left,
+            XrHandedness::Right=>Handedness::Right,
+            _=>Handedness::None,
+        }
+    }
+}
+
+impl std::fmt::Display for Handedness{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            Handedness::None=>write!(f, "none"),
+            Handedness::Left=>write!(f, "left"),
+            Handedness::Right=>write!(f, "right"),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum TargetRayMode{
+    Gaze,
+    TrackedPointer,
+    Screen,
+}
+
+impl TargetRayMode{
+    pub fn from_xr(mode: XrTargetRayMode)->Self{
+        match mode{
+            XrTargetRayMode::Gaze=>TargetRayMode::Gaze,
+            XrTargetRayMode::Screen=>TargetRayMode::Screen,
+            _=>TargetRayMode::TrackedPointer,
+        }
+    }
+}
+
+// 参照空間での位置と向き
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Pose{
+    pub position: [f32; 3],
+    // クォータニオン (x, y, z, w)
+    pub orientation: [f32; 4],
+}
+
+impl Default for Pose{
+    fn default()->Self{
+        Pose{position: [0.0; 3], orientation: [0.0, 0.0, 0.0, 1.0]}
+    }
+}
+
+impl Pose{
+    pub fn from_transform(transform: &XrRigidTransform)->Self{
+        let position = transform.position();
+        let orientation = transform.orientation();
+        Pose{
+            position: [position.x() as f32, position.y() as f32, position.z() as f32],
+            orientation: [orientation.x() as f32, orientation.y() as f32, orientation.z() as f32, orientation.w() as f32],
+        }
+    }
+
+    pub fn matrix(&self)->Mat4{
+        let mut matrix = mat4::create();
+        mat4::from_rotation_translation(&mut matrix, &self.orientation, &self.position);
+        matrix
+    }
+
+    // 拡大縮小を含まない行列から読む
+    pub fn from_matrix(matrix: &Mat4)->Self{
+        let mut position = [0.0; 3];
+        mat4::get_translation(&mut position, matrix);
+        let mut orientation = [0.0, 0.0, 0.0, 1.0];
+        mat4::get_rotation(&mut orientation, matrix);
+        Pose{position, orientation}
+    }
+
+    // 位置は線形に、向きは球面線形に補間する
+    pub fn lerp(&self, other: &Pose, t: f32)->Pose{
+        let mut position = [0.0; 3];
+        vec3::lerp(&mut position, &self.position, &other.position, t);
+        let mut orientation = [0.0, 0.0, 0.0, 1.0];
+        quat::slerp(&mut orientation, &self.orientation, &other.orientation, t);
+        Pose{position, orientation}
+    }
+
+    // 姿勢の-Z方向。ターゲットレイの向きになる
+    pub fn forward(&self)->[f32; 3]{
+        let m = self.matrix();
+        [-m[8], -m[9], -m[10]]
+    }
+}
+
+fn get_pose(frame: &XrFrame, space: &XrSpace, reference_space: &XrReferenceSpace)->Option<Pose>{
+    frame.get_pose(space, reference_space).map(|pose| Pose::from_transform(&pose.transform()))
+}
+
+// 1つの入力ソースのこのフレームでの状態
+#[derive(Debug,Clone)]
+pub struct InputSourceState{
+    pub handedness: Handedness,
+    pub target_ray_mode: TargetRayMode,
+    // 入力プロファイル名。具体的なものから順に並ぶ (例: "oculus-touch-v3", "generic-trigger-squeeze-thumbstick")
+    pub profiles: Vec<String>,
+    // コントローラーを握っている位置。手やコントローラーを持たない入力ソースではNone
+    pub grip_pose: Option<Pose>,
+    // ポインティングに使うレイの始点と向き
+    pub target_ray_pose: Option<Pose>,
+    // ボタンと軸の値。ハンドトラッキングなどゲームパッドを持たない入力ソースではNone
+    pub gamepad: Option<GamepadSnapshot>,
+    // ハンドトラッキング中の関節。コントローラーを持っているときや追跡が外れたときはNone
+    pub hand: Option<HandSkeleton>,
+    // シミュレーターが作った入力ソースではNone
+    pub source: Option<XrInputSource>,
+}
+
+impl InputSourceState{
+    fn from_xr(source: XrInputSource, frame: &XrFrame, reference_space: &XrReferenceSpace)->Self{
+        let grip_pose = source.grip_space().and_then(|space| get_pose(frame, &space, reference_space));
+        let target_ray_pose = get_pose(frame, &source.target_ray_space(), reference_space);
+        let handedness = Handedness::from_xr(source.handedness());
+        let hand = source.hand().and_then(|hand| HandSkeleton::from_xr(handedness, &hand, frame, reference_space));
+        InputSourceState{
+            handedness,
+            target_ray_mode: TargetRayMode::from_xr(source.target_ray_mode()),
+            profiles: source.profiles().iter().filter_map(|profile| profile.as_string()).collect(),
+            grip_pose,
+            target_ray_pose,
+            gamepad: source.gamepad().map(|gamepad| GamepadSnapshot::from_gamepad(&gamepad)),
+            hand,
+            source: Some(source),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum InputEventKind{
+    SelectStart,
+    Select,
+    SelectEnd,
+    SqueezeStart,
+    Squeeze,
+    SqueezeEnd,
+}
+
+impl InputEventKind{
+    pub const ALL: [InputEventKind; 6] = [
+        InputEventKind::SelectStart,
+        InputEventKind::Select,
+        InputEventKind::SelectEnd,
+        InputEventKind::SqueezeStart,
+        InputEventKind::Squeeze,
+        InputEventKind::SqueezeEnd,
+    ];
+
+    // XrSessionのイベント名
+    pub fn event_type(&self)->&'static str{
+        match self{
+            InputEventKind::SelectStart=>"selectstart",
+            InputEventKind::Select=>"select",
+            InputEventKind::SelectEnd=>"selectend",
+            InputEventKind::SqueezeStart=>"squeezestart",
+            InputEventKind::Squeeze=>"squeeze",
+            InputEventKind::SqueezeEnd=>"squeezeend",
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct InputEvent{
+    pub kind: InputEventKind,
+    pub handedness: Handedness,
+    // イベントが起きた時点のターゲットレイの姿勢
+    pub target_ray_pose: Option<Pose>,
+    // シミュレーターが起こしたイベントではNone
+    pub source: Option<XrInputSource>,
+}
+
+// 入力イベントの購読者の一覧。フレームループが受け取ったイベントを全ての購読者に配る
+// クローンしても同じ一覧を指すので、ループに渡した後からでも購読できる
+#[derive(Clone,Default)]
+pub struct InputEventSubscribers{
+    subscribers: Rc<RefCell<Vec<mpsc::UnboundedSender<InputEvent>>>>,
+}
+
+impl InputEventSubscribers{
+    pub fn new()->Self{
+        InputEventSubscribers::default()
+    }
+
+    // 購読を始め、イベントを受け取るReceiverを返す。Receiverを破棄すると購読をやめる
+    pub fn subscribe(&self)->mpsc::UnboundedReceiver<InputEvent>{
+        let (event_tx, event_rx) = mpsc::unbounded::<InputEvent>();
+        self.subscribers.borrow_mut().push(event_tx);
+        event_rx
+    }
+
+    // 全ての購読者にイベントを送る。Receiverが破棄された購読者はここで外す
+    pub fn dispatch(&self, event: &InputEvent){
+        self.subscribers.borrow_mut().retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    pub fn len(&self)->usize{
+        self.subscribers.borrow().len()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.len() == 0
+    }
+}
+
+type InputListener = Closure<dyn FnMut(XrInputSourceEvent)>;
+
+pub struct Input{
+    session: XrSession,
+    // 移動で参照空間が変わっても、イベントの姿勢が同じ空間で届くように共有する
+    reference_space: Rc<RefCell<XrReferenceSpace>>,
+    sources: Vec<InputSourceState>,
+    listeners: Vec<(InputEventKind, InputListener)>,
+}
+
+impl Input{
+    // select/squeezeのリスナーを登録し、イベントを受け取るReceiverを返す
+    pub fn new(session: &XrSession, reference_space: &XrReferenceSpace)->(Self, mpsc::UnboundedReceiver<InputEvent>){
+        let (event_tx, event_rx) = mpsc::unbounded::<InputEvent>();
+        let reference_space = Rc::new(RefCell::new(reference_space.clone()));
+        let mut listeners = Vec::new();
+        for kind in InputEventKind::ALL{
+            let event_tx = event_tx.clone();
+            let reference_space = Rc::clone(&reference_space);
+            let listener = Closure::wrap(Box::new(move |event: XrInputSourceEvent|{
+                let source = event.input_source();
+                // イベントのフレームはこのハンドラの中でだけ有効
+                let target_ray_pose = get_pose(&event.frame(), &source.target_ray_space(), &reference_space.borrow());
+                let _ = event_tx.unbounded_send(InputEvent{
+                    kind,
+                    handedness: Handedness::from_xr(source.handedness()),
+                    target_ray_pose,
+                    source: Some(source),
+                });
+            }) as Box<dyn FnMut(XrInputSourceEvent)>);
+            if session.add_event_listener_with_callback(kind.event_type(), listener.as_ref().unchecked_ref()).is_err(){
+                console::log_1(&format!("[Error] Could not listen to {} events", kind.event_type()).into());
+            }
+            listeners.push((kind, listener));
+        }
+        let input = Input{
+            session: session.clone(),
+            reference_space,
+            sources: Vec::new(),
+            listeners,
+        };
+        (input, event_rx)
+    }
+
+    // 毎フレーム呼び、全ての入力ソースの姿勢を更新する
+    pub fn update(&mut self, frame: &XrFrame, reference_space: &XrReferenceSpace){
+        *self.reference_space.borrow_mut() = reference_space.clone();
+        let input_sources = self.session.input_sources();
+        self.sources.clear();
+        for index in 0..input_sources.length(){
+            if let Some(source) = input_sources.get(index){
+                self.sources.push(InputSourceState::from_xr(source, frame, reference_space));
+            }
+        }
+    }
+
+    pub fn sources(&self)->&[InputSourceState]{
+        &self.sources
+    }
+
+    pub fn source(&self, handedness: Handedness)->Option<&InputSourceState>{
+        self.sources.iter().find(|source| source.handedness == handedness)
+    }
+}
+
+impl Drop for Input{
+    fn drop(&mut self){
+        for (kind, listener) in self.listeners.iter(){
+            let _ = self.session.remove_event_listener_with_callback(kind.event_type(), listener.as_ref().unchecked_ref());
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::{HashMap,HashSet};
+use futures::channel::mpsc;
+use wasm_bindgen::prelude::*;
+#[cfg(target_arch = "wasm32")]
+use web_sys::*;
+use gl_matrix::common::Mat4;
+use gl_matrix::mat4;
+use crate::{GlProgram,add_gltf_to_scene};
+#[cfg(target_arch = "wasm32")]
+use crate::load_gltf;
+use crate::gltf::GltfAsset;
+use crate::input::{Handedness,InputSourceState,TargetRayMode};
+use crate::locomotion::ArcHit;
+use crate::mesh::{Mesh,VertexAttribute,VertexLayout};
+use crate::platform::{self,GraphicsDevice};
+use crate::scene::{MeshHandle,Scene};
+
+// コントローラー・手・レーザーポインターの表示
+// 入力プロファイルのglTFがあればそれを使い、無ければ箱を組み合わせた代わりのメッシュを描く
+
+// WebXR Input Profiles の配置 (<id>/<handedness>.glb) に合わせたローカルのフォルダ
+pub const PROFILE_ASSET_DIR: &str = "../assets/profiles";
+
+// レーザーポインターの長さ(メートル)
+pub const LASER_LENGTH: f32 = 5.0;
+
+const CONTROLLER_COLOR: [f32; 4] = [0.25, 0.25, 0.28, 1.0];
+const CONTROLLER_ACCENT_COLOR: [f32; 4] = [0.35, 0.6, 0.95, 1.0];
+const JOINT_COLOR: [f32; 4] = [0.95, 0.8, 0.7, 1.0];
+const LASER_COLOR: [f32; 4] = [0.3, 0.9, 1.0, 1.0];
+
+// テレポートの放物線はレーザーのメッシュをこの倍率で太くして描く
+const ARC_THICKNESS: f32 = 4.0;
+// 着地点に置く平たい箱の大きさ(メートル)
+const LANDING_MARKER_SIZE: [f32; 3] = [0.3, 0.01, 0.3];
+
+// 位置と頂点色だけを持つ箱の組み合わせ
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct BoxMeshBuilder{
+    pub vertices: Vec<f32>,
+    pub indices: Vec<u16>,
+}
+
+impl BoxMeshBuilder{
+    pub fn new()->Self{
+        BoxMeshBuilder::default()
+    }
+
+    pub fn layout()->VertexLayout{
+        VertexLayout::new(&[VertexAttribute::Position, VertexAttribute::Color]).expect("position and color are distinct attributes")
+    }
+
+    // minからmaxまでの箱を追加する。面は外から見て反時計回り
+    // 面ごとに明るさを変えて、ライティング無しでも形が分かるようにする
+    pub fn add_box(mut self, min: [f32; 3], max: [f32; 3], color: [f32; 4])->Self{
+        // 法線の軸と、その面を張る2軸(u×vが法線の向きになる順)
+        const FACES: [(usize, usize, usize, f32); 3] = [(0, 1, 2, 0.85), (1, 2, 0, 1.0), (2, 0, 1, 0.7)];
+        for (axis, u, v, shade) in FACES{
+            for positive in [true, false]{
+                let base = (self.vertices.len() / 7) as u16;
+                let corners = if positive{
+                    [(false, false), (true, false), (true, true), (false, true)]
+                }
+                else{
+                    [(false, false), (false, true), (true, true), (true, false)]
+                };
+                for (u_max, v_max) in corners{
+                    let mut position = [0.0; 3];
+                    position[axis] = if positive{max[axis]} else{min[axis]};
+                    position[u] = if u_max{max[u]} else{min[u]};
+                    position[v] = if v_max{max[v]} else{min[v]};
+                    self.vertices.extend_from_slice(&position);
+                    self.vertices.extend_from_slice(&[color[0] * shade, color[1] * shade, color[2] * shade, color[3]]);
+                }
+                self.indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+            }
+        }
+        self
+    }
+}
+
+// グリップの原点を握った位置として、-Z方向に伸びるコントローラー
+pub fn controller_mesh_data()->BoxMeshBuilder{
+    BoxMeshBuilder::new()
+        .add_box([-0.015, -0.02, -0.05], [0.015, 0.015, 0.06], CONTROLLER_COLOR)
+        .add_box([-0.025, -0.005, -0.09], [0.025, 0.025, -0.05], CONTROLLER_ACCENT_COLOR)
+}
+
+// 大きさ1の立方体。関節の半径に合わせて拡大縮小する
+pub fn joint_mesh_data()->BoxMeshBuilder{
+    BoxMeshBuilder::new().add_box([-0.5, -0.5, -0.5], [0.5, 0.5, 0.5], JOINT_COLOR)
+}
+
+// 原点から-Zに長さ1の細い棒。レイの長さに合わせてZ方向に伸ばす
+pub fn laser_mesh_data()->BoxMeshBuilder{
+    BoxMeshBuilder::new().add_box([-0.001, -0.001, -1.0], [0.001, 0.001, 0.0], LASER_COLOR)
+}
+
+// laser_mesh_dataの棒をstartからendまで伸ばす行列
+pub fn segment_matrix(start: [f32; 3], end: [f32; 3], thickness: f32)->Mat4{
+    // 棒の-Zがendを向くので、Z軸はendからstartへの向き
+    let z = [start[0] - end[0], start[1] - end[1], start[2] - end[2]];
+    let length = (z[0] * z[0] + z[1] * z[1] + z[2] * z[2]).sqrt();
+    if length == 0.0{
+        let mut matrix = mat4::create();
+        mat4::from_translation(&mut matrix, &start);
+        return matrix;
+    }
+    let direction = [z[0] / length, z[1] / length, z[2] / length];
+    // Z軸とほぼ平行にならない軸を選んで、直交する2軸を作る
+    let up = if direction[1].abs() < 0.99{[0.0, 1.0, 0.0]} else{[1.0, 0.0, 0.0]};
+    let x = normalize(cross(up, direction));
+    let y = cross(direction, x);
+    [
+        x[0] * thickness, x[1] * thickness, x[2] * thickness, 0.0,
+        y[0] * thickness, y[1] * thickness, y[2] * thickness, 0.0,
+        z[0], z[1], z[2], 0.0,
+        start[0], start[1], start[2], 1.0,
+    ]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3])->[f32; 3]{
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(a: [f32; 3])->[f32; 3]{
+    let length = (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+    [a[0] / length, a[1] / length, a[2] / length]
+}
+
+fn add_box_mesh<G: GraphicsDevice>(gl_program: &GlProgram<G>, meshes: &mut Vec<Mesh<G>>, data: BoxMeshBuilder)->Result<MeshHandle,JsValue>{
+    let mesh = Mesh::new(gl_program, BoxMeshBuilder::layout(), &data.vertices, &data.indices)?;
+    meshes.push(mesh);
+    Ok(MeshHandle(meshes.len() - 1))
+}
+
+// 描画する1つのメッシュと、そのワールド行列
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct ModelDraw{
+    pub mesh: MeshHandle,
+    pub world: Mat4,
+}
+
+// モデルを構成するメッシュと、モデルの原点からの行列
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct InputModel{
+    pub parts: Vec<ModelDraw>,
+}
+
+impl InputModel{
+    fn draws(&self, origin: &Mat4, draws: &mut Vec<ModelDraw>){
+        for part in self.parts.iter(){
+            let mut world = mat4::create();
+            mat4::multiply(&mut world, origin, &part.world);
+            draws.push(ModelDraw{mesh: part.mesh, world});
+        }
+    }
+}
+
+// 読み込みが終わったプロファイルのglTF。メッシュは描画側で受け取ったときのプログラムで作る
+struct LoadedProfile{
+    key: ProfileKey,
+    path: String,
+    asset: GltfAsset,
+}
+
+// 入力ソースのプロファイル一覧と手の組み合わせ
+type ProfileKey = (Vec<String>, Handedness);
+
+// 読み込みを始めるrequest_profilesはブラウザでしか使わない
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+pub struct InputModels{
+    controller: InputModel,
+    joint: MeshHandle,
+    laser: MeshHandle,
+    profiles: HashMap<ProfileKey,InputModel>,
+    requested: HashSet<ProfileKey>,
+    loaded_tx: mpsc::UnboundedSender<LoadedProfile>,
+    loaded_rx: mpsc::UnboundedReceiver<LoadedProfile>,
+}
+
+impl InputModels{
+    // 代わりのメッシュを作ってmeshesに追加する
+    pub fn new<G: GraphicsDevice>(gl_program: &GlProgram<G>, meshes: &mut Vec<Mesh<G>>)->Result<Self,JsValue>{
+        let controller = add_box_mesh(gl_program, meshes, controller_mesh_data())?;
+        let joint = add_box_mesh(gl_program, meshes, joint_mesh_data())?;
+        let laser = add_box_mesh(gl_program, meshes, laser_mesh_data())?;
+        let (loaded_tx, loaded_rx) = mpsc::unbounded();
+        Ok(InputModels{
+            controller: InputModel{parts: vec![ModelDraw{mesh: controller, world: mat4::create()}]},
+            joint,
+            laser,
+            profiles: HashMap::new(),
+            requested: HashSet::new(),
+            loaded_tx,
+            loaded_rx,
+        })
+    }
+
+    // 新しく現れたコントローラーについて、プロファイルのglTFを具体的なものから順に探す
+    #[cfg(target_arch = "wasm32")]
+    pub fn request_profiles(&mut self, window: &Window, sources: &[InputSourceState]){
+        for source in sources.iter(){
+            if source.gamepad.is_none() || source.profiles.is_empty(){
+                continue;
+            }
+            let key = (source.profiles.clone(), source.handedness);
+            if !self.requested.insert(key.clone()){
+                continue;
+            }
+            let window = window.clone();
+            let loaded_tx = self.loaded_tx.clone();
+            wasm_bindgen_futures::spawn_local(async move{
+                for profile in key.0.iter(){
+                    let path = format!("{}/{}/{}.glb", PROFILE_ASSET_DIR, profile, key.1);
+                    let Ok(asset) = load_gltf(&window, &path).await else{
+                        continue;
+                    };
+                    let _ = loaded_tx.unbounded_send(LoadedProfile{key, path, asset});
+                    return;
+                }
+                console::log_1(&format!("No input profile model was found for {}, using the built-in controller", key.0.join(", ")).into());
+            });
+        }
+    }
+
+    // 読み込みが終わったモデルのメッシュを今のプログラムで作ってmeshesに追加する
+    // シェーダーを再読み込みしても、読み込み開始時の古いプログラムを使わないようにここで作る
+    pub fn receive<G: GraphicsDevice>(&mut self, gl_program: &GlProgram<G>, meshes: &mut Vec<Mesh<G>>){
+        while let Ok(loaded) = self.loaded_rx.try_recv(){
+            // モデル内の階層はシーンに展開して、各メッシュの行列を求めておく
+            let mut scene = Scene::new();
+            let offset = meshes.len();
+            if add_gltf_to_scene(gl_program, &loaded.asset, &mut scene, None, meshes).is_err(){
+                platform::log(&format!("[Error] Could not create meshes for input profile {}", loaded.path));
+                // 途中まで作ったメッシュは捨て、代わりのコントローラーを使い続ける
+                for mesh in meshes.drain(offset..){
+                    mesh.delete(gl_program.gl());
+                }
+                continue;
+            }
+            let parts = scene.draw_items().iter().map(|item| ModelDraw{mesh: item.mesh, world: item.world}).collect();
+            platform::log(&format!("Loaded input profile {}", loaded.path));
+            self.profiles.insert(loaded.key, InputModel{parts});
+        }
+    }
+
+    // テレポートの放物線と、着地できる場合は着地点の印
+    pub fn arc_draws(&self, arc: &ArcHit)->Vec<ModelDraw>{
+        let mut draws: Vec<ModelDraw> = arc.points.windows(2)
+            .map(|segment| ModelDraw{mesh: self.laser, world: segment_matrix(segment[0], segment[1], ARC_THICKNESS)})
+            .collect();
+        if let Some(landing) = arc.landing{
+            let mut world = mat4::create();
+            mat4::from_translation(&mut world, &landing);
+            let mut marker = mat4::create();
+            mat4::scale(&mut marker, &world, &LANDING_MARKER_SIZE);
+            draws.push(ModelDraw{mesh: self.joint, world: marker});
+        }
+        draws
+    }
+
+    // このフレームで描くコントローラー・手・レーザー
+    pub fn draws(&self, sources: &[InputSourceState])->Vec<ModelDraw>{
+        let mut draws = Vec::new();
+        for source in sources.iter(){
+            if let Some(hand) = source.hand.as_ref(){
+                for joint in hand.joints().iter(){
+                    let size = joint.radius * 2.0;
+                    let mut world = mat4::create();
+                    mat4::scale(&mut world, &joint.pose.matrix(), &[size, size, size]);
+                    draws.push(ModelDraw{mesh: self.joint, world});
+                }
+            }
+            else if let Some(grip_pose) = source.grip_pose.as_ref(){
+                let key = (source.profiles.clone(), source.handedness);
+                let model = self.profiles.get(&key).unwrap_or(&self.controller);
+                model.draws(&grip_pose.matrix(), &mut draws);
+            }
+
+            // 視線や画面タップのレイは見えても邪魔なので描かない
+            if source.target_ray_mode != TargetRayMode::TrackedPointer{
+                continue;
+            }
+            if let Some(target_ray_pose) = source.target_ray_pose.as_ref(){
+                let mut world = mat4::create();
+                mat4::scale(&mut world, &target_ray_pose.matrix(), &[1.0, 1.0, LASER_LENGTH]);
+                draws.push(ModelDraw{mesh: self.laser, world});
+            }
+        }
+        draws
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::hand::{HandSkeleton,JointPose,JOINT_COUNT};
+    use crate::input::Pose;
+    use crate::platform::mock::MockDevice;
+    use crate::test_support::demo_scene;
+
+    fn position(data: &BoxMeshBuilder, index: u16)->[f32; 3]{
+        let start = index as usize * BoxMeshBuilder::layout().floats_per_vertex();
+        [data.vertices[start], data.vertices[start + 1], data.vertices[start + 2]]
+    }
+
+    fn sub(a: [f32; 3], b: [f32; 3])->[f32; 3]{
+        [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+    }
+
+    fn transform(matrix: &Mat4, point: [f32; 3])->[f32; 3]{
+        let mut out = [0.0; 3];
+        gl_matrix::vec3::transform_mat4(&mut out, &point, matrix);
+        out
+    }
+
+    fn assert_near(actual: [f32; 3], expected: [f32; 3]){
+        for (a, e) in actual.iter().zip(expected.iter()){
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn add_box_winds_faces_counter_clockwise_from_outside(){
+        let (min, max) = ([-1.0, -2.0, -3.0], [1.0, 2.0, 3.0]);
+        let data = BoxMeshBuilder::new().add_box(min, max, [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(data.vertices.len(), 24 * 7);
+        assert_eq!(data.indices.len(), 36);
+
+        // 三角形ごとの(b-a)×(c-a)の向きが、その面の外向きの法線になる
+        let mut normals = Vec::new();
+        for triangle in data.indices.chunks(3){
+            let [a, b, c] = [position(&data, triangle[0]), position(&data, triangle[1]), position(&data, triangle[2])];
+            let normal = cross(sub(b, a), sub(c, a));
+            let axis = (0..3).find(|axis| normal[*axis] != 0.0).unwrap();
+            assert!((0..3).filter(|other| *other != axis).all(|other| normal[other] == 0.0), "{:?}", normal);
+            // 面上の点はどれもその軸のminかmaxにある
+            let outward = if normal[axis] > 0.0{max[axis]} else{min[axis]};
+            assert!([a, b, c].iter().all(|point| point[axis] == outward), "{:?}", triangle);
+            normals.push((axis, normal[axis] > 0.0));
+        }
+        // 6面それぞれに三角形が2つずつある
+        for axis in 0..3{
+            for positive in [true, false]{
+                assert_eq!(normals.iter().filter(|normal| **normal == (axis, positive)).count(), 2);
+            }
+        }
+    }
+
+    #[test]
+    fn add_box_offsets_indices_of_later_boxes(){
+        let data = BoxMeshBuilder::new()
+            .add_box([0.0; 3], [1.0; 3], [1.0; 4])
+            .add_box([2.0; 3], [3.0; 3], [0.5, 0.5, 0.5, 1.0]);
+        assert_eq!(data.indices[36], 24);
+        assert_eq!(data.indices.iter().copied().max(), Some(47));
+        assert!(BoxMeshBuilder::layout().validate(&data.vertices, &data.indices).is_ok());
+        // 面ごとの明るさはアルファに掛けない
+        assert!(data.vertices.chunks(7).all(|vertex| vertex[6] == 1.0));
+    }
+
+    #[test]
+    fn segment_matrix_stretches_laser_from_start_to_end(){
+        let (start, end) = ([0.5, 1.0, -0.2], [1.5, 3.0, -2.2]);
+        for thickness in [1.0, 4.0]{
+            let matrix = segment_matrix(start, end, thickness);
+            // 棒の根元は原点、先端は-Z方向に1
+            assert_near(transform(&matrix, [0.0, 0.0, 0.0]), start);
+            assert_near(transform(&matrix, [0.0, 0.0, -1.0]), end);
+            // 太さの方向は棒と直交し、長さはthickness倍になる
+            let side = sub(transform(&matrix, [1.0, 0.0, 0.0]), start);
+            let direction = sub(end, start);
+            assert!((side[0] * direction[0] + side[1] * direction[1] + side[2] * direction[2]).abs() < 1e-5);
+            assert!(((side[0] * side[0] + side[1] * side[1] + side[2] * side[2]).sqrt() - thickness).abs() < 1e-5);
+        }
+        // 真上に伸ばしても軸が潰れない
+        let matrix = segment_matrix([0.0, 0.0, 0.0], [0.0, 2.0, 0.0], 1.0);
+        assert_near(transform(&matrix, [0.0, 0.0, -1.0]), [0.0, 2.0, 0.0]);
+        assert!(matrix.iter().all(|value| value.is_finite()));
+    }
+
+    #[test]
+    fn segment_matrix_of_zero_length_is_translation(){
+        let matrix = segment_matrix([1.0, 2.0, 3.0], [1.0, 2.0, 3.0], 0.5);
+        let mut expected = mat4::create();
+        mat4::from_translation(&mut expected, &[1.0, 2.0, 3.0]);
+        assert_eq!(matrix, expected);
+    }
+
+    fn source(target_ray_mode: TargetRayMode, grip_pose: Option<Pose>, hand: Option<HandSkeleton>)->InputSourceState{
+        InputSourceState{
+            handedness: Handedness::Right,
+            target_ray_mode,
+            profiles: vec!["generic-trigger".to_string()],
+            grip_pose,
+            target_ray_pose: Some(Pose::default()),
+            gamepad: None,
+            hand,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn draws_joints_for_hands_and_the_fallback_for_controllers(){
+        let gl = MockDevice::new();
+        let (renderer, _) = demo_scene(&gl);
+        let models = &renderer.input_models;
+        let meshes = |draws: &[ModelDraw]| draws.iter().map(|draw| draw.mesh).collect::<Vec<_>>();
+
+        // プロファイルのモデルが届いていないので、箱のコントローラーとレーザー
+        let grip = Pose{position: [0.2, 1.0, -0.3], ..Pose::default()};
+        let draws = models.draws(&[source(TargetRayMode::TrackedPointer, Some(grip), None)]);
+        assert_eq!(meshes(&draws), vec![models.controller.parts[0].mesh, models.laser]);
+        assert_eq!(&draws[0].world[12..15], &grip.position);
+
+        // 手は関節ごとに直径の大きさの箱を描き、コントローラーは描かない
+        let joints = [JointPose{pose: Pose::default(), radius: 0.01}; JOINT_COUNT];
+        let hand = HandSkeleton::new(Handedness::Right, joints);
+        let draws = models.draws(&[source(TargetRayMode::TrackedPointer, Some(grip), Some(hand))]);
+        assert_eq!(draws.len(), JOINT_COUNT + 1);
+        assert!(draws[..JOINT_COUNT].iter().all(|draw| draw.mesh == models.joint && draw.world[0] == 0.02));
+        assert_eq!(draws[JOINT_COUNT].mesh, models.laser);
+
+        // 視線のレイは描かない
+        assert!(models.draws(&[source(TargetRayMode::Gaze, None, None)]).is_empty());
+    }
+}
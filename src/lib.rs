@@ -1,30 +1,269 @@
+#[cfg(target_arch = "wasm32")]
 mod logger;
+pub mod scene;
+pub mod gltf;
+pub mod mesh;
+pub mod shader;
+pub mod hot_reload;
+pub mod preprocess;
+pub mod program_cache;
+pub mod uniform;
+pub mod std140;
+pub mod multiview;
+pub mod input;
+pub mod gamepad;
+pub mod hand;
+pub mod input_models;
+pub mod session;
+pub mod camera;
+#[cfg(target_arch = "wasm32")]
+pub mod preview;
+pub mod reference_space;
+pub mod lifecycle;
+pub mod locomotion;
+pub mod frame_loop;
+pub mod session_recording;
+pub mod platform;
+pub mod image;
+use crate::scene::{Scene,Node,NodeId,Transform,MeshHandle};
+use crate::gltf::{GltfAsset,GltfDocument};
+use crate::mesh::{Mesh,VertexLayout,VertexAttribute};
+use crate::shader::{ShaderError,ShaderStage};
+use crate::hot_reload::ShaderSources;
+use crate::preprocess::{Defines,PreprocessedSource};
+use crate::program_cache::ProgramCache;
+use crate::multiview::Multiview;
+use crate::input::InputEvent;
+use crate::input_models::{InputModels,ModelDraw};
+use crate::session::BlendMode;
+use crate::uniform::{UniformBuffer,UniformInfo,UniformType,MAX_VIEWS};
+use crate::platform::{AssetError,AssetFetcher,GraphicsDevice,GraphicsError,ViewData};
+// ブラウザでセッションを動かす部分だけが使うもの
+#[cfg(target_arch = "wasm32")]
 use crate::logger::Logger;
+#[cfg(target_arch = "wasm32")]
+use crate::program_cache::ProgramError;
+#[cfg(target_arch = "wasm32")]
+use crate::input::{Input,InputEventSubscribers,InputSourceState,Pose};
+#[cfg(target_arch = "wasm32")]
+use crate::session::{ActiveSession,SessionConfig};
+#[cfg(target_arch = "wasm32")]
+use crate::reference_space::{ReferenceSpace,ReferenceSpaceConfig};
+#[cfg(target_arch = "wasm32")]
+use crate::lifecycle::{SessionLifecycle,Visibility};
+#[cfg(target_arch = "wasm32")]
+use crate::locomotion::WalkableArea;
+#[cfg(target_arch = "wasm32")]
+use crate::frame_loop::{FrameClock,FrameLoop,Interaction};
+#[cfg(target_arch = "wasm32")]
+use crate::session_recording::{RecordedFrame,SessionRecorder};
+#[cfg(target_arch = "wasm32")]
+use crate::platform::XrFrameSource;
+#[cfg(target_arch = "wasm32")]
+use crate::platform::web::{WebFetcher,WebXrFrame,WebXrInput};
+use std::collections::{HashMap,HashSet};
 use wasm_bindgen::prelude::*;
 use futures::stream::StreamExt;
 use web_sys::*;
 use wasm_bindgen_futures::JsFuture;
 use futures::channel:: mpsc;
-use gl_matrix::common::*;
-use gl_matrix::{vec3,mat4,quat};
+use gl_matrix::common::Mat4;
 use std::rc::Rc;
 use std::cell::RefCell;
 
-pub struct GlProgram{
-    gl: WebGl2RenderingContext,
-    program: WebGlProgram,
+#[derive(Clone)]
+pub struct GlProgram<G: GraphicsDevice>{
+    gl: G,
+    program: G::Program,
+    // クローンしたGlProgramの間で共有する
+    state: Rc<ProgramState<G::UniformLocation>>,
 }
-impl From<GlProgram> for JsValue{
-    fn from(gl_program: GlProgram)->Self{
+
+struct ProgramState<L>{
+    // リンク直後にgetActiveUniformで調べたuniform
+    uniforms: HashMap<String,UniformInfo<L>>,
+    attributes: RefCell<HashMap<String,i32>>,
+    // 一度警告したuniform名。毎フレーム同じ警告を出さないようにする
+    warned: RefCell<HashSet<String>>,
+}
+
+impl<G: GraphicsDevice> GlProgram<G>{
+    // programはリンク済みであること
+    pub fn new(gl: G, program: G::Program)->Self{
+        uniform::bind_uniform_blocks(&gl, &program);
+        let uniforms = uniform::introspect_uniforms(&gl, &program);
+        GlProgram{
+            gl,
+            program,
+            state: Rc::new(ProgramState{
+                uniforms,
+                attributes: RefCell::new(HashMap::new()),
+                warned: RefCell::new(HashSet::new()),
+            }),
+        }
+    }
+
+    pub fn gl(&self)->&G{
+        &self.gl
+    }
+
+    pub fn program(&self)->&G::Program{
+        &self.program
+    }
+
+    // 同じプログラムから作られたGlProgramかどうか
+    pub fn same_program(&self, other: &GlProgram<G>)->bool{
+        Rc::ptr_eq(&self.state, &other.state)
+    }
+
+    pub fn uniform(&self, name: &str)->Option<&UniformInfo<G::UniformLocation>>{
+        self.state.uniforms.get(name)
+    }
+
+    pub fn uniforms(&self)->impl Iterator<Item=(&str,&UniformInfo<G::UniformLocation>)>{
+        self.state.uniforms.iter().map(|(name, info)| (name.as_str(), info))
+    }
+
+    pub fn uniform_location(&self, name: &str)->Option<&G::UniformLocation>{
+        self.uniform(name).map(|info| &info.location)
+    }
+
+    // attributeの位置。シェーダで使われていない場合は-1
+    pub fn attribute_location(&self, name: &str)->i32{
+        if let Some(location) = self.state.attributes.borrow().get(name){
+            return *location;
+        }
+        let location = self.gl.get_attrib_location(&self.program, name);
+        self.state.attributes.borrow_mut().insert(name.to_string(), location);
+        location
+    }
+
+    // 以下のセッターは、呼び出し前にuse_programしておくこと
+    // 名前が無い・型が違う場合は、名前ごとに一度だけ警告して何もしない
+
+    pub fn set_float(&self, name: &str, value: f32){
+        if let Some(location) = self.checked_location(name, |uniform_type| uniform_type == UniformType::Float, "float"){
+            self.gl.uniform1f(Some(location), value);
+        }
+    }
+
+    pub fn set_int(&self, name: &str, value: i32){
+        if let Some(location) = self.checked_location(name, |uniform_type| uniform_type.accepts_int(), "int"){
+            self.gl.uniform1i(Some(location), value);
+        }
+    }
+
+    pub fn set_vec2(&self, name: &str, value: &[f32; 2]){
+        if let Some(location) = self.checked_location(name, |uniform_type| uniform_type == UniformType::Vec2, "vec2"){
+            self.gl.uniform2fv(Some(location), value);
+        }
+    }
+
+    pub fn set_vec3(&self, name: &str, value: &[f32; 3]){
+        if let Some(location) = self.checked_location(name, |uniform_type| uniform_type == UniformType::Vec3, "vec3"){
+            self.gl.uniform3fv(Some(location), value);
+        }
+    }
+
+    pub fn set_vec4(&self, name: &str, value: &[f32; 4]){
+        if let Some(location) = self.checked_location(name, |uniform_type| uniform_type == UniformType::Vec4, "vec4"){
+            self.gl.uniform4fv(Some(location), value);
+        }
+    }
+
+    pub fn set_mat3(&self, name: &str, value: &[f32; 9]){
+        if let Some(location) = self.checked_location(name, |uniform_type| uniform_type == UniformType::Mat3, "mat3"){
+            self.gl.uniform_matrix3fv(Some(location), false, value);
+        }
+    }
+
+    pub fn set_mat4(&self, name: &str, value: &[f32; 16]){
+        if let Some(location) = self.checked_location(name, |uniform_type| uniform_type == UniformType::Mat4, "mat4"){
+            self.gl.uniform_matrix4fv(Some(location), false, value);
+        }
+    }
+
+    fn checked_location(&self, name: &str, accepts: impl Fn(UniformType)->bool, expected: &str)->Option<&G::UniformLocation>{
+        let Some(info) = self.state.uniforms.get(name) else{
+            self.warn_once(name, || format!("[Warning] Uniform `{}` is not active in the program", name));
+            return None;
+        };
+        if !accepts(info.uniform_type){
+            self.warn_once(name, || format!("[Warning] Uniform `{}` is {}, but was set as {}", name, info.uniform_type, expected));
+            return None;
+        }
+        Some(&info.location)
+    }
+
+    fn warn_once(&self, name: &str, message: impl FnOnce()->String){
+        if self.state.warned.borrow_mut().insert(name.to_string()){
+            platform::log(&message());
+        }
+    }
+}
+#[cfg(target_arch = "wasm32")]
+impl From<GlProgram<WebGl2RenderingContext>> for JsValue{
+    fn from(gl_program: GlProgram<WebGl2RenderingContext>)->Self{
         JsValue::from(gl_program.program)
     }
 }
-impl wasm_bindgen::describe::WasmDescribe for GlProgram{
+#[cfg(target_arch = "wasm32")]
+impl wasm_bindgen::describe::WasmDescribe for GlProgram<WebGl2RenderingContext>{
     fn describe() {
         <JsValue as wasm_bindgen::describe::WasmDescribe>::describe();
     }
 }
 
+// 描画に使うGLの状態をまとめたもの
+pub struct Renderer<G: GraphicsDevice>{
+    pub programs: ProgramCache<G>,
+    pub program: GlProgram<G>,
+    pub defines: Defines,
+    pub meshes: Vec<Mesh<G>>,
+    // 両目のview・projection行列。フレームごとに一度だけ送る
+    pub camera: UniformBuffer<G>,
+    // Noneのときはビューごとに描画する
+    pub multiview: Option<Multiview<G>>,
+    pub input_models: InputModels,
+    // ARでは背景を透明にしてパススルーの映像を見せる
+    pub blend_mode: BlendMode,
+}
+
+impl<G: GraphicsDevice> Renderer<G>{
+    pub fn gl(&self)->&G{
+        self.programs.gl()
+    }
+
+    // セッションの終了時に、GPU上のリソースを全て解放する
+    pub fn delete(mut self){
+        let gl = self.gl().clone();
+        for mesh in self.meshes.iter(){
+            mesh.delete(&gl);
+        }
+        self.camera.delete(&gl);
+        if let Some(multiview) = self.multiview.as_mut(){
+            multiview.delete(&gl);
+        }
+        self.programs.clear();
+    }
+}
+
+// テレポートできる床の一辺(メートル)
+#[cfg(target_arch = "wasm32")]
+const WALKABLE_FLOOR_SIZE: f32 = 20.0;
+// フレームが長く止まったときに移動量が跳ねないようにする(秒)
+#[cfg(target_arch = "wasm32")]
+const MAX_DELTA_TIME: f32 = 0.1;
+// セッションの記録の上限。72Hzでおよそ5分
+#[cfg(target_arch = "wasm32")]
+const MAX_RECORDED_FRAMES: usize = 72 * 60 * 5;
+
+#[cfg(target_arch = "wasm32")]
+const VERTEX_SHADER_PATH: &str = "../shader/vertex_shader.glsl";
+#[cfg(target_arch = "wasm32")]
+const FRAGMENT_SHADER_PATH: &str = "../shader/fragment_shader.glsl";
+
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen(start)]
 pub async fn run() -> Result<(), JsValue>{
 
@@ -44,159 +283,463 @@ pub async fn run() -> Result<(), JsValue>{
     let onclick_func = Closure::wrap(Box::new(move ||{
         button_clone.set_inner_text("WebXR is starting...");
         // 送れるまでbutton_txを送る
-        loop{
-            let Err(_) = button_tx.try_send(()) else{
-                break;
-            };
-        }
+        while button_tx.try_send(()).is_err(){}
     })as Box<dyn FnMut()>);
     button.set_onclick(Some(onclick_func.as_ref().unchecked_ref::<js_sys::Function>()));
     let _ = body.append_child(&button)?;
 
-    button_rx.next().await;
-    
-    // XRSystemを取得して、環境でwebXRが実行可能であるか確認
-    let xrsystem = window.navigator().xr();
-    let Some(xrsession) = webxr_available(&xrsystem,&document).await? else{
-        console::log_1(&"WebXR is not available".into());
-        display_error_page(&document, "WebXR is not available").await?;
-        return Ok(());
-    };
+    // セッションが終わったらボタンを戻し、同じページでもう一度開始できるようにする
+    loop{
+        button_rx.next().await;
+        button.set_disabled(true);
+
+        // ?replay=<パス>のときは、ヘッドセットの代わりに記録したセッションをプレビューで再生する
+        if let Some(path) = session_recording::replay_path(&window){
+            button.set_inner_text("Replaying session");
+            let recording = session_recording::load_recording(&WebFetcher{window: window.clone()}, &path).await?;
+            let (renderer, scene, shader_reload_rx) = create_renderer(&window, &document, None).await?;
+            // 記録した入力イベントも、XRのセッションと同じく購読者に配る
+            let input_events = InputEventSubscribers::new();
+            wasm_bindgen_futures::spawn_local(log_input_events(input_events.subscribe()));
+            return preview::start_replay_preview(&window, renderer, scene, recording, shader_reload_rx, input_events);
+        }
+
+        // XRSystemを取得して、環境でwebXRが実行可能であるか確認
+        // 使えなければヘッドセット無しのデスクトッププレビューにする
+        let session = if has_xr_system(&window){
+            match start_xr_session(&window.navigator().xr(), &document, &SessionConfig::default()).await{
+                Ok(session)=>session,
+                Err(error)=>{
+                    console::log_1(&format!("[Error] Could not start WebXR session: {:?}", error).into());
+                    restore_start_button(&button, &mut button_rx);
+                    continue;
+                },
+            }
+        }
+        else{
+            None
+        };
+        let Some(session) = session else{
+            console::log_1(&"WebXR is not available, falling back to desktop preview".into());
+            button.set_inner_text("Desktop preview");
+            let (renderer, scene, shader_reload_rx) = create_renderer(&window, &document, None).await?;
+            return preview::start_desktop_preview(&window, renderer, scene, shader_reload_rx);
+        };
 
+        // セッションは開始済みなので、準備に失敗したら終わらせてから押し直せるようにする
+        let (renderer, scene, shader_reload_rx) = match create_renderer(&window, &document, Some(&session)).await{
+            Ok(created)=>created,
+            Err(error)=>{
+                console::log_1(&format!("[Error] Could not prepare the renderer: {:?}", error).into());
+                end_session(&session.session).await;
+                restore_start_button(&button, &mut button_rx);
+                continue;
+            },
+        };
+        button.set_inner_text("WebXR is running");
+        // 入力イベントは購読者に配られる。ここではログに出すだけ
+        let input_events = InputEventSubscribers::new();
+        wasm_bindgen_futures::spawn_local(log_input_events(input_events.subscribe()));
+        create_webxr_session(session, renderer, performance.clone(), scene, shader_reload_rx, input_events).await;
+        restore_start_button(&button, &mut button_rx);
+    }
+}
+
+// セッションが終わったか開始に失敗したときに、もう一度押せるようにする
+#[cfg(target_arch = "wasm32")]
+fn restore_start_button(button: &HtmlButtonElement, button_rx: &mut mpsc::Receiver<()>){
+    button.set_inner_text("Start WebXR");
+    button.set_disabled(false);
+    // セッション中に溜まったクリックは捨てる
+    while button_rx.try_recv().is_ok(){}
+}
+
+// シェーダ・メッシュ・シーンを用意する。XRセッションがあれば、そのモードに合わせる
+#[cfg(target_arch = "wasm32")]
+async fn create_renderer(window: &Window, document: &Document, session: Option<&ActiveSession>)->Result<(Renderer<WebGl2RenderingContext>,Scene,Option<mpsc::Receiver<ShaderSources>>),JsValue>{
     // webgl2のコンテキストを作成し、webXRに対応させる
-    let gl = create_webgl2_context(&document).await?;
+    let gl = create_webgl2_context(document).await?;
     console::log_1(&"created webgl2 context".into());
-    wasm_bindgen_futures::JsFuture::from(gl.make_xr_compatible()).await?;
-    console::log_1(&"made webgl2 context xr compatible".into());
-    let gl_program = ready_webgl2_context(&window, &document,gl).await?;
+    if session.is_some(){
+        wasm_bindgen_futures::JsFuture::from(gl.make_xr_compatible()).await?;
+        console::log_1(&"made webgl2 context xr compatible".into());
+    }
+    // シェーダに注入する#define
+    let mut defines = Defines::new();
+    // OVR_multiview2が使えれば両目を1パスで描画する。inlineは片目だけなので使わない
+    let multiview = if session.is_some_and(|session| session.mode.is_immersive()){
+        multiview::detect_multiview(&gl)
+    }
+    else{
+        None
+    };
+    if multiview.is_some(){
+        defines.set(multiview::MULTIVIEW_DEFINE, "");
+        console::log_1(&"Using OVR_multiview2 for single-pass stereo".into());
+    }
+    let mut programs = ProgramCache::new(&gl);
+    let (gl_program, compiled_sources) = ready_webgl2_context_with_cache(window, document, &mut programs, &defines).await?;
     console::log_1(&"created webgl2 context".into());
-    
-    create_webxr_session(xrsession, gl_program.gl, gl_program.program,performance).await;
-    Ok(())
+
+    let cube = create_cube_mesh(&gl_program)?;
+    let mut meshes = vec![cube];
+    let mut scene = create_demo_scene(MeshHandle(0));
+
+    // glTFモデルはデモシーンに追加する。読み込めなくてもXRセッションは開始する
+    match load_gltf(window, "../assets/models/pyramid.glb").await{
+        Ok(asset)=>{
+            let _ = add_gltf_to_scene(&gl_program, &asset, &mut scene, None, &mut meshes)?;
+            console::log_1(&"loaded glTF model".into());
+        },
+        Err(_)=>console::log_1(&"[Error] Could not load glTF model".into()),
+    }
+
+    // コントローラーや手の代わりに表示するメッシュ
+    let input_models = InputModels::new(&gl_program, &mut meshes)?;
+
+    // 開発モードではシェーダの変更を監視する
+    let shader_reload_rx = if hot_reload::hot_reload_enabled(window){
+        Some(hot_reload::watch_shaders(window, VERTEX_SHADER_PATH, FRAGMENT_SHADER_PATH, &compiled_sources))
+    }
+    else{
+        None
+    };
+
+    let camera = UniformBuffer::new(&gl, uniform::camera_layout(), uniform::CAMERA_BINDING)?;
+    let renderer = Renderer{
+        programs,
+        program: gl_program,
+        defines,
+        meshes,
+        camera,
+        multiview,
+        input_models,
+        blend_mode: session.map(|session| BlendMode::of_session(&session.session)).unwrap_or(BlendMode::Opaque),
+    };
+    Ok((renderer, scene, shader_reload_rx))
 }
 
-#[wasm_bindgen]
-pub async fn create_webxr_session(xrsession: XrSession, gl: WebGl2RenderingContext, program: WebGlProgram, performance: Performance){
+// 立方体を親子関係付きで並べたデモ用のシーン
+pub fn create_demo_scene(cube: MeshHandle)->Scene{
+    let mut scene = Scene::new();
+    let root = Node::new("cube")
+        .with_transform(Transform::default().with_scale([0.3,0.3,0.3]))
+        .with_mesh(cube);
+    let root = scene.add_node(None, root).expect("root node can always be added");
+
+    // 子ノードは親のスケールを引き継ぐので、親の座標系で配置する
+    let left = Node::new("cube_left")
+        .with_transform(Transform::from_translation([-1.5,0.0,0.0]).with_scale([0.5,0.5,0.5]))
+        .with_mesh(cube);
+    let right = Node::new("cube_right")
+        .with_transform(Transform::from_translation([1.5,0.0,0.0]).with_scale([0.5,0.5,0.5]))
+        .with_mesh(cube);
+    let _ = scene.add_node(Some(root), left);
+    let _ = scene.add_node(Some(root), right);
+    scene
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn create_webxr_session(session: ActiveSession, renderer: Renderer<WebGl2RenderingContext>, performance: Performance, scene: Scene, mut shader_reload_rx: Option<mpsc::Receiver<ShaderSources>>, input_events: InputEventSubscribers){
     let render_state = XrRenderStateInit::new();
-    let Ok(webgl_layer) = XrWebGlLayer::new_with_web_gl2_rendering_context(&xrsession, &gl) else{
+    let layer_init = XrWebGlLayerInit::new();
+    // マルチビューではテクスチャ配列からblitで転送するため、転送先はマルチサンプルにできない
+    layer_init.set_antialias(renderer.multiview.is_none());
+    let ActiveSession{session: xrsession, mode, features} = session;
+    let Ok(webgl_layer) = XrWebGlLayer::new_with_web_gl2_rendering_context_and_layer_init(&xrsession, renderer.gl(), &layer_init) else{
         console::log_1(&"[Error] Could not create WebGlLayer".into());
+        abandon_session(&xrsession, renderer).await;
         return;
     };
     
     render_state.set_base_layer(Some(&webgl_layer));
     xrsession.update_render_state_with_state(&render_state);
-    let Ok(reference_space_js) = JsFuture::from(xrsession.request_reference_space(XrReferenceSpaceType::Local)).await else{
-        console::log_1(&"[Error] Could not get reference space".into());
-        return;
+    // 床を原点にできる空間から順に試す
+    let mut reference_space = match ReferenceSpace::request(&xrsession, &ReferenceSpaceConfig::for_mode(mode)).await{
+        Ok(reference_space)=>reference_space,
+        Err(_)=>{
+            console::log_1(&"[Error] Could not get reference space".into());
+            abandon_session(&xrsession, renderer).await;
+            return;
+        },
     };
+    log_play_area(&reference_space);
+
+    let lifecycle = Rc::new(SessionLifecycle::new(&xrsession));
+    // セッションの終了後にGLのリソースを解放するため、ループのクロージャと共有する
+    let renderer = Rc::new(RefCell::new(renderer));
+    let animation_loop = Rc::new(RefCell::new(None::<Closure<dyn FnMut(f64,XrFrame)>>));
+    let mut fps_tracker = Logger::new(performance);
+    //初期状態はNone
+    //RefCellはClosureを後で自分自身を参照できるようにするためのラッパー
+    //Rcは複数の所有者を持つためのスマートポインタ
+
+    let (mut input, mut input_rx) = Input::new(&xrsession, reference_space.space());
+    let interaction = Interaction::new(mode, &features, vec![WalkableArea::floor(WALKABLE_FLOOR_SIZE)]);
+    let mut frame_loop = FrameLoop::new(FrameClock::new(MAX_DELTA_TIME), interaction, input_events);
+    // ?recordのときだけ、フレームごとの姿勢と入力を記録する。終了後にダウンロードできるようにする
+    let recorder = Rc::new(RefCell::new(web_sys::window()
+        .filter(session_recording::recording_enabled)
+        .map(|_| SessionRecorder::new(MAX_RECORDED_FRAMES))));
+    if recorder.borrow().is_some(){
+        console::log_1(&"Recording WebXR session".into());
+    }
 
-    if XrReferenceSpace::instanceof(&reference_space_js){
-        let reference_space = XrReferenceSpace::unchecked_from_js(reference_space_js);
-        let animation_loop = Rc::new(RefCell::new(None::<Closure<dyn FnMut(f64,XrFrame)>>));
-        let mut fps_tracker = Logger::new(performance);
-        //初期状態はNone
-        //RefCellはClosureを後で自分自身を参照できるようにするためのラッパー
-        //Rcは複数の所有者を持つためのスマートポインタ
+    let animation_loop_clone = Rc::clone(&animation_loop);
+    let lifecycle_clone = Rc::clone(&lifecycle);
+    let renderer_clone = Rc::clone(&renderer);
+    let recorder_clone = Rc::clone(&recorder);
+    let session_clone = xrsession.clone();
+    *animation_loop.borrow_mut() = Some(Closure::wrap(Box::new(move |time: f64, frame: XrFrame|{
+        let mut renderer = renderer_clone.borrow_mut();
+        let renderer = &mut *renderer;
+        fps_tracker.track_frame();
+        fps_tracker.log_fps();
+        fps_tracker.log_memory_usage();
+        // フレームの間でだけプログラムを差し替えるので、描画途中で切り替わることはない
+        if let Some(Ok(sources)) = shader_reload_rx.as_mut().map(|receiver| receiver.try_recv()){
+            reload_program(renderer, &sources);
+        }
+        // ユーザーが向きをリセットしたら、読み直した境界を表示し、移動した分を捨てて新しい原点に立つ
+        let reset = reference_space.take_reset();
+        if reset{
+            log_play_area(&reference_space);
+        }
+        let visibility = lifecycle_clone.visibility();
+        let mut frame_input = WebXrInput{frame: &frame, reference_space: &mut reference_space, input: &mut input, events: &mut input_rx};
+        if reset{
+            frame_loop.reset(&mut frame_input);
+        }
+        let step = frame_loop.step(&mut frame_input, time, visibility);
+        if visibility.should_simulate(){
+            if let Some(window) = web_sys::window(){
+                renderer.input_models.request_profiles(&window, input.sources());
+            }
+        }
+        renderer.input_models.receive(&renderer.program, &mut renderer.meshes);
+        let mut input_draws = renderer.input_models.draws(input.sources());
+        if let Some(arc) = frame_loop.interaction.locomotion.as_ref().and_then(|locomotion| locomotion.arc()){
+            input_draws.extend(renderer.input_models.arc_draws(arc));
+        }
+        if let Some(gl_layer) = session_clone.render_state().base_layer(){
+            let source = WebXrFrame{frame: &frame, reference_space: reference_space.space(), layer: &gl_layer};
+            if let Some(recorder) = recorder_clone.borrow_mut().as_mut(){
+                record_frame(recorder, time, &source, visibility, input.sources(), &step.events);
+            }
+            if let Some(views) = source.views(){
+                if renderer.multiview.is_none(){
+                    fit_canvas_to_views(renderer.gl(), &views);
+                }
+                render_frame(&views, gl_layer.framebuffer().as_ref(), renderer, &scene, &input_draws);
+            }
+        }
+        if let Some(animation_loop) = animation_loop_clone.borrow().as_ref(){
+            lifecycle_clone.request_animation_frame(animation_loop.as_ref().unchecked_ref::<js_sys::Function>());
+        }
+    }) as Box<dyn FnMut(f64,XrFrame)>));
 
-        let animation_loop_clone = Rc::clone(&animation_loop);
-        let session_clone = xrsession.clone();
-        *animation_loop.borrow_mut() = Some(Closure::wrap(Box::new(move |time: f64, frame: XrFrame|{
-            fps_tracker.track_frame();
-            fps_tracker.log_fps();
-            fps_tracker.log_memory_usage();
-            render_frame(time, &frame, &reference_space, &session_clone, &gl, &program);
-            session_clone.request_animation_frame(animation_loop_clone.borrow().as_ref().unwrap().as_ref().unchecked_ref::<js_sys::Function>());
-        }) as Box<dyn FnMut(f64,XrFrame)>));
+    //最初のアニメーションフレームをリクエスト
+    if let Some(animation_loop) = animation_loop.borrow().as_ref(){
+        lifecycle.request_animation_frame(animation_loop.as_ref().unchecked_ref::<js_sys::Function>());
+    }
 
-        //最初のアニメーションフレームをリクエスト
-        let _animation_frame_request_id = xrsession.request_animation_frame(animation_loop.borrow().as_ref().unwrap().as_ref().unchecked_ref::<js_sys::Function>());
+    // ユーザーがXRを抜けるか、セッションが終了させられるまで待つ
+    lifecycle.wait_for_end().await;
+    lifecycle.cancel_animation_frame();
+    // クロージャを破棄すると、入力や参照空間のリスナーも外れる
+    drop(animation_loop.borrow_mut().take());
+    match Rc::try_unwrap(renderer){
+        Ok(renderer)=>renderer.into_inner().delete(),
+        Err(_)=>console::log_1(&"[Warning] Renderer is still in use, GL resources were not freed".into()),
+    }
+    let recorder = recorder.borrow_mut().take();
+    if let Some(recorder) = recorder{
+        console::log_1(&format!("Recorded {} frames", recorder.frame_count()).into());
+        let document = web_sys::window().and_then(|window| window.document());
+        if let Err(error) = document.ok_or_else(|| JsValue::from_str("no document")).and_then(|document| session_recording::offer_download(&document, &recorder.into_bytes())){
+            console::log_1(&format!("[Error] Could not offer the session recording for download: {:?}", error).into());
+        }
     }
 }
 
-#[wasm_bindgen]
-pub fn render_frame(_time: f64, frame: &XrFrame, reference_space: &XrReferenceSpace, _session: &XrSession, gl: &WebGl2RenderingContext, program: &web_sys::WebGlProgram){
-    let pose = frame.get_viewer_pose(reference_space);
-    if let Some(pose) = pose{
-        let gl_layer = frame.session().render_state().base_layer().unwrap();
-        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, gl_layer.framebuffer().as_ref());
-        console::log_1(&"bind framebuffer".into());
-
-        gl.clear_color(0.0, 0.0, 0.0, 1.0);
-        gl.clear_depth(1.0);
-        gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
-        console::log_1(&"gl clear".into());
-
-        for view in pose.views(){
-            let xrview = view.dyn_into::<XrView>().unwrap();
-            let viewport = gl_layer.get_viewport(&xrview).unwrap();
-            gl.viewport(viewport.x(), viewport.y(), viewport.width(), viewport.height());
-            console::log_1(&"setting viewport for eye".into());
-            let canvas = gl.canvas().unwrap().dyn_into::<HtmlCanvasElement>().unwrap();
-            canvas.set_width(viewport.width() as u32 * pose.views().length());
-            canvas.set_height(viewport.height() as u32);
-            render_scene(gl, &xrview, program);
-        }
+// このフレームで描くビューと、アプリが受け取った入力を記録する
+#[cfg(target_arch = "wasm32")]
+fn record_frame(recorder: &mut SessionRecorder, time: f64, source: &WebXrFrame, visibility: Visibility, input_sources: &[InputSourceState], events: &[InputEvent]){
+    let viewer_pose = source.frame.get_viewer_pose(source.reference_space).map(|viewer_pose| Pose::from_transform(&viewer_pose.transform()));
+    // XrInputSourceは記録に残せないので外す
+    let frame = RecordedFrame{
+        time,
+        visibility,
+        viewer_pose,
+        views: source.views().unwrap_or_default(),
+        input_sources: input_sources.iter().map(|source| InputSourceState{source: None, ..source.clone()}).collect(),
+        events: events.iter().map(|event| InputEvent{source: None, ..event.clone()}).collect(),
+    };
+    recorder.record(&frame);
+}
+
+// 描画を始める前に失敗したときに、GLのリソースを解放してセッションを終える
+#[cfg(target_arch = "wasm32")]
+async fn abandon_session(session: &XrSession, renderer: Renderer<WebGl2RenderingContext>){
+    renderer.delete();
+    end_session(session).await;
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn end_session(session: &XrSession){
+    if JsFuture::from(session.end()).await.is_err(){
+        console::log_1(&"[Warning] Could not end the XR session".into());
     }
 }
 
-#[wasm_bindgen]
-pub fn render_scene(gl: &WebGl2RenderingContext, view: &XrView, program: &web_sys::WebGlProgram){
+#[cfg(target_arch = "wasm32")]
+fn log_play_area(reference_space: &ReferenceSpace){
+    if let Some(bounds) = reference_space.bounds(){
+        let [width, depth] = bounds.size();
+        console::log_1(&format!("Play area is {:.2}m x {:.2}m", width, depth).into());
+    }
+}
 
-    // model行列の設定
-    let mut scale = mat4::create();
-    let scale_clone = scale;
-    mat4::scale(&mut scale,&scale_clone, &[0.3,0.3,0.3]);
+// 購読した入力イベントをログに出す。セッションが終わって購読者が破棄されると止まる
+pub async fn log_input_events(mut events: mpsc::UnboundedReceiver<InputEvent>){
+    while let Some(event) = events.next().await{
+        platform::log(&format!("{:?} ({})", event.kind, event.handedness));
+    }
+}
 
-    let mut rotation = mat4::create();
-    let rotation_clone = rotation;
-    mat4::rotate_z(&mut rotation, &rotation_clone, 0.0);
+// 新しいソースでプログラムを作り直す。失敗した場合は今のプログラムを使い続ける
+pub fn reload_program<G: GraphicsDevice>(renderer: &mut Renderer<G>, sources: &ShaderSources){
+    match renderer.programs.get_or_compile(&sources.vertex, &sources.fragment, &renderer.defines){
+        Ok(program)=>{
+            if !program.same_program(&renderer.program){
+                let previous = std::mem::replace(&mut renderer.program, program);
+                renderer.programs.evict(&previous);
+                platform::log("Reloaded shader program");
+            }
+        },
+        Err(error)=>{
+            platform::log(&format!("[Error] Shader reload failed, keeping the previous program\n{}", error));
+        },
+    }
+}
 
-    let mut translation = mat4::create();
-    let translation_clone = translation;
-    mat4::translate(&mut translation, &translation_clone, &[0.0,0.0,0.0]);
+// framebufferはXRのレイヤーのフレームバッファ。Noneなら既定のフレームバッファに描く
+pub fn render_frame<G: GraphicsDevice>(views: &[ViewData], framebuffer: Option<&G::Framebuffer>, renderer: &mut Renderer<G>, scene: &Scene, input_draws: &[ModelDraw]){
+    let gl = renderer.gl().clone();
+    gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, framebuffer);
+
+    let [red, green, blue, alpha] = renderer.blend_mode.clear_color();
+    gl.clear_color(red, green, blue, alpha);
+    gl.clear_depth(1.0);
+    gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
+
+    if let (Some(multiview), Some(first)) = (renderer.multiview.as_mut(), views.first()){
+        // 全てのビューの行列をまとめてuniformバッファに送る
+        write_views(&mut renderer.camera, views);
+        renderer.camera.upload(&gl);
+        // 両目のビューポートは同じ大きさである前提で、1回の描画で両方のレイヤーに描く
+        match multiview.begin(&gl, first.viewport.width, first.viewport.height){
+            Ok(())=>{
+                gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
+                render_scene(&renderer.program, None, scene, &renderer.meshes, input_draws);
+                let viewports: Vec<_> = views.iter().map(|view| view.viewport).collect();
+                multiview.end(&gl, framebuffer, &viewports);
+            },
+            Err(error)=>platform::log(&format!("[Error] {}", error)),
+        }
+        return;
+    }
+    render_views(&renderer.program, &mut renderer.camera, views, scene, &renderer.meshes, input_draws);
+}
 
-    let mut model = mat4::create();
-    let model_clone = model;
-    mat4::multiply(&mut model, &model_clone, &translation);
-    mat4::multiply(&mut model, &model_clone, &rotation);
-    mat4::multiply(&mut model, &model_clone, &scale);
+// マルチビューを使わないときは、キャンバスを全てのビューが横に並ぶ大きさにする
+#[cfg(target_arch = "wasm32")]
+fn fit_canvas_to_views(gl: &WebGl2RenderingContext, views: &[ViewData]){
+    let Some(first) = views.first() else{
+        return;
+    };
+    let Some(canvas) = gl.canvas().and_then(|canvas| canvas.dyn_into::<HtmlCanvasElement>().ok()) else{
+        return;
+    };
+    canvas.set_width(first.viewport.width as u32 * views.len() as u32);
+    canvas.set_height(first.viewport.height as u32);
+}
 
-    let view_transform = view.transform();
-    let view_position = view_transform.position();
-    let camera_position = vec3::from_values(-1.0*view_position.x() as f32, -1.0*view_position.y() as f32, -1.0*view_position.z() as f32);
-    let view_direction = view_transform.orientation();
-    let view_quat = quat::from_values(view_direction.x() as f32, view_direction.y() as f32, view_direction.z() as f32, -1.0*view_direction.w() as f32);
-    let mut view_matrix = mat4::create();
-    mat4::from_rotation_translation(&mut view_matrix, &view_quat, &camera_position);
+// ビューの行列をまとめてuniformバッファに書き込む。送るのはupload
+fn write_views<G: GraphicsDevice>(camera: &mut UniformBuffer<G>, views: &[ViewData]){
+    for (index, view) in views.iter().enumerate().take(MAX_VIEWS){
+        if let Err(error) = write_camera_matrices(camera, index, &view.view, &view.projection){
+            platform::log(&format!("[Error] {}", error));
+        }
+    }
+}
+
+// マルチビューを使わずに、ビューごとにビューポートを切り替えて描く
+// XRでもネイティブのテストでも、描画先のフレームバッファは呼び出し側がバインドしておく
+pub fn render_views<G: GraphicsDevice>(gl_program: &GlProgram<G>, camera: &mut UniformBuffer<G>, views: &[ViewData], scene: &Scene, meshes: &[Mesh<G>], input_draws: &[ModelDraw]){
+    let gl = gl_program.gl();
+    write_views(camera, views);
+    camera.upload(gl);
+    for (index, view) in views.iter().enumerate().take(MAX_VIEWS){
+        let viewport = view.viewport;
+        gl.viewport(viewport.x, viewport.y, viewport.width, viewport.height);
+        render_scene(gl_program, Some(index), scene, meshes, input_draws);
+    }
+}
 
-    // XrViewをもとにした、projection行列の設定
-    let projection_from_view = view.projection_matrix();
-    let projection:[f32; 16] = projection_from_view.try_into().unwrap();
+// index番目のビューの行列をカメラのuniformバッファに書き込む。送るのはupload
+pub fn write_camera_matrices<G: GraphicsDevice>(camera: &mut UniformBuffer<G>, index: usize, view: &Mat4, projection: &Mat4)->Result<(),std140::Std140Error>{
+    camera.data.set_mat4_at("views", index, view)?;
+    camera.data.set_mat4_at("projections", index, projection)
+}
 
-    let model_location = gl.get_uniform_location(program, "model");
-    let view_location = gl.get_uniform_location(program, "view");
-    let projection_location = gl.get_uniform_location(program, "projection");
-    gl.uniform_matrix4fv_with_f32_array(model_location.as_ref(), false, &model);
-    gl.uniform_matrix4fv_with_f32_array(view_location.as_ref(), false, &view_matrix);
-    gl.uniform_matrix4fv_with_f32_array(projection_location.as_ref(), false, &projection);
+// カメラの行列はuniformバッファに送ってあるので、ここではどのビューを描くかだけを指定する
+// マルチビューではシェーダがgl_ViewID_OVRでビューを選ぶので、view_indexはNone
+// input_drawsはシーングラフの外にある、コントローラーや手などのその場で作った描画
+pub fn render_scene<G: GraphicsDevice>(gl_program: &GlProgram<G>, view_index: Option<usize>, scene: &Scene, meshes: &[Mesh<G>], input_draws: &[ModelDraw]){
+    let gl = &gl_program.gl;
+    gl.use_program(Some(&gl_program.program));
+    if let Some(view_index) = view_index{
+        gl_program.set_int("view_index", view_index as i32);
+    }
 
-    gl.draw_elements_with_i32(WebGl2RenderingContext::TRIANGLES, 36, WebGl2RenderingContext::UNSIGNED_SHORT, 0);
+    // シーングラフを辿って、メッシュを持つノードを順に描画する
+    let scene_draws = scene.draw_items().into_iter().map(|item| ModelDraw{mesh: item.mesh, world: item.world});
+    for item in scene_draws.chain(input_draws.iter().copied()){
+        let Some(mesh) = meshes.get(item.mesh.0) else{
+            platform::log(&format!("[Error] Mesh {} was not found", item.mesh.0));
+            continue;
+        };
+        gl_program.set_mat4("model", &item.world);
+        mesh.draw(gl);
+    }
 }
 
 #[derive(Debug,Clone)]
 pub enum ShaderVariant{
-    Vertex(String),
-    Fragment(String),
+    Vertex(PreprocessedSource),
+    Fragment(PreprocessedSource),
 }
 
+#[cfg(target_arch = "wasm32")]
 pub struct Shader{
-    vertex_shader: Option<String>,
-    fragment_shader: Option<String>,
+    vertex_shader: Option<PreprocessedSource>,
+    fragment_shader: Option<PreprocessedSource>,
 }
 
 
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
-pub async fn ready_webgl2_context(window: &Window, document: &Document, gl: WebGl2RenderingContext)->Result<GlProgram ,JsValue>{
+pub async fn ready_webgl2_context(window: &Window, document: &Document, gl: WebGl2RenderingContext)->Result<GlProgram<WebGl2RenderingContext>,JsValue>{
+    let mut programs = ProgramCache::new(&gl);
+    let (gl_program, _) = ready_webgl2_context_with_cache(window, document, &mut programs, &Defines::new()).await?;
+    Ok(gl_program)
+}
+
+// シェーダを読み込み、#defineを注入したプログラムをキャッシュ経由で用意する
+// ホットリロードの比較の基準にするため、コンパイルしたソースも返す
+#[cfg(target_arch = "wasm32")]
+pub async fn ready_webgl2_context_with_cache(window: &Window, document: &Document, programs: &mut ProgramCache<WebGl2RenderingContext>, defines: &Defines)->Result<(GlProgram<WebGl2RenderingContext>,ShaderSources),JsValue>{
     let (shader_tx, mut shader_rx) = mpsc::channel::<ShaderVariant>(32);
 
     let shaders = async move{
@@ -235,16 +778,13 @@ pub async fn ready_webgl2_context(window: &Window, document: &Document, gl: WebG
     let mut vertex_tx = shader_tx.clone();
 
     wasm_bindgen_futures::spawn_local(async move{
-        let Ok(vertex_shader) = fetch_shader(window_clone, "../shader/vertex_shader.glsl").await else{
+        let Ok(vertex_shader) = load_shader(window_clone, VERTEX_SHADER_PATH).await else{
             console::log_1(&"[Error] Could not fetch vertex shader".into());
             let _ = display_error_page(&document_clone,"Could not fetch vertex shader").await;
             return;
         };
-        loop{
-            let Err(_) = vertex_tx.try_send(ShaderVariant::Vertex(vertex_shader.clone())) else{
-                break;
-            };
-        }
+        // 受け取る側が先に失敗して閉じていれば、送らずに終わる
+        let _ = vertex_tx.try_send(ShaderVariant::Vertex(vertex_shader));
     });
 
     let window_clone = window.clone();
@@ -252,29 +792,45 @@ pub async fn ready_webgl2_context(window: &Window, document: &Document, gl: WebG
     let mut fragment_tx = shader_tx.clone();
 
     wasm_bindgen_futures::spawn_local(async move{
-        let Ok(fragment_shader) = fetch_shader(window_clone, "../shader/fragment_shader.glsl").await else{
+        let Ok(fragment_shader) = load_shader(window_clone, FRAGMENT_SHADER_PATH).await else{
             console::log_1(&"[Error] Could not fetch fragment shader".into());
             let _ = display_error_page(&document_clone,"Could not fetch fragment shader").await;
             return;
         };
-        loop{
-            let Err(_) = fragment_tx.try_send(ShaderVariant::Fragment(fragment_shader.clone())) else{
-                break;
-            };
-        }
+        let _ = fragment_tx.try_send(ShaderVariant::Fragment(fragment_shader));
     });
 
+    // 読み込みタスクが失敗したときにチャンネルが閉じて待ち続けないよう、手元の送信側は先に捨てる
+    drop(shader_tx);
     let shader = shaders.await;
-    let program = compile_shader(&gl, &shader.vertex_shader.unwrap(), &shader.fragment_shader.unwrap()).await?;
+    let (Some(vertex), Some(fragment)) = (shader.vertex_shader, shader.fragment_shader) else{
+        return Err(JsValue::from_str("Could not fetch shaders"));
+    };
+    let sources = ShaderSources{vertex, fragment};
+    let gl_program = match programs.get_or_compile(&sources.vertex, &sources.fragment, defines){
+        Ok(gl_program)=>gl_program,
+        Err(ProgramError::Shader(error))=>{
+            console::log_1(&format!("[Error] {}", error).into());
+            display_shader_error(document, &error).await?;
+            return Err(error.into());
+        },
+        Err(error)=>{
+            console::log_1(&format!("[Error] {}", error).into());
+            display_error_page(document, &error.to_string()).await?;
+            return Err(error.into());
+        },
+    };
 
-    const VERTEX_SIZE: i32 = 3;
-    const COLOR_SIZE: i32 = 4;
+    let gl = programs.gl();
+    gl.enable(WebGl2RenderingContext::DEPTH_TEST);
+    gl.enable(WebGl2RenderingContext::CULL_FACE);
 
-    const FLOAT32_BYTES_PER_ELEMENT: i32 = 4;
-    const STRIDE: i32 = (VERTEX_SIZE + COLOR_SIZE) * FLOAT32_BYTES_PER_ELEMENT;
-    const POSITION_OFFSET: i32 = 0;
-    const COLOR_OFFSET: i32 = VERTEX_SIZE * FLOAT32_BYTES_PER_ELEMENT;
+    Ok((gl_program, sources))
+}
 
+// 頂点座標と頂点色を持つ立方体をGPUに転送する
+pub fn create_cube_mesh<G: GraphicsDevice>(gl_program: &GlProgram<G>)->Result<Mesh<G>,JsValue>{
+    let layout = VertexLayout::new(&[VertexAttribute::Position, VertexAttribute::Color])?;
     let vertices:[f32;56] = [
         0.0, 0.5, -0.5,  // 座標
         1.0, 1.0, 1.0, 1.0,      // 色
@@ -308,24 +864,130 @@ pub async fn ready_webgl2_context(window: &Window, document: &Document, gl: WebG
         5, 4, 6,
     ];
 
-    let interleaved_buffer = create_f32_buffer(WebGl2RenderingContext::ARRAY_BUFFER, &vertices, &gl).await?;
-    let index_buffer = create_u16_buffer(WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER, &indices, &gl).await?;
+    let mesh = Mesh::new(gl_program, layout, &vertices, &indices)?;
+    Ok(mesh)
+}
 
-    let vertex_attrib_location = gl.get_attrib_location(&program, "vertex_position");
-    let color_attrib_location = gl.get_attrib_location(&program, "color");
+// glTF(.gltf / .glb)をfetchして、外部バッファも含めて読み込む
+#[cfg(target_arch = "wasm32")]
+pub async fn load_gltf(window: &Window, path: &str)->Result<GltfAsset,JsValue>{
+    let fetcher = WebFetcher{window: window.clone()};
+    Ok(load_gltf_from(&fetcher, path).await?)
+}
 
-    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&interleaved_buffer));
-    gl.enable_vertex_attrib_array(vertex_attrib_location as u32);
-    gl.vertex_attrib_pointer_with_i32(vertex_attrib_location as u32, VERTEX_SIZE, WebGl2RenderingContext::FLOAT, false, STRIDE, POSITION_OFFSET);
-    gl.enable_vertex_attrib_array(color_attrib_location as u32);
-    gl.vertex_attrib_pointer_with_i32(color_attrib_location as u32, COLOR_SIZE, WebGl2RenderingContext::FLOAT, false, STRIDE, COLOR_OFFSET);
+// 外部バッファのURIはglTFファイルからの相対パスとして、同じfetcherで読み込む
+pub async fn load_gltf_from<A: AssetFetcher>(fetcher: &A, path: &str)->Result<GltfAsset,AssetError>{
+    let error = |message: String|{
+        platform::log(&format!("[Error] {}: {}", path, message));
+        AssetError{path: path.to_string(), message}
+    };
+    let bytes = fetcher.fetch_bytes(path).await?;
+    let document = GltfDocument::from_slice(&bytes).map_err(|gltf_error| error(gltf_error.to_string()))?;
 
-    gl.bind_buffer(WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER, Some(&index_buffer));
-    gl.enable(WebGl2RenderingContext::DEPTH_TEST);
-    gl.enable(WebGl2RenderingContext::CULL_FACE);
+    let base = match path.rfind('/'){
+        Some(index)=>&path[..=index],
+        None=>"",
+    };
+    let mut external_buffers = HashMap::new();
+    for uri in document.external_uris(){
+        let buffer = fetcher.fetch_bytes(&format!("{}{}", base, uri)).await?;
+        external_buffers.insert(uri, buffer);
+    }
 
-    let gl_program = GlProgram{gl, program};
-    Ok(gl_program)
+    document.load(&external_buffers).map_err(|gltf_error| error(gltf_error.to_string()))
+}
+
+// glTFのプリミティブをGPUに転送し、ノード階層をシーンに追加する
+pub fn add_gltf_to_scene<G: GraphicsDevice>(gl_program: &GlProgram<G>, asset: &GltfAsset, scene: &mut Scene, parent: Option<NodeId>, meshes: &mut Vec<Mesh<G>>)->Result<Vec<NodeId>,JsValue>{
+    let mut primitive_meshes = Vec::with_capacity(asset.meshes.len());
+    for mesh in asset.meshes.iter(){
+        let mut handles = Vec::with_capacity(mesh.primitives.len());
+        for primitive in mesh.primitives.iter(){
+            let material = asset.material(primitive);
+            let layout = primitive.vertex_layout();
+            let vertices = primitive.interleave(&layout, material.base_color_factor);
+            let indices = primitive.indices_u16().map_err(|error|{
+                console::log_1(&format!("[Error] {}: {}", mesh.name, error).into());
+                JsValue::from_str(&error.to_string())
+            })?;
+
+            let gpu_mesh = Mesh::new(gl_program, layout, &vertices, &indices).map_err(|error|{
+                console::log_1(&format!("[Error] {}: {}", mesh.name, error).into());
+                JsValue::from(error)
+            })?;
+            handles.push(MeshHandle(meshes.len()));
+            meshes.push(gpu_mesh);
+        }
+        primitive_meshes.push(handles);
+    }
+    Ok(asset.instantiate(scene, parent, &primitive_meshes))
+}
+
+#[wasm_bindgen]
+pub async fn fetch_bytes(window: Window, path: &str)->Result<Vec<u8>,JsValue>{
+    let response = JsFuture::from(
+        window.fetch_with_str(path),
+    ).await?;
+
+    let response = response.dyn_into::<Response>()?;
+    if !response.ok(){
+        console::log_1(&format!("[Error] Could not fetch {} ({})", path, response.status()).into());
+        return Err(JsValue::null());
+    }
+
+    let buffer = JsFuture::from(
+        response.array_buffer()?
+    ).await?;
+
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
+// シェーダを読み込み、#includeを展開する。#defineはProgramCacheで注入する
+#[cfg(target_arch = "wasm32")]
+pub async fn load_shader(window: Window, path: &str)->Result<PreprocessedSource,JsValue>{
+    load_shader_with_query(window, path, "").await
+}
+
+// fetchするURLにだけクエリを付ける。ホットリロードでキャッシュを避けるために使う
+#[cfg(target_arch = "wasm32")]
+pub async fn load_shader_with_query(window: Window, path: &str, query: &str)->Result<PreprocessedSource,JsValue>{
+    let fetcher = WebFetcher{window};
+    load_shader_from(&fetcher, path, query).await.map_err(|_| JsValue::null())
+}
+
+// #includeを辿って全てのファイルを読み込んでから展開する
+pub async fn load_shader_from<A: AssetFetcher>(fetcher: &A, path: &str, query: &str)->Result<PreprocessedSource,AssetError>{
+    let mut files = HashMap::new();
+    let mut pending = vec![path.to_string()];
+    // 循環しているインクルードは取得済みなので、ここでは止まり、展開時にエラーになる
+    while let Some(file) = pending.pop(){
+        if files.contains_key(&file){
+            continue;
+        }
+        let source = match fetcher.fetch_text(&format!("{}{}", file, query)).await{
+            Ok(source)=>source,
+            Err(error)=>{
+                platform::log(&format!("[Error] Could not fetch shader {}", file));
+                return Err(error);
+            },
+        };
+        pending.extend(preprocess::include_paths(&file, &source));
+        files.insert(file, source);
+    }
+
+    preprocess::resolve_includes(path, &files).map_err(|error|{
+        platform::log(&format!("[Error] {}", error));
+        AssetError{path: path.to_string(), message: error.to_string()}
+    })
+}
+
+// 前処理済みのソースからプログラムを作る。エラーは展開前のファイルと行番号で報告する
+pub fn compile_preprocessed<G: GraphicsDevice>(gl: &G, vertex: &PreprocessedSource, fragment: &PreprocessedSource)->Result<G::Program,ShaderError>{
+    compile_program(gl, &vertex.code, &fragment.code).map_err(|error| match error.stage{
+        ShaderStage::Vertex=>error.with_source_map(vertex),
+        ShaderStage::Fragment=>error.with_source_map(fragment),
+        ShaderStage::Link=>error,
+    })
 }
 
 #[wasm_bindgen]
@@ -348,87 +1010,164 @@ pub async fn fetch_shader(window: Window, shader_path: &str)->Result<String,JsVa
     Ok(shader_text)
 }
 
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
-pub async fn compile_shader(gl: &WebGl2RenderingContext, vertex: &str, fragment: &str)->Result<WebGlProgram,JsValue>{
-    let vertex_shader = gl.create_shader(WebGl2RenderingContext::VERTEX_SHADER).unwrap();
-    gl.shader_source(&vertex_shader, vertex);
-    gl.compile_shader(&vertex_shader);
+pub fn compile_shader(gl: &WebGl2RenderingContext, vertex: &str, fragment: &str)->Result<WebGlProgram,ShaderError>{
+    compile_program(gl, vertex, fragment)
+}
 
-    let fragment_shader = gl.create_shader(WebGl2RenderingContext::FRAGMENT_SHADER).unwrap();
-    gl.shader_source(&fragment_shader, fragment);
-    gl.compile_shader(&fragment_shader);
+// シェーダをコンパイル・リンクして、そのプログラムをuse_programしておく
+pub fn compile_program<G: GraphicsDevice>(gl: &G, vertex: &str, fragment: &str)->Result<G::Program,ShaderError>{
+    let vertex_shader = compile_stage(gl, ShaderStage::Vertex, vertex)?;
+    let fragment_shader = match compile_stage(gl, ShaderStage::Fragment, fragment){
+        Ok(fragment_shader)=>fragment_shader,
+        Err(error)=>{
+            gl.delete_shader(Some(&vertex_shader));
+            return Err(error);
+        },
+    };
 
     let Some(program) = gl.create_program() else{
-        console::log_1(&"[Error] Could not create program".into());
-        return Err(JsValue::null());
+        platform::log("[Error] Could not create program");
+        gl.delete_shader(Some(&vertex_shader));
+        gl.delete_shader(Some(&fragment_shader));
+        return Err(ShaderError::new(ShaderStage::Link, "Could not create program", None));
     };
     gl.attach_shader(&program, &vertex_shader);
     gl.attach_shader(&program, &fragment_shader);
+    // 頂点属性の位置を固定しておき、プログラムを差し替えてもメッシュのVAOをそのまま使えるようにする
+    for (location, attribute) in VertexAttribute::ALL.iter().enumerate(){
+        gl.bind_attrib_location(&program, location as u32, attribute.shader_name());
+    }
     gl.link_program(&program);
 
+    // リンク後はシェーダオブジェクトが不要になる
+    gl.detach_shader(&program, &vertex_shader);
+    gl.detach_shader(&program, &fragment_shader);
+    gl.delete_shader(Some(&vertex_shader));
+    gl.delete_shader(Some(&fragment_shader));
+
+    if gl.get_program_parameter(&program, WebGl2RenderingContext::LINK_STATUS) == 0{
+        let info_log = gl.get_program_info_log(&program).unwrap_or_default();
+        gl.delete_program(Some(&program));
+        return Err(ShaderError::new(ShaderStage::Link, &info_log, None));
+    }
+
     gl.use_program(Some(&program));
 
     Ok(program)
 }
 
-#[wasm_bindgen]
-pub async fn create_f32_buffer(buffer_type: u32, typed_data_array: &[f32], gl: &WebGl2RenderingContext) -> Result<web_sys::WebGlBuffer, JsValue>{
-    let buffer = gl.create_buffer().unwrap();
-    gl.bind_buffer(buffer_type, Some(&buffer));
-    let array = js_sys::Float32Array::from(typed_data_array);
-    gl.buffer_data_with_array_buffer_view(buffer_type, &array, WebGl2RenderingContext::STATIC_DRAW);
+// シェーダを1つコンパイルし、COMPILE_STATUSを確認する
+fn compile_stage<G: GraphicsDevice>(gl: &G, stage: ShaderStage, source: &str)->Result<G::Shader,ShaderError>{
+    let shader_type = match stage{
+        ShaderStage::Vertex=>WebGl2RenderingContext::VERTEX_SHADER,
+        _=>WebGl2RenderingContext::FRAGMENT_SHADER,
+    };
+    let Some(shader) = gl.create_shader(shader_type) else{
+        return Err(ShaderError::new(stage, "Could not create shader", None));
+    };
+    gl.shader_source(&shader, source);
+    gl.compile_shader(&shader);
 
-    // バッファのバインドを解除
-    gl.bind_buffer(buffer_type, None);
+    if gl.get_shader_parameter(&shader, WebGl2RenderingContext::COMPILE_STATUS) == 0{
+        let info_log = gl.get_shader_info_log(&shader).unwrap_or_default();
+        gl.delete_shader(Some(&shader));
+        return Err(ShaderError::new(stage, &info_log, Some(source)));
+    }
+    Ok(shader)
+}
 
-    Ok(buffer)
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub async fn create_f32_buffer(buffer_type: u32, typed_data_array: &[f32], gl: &WebGl2RenderingContext) -> Result<web_sys::WebGlBuffer, JsValue>{
+    Ok(upload_f32_buffer(gl, buffer_type, typed_data_array)?)
 }
 
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 pub async fn create_u16_buffer(buffer_type: u32, typed_data_array: &[u16], gl: &WebGl2RenderingContext) -> Result<web_sys::WebGlBuffer, JsValue>{
-    let buffer = gl.create_buffer().unwrap();
-    gl.bind_buffer(buffer_type, Some(&buffer));
-    let array = js_sys::Uint16Array::from(typed_data_array);
-    gl.buffer_data_with_array_buffer_view(buffer_type, &array, WebGl2RenderingContext::STATIC_DRAW);
+    Ok(upload_u16_buffer(gl, buffer_type, typed_data_array)?)
+}
 
-    // バッファのバインドを解除
+// バッファを作ってデータを送り、バインドを解除して返す
+pub fn upload_f32_buffer<G: GraphicsDevice>(gl: &G, buffer_type: u32, data: &[f32])->Result<G::Buffer,GraphicsError>{
+    let Some(buffer) = gl.create_buffer() else{
+        return Err(GraphicsError("Could not create buffer".to_string()));
+    };
+    gl.bind_buffer(buffer_type, Some(&buffer));
+    gl.buffer_data_f32(buffer_type, data, WebGl2RenderingContext::STATIC_DRAW);
     gl.bind_buffer(buffer_type, None);
+    Ok(buffer)
+}
 
+pub fn upload_u16_buffer<G: GraphicsDevice>(gl: &G, buffer_type: u32, data: &[u16])->Result<G::Buffer,GraphicsError>{
+    let Some(buffer) = gl.create_buffer() else{
+        return Err(GraphicsError("Could not create buffer".to_string()));
+    };
+    gl.bind_buffer(buffer_type, Some(&buffer));
+    gl.buffer_data_u16(buffer_type, data, WebGl2RenderingContext::STATIC_DRAW);
+    gl.bind_buffer(buffer_type, None);
     Ok(buffer)
 }
 
 // webXRの使用可否を確認して、webXRセッションを返す関数
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 pub async fn webxr_available(xrsystem: &XrSystem,document: &Document)->Result<Option<XrSession>,JsValue>{
+    let session = start_xr_session_or_display_error(xrsystem, document, &SessionConfig::default()).await?;
+    Ok(session.map(|session| session.session))
+}
+
+// 必要な機能と、あれば使う機能を指定してwebXRセッションを返す関数
+// 有効になった機能はsession.enabledFeaturesで確認できる
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub async fn webxr_available_with_features(xrsystem: &XrSystem,document: &Document,required_features: Vec<String>,optional_features: Vec<String>)->Result<Option<XrSession>,JsValue>{
+    let mut config = SessionConfig::default();
+    for feature in required_features.iter(){
+        config = config.with_required_feature(feature);
+    }
+    for feature in optional_features.iter(){
+        config = config.with_optional_feature(feature);
+    }
+    let session = start_xr_session_or_display_error(xrsystem, document, &config).await?;
+    Ok(session.map(|session| session.session))
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn start_xr_session_or_display_error(xrsystem: &XrSystem, document: &Document, config: &SessionConfig)->Result<Option<ActiveSession>,JsValue>{
+    let session = start_xr_session(xrsystem, document, config).await?;
+    if session.is_none(){
+        let modes: Vec<String> = config.modes.iter().map(|mode| mode.to_string()).collect();
+        display_error_page(document, &format!("{} is not Available.", modes.join(", "))).await?;
+    }
+    Ok(session)
+}
+
+// navigator.xrが無いブラウザ(非セキュアコンテキストなど)ではXRSystemを取得できない
+#[cfg(target_arch = "wasm32")]
+fn has_xr_system(window: &Window)->bool{
+    js_sys::Reflect::get(&window.navigator(), &"xr".into()).is_ok_and(|xr| !xr.is_undefined() && !xr.is_null())
+}
+
+// 設定の優先順に使えるモードを探してセッションを開始し、選ばれたモード・有効になった機能と一緒に返す
+// どのモードも使えなければNone。エラーページを出すかは呼び出し側が決める
+#[cfg(target_arch = "wasm32")]
+pub async fn start_xr_session(xrsystem: &XrSystem, document: &Document, config: &SessionConfig)->Result<Option<ActiveSession>,JsValue>{
     console::log_1(&"Starting WebXR Support Check".into());
-    if let Some(is_supported) = JsFuture::from(
-        xrsystem.is_session_supported(
-            XrSessionMode::ImmersiveVr
-        )
-    ).await.unwrap().as_bool(){
-        if is_supported{
-            console::log_1(&"WebXR ImmersiveVr is Available!".into());
-            let session_jsval = JsFuture::from(xrsystem.request_session(XrSessionMode::ImmersiveVr)).await?;
-            if XrSession::instanceof(&session_jsval){
-                let xrsession = XrSession::unchecked_from_js(session_jsval);
-                Ok(Some(xrsession))
-            }
-            else{
-                console::log_1(&"[Error] WebXR ImmersiveVr is Available but Session is not instance of Xrsession".into());
-                display_error_page(document, "ImmersiveVr is Available but Session is not instance of Xrsession").await?;
-                Err(JsValue::null())
-            }
-        }
-        else{
-            console::log_1(&"WebXR ImmersiveVr is not Available.".into());
-            display_error_page(document, "ImmersiveVr is not Available.").await?;
+    match session::request_session(xrsystem, config).await{
+        Ok(Some(session))=>Ok(Some(session)),
+        Ok(None)=>{
+            let modes: Vec<String> = config.modes.iter().map(|mode| mode.to_string()).collect();
+            console::log_1(&format!("WebXR {} is not Available.", modes.join(", ")).into());
             Ok(None)
-        }
-    }
-    else{
-        console::log_1(&"[Error] WebXR Support unknown.".into());
-        display_error_page(document, "WebXR Support unknown.").await?;
-        Err(JsValue::null())
+        },
+        Err(error)=>{
+            console::log_1(&"[Error] Could not start WebXR session".into());
+            display_error_page(document, "Could not start WebXR session").await?;
+            Err(error)
+        },
     }
 }
 
@@ -467,3 +1206,127 @@ pub async fn display_error_page(document: &Document, error_msg: &str) -> Result<
     let _ = body.append_child(&default_val)?;
     Ok(())
 }
+
+// シェーダのエラーを、注釈付きのソースと一緒にエラーページに表示する関数
+pub async fn display_shader_error(document: &Document, error: &ShaderError) -> Result<(), JsValue>{
+    display_error_page(document, &error.summary()).await?;
+    let body = document.body().expect("document doesn't have body.");
+    let details = document.create_element("pre")?;
+    details.set_text_content(Some(&error.to_string()));
+    let _ = body.append_child(&details)?;
+    Ok(())
+}
+
+// ネイティブのテストで共通に使う、デモのシーンと固定した視点
+#[cfg(test)]
+pub(crate) mod test_support{
+    use super::*;
+    use crate::camera::projection_matrix;
+    use crate::platform::Viewport;
+
+    const VERTEX_SHADER_FILE: &str = "shader/vertex_shader.glsl";
+    const FRAGMENT_SHADER_FILE: &str = "shader/fragment_shader.glsl";
+
+    // shader/のデモのシェーダを、ブラウザと同じく#includeを展開して読む
+    pub fn demo_shaders()->(PreprocessedSource, PreprocessedSource){
+        let files: HashMap<String,String> = [VERTEX_SHADER_FILE, FRAGMENT_SHADER_FILE, "shader/common.glsl"].iter()
+            .map(|path| (path.to_string(), std::fs::read_to_string(path).unwrap()))
+            .collect();
+        let vertex = preprocess::resolve_includes(VERTEX_SHADER_FILE, &files).unwrap();
+        let fragment = preprocess::resolve_includes(FRAGMENT_SHADER_FILE, &files).unwrap();
+        (vertex, fragment)
+    }
+
+    // create_rendererと同じ手順で、立方体と入力用のメッシュを持つレンダラーとデモのシーンを用意する
+    // マルチビューはデバイスが対応していれば使う
+    pub fn demo_scene<G: GraphicsDevice>(gl: &G)->(Renderer<G>, Scene){
+        let mut defines = Defines::new();
+        let multiview = multiview::detect_multiview(gl);
+        if multiview.is_some(){
+            defines.set(multiview::MULTIVIEW_DEFINE, "");
+        }
+        let (vertex, fragment) = demo_shaders();
+        let mut programs = ProgramCache::new(gl);
+        let program = programs.get_or_compile(&vertex, &fragment, &defines).unwrap();
+        gl.enable(WebGl2RenderingContext::DEPTH_TEST);
+        gl.enable(WebGl2RenderingContext::CULL_FACE);
+
+        let mut meshes = vec![create_cube_mesh(&program).map_err(|_| ()).unwrap()];
+        let input_models = InputModels::new(&program, &mut meshes).map_err(|_| ()).unwrap();
+        let camera = UniformBuffer::new(gl, uniform::camera_layout(), uniform::CAMERA_BINDING).unwrap();
+        let renderer = Renderer{
+            programs,
+            program,
+            defines,
+            meshes,
+            camera,
+            multiview,
+            input_models,
+            blend_mode: BlendMode::Opaque,
+        };
+        (renderer, create_demo_scene(MeshHandle(0)))
+    }
+
+    // 正面1.2メートル手前の少し上から見た両目のビュー。左右に並べたeye_width×eye_heightのビューポート
+    pub fn stereo_views(eye_width: i32, eye_height: i32)->Vec<ViewData>{
+        let projection = projection_matrix(1.0, eye_width as f32 / eye_height as f32, 0.1, 100.0);
+        [-0.032f32, 0.032].iter().enumerate().map(|(index, eye_x)|{
+            let mut view = gl_matrix::mat4::create();
+            gl_matrix::mat4::from_translation(&mut view, &[-eye_x, -0.1, -1.2]);
+            ViewData{view, projection, viewport: Viewport{x: index as i32 * eye_width, y: 0, width: eye_width, height: eye_height}}
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::platform::mock::MockDevice;
+    use crate::test_support::{demo_scene,stereo_views};
+
+    #[test]
+    fn render_frame_draws_each_view_in_its_viewport(){
+        let gl = MockDevice::new();
+        let (mut renderer, scene) = demo_scene(&gl);
+        let framebuffer = gl.create_framebuffer();
+        gl.clear_records();
+        let views = stereo_views(64, 32);
+        render_frame(&views, framebuffer.as_ref(), &mut renderer, &scene, &[]);
+
+        let clears = gl.clears();
+        assert_eq!(clears.len(), 1);
+        assert_eq!(clears[0].color, BlendMode::Opaque.clear_color());
+        let draws = gl.draws();
+        let items = scene.draw_items().len();
+        assert_eq!(draws.len(), items * views.len());
+        for (index, view) in views.iter().enumerate(){
+            for draw in draws[index * items..(index + 1) * items].iter(){
+                assert_eq!((draw.framebuffer, draw.viewport), (framebuffer, view.viewport));
+            }
+        }
+        assert!(gl.blits().is_empty());
+    }
+
+    #[test]
+    fn render_frame_draws_once_and_blits_with_multiview(){
+        let gl = MockDevice::new();
+        gl.set_multiview(true);
+        let (mut renderer, scene) = demo_scene(&gl);
+        renderer.blend_mode = BlendMode::AlphaBlend;
+        let framebuffer = gl.create_framebuffer();
+        let views = stereo_views(64, 32);
+        render_frame(&views, framebuffer.as_ref(), &mut renderer, &scene, &[]);
+
+        // XRのフレームバッファと、マルチビューのレイヤーをそれぞれクリアする
+        let clears = gl.clears();
+        assert_eq!(clears.len(), 2);
+        assert_eq!(clears[0].color, BlendMode::AlphaBlend.clear_color());
+        let draws = gl.draws();
+        assert_eq!(draws.len(), scene.draw_items().len());
+        assert!(draws.iter().all(|draw| draw.framebuffer.is_some() && draw.framebuffer != framebuffer));
+        let blits = gl.blits();
+        assert_eq!(blits.len(), 2);
+        assert!(blits.iter().all(|blit| blit.draw_framebuffer == framebuffer));
+        assert_eq!(blits[1].destination, [64, 0, 128, 32]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::cell::{Cell,RefCell};
+use std::rc::Rc;
+use futures::channel::oneshot;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::*;
+
+// XRセッションの開始から終了まで
+// endとvisibilitychangeを受け取り、アニメーションフレームの要求もここを通して管理する
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Visibility{
+    Visible,
+    // システムのメニューなどが手前に出ていて、入力が届かない
+    VisibleBlurred,
+    // 描画結果が表示されていない。フレームも届かない
+    Hidden,
+}
+
+impl Visibility{
+    pub fn from_xr(state: XrVisibilityState)->Self{
+        match state{
+            XrVisibilityState::VisibleBlurred=>Visibility::VisibleBlurred,
+            XrVisibilityState::Hidden=>Visibility::Hidden,
+            _=>Visibility::Visible,
+        }
+    }
+
+    // 入力の処理などを進めてよいか。描画はvisible-blurredでも続ける
+    pub fn should_simulate(&self)->bool{
+        matches!(self, Visibility::Visible)
+    }
+}
+
+impl std::fmt::Display for Visibility{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            Visibility::Visible=>write!(f, "visible"),
+            Visibility::VisibleBlurred=>write!(f, "visible-blurred"),
+            Visibility::Hidden=>write!(f, "hidden"),
+        }
+    }
+}
+
+type SessionListener = Closure<dyn FnMut(Event)>;
+
+// アニメーションループのクロージャと共有するので、状態は全てCellに入れる
+pub struct SessionLifecycle{
+    session: XrSession,
+    visibility: Rc<Cell<Visibility>>,
+    ended: Rc<Cell<bool>>,
+    frame_request: Cell<Option<u32>>,
+    end_rx: RefCell<Option<oneshot::Receiver<()>>>,
+    listeners: Vec<(&'static str, SessionListener)>,
+}
+
+impl SessionLifecycle{
+    pub fn new(session: &XrSession)->Self{
+        let visibility = Rc::new(Cell::new(Visibility::from_xr(session.visibility_state())));
+        let ended = Rc::new(Cell::new(false));
+        let (end_tx, end_rx) = oneshot::channel::<()>();
+        let mut listeners = Vec::new();
+
+        let ended_clone = Rc::clone(&ended);
+        let mut end_tx = Some(end_tx);
+        let end_listener = Closure::wrap(Box::new(move |_: Event|{
+            console::log_1(&"WebXR session ended".into());
+            ended_clone.set(true);
+            if let Some(end_tx) = end_tx.take(){
+                let _ = end_tx.send(());
+            }
+        }) as Box<dyn FnMut(Event)>);
+        listeners.push(("end", end_listener));
+
+        let visibility_clone = Rc::clone(&visibility);
+        let session_clone = session.clone();
+        let visibility_listener = Closure::wrap(Box::new(move |_: Event|{
+            let visibility = Visibility::from_xr(session_clone.visibility_state());
+            console::log_1(&format!("WebXR session is {}", visibility).into());
+            visibility_clone.set(visibility);
+        }) as Box<dyn FnMut(Event)>);
+        listeners.push(("visibilitychange", visibility_listener));
+
+        for (event_type, listener) in listeners.iter(){
+            if session.add_event_listener_with_callback(event_type, listener.as_ref().unchecked_ref()).is_err(){
+                console::log_1(&format!("[Error] Could not listen to {} events", event_type).into());
+            }
+        }
+        SessionLifecycle{
+            session: session.clone(),
+            visibility,
+            ended,
+            frame_request: Cell::new(None),
+            end_rx: RefCell::new(Some(end_rx)),
+            listeners,
+        }
+    }
+
+    pub fn visibility(&self)->Visibility{
+        self.visibility.get()
+    }
+
+    pub fn is_ended(&self)->bool{
+        self.ended.get()
+    }
+
+    // 終了したセッションには次のフレームを要求しない
+    pub fn request_animation_frame(&self, callback: &js_sys::Function){
+        if self.is_ended(){
+            return;
+        }
+        self.frame_request.set(Some(self.session.request_animation_frame(callback)));
+    }
+
+    pub fn cancel_animation_frame(&self){
+        if let Some(handle) = self.frame_request.take(){
+            self.session.cancel_animation_frame(handle);
+        }
+    }
+
+    // アプリ側からセッションを終える。endイベントは後から届く
+    pub async fn end(&self)->Result<(),JsValue>{
+        if self.is_ended(){
+            return Ok(());
+        }
+        wasm_bindgen_futures::JsFuture::from(self.session.end()).await?;
+        Ok(())
+    }
+
+    // endイベントが届くまで待つ。2回目以降の呼び出しはすぐに返る
+    pub async fn wait_for_end(&self){
+        let end_rx = self.end_rx.borrow_mut().take();
+        if let Some(end_rx) = end_rx{
+            let _ = end_rx.await;
+        }
+    }
+}
+
+impl Drop for SessionLifecycle{
+    fn drop(&mut self){
+        self.cancel_animation_frame();
+        for (event_type, listener) in self.listeners.iter(){
+            let _ = self.session.remove_event_listener_with_callback(event_type, listener.as_ref().unchecked_ref());
+        }
+    }
+}
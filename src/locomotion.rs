@@ -0,0 +1,429 @@
+// テレポートとスティックでの移動・回転
+// 移動した量はPlayerRigに溜め、参照空間のオフセットとして描画と入力に反映する
+// 座標はすべてワールド(オフセット後の参照空間)で、web-sysには依存しない
+
+pub const GRAVITY: f32 = -9.8;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TurnMode{
+    // スティックを倒すたびに一定の角度だけ回る(ラジアン)
+    Snap{angle: f32},
+    // 倒している間回り続ける(ラジアン/秒)
+    Smooth{speed: f32},
+}
+
+// テレポートの放物線
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct ArcSettings{
+    // 打ち出す速さ(メートル/秒)
+    pub speed: f32,
+    pub gravity: f32,
+    // 放物線を折れ線にするときの時間の刻み(秒)
+    pub time_step: f32,
+    pub max_steps: usize,
+}
+
+impl Default for ArcSettings{
+    // 水平に打ち出すと、目の高さからおよそ4メートル先に落ちる
+    fn default()->Self{
+        ArcSettings{
+            speed: 7.0,
+            gravity: GRAVITY,
+            time_step: 0.03,
+            max_steps: 80,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct LocomotionSettings{
+    pub turn: TurnMode,
+    pub smooth_move: bool,
+    pub teleport: bool,
+    // 移動の速さ(メートル/秒)
+    pub move_speed: f32,
+    pub arc: ArcSettings,
+    // スティックをこれより倒したらスナップ回転・テレポートの照準を始める
+    pub stick_engage: f32,
+    // これより戻したらスティックを離したとみなす
+    pub stick_release: f32,
+}
+
+impl Default for LocomotionSettings{
+    fn default()->Self{
+        LocomotionSettings{
+            turn: TurnMode::Snap{angle: 30.0 * std::f32::consts::PI / 180.0},
+            smooth_move: true,
+            teleport: true,
+            move_speed: 2.0,
+            arc: ArcSettings::default(),
+            stick_engage: 0.6,
+            stick_release: 0.3,
+        }
+    }
+}
+
+// テレポートできる水平な長方形
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct WalkableArea{
+    // XZ平面での範囲
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+    pub height: f32,
+}
+
+impl WalkableArea{
+    // 原点を中心とした、一辺size(メートル)の床
+    pub fn floor(size: f32)->Self{
+        let half = size * 0.5;
+        WalkableArea{min: [-half, -half], max: [half, half], height: 0.0}
+    }
+
+    pub fn contains(&self, x: f32, z: f32)->bool{
+        x >= self.min[0] && x <= self.max[0] && z >= self.min[1] && z <= self.max[1]
+    }
+}
+
+// 放物線の時刻ごとの位置。地面との当たりは見ない
+pub fn arc_points(origin: [f32; 3], direction: [f32; 3], settings: &ArcSettings)->Vec<[f32; 3]>{
+    let velocity = scale(normalize(direction), settings.speed);
+    (0..=settings.max_steps).map(|step|{
+        let time = step as f32 * settings.time_step;
+        let mut point = add(origin, scale(velocity, time));
+        point[1] += 0.5 * settings.gravity * time * time;
+        point
+    }).collect()
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct ArcHit{
+    // 描画する折れ線。着地した場合は最後の点が着地点
+    pub points: Vec<[f32; 3]>,
+    pub landing: Option<[f32; 3]>,
+}
+
+// 放物線を歩ける面と当てて、最初に上から当たった点を着地点にする
+// 面の外側を通り抜けた場合は、その下の面を探し続ける
+pub fn trace_arc(origin: [f32; 3], direction: [f32; 3], walkable: &[WalkableArea], settings: &ArcSettings)->ArcHit{
+    let points = arc_points(origin, direction, settings);
+    let mut traced = Vec::with_capacity(points.len());
+    for segment in points.windows(2){
+        traced.push(segment[0]);
+        if let Some(landing) = segment_hit(segment[0], segment[1], walkable){
+            traced.push(landing);
+            return ArcHit{points: traced, landing: Some(landing)};
+        }
+    }
+    traced.extend(points.last());
+    ArcHit{points: traced, landing: None}
+}
+
+// 線分が上から下へ面を横切る点のうち、startに最も近いもの
+fn segment_hit(start: [f32; 3], end: [f32; 3], walkable: &[WalkableArea])->Option<[f32; 3]>{
+    let mut nearest: Option<(f32, [f32; 3])> = None;
+    for area in walkable.iter(){
+        if !(start[1] >= area.height && end[1] < area.height){
+            continue;
+        }
+        let t = (start[1] - area.height) / (start[1] - end[1]);
+        let point = add(start, scale(sub(end, start), t));
+        if !area.contains(point[0], point[2]){
+            continue;
+        }
+        if nearest.is_none_or(|(nearest_t, _)| t < nearest_t){
+            nearest = Some((t, [point[0], area.height, point[2]]));
+        }
+    }
+    nearest.map(|(_, point)| point)
+}
+
+// 参照空間の原点をワールドのどこに置くか。位置は床の上、向きはY軸回り
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct PlayerRig{
+    pub position: [f32; 3],
+    pub yaw: f32,
+}
+
+impl PlayerRig{
+    // 参照空間の座標をワールドの座標にする
+    pub fn to_world(&self, point: [f32; 3])->[f32; 3]{
+        add(rotate_y(point, self.yaw), self.position)
+    }
+
+    // ワールドのpivotを中心に回る。頭を中心にすれば、回っても頭の位置は動かない
+    pub fn rotate_around(&mut self, pivot: [f32; 3], angle: f32){
+        self.position = add(pivot, rotate_y(sub(self.position, pivot), angle));
+        self.yaw += angle;
+    }
+
+    // getOffsetReferenceSpaceに渡す(位置, クォータニオン)
+    // オフセットした空間での姿勢がワールドの姿勢になるように、PlayerRigの逆変換を渡す
+    pub fn origin_offset(&self)->([f32; 3], [f32; 4]){
+        let position = rotate_y(scale(self.position, -1.0), -self.yaw);
+        let (sin, cos) = (-self.yaw * 0.5).sin_cos();
+        (position, [0.0, sin, 0.0, cos])
+    }
+}
+
+// 1フレーム分の入力。位置と向きはワールド座標
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct LocomotionInput{
+    // 移動に使うスティック。xr-standardの向き(x: 右, y: 手前が正)
+    pub move_stick: [f32; 2],
+    // 回転とテレポートに使うスティック
+    pub turn_stick: [f32; 2],
+    pub head_position: [f32; 3],
+    pub head_forward: [f32; 3],
+    // テレポートの照準に使うレイ(原点, 向き)
+    pub aim: Option<([f32; 3], [f32; 3])>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Locomotion{
+    pub settings: LocomotionSettings,
+    pub walkable: Vec<WalkableArea>,
+    pub rig: PlayerRig,
+    turn_engaged: bool,
+    aiming: bool,
+    arc: Option<ArcHit>,
+}
+
+impl Locomotion{
+    pub fn new(settings: LocomotionSettings, walkable: Vec<WalkableArea>)->Self{
+        Locomotion{
+            settings,
+            walkable,
+            rig: PlayerRig::default(),
+            turn_engaged: false,
+            aiming: false,
+            arc: None,
+        }
+    }
+
+    // 参照空間の原点がリセットされたときに、立ち位置を新しい原点に戻し、照準も取り消す
+    pub fn reset(&mut self){
+        self.rig = PlayerRig::default();
+        self.turn_engaged = false;
+        self.aiming = false;
+        self.arc = None;
+    }
+
+    // 照準中の放物線
+    pub fn arc(&self)->Option<&ArcHit>{
+        self.arc.as_ref()
+    }
+
+    // delta_timeは秒。rigが変わったらtrue
+    pub fn update(&mut self, input: &LocomotionInput, delta_time: f32)->bool{
+        let before = self.rig;
+        if self.settings.teleport{
+            self.update_teleport(input);
+        }
+        // 照準中はスティックを前に倒しているので、回転はしない
+        if !self.aiming{
+            self.update_turn(input, delta_time);
+        }
+        if self.settings.smooth_move{
+            self.update_move(input, delta_time);
+        }
+        self.rig != before
+    }
+
+    fn update_teleport(&mut self, input: &LocomotionInput){
+        // スティックを前(-y)に倒すと照準、戻すとテレポート
+        let forward = -input.turn_stick[1];
+        if !self.aiming{
+            if forward > self.settings.stick_engage{
+                self.aiming = true;
+            }
+            else{
+                return;
+            }
+        }
+        if forward > self.settings.stick_release{
+            self.arc = input.aim.map(|(origin, direction)| trace_arc(origin, direction, &self.walkable, &self.settings.arc));
+            return;
+        }
+        if let Some(landing) = self.arc.take().and_then(|arc| arc.landing){
+            self.teleport(input.head_position, landing);
+        }
+        self.aiming = false;
+    }
+
+    // 頭の真下がlandingに来るように移動する
+    // 床を原点にした参照空間では、足元の高さはrigの高さと同じ
+    pub fn teleport(&mut self, head_position: [f32; 3], landing: [f32; 3]){
+        self.rig.position[0] += landing[0] - head_position[0];
+        self.rig.position[2] += landing[2] - head_position[2];
+        self.rig.position[1] = landing[1];
+    }
+
+    fn update_turn(&mut self, input: &LocomotionInput, delta_time: f32){
+        let x = input.turn_stick[0];
+        match self.settings.turn{
+            TurnMode::Snap{angle}=>{
+                if self.turn_engaged{
+                    self.turn_engaged = x.abs() > self.settings.stick_release;
+                }
+                else if x.abs() > self.settings.stick_engage{
+                    // 右に倒すと右(上から見て時計回り)に回る
+                    self.rig.rotate_around(input.head_position, -angle * x.signum());
+                    self.turn_engaged = true;
+                }
+            },
+            TurnMode::Smooth{speed}=>{
+                if x != 0.0{
+                    self.rig.rotate_around(input.head_position, -x * speed * delta_time);
+                }
+            },
+        }
+    }
+
+    fn update_move(&mut self, input: &LocomotionInput, delta_time: f32){
+        let [x, y] = input.move_stick;
+        if x == 0.0 && y == 0.0{
+            return;
+        }
+        // 頭の向きを水平にした方向に進む。真上や真下を見ているときは進めない
+        let forward = [input.head_forward[0], 0.0, input.head_forward[2]];
+        if length(forward) < 1.0e-4{
+            return;
+        }
+        let forward = normalize(forward);
+        let right = [-forward[2], 0.0, forward[0]];
+        let movement = add(scale(right, x), scale(forward, -y));
+        self.rig.position = add(self.rig.position, scale(movement, self.settings.move_speed * delta_time));
+    }
+}
+
+// Y軸回りの回転。正の角度で上から見て反時計回り
+fn rotate_y(point: [f32; 3], angle: f32)->[f32; 3]{
+    let (sin, cos) = angle.sin_cos();
+    [point[0] * cos + point[2] * sin, point[1], -point[0] * sin + point[2] * cos]
+}
+
+fn add(a: [f32; 3], b: [f32; 3])->[f32; 3]{
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3])->[f32; 3]{
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f32; 3], s: f32)->[f32; 3]{
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn length(a: [f32; 3])->f32{
+    (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt()
+}
+
+fn normalize(a: [f32; 3])->[f32; 3]{
+    let length = length(a);
+    if length == 0.0{
+        return a;
+    }
+    scale(a, 1.0 / length)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use gl_matrix::{mat4,vec3};
+
+    fn assert_near(actual: [f32; 3], expected: [f32; 3]){
+        for (a, e) in actual.iter().zip(expected.iter()){
+            assert!((a - e).abs() < 1e-4, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn arc_lands_on_walkable_area(){
+        let area = WalkableArea{min: [-1.0, -5.0], max: [1.0, -2.0], height: 0.0};
+        let hit = trace_arc([0.0, 1.5, 0.0], [0.0, 0.0, -1.0], &[area], &ArcSettings::default());
+        let landing = hit.landing.expect("arc should land");
+        assert_eq!(landing[1], 0.0);
+        assert!(area.contains(landing[0], landing[2]));
+        // 1.5メートル落ちる時間だけ水平に進む
+        let time = (2.0 * 1.5 / -GRAVITY).sqrt();
+        assert!((landing[2] + 7.0 * time).abs() < 0.05);
+        assert_eq!(hit.points.last(), Some(&landing));
+        assert_eq!(hit.points[0], [0.0, 1.5, 0.0]);
+    }
+
+    #[test]
+    fn arc_misses_outside_walkable_area(){
+        let settings = ArcSettings::default();
+        let area = WalkableArea{min: [-1.0, -1.0], max: [1.0, 1.0], height: 0.0};
+        let hit = trace_arc([0.0, 1.5, 0.0], [0.0, 0.0, -1.0], &[area], &settings);
+        assert!(hit.landing.is_none());
+        // 着地しなければ放物線を最後まで描く
+        assert_eq!(hit.points, arc_points([0.0, 1.5, 0.0], [0.0, 0.0, -1.0], &settings));
+
+        // 上向きに横切る線分や、面の外を通る線分は当たらない
+        assert_eq!(segment_hit([0.0, -1.0, 0.0], [0.0, 1.0, 0.0], &[area]), None);
+        assert_eq!(segment_hit([3.0, 1.0, 0.0], [3.0, -1.0, 0.0], &[area]), None);
+    }
+
+    #[test]
+    fn segment_hits_the_nearest_area_from_above(){
+        let floor = WalkableArea::floor(10.0);
+        let platform = WalkableArea{min: [-1.0, -1.0], max: [1.0, 1.0], height: 1.0};
+        let hit = segment_hit([0.5, 2.0, 0.0], [0.5, -1.0, 0.0], &[floor, platform]);
+        assert_eq!(hit, Some([0.5, 1.0, 0.0]));
+        // 台の外側を通り抜けたら下の床に当たる
+        let hit = segment_hit([2.0, 2.0, 0.0], [2.0, -1.0, 0.0], &[floor, platform]);
+        assert_eq!(hit, Some([2.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn snap_turn_pivots_around_head(){
+        let mut locomotion = Locomotion::new(LocomotionSettings{smooth_move: false, teleport: false, ..Default::default()}, vec![]);
+        locomotion.rig = PlayerRig{position: [0.5, 0.0, -0.5], yaw: 0.2};
+        let head_local = [1.0, 1.6, 0.3];
+        let head = locomotion.rig.to_world(head_local);
+        let input = LocomotionInput{turn_stick: [1.0, 0.0], head_position: head, head_forward: [0.0, 0.0, -1.0], ..Default::default()};
+        assert!(locomotion.update(&input, 0.1));
+        // 右に回り、頭のワールドの位置は変わらない
+        let angle = 30.0 * std::f32::consts::PI / 180.0;
+        assert!((locomotion.rig.yaw - (0.2 - angle)).abs() < 1e-6);
+        assert_near(locomotion.rig.to_world(head_local), head);
+
+        // 倒したままでは回らず、離してからもう一度倒すと回る
+        assert!(!locomotion.update(&input, 0.1));
+        locomotion.update(&LocomotionInput{turn_stick: [0.0, 0.0], ..input}, 0.1);
+        assert!(locomotion.update(&LocomotionInput{turn_stick: [-1.0, 0.0], ..input}, 0.1));
+        assert!((locomotion.rig.yaw - 0.2).abs() < 1e-6);
+        assert_near(locomotion.rig.to_world(head_local), head);
+    }
+
+    #[test]
+    fn origin_offset_is_inverse_of_rig(){
+        let rig = PlayerRig{position: [1.0, 0.2, -2.0], yaw: 0.7};
+        let (position, orientation) = rig.origin_offset();
+        let mut offset = mat4::create();
+        mat4::from_rotation_translation(&mut offset, &orientation, &position);
+        for point in [[0.0, 0.0, 0.0], [0.3, 1.6, -0.4], [-2.0, 0.5, 1.0]]{
+            let mut local = [0.0; 3];
+            vec3::transform_mat4(&mut local, &rig.to_world(point), &offset);
+            assert_near(local, point);
+        }
+        assert_eq!(PlayerRig::default().origin_offset(), ([0.0; 3], [0.0, 0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn reset_returns_to_origin_and_cancels_aim(){
+        let mut locomotion = Locomotion::new(LocomotionSettings::default(), vec![WalkableArea::floor(10.0)]);
+        let aim = LocomotionInput{turn_stick: [0.0, -1.0], head_position: [0.0, 1.6, 0.0], aim: Some(([0.0, 1.2, 0.0], [0.0, 0.5, -1.0])), ..Default::default()};
+        locomotion.update(&aim, 0.0);
+        assert!(locomotion.arc().is_some());
+        locomotion.rig = PlayerRig{position: [1.0, 0.0, 2.0], yaw: 0.5};
+
+        locomotion.reset();
+        assert_eq!(locomotion.rig, PlayerRig::default());
+        assert!(locomotion.arc().is_none());
+        // 照準は取り消されたので、スティックを戻してもテレポートしない
+        locomotion.update(&LocomotionInput{head_position: [0.0, 1.6, 0.0], ..Default::default()}, 0.0);
+        assert_eq!(locomotion.rig, PlayerRig::default());
+    }
+}
@@ -0,0 +1,347 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use crate::{GlProgram,upload_f32_buffer,upload_u16_buffer};
+use crate::platform::GraphicsDevice;
+
+const FLOAT32_BYTES_PER_ELEMENT: i32 = 4;
+
+// 頂点が持つことのできる属性
+// シェーダ側の in 変数名はshader_nameに合わせる
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum VertexAttribute{
+    Position,
+    Normal,
+    Uv,
+    Color,
+    Tangent,
+    Joints,
+    Weights,
+}
+
+impl VertexAttribute{
+    pub const ALL: [VertexAttribute; 7] = [
+        VertexAttribute::Position,
+        VertexAttribute::Normal,
+        VertexAttribute::Uv,
+        VertexAttribute::Color,
+        VertexAttribute::Tangent,
+        VertexAttribute::Joints,
+        VertexAttribute::Weights,
+    ];
+
+    // 1頂点あたりのf32の個数
+    pub fn components(&self)->i32{
+        match self{
+            VertexAttribute::Position=>3,
+            VertexAttribute::Normal=>3,
+            VertexAttribute::Uv=>2,
+            VertexAttribute::Color=>4,
+            VertexAttribute::Tangent=>4,
+            VertexAttribute::Joints=>4,
+            VertexAttribute::Weights=>4,
+        }
+    }
+
+    pub fn shader_name(&self)->&'static str{
+        match self{
+            VertexAttribute::Position=>"vertex_position",
+            VertexAttribute::Normal=>"normal",
+            VertexAttribute::Uv=>"uv",
+            VertexAttribute::Color=>"color",
+            VertexAttribute::Tangent=>"tangent",
+            VertexAttribute::Joints=>"joints",
+            VertexAttribute::Weights=>"weights",
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct VertexElement{
+    pub attribute: VertexAttribute,
+    // 頂点の先頭からのバイトオフセット
+    pub offset: i32,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum MeshError{
+    EmptyLayout,
+    DuplicateAttribute(VertexAttribute),
+    // 頂点データの長さが1頂点あたりのf32数で割り切れない
+    InvalidVertexData{length: usize, floats_per_vertex: usize},
+    IndexOutOfRange{index: u16, vertex_count: usize},
+    Gl(String),
+}
+
+impl std::fmt::Display for MeshError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            MeshError::EmptyLayout=>write!(f, "vertex layout has no attributes"),
+            MeshError::DuplicateAttribute(attribute)=>write!(f, "vertex attribute {:?} appears twice in the layout", attribute),
+            MeshError::InvalidVertexData{length, floats_per_vertex}=>write!(f, "vertex data of length {} is not a multiple of {} floats per vertex", length, floats_per_vertex),
+            MeshError::IndexOutOfRange{index, vertex_count}=>write!(f, "index {} is out of range for {} vertices", index, vertex_count),
+            MeshError::Gl(reason)=>write!(f, "WebGL error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MeshError{}
+
+impl From<MeshError> for JsValue{
+    fn from(error: MeshError)->Self{
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+// インターリーブされた頂点バッファのレイアウト
+// 属性を並べた順にオフセットが決まり、strideも自動で計算される
+#[derive(Debug,Clone,PartialEq)]
+pub struct VertexLayout{
+    elements: Vec<VertexElement>,
+    stride: i32,
+}
+
+impl VertexLayout{
+    pub fn new(attributes: &[VertexAttribute])->Result<Self,MeshError>{
+        if attributes.is_empty(){
+            return Err(MeshError::EmptyLayout);
+        }
+        let mut elements = Vec::with_capacity(attributes.len());
+        let mut offset = 0;
+        for attribute in attributes.iter(){
+            if elements.iter().any(|element: &VertexElement| element.attribute == *attribute){
+                return Err(MeshError::DuplicateAttribute(*attribute));
+            }
+            elements.push(VertexElement{attribute: *attribute, offset});
+            offset += attribute.components() * FLOAT32_BYTES_PER_ELEMENT;
+        }
+        Ok(VertexLayout{elements, stride: offset})
+    }
+
+    pub fn elements(&self)->&[VertexElement]{
+        &self.elements
+    }
+
+    // 1頂点あたりのバイト数
+    pub fn stride(&self)->i32{
+        self.stride
+    }
+
+    pub fn floats_per_vertex(&self)->usize{
+        (self.stride / FLOAT32_BYTES_PER_ELEMENT) as usize
+    }
+
+    pub fn offset(&self, attribute: VertexAttribute)->Option<i32>{
+        self.elements.iter().find(|element| element.attribute == attribute).map(|element| element.offset)
+    }
+
+    pub fn contains(&self, attribute: VertexAttribute)->bool{
+        self.offset(attribute).is_some()
+    }
+
+    // 頂点データの長さを検証して、頂点数を返す
+    pub fn vertex_count(&self, vertices: &[f32])->Result<usize,MeshError>{
+        let floats_per_vertex = self.floats_per_vertex();
+        if !vertices.len().is_multiple_of(floats_per_vertex){
+            return Err(MeshError::InvalidVertexData{length: vertices.len(), floats_per_vertex});
+        }
+        Ok(vertices.len() / floats_per_vertex)
+    }
+
+    // 頂点データとインデックスの整合性を検証する
+    pub fn validate(&self, vertices: &[f32], indices: &[u16])->Result<usize,MeshError>{
+        let vertex_count = self.vertex_count(vertices)?;
+        if let Some(index) = indices.iter().find(|index| **index as usize >= vertex_count){
+            return Err(MeshError::IndexOutOfRange{index: *index, vertex_count});
+        }
+        Ok(vertex_count)
+    }
+}
+
+// VAOに頂点属性の設定をまとめたメッシュ
+pub struct Mesh<G: GraphicsDevice>{
+    layout: VertexLayout,
+    vao: G::VertexArray,
+    vertex_buffer: G::Buffer,
+    index_buffer: G::Buffer,
+    index_count: i32,
+    vertex_count: usize,
+}
+
+impl<G: GraphicsDevice> Mesh<G>{
+    pub fn new(gl_program: &GlProgram<G>, layout: VertexLayout, vertices: &[f32], indices: &[u16])->Result<Self,MeshError>{
+        let vertex_count = layout.validate(vertices, indices)?;
+        let gl = gl_program.gl();
+
+        // バッファ作成時にELEMENT_ARRAY_BUFFERのバインドを解除するので、VAOより先に作る
+        let vertex_buffer = upload_f32_buffer(gl, WebGl2RenderingContext::ARRAY_BUFFER, vertices)
+            .map_err(|_| MeshError::Gl("could not create vertex buffer".to_string()))?;
+        let index_buffer = match upload_u16_buffer(gl, WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER, indices){
+            Ok(index_buffer)=>index_buffer,
+            Err(_)=>{
+                gl.delete_buffer(Some(&vertex_buffer));
+                return Err(MeshError::Gl("could not create index buffer".to_string()));
+            },
+        };
+        // 途中で失敗したら、作ったバッファを解放してから返す
+        let Some(vao) = gl.create_vertex_array() else{
+            gl.delete_buffer(Some(&vertex_buffer));
+            gl.delete_buffer(Some(&index_buffer));
+            return Err(MeshError::Gl("could not create vertex array object".to_string()));
+        };
+
+        gl.bind_vertex_array(Some(&vao));
+        gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&vertex_buffer));
+        for element in layout.elements(){
+            let location = gl_program.attribute_location(element.attribute.shader_name());
+            // シェーダで使われていない属性はバインドしない
+            if location < 0{
+                continue;
+            }
+            gl.enable_vertex_attrib_array(location as u32);
+            gl.vertex_attrib_pointer(location as u32, element.attribute.components(), WebGl2RenderingContext::FLOAT, false, layout.stride(), element.offset);
+        }
+        gl.bind_buffer(WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER, Some(&index_buffer));
+        gl.bind_vertex_array(None);
+        gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, None);
+
+        Ok(Mesh{
+            layout,
+            vao,
+            vertex_buffer,
+            index_buffer,
+            index_count: indices.len() as i32,
+            vertex_count,
+        })
+    }
+
+    pub fn layout(&self)->&VertexLayout{
+        &self.layout
+    }
+
+    pub fn index_count(&self)->i32{
+        self.index_count
+    }
+
+    pub fn vertex_count(&self)->usize{
+        self.vertex_count
+    }
+
+    pub fn draw(&self, gl: &G){
+        gl.bind_vertex_array(Some(&self.vao));
+        gl.draw_elements(WebGl2RenderingContext::TRIANGLES, self.index_count, WebGl2RenderingContext::UNSIGNED_SHORT, 0);
+        gl.bind_vertex_array(None);
+    }
+
+    // GPU上のバッファとVAOを解放する
+    pub fn delete(&self, gl: &G){
+        gl.delete_vertex_array(Some(&self.vao));
+        gl.delete_buffer(Some(&self.vertex_buffer));
+        gl.delete_buffer(Some(&self.index_buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::compile_program;
+    use crate::platform::mock::MockDevice;
+
+    // normalを宣言していないので、その属性はバインドされない
+    const VERTEX: &str = "#version 300 es\nin vec3 vertex_position;\nin vec4 color;\nout vec4 v_color;\nvoid main(){\n    v_color = color;\n    gl_Position = vec4(vertex_position, 1.0);\n}";
+    const FRAGMENT: &str = "#version 300 es\nprecision highp float;\nin vec4 v_color;\nout vec4 fragment_color;\nvoid main(){\n    fragment_color = v_color;\n}";
+
+    fn program()->GlProgram<MockDevice>{
+        let gl = MockDevice::new();
+        let program = compile_program(&gl, VERTEX, FRAGMENT).unwrap();
+        GlProgram::new(gl, program)
+    }
+
+    fn triangle(layout: &VertexLayout)->Vec<f32>{
+        (0..3).flat_map(|vertex| (0..layout.floats_per_vertex()).map(move |component| (vertex * 10 + component) as f32)).collect()
+    }
+
+    #[test]
+    fn layout_computes_offsets_and_rejects_duplicates(){
+        let layout = VertexLayout::new(&[VertexAttribute::Position, VertexAttribute::Normal, VertexAttribute::Color]).unwrap();
+        assert_eq!(layout.stride(), 40);
+        assert_eq!(layout.offset(VertexAttribute::Normal), Some(12));
+        assert_eq!(layout.offset(VertexAttribute::Color), Some(24));
+        assert_eq!(layout.offset(VertexAttribute::Uv), None);
+        assert_eq!(VertexLayout::new(&[]), Err(MeshError::EmptyLayout));
+        assert_eq!(VertexLayout::new(&[VertexAttribute::Uv, VertexAttribute::Uv]), Err(MeshError::DuplicateAttribute(VertexAttribute::Uv)));
+    }
+
+    #[test]
+    fn validate_rejects_partial_vertices_and_out_of_range_indices(){
+        let layout = VertexLayout::new(&[VertexAttribute::Position, VertexAttribute::Color]).unwrap();
+        let vertices = vec![0.0; 21];
+        assert_eq!(layout.validate(&vertices, &[0, 1, 2]), Ok(3));
+        assert_eq!(layout.validate(&vertices[..20], &[0, 1, 2]), Err(MeshError::InvalidVertexData{length: 20, floats_per_vertex: 7}));
+        assert_eq!(layout.validate(&vertices, &[0, 1, 3]), Err(MeshError::IndexOutOfRange{index: 3, vertex_count: 3}));
+    }
+
+    #[test]
+    fn new_uploads_buffers_and_records_attributes_in_vao(){
+        let program = program();
+        let gl = program.gl().clone();
+        let layout = VertexLayout::new(&[VertexAttribute::Position, VertexAttribute::Normal, VertexAttribute::Color]).unwrap();
+        let vertices = triangle(&layout);
+        let mesh = Mesh::new(&program, layout, &vertices, &[0, 1, 2]).unwrap();
+        assert_eq!((mesh.vertex_count(), mesh.index_count()), (3, 3));
+        // VAOのバインドは外してある
+        assert_eq!(gl.current_vertex_array(), None);
+
+        let state = gl.vertex_array_state(&mesh.vao).unwrap();
+        let position = program.attribute_location("vertex_position") as u32;
+        let color = program.attribute_location("color") as u32;
+        assert_eq!(program.attribute_location("normal"), -1);
+        assert_eq!(state.enabled, vec![position, color]);
+        let pointer = state.pointers[&color];
+        assert_eq!((pointer.buffer, pointer.size, pointer.stride, pointer.offset), (mesh.vertex_buffer, 4, 40, 24));
+        assert_eq!(state.element_buffer, Some(mesh.index_buffer));
+
+        let expected: Vec<u8> = vertices.iter().flat_map(|value| value.to_le_bytes()).collect();
+        assert_eq!(gl.buffer_data(&mesh.vertex_buffer), Some(expected));
+        assert_eq!(gl.buffer_data(&mesh.index_buffer), Some(vec![0, 0, 1, 0, 2, 0]));
+
+        mesh.draw(&gl);
+        let draws = gl.draws();
+        assert_eq!((draws[0].vertex_array, draws[0].count), (Some(mesh.vao), 3));
+
+        // プログラムだけが残る
+        mesh.delete(&gl);
+        assert_eq!(gl.live_objects(), 1);
+    }
+
+    #[test]
+    fn new_rejects_invalid_data_before_touching_gl(){
+        let program = program();
+        let layout = VertexLayout::new(&[VertexAttribute::Position, VertexAttribute::Color]).unwrap();
+        let vertices = triangle(&layout);
+        assert_eq!(
+            Mesh::new(&program, layout.clone(), &vertices[..20], &[0, 1, 2]).err(),
+            Some(MeshError::InvalidVertexData{length: 20, floats_per_vertex: 7}),
+        );
+        assert_eq!(
+            Mesh::new(&program, layout, &vertices, &[0, 1, 3]).err(),
+            Some(MeshError::IndexOutOfRange{index: 3, vertex_count: 3}),
+        );
+        assert_eq!(program.gl().live_objects(), 1);
+    }
+
+    #[test]
+    fn new_frees_buffers_when_creation_fails(){
+        let program = program();
+        let gl = program.gl().clone();
+        let layout = VertexLayout::new(&[VertexAttribute::Position, VertexAttribute::Color]).unwrap();
+        let vertices = triangle(&layout);
+        // 頂点バッファ・インデックスバッファ・VAOのそれぞれで作成に失敗させる
+        for limit in [1, 2, 3]{
+            gl.set_object_limit(Some(limit));
+            assert!(matches!(Mesh::new(&program, layout.clone(), &vertices, &[0, 1, 2]), Err(MeshError::Gl(_))));
+            assert_eq!(gl.live_objects(), 1);
+        }
+        gl.set_object_limit(Some(4));
+        assert!(Mesh::new(&program, layout, &vertices, &[0, 1, 2]).is_ok());
+    }
+}
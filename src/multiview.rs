@@ -0,0 +1,230 @@
+use web_sys::WebGl2RenderingContext as Gl;
+use crate::platform::{GraphicsDevice,GraphicsError,Viewport};
+use crate::uniform::MAX_VIEWS;
+
+// OVR_multiview2による1パスのステレオ描画
+// 両目をテクスチャ配列の各レイヤーに一度に描き、最後にXRのフレームバッファへ転送する
+
+pub const MULTIVIEW_EXTENSION: &str = "OVR_multiview2";
+
+// シェーダ側でマルチビューの分岐に使う#define
+pub const MULTIVIEW_DEFINE: &str = "MULTIVIEW";
+
+// 拡張が使えれば、マルチビューの描画先を返す
+pub fn detect_multiview<G: GraphicsDevice>(gl: &G)->Option<Multiview<G>>{
+    gl.has_multiview().then(Multiview::new)
+}
+
+// 両目分のレイヤーを持つ描画先
+struct MultiviewTarget<G: GraphicsDevice>{
+    framebuffer: G::Framebuffer,
+    // 転送時にレイヤーを1枚ずつ読み出すためのフレームバッファ
+    read_framebuffer: G::Framebuffer,
+    color: G::Texture,
+    depth: G::Texture,
+    width: i32,
+    height: i32,
+}
+
+impl<G: GraphicsDevice> MultiviewTarget<G>{
+    fn new(gl: &G, width: i32, height: i32)->Result<Self,GraphicsError>{
+        // 途中で作成に失敗したら、それまでに作ったものを解放してから返す
+        let Some(framebuffer) = gl.create_framebuffer() else{
+            return Err(GraphicsError("Could not create multiview framebuffer".to_string()));
+        };
+        let Some(read_framebuffer) = gl.create_framebuffer() else{
+            gl.delete_framebuffer(Some(&framebuffer));
+            return Err(GraphicsError("Could not create multiview framebuffer".to_string()));
+        };
+        let Some(color) = gl.create_texture() else{
+            gl.delete_framebuffer(Some(&framebuffer));
+            gl.delete_framebuffer(Some(&read_framebuffer));
+            return Err(GraphicsError("Could not create multiview texture".to_string()));
+        };
+        let Some(depth) = gl.create_texture() else{
+            gl.delete_framebuffer(Some(&framebuffer));
+            gl.delete_framebuffer(Some(&read_framebuffer));
+            gl.delete_texture(Some(&color));
+            return Err(GraphicsError("Could not create multiview texture".to_string()));
+        };
+
+        gl.bind_texture(Gl::TEXTURE_2D_ARRAY, Some(&color));
+        gl.tex_storage_3d(Gl::TEXTURE_2D_ARRAY, 1, Gl::RGBA8, width, height, MAX_VIEWS as i32);
+        gl.bind_texture(Gl::TEXTURE_2D_ARRAY, Some(&depth));
+        gl.tex_storage_3d(Gl::TEXTURE_2D_ARRAY, 1, Gl::DEPTH_COMPONENT24, width, height, MAX_VIEWS as i32);
+        gl.bind_texture(Gl::TEXTURE_2D_ARRAY, None);
+
+        gl.bind_framebuffer(Gl::DRAW_FRAMEBUFFER, Some(&framebuffer));
+        gl.framebuffer_texture_multiview_ovr(Gl::DRAW_FRAMEBUFFER, Gl::COLOR_ATTACHMENT0, Some(&color), 0, 0, MAX_VIEWS as i32);
+        gl.framebuffer_texture_multiview_ovr(Gl::DRAW_FRAMEBUFFER, Gl::DEPTH_ATTACHMENT, Some(&depth), 0, 0, MAX_VIEWS as i32);
+        let status = gl.check_framebuffer_status(Gl::DRAW_FRAMEBUFFER);
+        gl.bind_framebuffer(Gl::DRAW_FRAMEBUFFER, None);
+
+        let target = MultiviewTarget{framebuffer, read_framebuffer, color, depth, width, height};
+        if status != Gl::FRAMEBUFFER_COMPLETE{
+            target.delete(gl);
+            return Err(GraphicsError(format!("Multiview framebuffer is incomplete (0x{:04x})", status)));
+        }
+        Ok(target)
+    }
+
+    fn delete(&self, gl: &G){
+        gl.delete_framebuffer(Some(&self.framebuffer));
+        gl.delete_framebuffer(Some(&self.read_framebuffer));
+        gl.delete_texture(Some(&self.color));
+        gl.delete_texture(Some(&self.depth));
+    }
+}
+
+pub struct Multiview<G: GraphicsDevice>{
+    // ビューポートの大きさが分かる最初のフレームで作る
+    target: Option<MultiviewTarget<G>>,
+}
+
+impl<G: GraphicsDevice> Default for Multiview<G>{
+    fn default()->Self{
+        Multiview::new()
+    }
+}
+
+impl<G: GraphicsDevice> Multiview<G>{
+    pub fn new()->Self{
+        Multiview{
+            target: None,
+        }
+    }
+
+    // 描画先をテクスチャ配列に切り替える。大きさが変わったときは作り直す
+    pub fn begin(&mut self, gl: &G, width: i32, height: i32)->Result<(),GraphicsError>{
+        let resized = self.target.as_ref().is_none_or(|target| target.width != width || target.height != height);
+        if resized{
+            if let Some(target) = self.target.take(){
+                target.delete(gl);
+            }
+            self.target = Some(MultiviewTarget::new(gl, width, height)?);
+        }
+        let Some(target) = self.target.as_ref() else{
+            return Err(GraphicsError("Multiview target is not ready".to_string()));
+        };
+        gl.bind_framebuffer(Gl::FRAMEBUFFER, Some(&target.framebuffer));
+        gl.viewport(0, 0, width, height);
+        Ok(())
+    }
+
+    // 各レイヤーをXRのフレームバッファのビューポートへ転送する
+    // 転送先がマルチサンプルだとblitできないので、レイヤーはantialiasなしで作ること
+    pub fn end(&self, gl: &G, framebuffer: Option<&G::Framebuffer>, viewports: &[Viewport]){
+        let Some(target) = self.target.as_ref() else{
+            return;
+        };
+        gl.bind_framebuffer(Gl::READ_FRAMEBUFFER, Some(&target.read_framebuffer));
+        gl.bind_framebuffer(Gl::DRAW_FRAMEBUFFER, framebuffer);
+        for (layer, viewport) in viewports.iter().enumerate().take(MAX_VIEWS){
+            gl.framebuffer_texture_layer(Gl::READ_FRAMEBUFFER, Gl::COLOR_ATTACHMENT0, Some(&target.color), 0, layer as i32);
+            gl.blit_framebuffer(
+                [0, 0, target.width, target.height],
+                [viewport.x, viewport.y, viewport.x + viewport.width, viewport.y + viewport.height],
+                Gl::COLOR_BUFFER_BIT,
+                Gl::NEAREST,
+            );
+        }
+        gl.bind_framebuffer(Gl::READ_FRAMEBUFFER, None);
+        gl.bind_framebuffer(Gl::FRAMEBUFFER, framebuffer);
+    }
+
+    pub fn delete(&mut self, gl: &G){
+        if let Some(target) = self.target.take(){
+            target.delete(gl);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::platform::mock::MockDevice;
+
+    fn device()->MockDevice{
+        let gl = MockDevice::new();
+        gl.set_multiview(true);
+        gl
+    }
+
+    #[test]
+    fn detect_multiview_follows_extension_support(){
+        let gl = MockDevice::new();
+        assert!(detect_multiview(&gl).is_none());
+        gl.set_multiview(true);
+        assert!(detect_multiview(&gl).is_some());
+    }
+
+    #[test]
+    fn begin_creates_layered_target_once_per_size(){
+        let gl = device();
+        let mut multiview = Multiview::new();
+        multiview.begin(&gl, 64, 32).unwrap();
+        let target = multiview.target.as_ref().unwrap();
+        assert_eq!(gl.texture_size(&target.color), Some([64, 32, MAX_VIEWS as i32]));
+        assert_eq!(gl.texture_size(&target.depth), Some([64, 32, MAX_VIEWS as i32]));
+        assert_eq!(gl.framebuffer_attachments(&target.framebuffer), Some(vec![Gl::COLOR_ATTACHMENT0, Gl::DEPTH_ATTACHMENT]));
+        assert_eq!(gl.current_viewport(), Viewport{x: 0, y: 0, width: 64, height: 32});
+        assert_eq!(gl.live_objects(), 4);
+
+        // 同じ大きさなら作り直さない
+        let framebuffer = target.framebuffer;
+        multiview.begin(&gl, 64, 32).unwrap();
+        assert_eq!(multiview.target.as_ref().unwrap().framebuffer, framebuffer);
+
+        // 大きさが変われば古いものを解放して作り直す
+        multiview.begin(&gl, 128, 64).unwrap();
+        let target = multiview.target.as_ref().unwrap();
+        assert_ne!(target.framebuffer, framebuffer);
+        assert_eq!(gl.texture_size(&target.color), Some([128, 64, MAX_VIEWS as i32]));
+        assert_eq!(gl.live_objects(), 4);
+
+        multiview.delete(&gl);
+        assert!(multiview.target.is_none());
+        assert_eq!(gl.live_objects(), 0);
+    }
+
+    #[test]
+    fn end_blits_each_layer_to_its_viewport(){
+        let gl = device();
+        let xr_framebuffer = gl.create_framebuffer().unwrap();
+        let mut multiview = Multiview::new();
+        multiview.begin(&gl, 64, 32).unwrap();
+        let viewports = [
+            Viewport{x: 0, y: 0, width: 64, height: 32},
+            Viewport{x: 64, y: 0, width: 64, height: 32},
+        ];
+        multiview.end(&gl, Some(&xr_framebuffer), &viewports);
+
+        let read_framebuffer = multiview.target.as_ref().unwrap().read_framebuffer;
+        let blits = gl.blits();
+        assert_eq!(blits.len(), 2);
+        for (blit, destination) in blits.iter().zip([[0, 0, 64, 32], [64, 0, 128, 32]]){
+            assert_eq!(blit.read_framebuffer, Some(read_framebuffer));
+            assert_eq!(blit.draw_framebuffer, Some(xr_framebuffer));
+            assert_eq!((blit.source, blit.destination), ([0, 0, 64, 32], destination));
+        }
+    }
+
+    #[test]
+    fn failed_creation_frees_what_was_created(){
+        // 4つのオブジェクトのどこで作成に失敗しても、何も残さない
+        for limit in 0..4{
+            let gl = device();
+            gl.set_object_limit(Some(limit));
+            let mut multiview = Multiview::new();
+            assert!(multiview.begin(&gl, 64, 32).is_err());
+            assert!(multiview.target.is_none());
+            assert_eq!(gl.live_objects(), 0);
+        }
+
+        // 拡張が無いとアタッチメントが付かず、不完全なフレームバッファも解放する
+        let gl = MockDevice::new();
+        let mut multiview = Multiview::new();
+        assert!(multiview.begin(&gl, 64, 32).is_err());
+        assert_eq!(gl.live_objects(), 0);
+    }
+}
@@ -0,0 +1,207 @@
+use std::future::Future;
+use gl_matrix::common::Mat4;
+use crate::input::{InputEvent,InputSourceState,Pose};
+use crate::session::SessionMode;
+
+// ブラウザに依存する部分の抽象化
+// 描画・セッション・アセット読み込みのロジックはこのトレイト越しに書き、
+// ブラウザではweb-sysの実装を、ネイティブのテストではmockの実装を使う
+// web実装はブラウザでしか動かないので、wasm32のときだけビルドする
+
+#[cfg(target_arch = "wasm32")]
+pub mod web;
+// どちらの実装も包んで、呼び出しをコマンド列として記録する
+pub mod recording;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mock;
+// 台本どおりに頭と入力ソースを動かすXRランタイム。web-sysを呼ばないのでどのターゲットでも使える
+pub mod simulated;
+// mockの上にCPUのラスタライザを載せたもの
+#[cfg(not(target_arch = "wasm32"))]
+pub mod software;
+
+// ブラウザではコンソールへ、ネイティブでは標準エラーへ出力する
+// トレイト越しに呼ばれるコードは、ネイティブでconsole::log_1を呼ぶとpanicするのでこちらを使う
+pub fn log(message: &str){
+    #[cfg(target_arch = "wasm32")]
+    web_sys::console::log_1(&message.into());
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{}", message);
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct GraphicsError(pub String);
+
+impl std::fmt::Display for GraphicsError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        write!(f, "graphics error: {}", self.0)
+    }
+}
+
+impl std::error::Error for GraphicsError{}
+
+impl From<GraphicsError> for wasm_bindgen::JsValue{
+    fn from(error: GraphicsError)->Self{
+        wasm_bindgen::JsValue::from_str(&error.to_string())
+    }
+}
+
+// getActiveUniformの結果
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ActiveInfo{
+    pub name: String,
+    // GLの型定数 (FLOAT_MAT4 など)
+    pub gl_type: u32,
+    pub size: i32,
+}
+
+// WebGL2のうち、このエンジンが使う呼び出しだけを集めたもの
+// 名前と引数はWebGl2RenderingContextに合わせ、GLの定数もそのまま渡す
+pub trait GraphicsDevice: Clone{
+    type Buffer: Clone;
+    type VertexArray: Clone;
+    type Shader: Clone;
+    type Program: Clone;
+    type UniformLocation: Clone;
+    type Framebuffer: Clone;
+    type Texture: Clone;
+
+    fn create_buffer(&self)->Option<Self::Buffer>;
+    fn bind_buffer(&self, target: u32, buffer: Option<&Self::Buffer>);
+    fn buffer_data_f32(&self, target: u32, data: &[f32], usage: u32);
+    fn buffer_data_u16(&self, target: u32, data: &[u16], usage: u32);
+    // 中身を確保するだけで、データは後からbuffer_sub_dataで送る
+    fn buffer_data_size(&self, target: u32, size: i32, usage: u32);
+    fn buffer_sub_data_u8(&self, target: u32, offset: i32, data: &[u8]);
+    fn bind_buffer_base(&self, target: u32, index: u32, buffer: Option<&Self::Buffer>);
+    fn delete_buffer(&self, buffer: Option<&Self::Buffer>);
+
+    fn create_vertex_array(&self)->Option<Self::VertexArray>;
+    fn bind_vertex_array(&self, vertex_array: Option<&Self::VertexArray>);
+    fn delete_vertex_array(&self, vertex_array: Option<&Self::VertexArray>);
+    fn enable_vertex_attrib_array(&self, index: u32);
+    fn vertex_attrib_pointer(&self, index: u32, size: i32, data_type: u32, normalized: bool, stride: i32, offset: i32);
+
+    fn create_shader(&self, shader_type: u32)->Option<Self::Shader>;
+    fn shader_source(&self, shader: &Self::Shader, source: &str);
+    fn compile_shader(&self, shader: &Self::Shader);
+    // 真偽値のパラメータは0か1で返す
+    fn get_shader_parameter(&self, shader: &Self::Shader, pname: u32)->i32;
+    fn get_shader_info_log(&self, shader: &Self::Shader)->Option<String>;
+    fn delete_shader(&self, shader: Option<&Self::Shader>);
+
+    fn create_program(&self)->Option<Self::Program>;
+    fn attach_shader(&self, program: &Self::Program, shader: &Self::Shader);
+    fn detach_shader(&self, program: &Self::Program, shader: &Self::Shader);
+    fn bind_attrib_location(&self, program: &Self::Program, index: u32, name: &str);
+    fn link_program(&self, program: &Self::Program);
+    fn get_program_parameter(&self, program: &Self::Program, pname: u32)->i32;
+    fn get_program_info_log(&self, program: &Self::Program)->Option<String>;
+    fn use_program(&self, program: Option<&Self::Program>);
+    fn delete_program(&self, program: Option<&Self::Program>);
+
+    fn get_attrib_location(&self, program: &Self::Program, name: &str)->i32;
+    fn get_active_uniform(&self, program: &Self::Program, index: u32)->Option<ActiveInfo>;
+    fn get_uniform_location(&self, program: &Self::Program, name: &str)->Option<Self::UniformLocation>;
+    fn get_uniform_block_index(&self, program: &Self::Program, name: &str)->u32;
+    fn uniform_block_binding(&self, program: &Self::Program, index: u32, binding: u32);
+    fn uniform1f(&self, location: Option<&Self::UniformLocation>, value: f32);
+    fn uniform1i(&self, location: Option<&Self::UniformLocation>, value: i32);
+    fn uniform2fv(&self, location: Option<&Self::UniformLocation>, value: &[f32]);
+    fn uniform3fv(&self, location: Option<&Self::UniformLocation>, value: &[f32]);
+    fn uniform4fv(&self, location: Option<&Self::UniformLocation>, value: &[f32]);
+    fn uniform_matrix3fv(&self, location: Option<&Self::UniformLocation>, transpose: bool, value: &[f32]);
+    fn uniform_matrix4fv(&self, location: Option<&Self::UniformLocation>, transpose: bool, value: &[f32]);
+
+    fn create_framebuffer(&self)->Option<Self::Framebuffer>;
+    fn bind_framebuffer(&self, target: u32, framebuffer: Option<&Self::Framebuffer>);
+    fn check_framebuffer_status(&self, target: u32)->u32;
+    fn framebuffer_texture_layer(&self, target: u32, attachment: u32, texture: Option<&Self::Texture>, level: i32, layer: i32);
+    // 範囲は(x0, y0, x1, y1)。引数が多いので、GLの8つの座標を2つにまとめる
+    fn blit_framebuffer(&self, source: [i32; 4], destination: [i32; 4], mask: u32, filter: u32);
+    fn delete_framebuffer(&self, framebuffer: Option<&Self::Framebuffer>);
+
+    fn create_texture(&self)->Option<Self::Texture>;
+    fn bind_texture(&self, target: u32, texture: Option<&Self::Texture>);
+    fn tex_storage_3d(&self, target: u32, levels: i32, internal_format: u32, width: i32, height: i32, depth: i32);
+    fn delete_texture(&self, texture: Option<&Self::Texture>);
+
+    // OVR_multiview2が使えるか
+    fn has_multiview(&self)->bool;
+    // テクスチャ配列のnum_views枚のレイヤーを、1回の描画の描画先にする。拡張が無ければ何もしない
+    fn framebuffer_texture_multiview_ovr(&self, target: u32, attachment: u32, texture: Option<&Self::Texture>, level: i32, base_view_index: i32, num_views: i32);
+
+    fn enable(&self, capability: u32);
+    fn viewport(&self, x: i32, y: i32, width: i32, height: i32);
+    fn clear_color(&self, red: f32, green: f32, blue: f32, alpha: f32);
+    fn clear_depth(&self, depth: f32);
+    fn clear(&self, mask: u32);
+    fn draw_elements(&self, mode: u32, count: i32, index_type: u32, offset: i32);
+}
+
+// 描画先の範囲(ピクセル)
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct Viewport{
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+// 1つのビュー(片目)を描くのに必要なもの
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct ViewData{
+    // 視点の姿勢の逆行列
+    pub view: Mat4,
+    pub projection: Mat4,
+    pub viewport: Viewport,
+}
+
+// XRシステム。どのモードでセッションを開始できるか
+pub trait XrBackend{
+    fn is_session_supported(&self, mode: SessionMode)->impl Future<Output = bool>;
+}
+
+// XRのフレーム。このフレームで描くビューを返す
+pub trait XrFrameSource{
+    // 視点の姿勢が取れないフレームではNone
+    fn views(&self)->Option<Vec<ViewData>>;
+}
+
+// XRのフレームの入力。FrameLoop::stepがWebXRでもシミュレーターでも同じ順番で読む
+pub trait XrFrameInput{
+    // 入力ソースの姿勢を今の参照空間で読み直す
+    fn update_input(&mut self);
+    fn input_sources(&self)->&[InputSourceState];
+    fn viewer_pose(&self)->Option<Pose>;
+    // 参照空間の原点を動かす。動かせなかったらfalse
+    fn set_origin_offset(&mut self, position: [f32; 3], orientation: [f32; 4])->bool;
+    // 前のフレームの後に届いたselect/squeezeを取り出す
+    fn take_events(&mut self)->Vec<InputEvent>;
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct AssetError{
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for AssetError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for AssetError{}
+
+impl From<AssetError> for wasm_bindgen::JsValue{
+    fn from(error: AssetError)->Self{
+        wasm_bindgen::JsValue::from_str(&error.to_string())
+    }
+}
+
+// パスを指定してファイルを読み込む。ブラウザではfetch
+pub trait AssetFetcher{
+    fn fetch_bytes(&self, path: &str)->impl Future<Output = Result<Vec<u8>,AssetError>>;
+    fn fetch_text(&self, path: &str)->impl Future<Output = Result<String,AssetError>>;
+}
@@ -0,0 +1,924 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap,HashMap};
+use std::rc::Rc;
+use web_sys::WebGl2RenderingContext as Gl;
+use super::{ActiveInfo,AssetError,AssetFetcher,GraphicsDevice,ViewData,Viewport,XrBackend,XrFrameSource};
+use crate::session::SessionMode;
+
+// ネイティブのテスト用の実装。GPUもブラウザも使わず、呼び出された内容を記録する
+// シェーダはコンパイルせず、ソースのuniform・in宣言を読んでイントロスペクションに答える
+// #ifdefなどのプリプロセッサは評価しないので、分岐の両側の宣言が見える
+
+// バッファやプログラムなどのオブジェクトを表す番号
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct MockHandle(pub u32);
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum UniformValue{
+    Float(f32),
+    Int(i32),
+    Vector(Vec<f32>),
+    Matrix(Vec<f32>),
+}
+
+// draw_elementsの呼び出し1回分。その時点のプログラム・ビューポート・uniformの値を持つ
+#[derive(Debug,Clone,PartialEq)]
+pub struct DrawCall{
+    pub program: Option<MockHandle>,
+    // 描画先のフレームバッファ。Noneは既定のフレームバッファ
+    pub framebuffer: Option<MockHandle>,
+    pub vertex_array: Option<MockHandle>,
+    pub mode: u32,
+    pub count: i32,
+    pub viewport: Viewport,
+    pub uniforms: BTreeMap<String,UniformValue>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct ClearCall{
+    pub color: [f32; 4],
+    pub depth: f32,
+    pub mask: u32,
+}
+
+// blit_framebufferの呼び出し1回分。範囲は(x0, y0, x1, y1)
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct BlitCall{
+    pub read_framebuffer: Option<MockHandle>,
+    pub draw_framebuffer: Option<MockHandle>,
+    pub source: [i32; 4],
+    pub destination: [i32; 4],
+}
+
+// vertex_attrib_pointerの設定。呼び出したときにARRAY_BUFFERにあったバッファを持つ
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct AttribPointer{
+    pub buffer: MockHandle,
+    pub size: i32,
+    pub data_type: u32,
+    pub normalized: bool,
+    pub stride: i32,
+    pub offset: i32,
+}
+
+// VAOに記録される状態
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct VertexArrayState{
+    pub enabled: Vec<u32>,
+    pub pointers: BTreeMap<u32,AttribPointer>,
+    // VAOをバインドしている間にELEMENT_ARRAY_BUFFERへバインドしたバッファ
+    pub element_buffer: Option<MockHandle>,
+}
+
+#[derive(Debug,Clone,Default)]
+struct MockShader{
+    shader_type: u32,
+    source: String,
+    compiled: bool,
+    info_log: Option<String>,
+}
+
+#[derive(Debug,Clone,Default)]
+struct MockProgram{
+    shaders: Vec<MockHandle>,
+    bound_attributes: HashMap<String,u32>,
+    linked: bool,
+    info_log: Option<String>,
+    // リンク時にソースから読んだ宣言
+    uniforms: Vec<ActiveInfo>,
+    attributes: HashMap<String,i32>,
+    blocks: Vec<String>,
+    block_bindings: HashMap<u32,u32>,
+    values: BTreeMap<String,UniformValue>,
+}
+
+#[derive(Debug,Default)]
+struct MockState{
+    next_handle: u32,
+    buffers: HashMap<MockHandle,Vec<u8>>,
+    vertex_arrays: HashMap<MockHandle,VertexArrayState>,
+    shaders: HashMap<MockHandle,MockShader>,
+    programs: HashMap<MockHandle,MockProgram>,
+    framebuffers: HashMap<MockHandle,Vec<u32>>,
+    textures: HashMap<MockHandle,Option<[i32; 3]>>,
+    // uniformの位置からプログラムと名前を引く
+    locations: HashMap<MockHandle,(MockHandle,String)>,
+    bound_buffers: HashMap<u32,MockHandle>,
+    uniform_bindings: HashMap<u32,MockHandle>,
+    vertex_array: Option<MockHandle>,
+    program: Option<MockHandle>,
+    draw_framebuffer: Option<MockHandle>,
+    read_framebuffer: Option<MockHandle>,
+    textures_bound: HashMap<u32,MockHandle>,
+    multiview: bool,
+    enabled: Vec<u32>,
+    viewport: Viewport,
+    clear_color: [f32; 4],
+    clear_depth: f32,
+    clears: Vec<ClearCall>,
+    draws: Vec<DrawCall>,
+    blits: Vec<BlitCall>,
+    // 残っているオブジェクトがこの数に達したら、create_*がNoneを返す
+    object_limit: Option<usize>,
+}
+
+impl MockState{
+    fn allocate(&mut self)->MockHandle{
+        self.next_handle += 1;
+        MockHandle(self.next_handle)
+    }
+
+    fn live_objects(&self)->usize{
+        self.buffers.len() + self.vertex_arrays.len() + self.shaders.len() + self.programs.len() + self.framebuffers.len() + self.textures.len()
+    }
+
+    fn bound_framebuffer(&self, target: u32)->Option<MockHandle>{
+        match target{
+            Gl::READ_FRAMEBUFFER=>self.read_framebuffer,
+            _=>self.draw_framebuffer,
+        }
+    }
+
+    // バッファやプログラムなどのオブジェクトを作れるか。GPUのメモリ不足やコンテキストの消失を真似る
+    fn can_create(&self)->bool{
+        self.object_limit.is_none_or(|limit| self.live_objects() < limit)
+    }
+
+    fn set_uniform(&mut self, location: Option<&MockHandle>, value: UniformValue){
+        let Some((program, name)) = location.and_then(|location| self.locations.get(location)).cloned() else{
+            return;
+        };
+        if let Some(program) = self.programs.get_mut(&program){
+            program.values.insert(name, value);
+        }
+    }
+
+    fn bound_buffer_mut(&mut self, target: u32)->Option<&mut Vec<u8>>{
+        let handle = self.bound_buffers.get(&target)?;
+        self.buffers.get_mut(handle)
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct MockDevice{
+    state: Rc<RefCell<MockState>>,
+}
+
+impl Default for MockDevice{
+    fn default()->Self{
+        MockDevice::new()
+    }
+}
+
+impl MockDevice{
+    // クリアの深度はGLの初期値に合わせる
+    pub fn new()->Self{
+        let state = MockState{clear_depth: 1.0, ..MockState::default()};
+        MockDevice{state: Rc::new(RefCell::new(state))}
+    }
+
+    pub fn draws(&self)->Vec<DrawCall>{
+        self.state.borrow().draws.clone()
+    }
+
+    pub fn clears(&self)->Vec<ClearCall>{
+        self.state.borrow().clears.clone()
+    }
+
+    pub fn blits(&self)->Vec<BlitCall>{
+        self.state.borrow().blits.clone()
+    }
+
+    // 記録した描画とクリアを捨てる。フレームごとに確かめるときに使う
+    pub fn clear_records(&self){
+        let mut state = self.state.borrow_mut();
+        state.draws.clear();
+        state.clears.clear();
+        state.blits.clear();
+    }
+
+    // OVR_multiview2が使えるように振る舞う
+    pub fn set_multiview(&self, supported: bool){
+        self.state.borrow_mut().multiview = supported;
+    }
+
+    // フレームバッファに付けたアタッチメント
+    pub fn framebuffer_attachments(&self, framebuffer: &MockHandle)->Option<Vec<u32>>{
+        self.state.borrow().framebuffers.get(framebuffer).cloned()
+    }
+
+    // tex_storage_3dで確保した(幅, 高さ, 枚数)
+    pub fn texture_size(&self, texture: &MockHandle)->Option<[i32; 3]>{
+        *self.state.borrow().textures.get(texture)?
+    }
+
+    pub fn current_viewport(&self)->Viewport{
+        self.state.borrow().viewport
+    }
+
+    pub fn current_program(&self)->Option<MockHandle>{
+        self.state.borrow().program
+    }
+
+    pub fn current_vertex_array(&self)->Option<MockHandle>{
+        self.state.borrow().vertex_array
+    }
+
+    pub fn vertex_array_state(&self, vertex_array: &MockHandle)->Option<VertexArrayState>{
+        self.state.borrow().vertex_arrays.get(vertex_array).cloned()
+    }
+
+    // clear_color・clear_depthで設定した(色, 深度)
+    pub fn clear_values(&self)->([f32; 4], f32){
+        let state = self.state.borrow();
+        (state.clear_color, state.clear_depth)
+    }
+
+    pub fn is_enabled(&self, capability: u32)->bool{
+        self.state.borrow().enabled.contains(&capability)
+    }
+
+    // エンジンはdisableを使わないのでトレイトには無い。有効にした機能を外して結果を比べるときに使う
+    pub fn disable(&self, capability: u32){
+        self.state.borrow_mut().enabled.retain(|enabled| *enabled != capability);
+    }
+
+    pub fn buffer_data(&self, buffer: &MockHandle)->Option<Vec<u8>>{
+        self.state.borrow().buffers.get(buffer).cloned()
+    }
+
+    // bind_buffer_baseでバインディングポイントに結び付けたuniformバッファの中身
+    pub fn uniform_buffer_data(&self, binding: u32)->Option<Vec<u8>>{
+        let state = self.state.borrow();
+        let handle = state.uniform_bindings.get(&binding)?;
+        state.buffers.get(handle).cloned()
+    }
+
+    pub fn uniform_value(&self, program: &MockHandle, name: &str)->Option<UniformValue>{
+        self.state.borrow().programs.get(program)?.values.get(name).cloned()
+    }
+
+    pub fn shader_sources(&self)->Vec<String>{
+        self.state.borrow().shaders.values().map(|shader| shader.source.clone()).collect()
+    }
+
+    // 削除されずに残っているオブジェクトの数。リソースの解放漏れを確かめる
+    pub fn live_objects(&self)->usize{
+        self.state.borrow().live_objects()
+    }
+
+    // 残っているオブジェクトの数の上限。作成に失敗したときの後始末を確かめる
+    pub fn set_object_limit(&self, limit: Option<usize>){
+        self.state.borrow_mut().object_limit = limit;
+    }
+}
+
+// 1行ずつ宣言を読む。uniformブロックの中身は位置を持たないので飛ばす
+fn parse_declarations(shader_type: u32, source: &str, program: &mut MockProgram){
+    let mut in_block = false;
+    for line in source.lines(){
+        let line = line.split("//").next().unwrap_or("").trim();
+        if in_block{
+            if line.starts_with('}'){
+                in_block = false;
+            }
+            continue;
+        }
+        // layout(...)は読み飛ばす
+        let line = match line.strip_prefix("layout"){
+            Some(rest)=>rest.split_once(')').map(|(_, rest)| rest.trim()).unwrap_or(""),
+            None=>line,
+        };
+        let words: Vec<&str> = line.split(|c: char| c.is_whitespace() || c == ';' || c == '{').filter(|word| !word.is_empty()).collect();
+        let Some((&qualifier, rest)) = words.split_first() else{
+            continue;
+        };
+        match qualifier{
+            "uniform" if line.contains('{') || rest.len() == 1=>{
+                if let Some(name) = rest.first(){
+                    program.blocks.push(name.to_string());
+                }
+                in_block = !line.contains('}');
+            },
+            "uniform"=>{
+                let Some((type_name, name)) = declaration(rest) else{
+                    continue;
+                };
+                let (name, size) = array_size(name);
+                if program.uniforms.iter().all(|uniform| uniform.name.trim_end_matches("[0]") != name){
+                    let name = if size > 1{ format!("{}[0]", name) } else{ name.to_string() };
+                    program.uniforms.push(ActiveInfo{name, gl_type: gl_type(type_name), size});
+                }
+            },
+            "in" if shader_type == Gl::VERTEX_SHADER=>{
+                if let Some((_, name)) = declaration(rest){
+                    let location = program.bound_attributes.get(name).map(|location| *location as i32);
+                    let next = program.attributes.len() as i32;
+                    program.attributes.entry(name.to_string()).or_insert(location.unwrap_or(next));
+                }
+            },
+            _=>{},
+        }
+    }
+}
+
+// フラグメントシェーダのinのうち、頂点シェーダのoutに無いもの
+fn unmatched_varying(shaders: &[MockShader])->Option<String>{
+    let outputs: Vec<String> = shaders.iter()
+        .filter(|shader| shader.shader_type == Gl::VERTEX_SHADER)
+        .flat_map(|shader| varyings(&shader.source, "out"))
+        .collect();
+    shaders.iter()
+        .filter(|shader| shader.shader_type == Gl::FRAGMENT_SHADER)
+        .flat_map(|shader| varyings(&shader.source, "in"))
+        .find(|name| !outputs.contains(name))
+}
+
+// qualifierで宣言された変数の名前。補間修飾子とlayout(...)は読み飛ばす
+fn varyings(source: &str, qualifier: &str)->Vec<String>{
+    source.lines().filter_map(|line|{
+        let line = line.split("//").next().unwrap_or("").trim();
+        let line = match line.strip_prefix("layout"){
+            Some(rest)=>rest.split_once(')').map(|(_, rest)| rest.trim()).unwrap_or(""),
+            None=>line,
+        };
+        let words: Vec<&str> = line.split(|c: char| c.is_whitespace() || c == ';')
+            .filter(|word| !word.is_empty() && !matches!(*word, "flat" | "smooth" | "centroid"))
+            .collect();
+        match words.split_first(){
+            Some((&first, rest)) if first == qualifier=>declaration(rest).map(|(_, name)| name.to_string()),
+            _=>None,
+        }
+    }).collect()
+}
+
+// 精度修飾子を除いた(型, 名前)
+fn declaration<'a>(words: &[&'a str])->Option<(&'a str, &'a str)>{
+    let words: Vec<&str> = words.iter().copied().filter(|word| !matches!(*word, "highp" | "mediump" | "lowp" | "flat")).collect();
+    match words.as_slice(){
+        [type_name, name, ..]=>Some((type_name, name)),
+        _=>None,
+    }
+}
+
+// "lights[4]" を ("lights", 4) にする
+fn array_size(name: &str)->(&str, i32){
+    match name.split_once('['){
+        Some((base, rest))=>(base, rest.trim_end_matches(']').parse().unwrap_or(1)),
+        None=>(name, 1),
+    }
+}
+
+fn gl_type(type_name: &str)->u32{
+    match type_name{
+        "float"=>Gl::FLOAT,
+        "vec2"=>Gl::FLOAT_VEC2,
+        "vec3"=>Gl::FLOAT_VEC3,
+        "vec4"=>Gl::FLOAT_VEC4,
+        "int"=>Gl::INT,
+        "bool"=>Gl::BOOL,
+        "mat3"=>Gl::FLOAT_MAT3,
+        "mat4"=>Gl::FLOAT_MAT4,
+        "sampler2D"=>Gl::SAMPLER_2D,
+        "sampler3D"=>Gl::SAMPLER_3D,
+        "samplerCube"=>Gl::SAMPLER_CUBE,
+        "sampler2DArray"=>Gl::SAMPLER_2D_ARRAY,
+        _=>0,
+    }
+}
+
+impl GraphicsDevice for MockDevice{
+    type Buffer = MockHandle;
+    type VertexArray = MockHandle;
+    type Shader = MockHandle;
+    type Program = MockHandle;
+    type UniformLocation = MockHandle;
+    type Framebuffer = MockHandle;
+    type Texture = MockHandle;
+
+    fn create_buffer(&self)->Option<MockHandle>{
+        let mut state = self.state.borrow_mut();
+        if !state.can_create(){
+            return None;
+        }
+        let handle = state.allocate();
+        state.buffers.insert(handle, Vec::new());
+        Some(handle)
+    }
+
+    fn bind_buffer(&self, target: u32, buffer: Option<&MockHandle>){
+        let mut state = self.state.borrow_mut();
+        if target == Gl::ELEMENT_ARRAY_BUFFER{
+            if let Some(vertex_array) = state.vertex_array{
+                if let Some(vertex_array) = state.vertex_arrays.get_mut(&vertex_array){
+                    vertex_array.element_buffer = buffer.copied();
+                }
+            }
+        }
+        match buffer{
+            Some(buffer)=>{
+                state.bound_buffers.insert(target, *buffer);
+            },
+            None=>{
+                state.bound_buffers.remove(&target);
+            },
+        }
+    }
+
+    fn buffer_data_f32(&self, target: u32, data: &[f32], _usage: u32){
+        if let Some(buffer) = self.state.borrow_mut().bound_buffer_mut(target){
+            *buffer = data.iter().flat_map(|value| value.to_le_bytes()).collect();
+        }
+    }
+
+    fn buffer_data_u16(&self, target: u32, data: &[u16], _usage: u32){
+        if let Some(buffer) = self.state.borrow_mut().bound_buffer_mut(target){
+            *buffer = data.iter().flat_map(|value| value.to_le_bytes()).collect();
+        }
+    }
+
+    fn buffer_data_size(&self, target: u32, size: i32, _usage: u32){
+        if let Some(buffer) = self.state.borrow_mut().bound_buffer_mut(target){
+            *buffer = vec![0; size.max(0) as usize];
+        }
+    }
+
+    fn buffer_sub_data_u8(&self, target: u32, offset: i32, data: &[u8]){
+        if let Some(buffer) = self.state.borrow_mut().bound_buffer_mut(target){
+            let offset = offset.max(0) as usize;
+            if offset + data.len() <= buffer.len(){
+                buffer[offset..offset + data.len()].copy_from_slice(data);
+            }
+        }
+    }
+
+    fn bind_buffer_base(&self, target: u32, index: u32, buffer: Option<&MockHandle>){
+        let mut state = self.state.borrow_mut();
+        if target == Gl::UNIFORM_BUFFER{
+            match buffer{
+                Some(buffer)=>{
+                    state.uniform_bindings.insert(index, *buffer);
+                },
+                None=>{
+                    state.uniform_bindings.remove(&index);
+                },
+            }
+        }
+        // bindBufferBaseは汎用のバインドポイントも変える
+        if let Some(buffer) = buffer{
+            state.bound_buffers.insert(target, *buffer);
+        }
+    }
+
+    fn delete_buffer(&self, buffer: Option<&MockHandle>){
+        if let Some(buffer) = buffer{
+            self.state.borrow_mut().buffers.remove(buffer);
+        }
+    }
+
+    fn create_vertex_array(&self)->Option<MockHandle>{
+        let mut state = self.state.borrow_mut();
+        if !state.can_create(){
+            return None;
+        }
+        let handle = state.allocate();
+        state.vertex_arrays.insert(handle, VertexArrayState::default());
+        Some(handle)
+    }
+
+    fn bind_vertex_array(&self, vertex_array: Option<&MockHandle>){
+        self.state.borrow_mut().vertex_array = vertex_array.copied();
+    }
+
+    fn delete_vertex_array(&self, vertex_array: Option<&MockHandle>){
+        if let Some(vertex_array) = vertex_array{
+            self.state.borrow_mut().vertex_arrays.remove(vertex_array);
+        }
+    }
+
+    fn enable_vertex_attrib_array(&self, index: u32){
+        let mut state = self.state.borrow_mut();
+        let Some(vertex_array) = state.vertex_array else{
+            return;
+        };
+        if let Some(vertex_array) = state.vertex_arrays.get_mut(&vertex_array){
+            if !vertex_array.enabled.contains(&index){
+                vertex_array.enabled.push(index);
+            }
+        }
+    }
+
+    fn vertex_attrib_pointer(&self, index: u32, size: i32, data_type: u32, normalized: bool, stride: i32, offset: i32){
+        let mut state = self.state.borrow_mut();
+        let (Some(vertex_array), Some(buffer)) = (state.vertex_array, state.bound_buffers.get(&Gl::ARRAY_BUFFER).copied()) else{
+            return;
+        };
+        if let Some(vertex_array) = state.vertex_arrays.get_mut(&vertex_array){
+            vertex_array.pointers.insert(index, AttribPointer{buffer, size, data_type, normalized, stride, offset});
+        }
+    }
+
+    fn create_shader(&self, shader_type: u32)->Option<MockHandle>{
+        let mut state = self.state.borrow_mut();
+        if !state.can_create(){
+            return None;
+        }
+        let handle = state.allocate();
+        state.shaders.insert(handle, MockShader{shader_type, ..MockShader::default()});
+        Some(handle)
+    }
+
+    fn shader_source(&self, shader: &MockHandle, source: &str){
+        if let Some(shader) = self.state.borrow_mut().shaders.get_mut(shader){
+            shader.source = source.to_string();
+        }
+    }
+
+    // #errorディレクティブのある行をコンパイルエラーにする
+    fn compile_shader(&self, shader: &MockHandle){
+        let mut state = self.state.borrow_mut();
+        let Some(shader) = state.shaders.get_mut(shader) else{
+            return;
+        };
+        let error = shader.source.lines().enumerate().find(|(_, line)| line.trim_start().starts_with("#error"));
+        shader.compiled = error.is_none();
+        shader.info_log = error.map(|(index, line)| format!("ERROR: 0:{}: {}", index + 1, line.trim()));
+    }
+
+    fn get_shader_parameter(&self, shader: &MockHandle, pname: u32)->i32{
+        let state = self.state.borrow();
+        let Some(shader) = state.shaders.get(shader) else{
+            return 0;
+        };
+        match pname{
+            Gl::COMPILE_STATUS=>i32::from(shader.compiled),
+            Gl::SHADER_TYPE=>shader.shader_type as i32,
+            _=>0,
+        }
+    }
+
+    fn get_shader_info_log(&self, shader: &MockHandle)->Option<String>{
+        self.state.borrow().shaders.get(shader)?.info_log.clone()
+    }
+
+    fn delete_shader(&self, shader: Option<&MockHandle>){
+        if let Some(shader) = shader{
+            self.state.borrow_mut().shaders.remove(shader);
+        }
+    }
+
+    fn create_program(&self)->Option<MockHandle>{
+        let mut state = self.state.borrow_mut();
+        if !state.can_create(){
+            return None;
+        }
+        let handle = state.allocate();
+        state.programs.insert(handle, MockProgram::default());
+        Some(handle)
+    }
+
+    fn attach_shader(&self, program: &MockHandle, shader: &MockHandle){
+        if let Some(program) = self.state.borrow_mut().programs.get_mut(program){
+            program.shaders.push(*shader);
+        }
+    }
+
+    fn detach_shader(&self, program: &MockHandle, shader: &MockHandle){
+        if let Some(program) = self.state.borrow_mut().programs.get_mut(program){
+            program.shaders.retain(|attached| attached != shader);
+        }
+    }
+
+    fn bind_attrib_location(&self, program: &MockHandle, index: u32, name: &str){
+        if let Some(program) = self.state.borrow_mut().programs.get_mut(program){
+            program.bound_attributes.insert(name.to_string(), index);
+        }
+    }
+
+    fn link_program(&self, program: &MockHandle){
+        let mut state = self.state.borrow_mut();
+        let Some(mut linked) = state.programs.get(program).cloned() else{
+            return;
+        };
+        let shaders: Vec<MockShader> = linked.shaders.iter().filter_map(|shader| state.shaders.get(shader).cloned()).collect();
+        linked.uniforms.clear();
+        linked.attributes.clear();
+        linked.blocks.clear();
+        let has_stage = |shader_type: u32| shaders.iter().any(|shader| shader.shader_type == shader_type && shader.compiled);
+        if !(has_stage(Gl::VERTEX_SHADER) && has_stage(Gl::FRAGMENT_SHADER)){
+            linked.linked = false;
+            linked.info_log = Some("ERROR: program needs a compiled vertex and fragment shader".to_string());
+        }
+        else if let Some(name) = unmatched_varying(&shaders){
+            linked.linked = false;
+            linked.info_log = Some(format!("ERROR: Input varying '{}' is not written by the vertex shader", name));
+        }
+        else{
+            for shader in shaders.iter(){
+                parse_declarations(shader.shader_type, &shader.source, &mut linked);
+            }
+            linked.linked = true;
+            linked.info_log = None;
+        }
+        state.programs.insert(*program, linked);
+    }
+
+    fn get_program_parameter(&self, program: &MockHandle, pname: u32)->i32{
+        let state = self.state.borrow();
+        let Some(program) = state.programs.get(program) else{
+            return 0;
+        };
+        match pname{
+            Gl::LINK_STATUS=>i32::from(program.linked),
+            Gl::ACTIVE_UNIFORMS=>program.uniforms.len() as i32,
+            Gl::ACTIVE_ATTRIBUTES=>program.attributes.len() as i32,
+            Gl::ACTIVE_UNIFORM_BLOCKS=>program.blocks.len() as i32,
+            _=>0,
+        }
+    }
+
+    fn get_program_info_log(&self, program: &MockHandle)->Option<String>{
+        self.state.borrow().programs.get(program)?.info_log.clone()
+    }
+
+    fn use_program(&self, program: Option<&MockHandle>){
+        self.state.borrow_mut().program = program.copied();
+    }
+
+    fn delete_program(&self, program: Option<&MockHandle>){
+        let Some(program) = program else{
+            return;
+        };
+        let mut state = self.state.borrow_mut();
+        state.programs.remove(program);
+        state.locations.retain(|_, (owner, _)| owner != program);
+    }
+
+    fn get_attrib_location(&self, program: &MockHandle, name: &str)->i32{
+        let state = self.state.borrow();
+        state.programs.get(program).and_then(|program| program.attributes.get(name).copied()).unwrap_or(-1)
+    }
+
+    fn get_active_uniform(&self, program: &MockHandle, index: u32)->Option<ActiveInfo>{
+        self.state.borrow().programs.get(program)?.uniforms.get(index as usize).cloned()
+    }
+
+    fn get_uniform_location(&self, program: &MockHandle, name: &str)->Option<MockHandle>{
+        let mut state = self.state.borrow_mut();
+        let base = name.trim_end_matches("[0]");
+        if !state.programs.get(program)?.uniforms.iter().any(|uniform| uniform.name.trim_end_matches("[0]") == base){
+            return None;
+        }
+        let handle = state.allocate();
+        state.locations.insert(handle, (*program, base.to_string()));
+        Some(handle)
+    }
+
+    fn get_uniform_block_index(&self, program: &MockHandle, name: &str)->u32{
+        let state = self.state.borrow();
+        state.programs.get(program)
+            .and_then(|program| program.blocks.iter().position(|block| block == name))
+            .map(|index| index as u32)
+            .unwrap_or(Gl::INVALID_INDEX)
+    }
+
+    fn uniform_block_binding(&self, program: &MockHandle, index: u32, binding: u32){
+        if let Some(program) = self.state.borrow_mut().programs.get_mut(program){
+            program.block_bindings.insert(index, binding);
+        }
+    }
+
+    fn uniform1f(&self, location: Option<&MockHandle>, value: f32){
+        self.state.borrow_mut().set_uniform(location, UniformValue::Float(value));
+    }
+
+    fn uniform1i(&self, location: Option<&MockHandle>, value: i32){
+        self.state.borrow_mut().set_uniform(location, UniformValue::Int(value));
+    }
+
+    fn uniform2fv(&self, location: Option<&MockHandle>, value: &[f32]){
+        self.state.borrow_mut().set_uniform(location, UniformValue::Vector(value.to_vec()));
+    }
+
+    fn uniform3fv(&self, location: Option<&MockHandle>, value: &[f32]){
+        self.state.borrow_mut().set_uniform(location, UniformValue::Vector(value.to_vec()));
+    }
+
+    fn uniform4fv(&self, location: Option<&MockHandle>, value: &[f32]){
+        self.state.borrow_mut().set_uniform(location, UniformValue::Vector(value.to_vec()));
+    }
+
+    fn uniform_matrix3fv(&self, location: Option<&MockHandle>, _transpose: bool, value: &[f32]){
+        self.state.borrow_mut().set_uniform(location, UniformValue::Matrix(value.to_vec()));
+    }
+
+    fn uniform_matrix4fv(&self, location: Option<&MockHandle>, _transpose: bool, value: &[f32]){
+        self.state.borrow_mut().set_uniform(location, UniformValue::Matrix(value.to_vec()));
+    }
+
+    fn create_framebuffer(&self)->Option<MockHandle>{
+        let mut state = self.state.borrow_mut();
+        if !state.can_create(){
+            return None;
+        }
+        let handle = state.allocate();
+        state.framebuffers.insert(handle, Vec::new());
+        Some(handle)
+    }
+
+    fn bind_framebuffer(&self, target: u32, framebuffer: Option<&MockHandle>){
+        let mut state = self.state.borrow_mut();
+        let framebuffer = framebuffer.copied();
+        if target != Gl::READ_FRAMEBUFFER{
+            state.draw_framebuffer = framebuffer;
+        }
+        if target != Gl::DRAW_FRAMEBUFFER{
+            state.read_framebuffer = framebuffer;
+        }
+    }
+
+    // アタッチメントが1つでもあれば完全とみなす
+    fn check_framebuffer_status(&self, target: u32)->u32{
+        let state = self.state.borrow();
+        let attached = state.bound_framebuffer(target)
+            .and_then(|framebuffer| state.framebuffers.get(&framebuffer))
+            .is_some_and(|attachments| !attachments.is_empty());
+        if attached{ Gl::FRAMEBUFFER_COMPLETE } else{ Gl::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT }
+    }
+
+    fn framebuffer_texture_layer(&self, target: u32, attachment: u32, texture: Option<&MockHandle>, _level: i32, _layer: i32){
+        let mut state = self.state.borrow_mut();
+        if texture.is_none(){
+            return;
+        }
+        let Some(framebuffer) = state.bound_framebuffer(target) else{
+            return;
+        };
+        if let Some(attachments) = state.framebuffers.get_mut(&framebuffer){
+            if !attachments.contains(&attachment){
+                attachments.push(attachment);
+            }
+        }
+    }
+
+    fn blit_framebuffer(&self, source: [i32; 4], destination: [i32; 4], _mask: u32, _filter: u32){
+        let mut state = self.state.borrow_mut();
+        let blit = BlitCall{read_framebuffer: state.read_framebuffer, draw_framebuffer: state.draw_framebuffer, source, destination};
+        state.blits.push(blit);
+    }
+
+    fn delete_framebuffer(&self, framebuffer: Option<&MockHandle>){
+        if let Some(framebuffer) = framebuffer{
+            self.state.borrow_mut().framebuffers.remove(framebuffer);
+        }
+    }
+
+    fn create_texture(&self)->Option<MockHandle>{
+        let mut state = self.state.borrow_mut();
+        if !state.can_create(){
+            return None;
+        }
+        let handle = state.allocate();
+        state.textures.insert(handle, None);
+        Some(handle)
+    }
+
+    fn bind_texture(&self, target: u32, texture: Option<&MockHandle>){
+        let mut state = self.state.borrow_mut();
+        match texture{
+            Some(texture)=>{
+                state.textures_bound.insert(target, *texture);
+            },
+            None=>{
+                state.textures_bound.remove(&target);
+            },
+        }
+    }
+
+    fn tex_storage_3d(&self, target: u32, _levels: i32, _internal_format: u32, width: i32, height: i32, depth: i32){
+        let mut state = self.state.borrow_mut();
+        let Some(texture) = state.textures_bound.get(&target).copied() else{
+            return;
+        };
+        if let Some(size) = state.textures.get_mut(&texture){
+            *size = Some([width, height, depth]);
+        }
+    }
+
+    fn delete_texture(&self, texture: Option<&MockHandle>){
+        if let Some(texture) = texture{
+            let mut state = self.state.borrow_mut();
+            state.textures.remove(texture);
+            state.textures_bound.retain(|_, bound| bound != texture);
+        }
+    }
+
+    fn has_multiview(&self)->bool{
+        self.state.borrow().multiview
+    }
+
+    fn framebuffer_texture_multiview_ovr(&self, target: u32, attachment: u32, texture: Option<&MockHandle>, level: i32, _base_view_index: i32, _num_views: i32){
+        if self.has_multiview(){
+            self.framebuffer_texture_layer(target, attachment, texture, level, 0);
+        }
+    }
+
+    fn enable(&self, capability: u32){
+        let mut state = self.state.borrow_mut();
+        if !state.enabled.contains(&capability){
+            state.enabled.push(capability);
+        }
+    }
+
+    fn viewport(&self, x: i32, y: i32, width: i32, height: i32){
+        self.state.borrow_mut().viewport = Viewport{x, y, width, height};
+    }
+
+    fn clear_color(&self, red: f32, green: f32, blue: f32, alpha: f32){
+        self.state.borrow_mut().clear_color = [red, green, blue, alpha];
+    }
+
+    fn clear_depth(&self, depth: f32){
+        self.state.borrow_mut().clear_depth = depth;
+    }
+
+    fn clear(&self, mask: u32){
+        let mut state = self.state.borrow_mut();
+        let clear = ClearCall{color: state.clear_color, depth: state.clear_depth, mask};
+        state.clears.push(clear);
+    }
+
+    fn draw_elements(&self, mode: u32, count: i32, _index_type: u32, _offset: i32){
+        let mut state = self.state.borrow_mut();
+        let uniforms = state.program.and_then(|program| state.programs.get(&program)).map(|program| program.values.clone()).unwrap_or_default();
+        let draw = DrawCall{
+            program: state.program,
+            framebuffer: state.draw_framebuffer,
+            vertex_array: state.vertex_array,
+            mode,
+            count,
+            viewport: state.viewport,
+            uniforms,
+        };
+        state.draws.push(draw);
+    }
+}
+
+// 対応しているモードを指定するXRシステム
+#[derive(Debug,Clone,Default)]
+pub struct MockXr{
+    pub supported: Vec<SessionMode>,
+}
+
+impl XrBackend for MockXr{
+    async fn is_session_supported(&self, mode: SessionMode)->bool{
+        self.supported.contains(&mode)
+    }
+}
+
+// 決めたビューを返すフレーム。Noneなら視点の姿勢が取れなかったフレーム
+#[derive(Debug,Clone,Default)]
+pub struct MockFrame{
+    pub views: Option<Vec<ViewData>>,
+}
+
+impl XrFrameSource for MockFrame{
+    fn views(&self)->Option<Vec<ViewData>>{
+        self.views.clone()
+    }
+}
+
+// パスごとに中身を登録しておくアセット
+#[derive(Debug,Clone,Default)]
+pub struct MemoryAssets{
+    files: HashMap<String,Vec<u8>>,
+}
+
+impl MemoryAssets{
+    pub fn new()->Self{
+        MemoryAssets::default()
+    }
+
+    pub fn insert(&mut self, path: &str, contents: impl Into<Vec<u8>>){
+        self.files.insert(path.to_string(), contents.into());
+    }
+
+    pub fn with_file(mut self, path: &str, contents: impl Into<Vec<u8>>)->Self{
+        self.insert(path, contents);
+        self
+    }
+
+    fn get(&self, path: &str)->Result<&Vec<u8>,AssetError>{
+        self.files.get(path).ok_or_else(|| AssetError{path: path.to_string(), message: "not found".to_string()})
+    }
+}
+
+impl AssetFetcher for MemoryAssets{
+    async fn fetch_bytes(&self, path: &str)->Result<Vec<u8>,AssetError>{
+        self.get(path).cloned()
+    }
+
+    async fn fetch_text(&self, path: &str)->Result<String,AssetError>{
+        String::from_utf8(self.get(path)?.clone()).map_err(|error| AssetError{path: path.to_string(), message: error.to_string()})
+    }
+}
@@ -0,0 +1,615 @@
+use std::cell::{Cell,RefCell};
+use std::rc::Rc;
+use serde::{Deserialize,Serialize};
+use super::{ActiveInfo,GraphicsDevice};
+
+// 呼び出しを全て記録してから、中のデバイスに渡すGraphicsDevice
+// 記録はシリアライズできるので、描画のコマンド列をゴールデンファイルと比べるテストに使う
+// オブジェクトには作成順に番号を振るので、中のデバイスが何であっても同じ記録になる
+
+// 記録したデバイスが作ったオブジェクト。idで記録に現れる
+#[derive(Debug,Clone,PartialEq)]
+pub struct Recorded<T>{
+    pub id: u32,
+    pub inner: T,
+}
+
+// 呼び出し1回分。引数と、値を返す呼び出しはその結果を持つ
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum GlCommand{
+    CreateBuffer{buffer: Option<u32>},
+    BindBuffer{target: u32, buffer: Option<u32>},
+    BufferDataF32{target: u32, data: Vec<f32>, usage: u32},
+    BufferDataU16{target: u32, data: Vec<u16>, usage: u32},
+    BufferDataSize{target: u32, size: i32, usage: u32},
+    BufferSubDataU8{target: u32, offset: i32, data: Vec<u8>},
+    BindBufferBase{target: u32, index: u32, buffer: Option<u32>},
+    DeleteBuffer{buffer: Option<u32>},
+    CreateVertexArray{vertex_array: Option<u32>},
+    BindVertexArray{vertex_array: Option<u32>},
+    DeleteVertexArray{vertex_array: Option<u32>},
+    EnableVertexAttribArray{index: u32},
+    VertexAttribPointer{index: u32, size: i32, data_type: u32, normalized: bool, stride: i32, offset: i32},
+    CreateShader{shader_type: u32, shader: Option<u32>},
+    ShaderSource{shader: u32, source: String},
+    CompileShader{shader: u32},
+    GetShaderParameter{shader: u32, pname: u32, result: i32},
+    GetShaderInfoLog{shader: u32, result: Option<String>},
+    DeleteShader{shader: Option<u32>},
+    CreateProgram{program: Option<u32>},
+    AttachShader{program: u32, shader: u32},
+    DetachShader{program: u32, shader: u32},
+    BindAttribLocation{program: u32, index: u32, name: String},
+    LinkProgram{program: u32},
+    GetProgramParameter{program: u32, pname: u32, result: i32},
+    GetProgramInfoLog{program: u32, result: Option<String>},
+    UseProgram{program: Option<u32>},
+    DeleteProgram{program: Option<u32>},
+    GetAttribLocation{program: u32, name: String, result: i32},
+    GetActiveUniform{program: u32, index: u32, result: Option<String>},
+    GetUniformLocation{program: u32, name: String, location: Option<u32>},
+    GetUniformBlockIndex{program: u32, name: String, result: u32},
+    UniformBlockBinding{program: u32, index: u32, binding: u32},
+    Uniform1f{location: Option<u32>, value: f32},
+    Uniform1i{location: Option<u32>, value: i32},
+    Uniform2fv{location: Option<u32>, value: Vec<f32>},
+    Uniform3fv{location: Option<u32>, value: Vec<f32>},
+    Uniform4fv{location: Option<u32>, value: Vec<f32>},
+    UniformMatrix3fv{location: Option<u32>, transpose: bool, value: Vec<f32>},
+    UniformMatrix4fv{location: Option<u32>, transpose: bool, value: Vec<f32>},
+    CreateFramebuffer{framebuffer: Option<u32>},
+    BindFramebuffer{target: u32, framebuffer: Option<u32>},
+    CheckFramebufferStatus{target: u32, result: u32},
+    FramebufferTextureLayer{target: u32, attachment: u32, texture: Option<u32>, level: i32, layer: i32},
+    BlitFramebuffer{source: [i32; 4], destination: [i32; 4], mask: u32, filter: u32},
+    DeleteFramebuffer{framebuffer: Option<u32>},
+    CreateTexture{texture: Option<u32>},
+    BindTexture{target: u32, texture: Option<u32>},
+    TexStorage3d{target: u32, levels: i32, internal_format: u32, width: i32, height: i32, depth: i32},
+    DeleteTexture{texture: Option<u32>},
+    HasMultiview{result: bool},
+    FramebufferTextureMultiviewOvr{target: u32, attachment: u32, texture: Option<u32>, level: i32, base_view_index: i32, num_views: i32},
+    Enable{capability: u32},
+    Viewport{x: i32, y: i32, width: i32, height: i32},
+    ClearColor{red: f32, green: f32, blue: f32, alpha: f32},
+    ClearDepth{depth: f32},
+    Clear{mask: u32},
+    DrawElements{mode: u32, count: i32, index_type: u32, offset: i32},
+}
+
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub struct CommandLog{
+    pub commands: Vec<GlCommand>,
+}
+
+impl CommandLog{
+    pub fn len(&self)->usize{
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.commands.is_empty()
+    }
+
+    pub fn count(&self, predicate: impl Fn(&GlCommand)->bool)->usize{
+        self.commands.iter().filter(|command| predicate(command)).count()
+    }
+
+    // 1行に1コマンドのJSON。差分が行単位で読めるように、ゴールデンファイルはこの形式で保存する
+    pub fn to_json_lines(&self)->String{
+        self.commands.iter()
+            .map(|command| serde_json::to_string(command).unwrap_or_default() + "\n")
+            .collect()
+    }
+
+    pub fn from_json_lines(text: &str)->Result<Self,serde_json::Error>{
+        let commands = text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<GlCommand>,_>>()?;
+        Ok(CommandLog{commands})
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct RecordingDevice<G: GraphicsDevice>{
+    inner: G,
+    commands: Rc<RefCell<Vec<GlCommand>>>,
+    next_id: Rc<Cell<u32>>,
+}
+
+impl<G: GraphicsDevice> RecordingDevice<G>{
+    pub fn new(inner: G)->Self{
+        RecordingDevice{
+            inner,
+            commands: Rc::new(RefCell::new(Vec::new())),
+            next_id: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn inner(&self)->&G{
+        &self.inner
+    }
+
+    pub fn log(&self)->CommandLog{
+        CommandLog{commands: self.commands.borrow().clone()}
+    }
+
+    // ここまでの記録を取り出して空にする。セットアップと描画を分けて比べるときに使う
+    pub fn take_log(&self)->CommandLog{
+        CommandLog{commands: std::mem::take(&mut *self.commands.borrow_mut())}
+    }
+
+    fn record(&self, command: GlCommand){
+        self.commands.borrow_mut().push(command);
+    }
+
+    fn wrap<T>(&self, inner: Option<T>)->Option<Recorded<T>>{
+        let inner = inner?;
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        Some(Recorded{id, inner})
+    }
+}
+
+fn id_of<T>(handle: Option<&Recorded<T>>)->Option<u32>{
+    handle.map(|handle| handle.id)
+}
+
+fn inner_of<T>(handle: Option<&Recorded<T>>)->Option<&T>{
+    handle.map(|handle| &handle.inner)
+}
+
+impl<G: GraphicsDevice> GraphicsDevice for RecordingDevice<G>{
+    type Buffer = Recorded<G::Buffer>;
+    type VertexArray = Recorded<G::VertexArray>;
+    type Shader = Recorded<G::Shader>;
+    type Program = Recorded<G::Program>;
+    type UniformLocation = Recorded<G::UniformLocation>;
+    type Framebuffer = Recorded<G::Framebuffer>;
+    type Texture = Recorded<G::Texture>;
+
+    fn create_buffer(&self)->Option<Self::Buffer>{
+        let buffer = self.wrap(self.inner.create_buffer());
+        self.record(GlCommand::CreateBuffer{buffer: id_of(buffer.as_ref())});
+        buffer
+    }
+
+    fn bind_buffer(&self, target: u32, buffer: Option<&Self::Buffer>){
+        self.record(GlCommand::BindBuffer{target, buffer: id_of(buffer)});
+        self.inner.bind_buffer(target, inner_of(buffer));
+    }
+
+    fn buffer_data_f32(&self, target: u32, data: &[f32], usage: u32){
+        self.record(GlCommand::BufferDataF32{target, data: data.to_vec(), usage});
+        self.inner.buffer_data_f32(target, data, usage);
+    }
+
+    fn buffer_data_u16(&self, target: u32, data: &[u16], usage: u32){
+        self.record(GlCommand::BufferDataU16{target, data: data.to_vec(), usage});
+        self.inner.buffer_data_u16(target, data, usage);
+    }
+
+    fn buffer_data_size(&self, target: u32, size: i32, usage: u32){
+        self.record(GlCommand::BufferDataSize{target, size, usage});
+        self.inner.buffer_data_size(target, size, usage);
+    }
+
+    fn buffer_sub_data_u8(&self, target: u32, offset: i32, data: &[u8]){
+        self.record(GlCommand::BufferSubDataU8{target, offset, data: data.to_vec()});
+        self.inner.buffer_sub_data_u8(target, offset, data);
+    }
+
+    fn bind_buffer_base(&self, target: u32, index: u32, buffer: Option<&Self::Buffer>){
+        self.record(GlCommand::BindBufferBase{target, index, buffer: id_of(buffer)});
+        self.inner.bind_buffer_base(target, index, inner_of(buffer));
+    }
+
+    fn delete_buffer(&self, buffer: Option<&Self::Buffer>){
+        self.record(GlCommand::DeleteBuffer{buffer: id_of(buffer)});
+        self.inner.delete_buffer(inner_of(buffer));
+    }
+
+    fn create_vertex_array(&self)->Option<Self::VertexArray>{
+        let vertex_array = self.wrap(self.inner.create_vertex_array());
+        self.record(GlCommand::CreateVertexArray{vertex_array: id_of(vertex_array.as_ref())});
+        vertex_array
+    }
+
+    fn bind_vertex_array(&self, vertex_array: Option<&Self::VertexArray>){
+        self.record(GlCommand::BindVertexArray{vertex_array: id_of(vertex_array)});
+        self.inner.bind_vertex_array(inner_of(vertex_array));
+    }
+
+    fn delete_vertex_array(&self, vertex_array: Option<&Self::VertexArray>){
+        self.record(GlCommand::DeleteVertexArray{vertex_array: id_of(vertex_array)});
+        self.inner.delete_vertex_array(inner_of(vertex_array));
+    }
+
+    fn enable_vertex_attrib_array(&self, index: u32){
+        self.record(GlCommand::EnableVertexAttribArray{index});
+        self.inner.enable_vertex_attrib_array(index);
+    }
+
+    fn vertex_attrib_pointer(&self, index: u32, size: i32, data_type: u32, normalized: bool, stride: i32, offset: i32){
+        self.record(GlCommand::VertexAttribPointer{index, size, data_type, normalized, stride, offset});
+        self.inner.vertex_attrib_pointer(index, size, data_type, normalized, stride, offset);
+    }
+
+    fn create_shader(&self, shader_type: u32)->Option<Self::Shader>{
+        let shader = self.wrap(self.inner.create_shader(shader_type));
+        self.record(GlCommand::CreateShader{shader_type, shader: id_of(shader.as_ref())});
+        shader
+    }
+
+    fn shader_source(&self, shader: &Self::Shader, source: &str){
+        self.record(GlCommand::ShaderSource{shader: shader.id, source: source.to_string()});
+        self.inner.shader_source(&shader.inner, source);
+    }
+
+    fn compile_shader(&self, shader: &Self::Shader){
+        self.record(GlCommand::CompileShader{shader: shader.id});
+        self.inner.compile_shader(&shader.inner);
+    }
+
+    fn get_shader_parameter(&self, shader: &Self::Shader, pname: u32)->i32{
+        let result = self.inner.get_shader_parameter(&shader.inner, pname);
+        self.record(GlCommand::GetShaderParameter{shader: shader.id, pname, result});
+        result
+    }
+
+    fn get_shader_info_log(&self, shader: &Self::Shader)->Option<String>{
+        let result = self.inner.get_shader_info_log(&shader.inner);
+        self.record(GlCommand::GetShaderInfoLog{shader: shader.id, result: result.clone()});
+        result
+    }
+
+    fn delete_shader(&self, shader: Option<&Self::Shader>){
+        self.record(GlCommand::DeleteShader{shader: id_of(shader)});
+        self.inner.delete_shader(inner_of(shader));
+    }
+
+    fn create_program(&self)->Option<Self::Program>{
+        let program = self.wrap(self.inner.create_program());
+        self.record(GlCommand::CreateProgram{program: id_of(program.as_ref())});
+        program
+    }
+
+    fn attach_shader(&self, program: &Self::Program, shader: &Self::Shader){
+        self.record(GlCommand::AttachShader{program: program.id, shader: shader.id});
+        self.inner.attach_shader(&program.inner, &shader.inner);
+    }
+
+    fn detach_shader(&self, program: &Self::Program, shader: &Self::Shader){
+        self.record(GlCommand::DetachShader{program: program.id, shader: shader.id});
+        self.inner.detach_shader(&program.inner, &shader.inner);
+    }
+
+    fn bind_attrib_location(&self, program: &Self::Program, index: u32, name: &str){
+        self.record(GlCommand::BindAttribLocation{program: program.id, index, name: name.to_string()});
+        self.inner.bind_attrib_location(&program.inner, index, name);
+    }
+
+    fn link_program(&self, program: &Self::Program){
+        self.record(GlCommand::LinkProgram{program: program.id});
+        self.inner.link_program(&program.inner);
+    }
+
+    fn get_program_parameter(&self, program: &Self::Program, pname: u32)->i32{
+        let result = self.inner.get_program_parameter(&program.inner, pname);
+        self.record(GlCommand::GetProgramParameter{program: program.id, pname, result});
+        result
+    }
+
+    fn get_program_info_log(&self, program: &Self::Program)->Option<String>{
+        let result = self.inner.get_program_info_log(&program.inner);
+        self.record(GlCommand::GetProgramInfoLog{program: program.id, result: result.clone()});
+        result
+    }
+
+    fn use_program(&self, program: Option<&Self::Program>){
+        self.record(GlCommand::UseProgram{program: id_of(program)});
+        self.inner.use_program(inner_of(program));
+    }
+
+    fn delete_program(&self, program: Option<&Self::Program>){
+        self.record(GlCommand::DeleteProgram{program: id_of(program)});
+        self.inner.delete_program(inner_of(program));
+    }
+
+    fn get_attrib_location(&self, program: &Self::Program, name: &str)->i32{
+        let result = self.inner.get_attrib_location(&program.inner, name);
+        self.record(GlCommand::GetAttribLocation{program: program.id, name: name.to_string(), result});
+        result
+    }
+
+    // 記録には名前だけを残す
+    fn get_active_uniform(&self, program: &Self::Program, index: u32)->Option<ActiveInfo>{
+        let result = self.inner.get_active_uniform(&program.inner, index);
+        self.record(GlCommand::GetActiveUniform{program: program.id, index, result: result.as_ref().map(|info| info.name.clone())});
+        result
+    }
+
+    fn get_uniform_location(&self, program: &Self::Program, name: &str)->Option<Self::UniformLocation>{
+        let location = self.wrap(self.inner.get_uniform_location(&program.inner, name));
+        self.record(GlCommand::GetUniformLocation{program: program.id, name: name.to_string(), location: id_of(location.as_ref())});
+        location
+    }
+
+    fn get_uniform_block_index(&self, program: &Self::Program, name: &str)->u32{
+        let result = self.inner.get_uniform_block_index(&program.inner, name);
+        self.record(GlCommand::GetUniformBlockIndex{program: program.id, name: name.to_string(), result});
+        result
+    }
+
+    fn uniform_block_binding(&self, program: &Self::Program, index: u32, binding: u32){
+        self.record(GlCommand::UniformBlockBinding{program: program.id, index, binding});
+        self.inner.uniform_block_binding(&program.inner, index, binding);
+    }
+
+    fn uniform1f(&self, location: Option<&Self::UniformLocation>, value: f32){
+        self.record(GlCommand::Uniform1f{location: id_of(location), value});
+        self.inner.uniform1f(inner_of(location), value);
+    }
+
+    fn uniform1i(&self, location: Option<&Self::UniformLocation>, value: i32){
+        self.record(GlCommand::Uniform1i{location: id_of(location), value});
+        self.inner.uniform1i(inner_of(location), value);
+    }
+
+    fn uniform2fv(&self, location: Option<&Self::UniformLocation>, value: &[f32]){
+        self.record(GlCommand::Uniform2fv{location: id_of(location), value: value.to_vec()});
+        self.inner.uniform2fv(inner_of(location), value);
+    }
+
+    fn uniform3fv(&self, location: Option<&Self::UniformLocation>, value: &[f32]){
+        self.record(GlCommand::Uniform3fv{location: id_of(location), value: value.to_vec()});
+        self.inner.uniform3fv(inner_of(location), value);
+    }
+
+    fn uniform4fv(&self, location: Option<&Self::UniformLocation>, value: &[f32]){
+        self.record(GlCommand::Uniform4fv{location: id_of(location), value: value.to_vec()});
+        self.inner.uniform4fv(inner_of(location), value);
+    }
+
+    fn uniform_matrix3fv(&self, location: Option<&Self::UniformLocation>, transpose: bool, value: &[f32]){
+        self.record(GlCommand::UniformMatrix3fv{location: id_of(location), transpose, value: value.to_vec()});
+        self.inner.uniform_matrix3fv(inner_of(location), transpose, value);
+    }
+
+    fn uniform_matrix4fv(&self, location: Option<&Self::UniformLocation>, transpose: bool, value: &[f32]){
+        self.record(GlCommand::UniformMatrix4fv{location: id_of(location), transpose, value: value.to_vec()});
+        self.inner.uniform_matrix4fv(inner_of(location), transpose, value);
+    }
+
+    fn create_framebuffer(&self)->Option<Self::Framebuffer>{
+        let framebuffer = self.wrap(self.inner.create_framebuffer());
+        self.record(GlCommand::CreateFramebuffer{framebuffer: id_of(framebuffer.as_ref())});
+        framebuffer
+    }
+
+    fn bind_framebuffer(&self, target: u32, framebuffer: Option<&Self::Framebuffer>){
+        self.record(GlCommand::BindFramebuffer{target, framebuffer: id_of(framebuffer)});
+        self.inner.bind_framebuffer(target, inner_of(framebuffer));
+    }
+
+    fn check_framebuffer_status(&self, target: u32)->u32{
+        let result = self.inner.check_framebuffer_status(target);
+        self.record(GlCommand::CheckFramebufferStatus{target, result});
+        result
+    }
+
+    fn framebuffer_texture_layer(&self, target: u32, attachment: u32, texture: Option<&Self::Texture>, level: i32, layer: i32){
+        self.record(GlCommand::FramebufferTextureLayer{target, attachment, texture: id_of(texture), level, layer});
+        self.inner.framebuffer_texture_layer(target, attachment, inner_of(texture), level, layer);
+    }
+
+    fn blit_framebuffer(&self, source: [i32; 4], destination: [i32; 4], mask: u32, filter: u32){
+        self.record(GlCommand::BlitFramebuffer{source, destination, mask, filter});
+        self.inner.blit_framebuffer(source, destination, mask, filter);
+    }
+
+    fn delete_framebuffer(&self, framebuffer: Option<&Self::Framebuffer>){
+        self.record(GlCommand::DeleteFramebuffer{framebuffer: id_of(framebuffer)});
+        self.inner.delete_framebuffer(inner_of(framebuffer));
+    }
+
+    fn create_texture(&self)->Option<Self::Texture>{
+        let texture = self.wrap(self.inner.create_texture());
+        self.record(GlCommand::CreateTexture{texture: id_of(texture.as_ref())});
+        texture
+    }
+
+    fn bind_texture(&self, target: u32, texture: Option<&Self::Texture>){
+        self.record(GlCommand::BindTexture{target, texture: id_of(texture)});
+        self.inner.bind_texture(target, inner_of(texture));
+    }
+
+    fn tex_storage_3d(&self, target: u32, levels: i32, internal_format: u32, width: i32, height: i32, depth: i32){
+        self.record(GlCommand::TexStorage3d{target, levels, internal_format, width, height, depth});
+        self.inner.tex_storage_3d(target, levels, internal_format, width, height, depth);
+    }
+
+    fn delete_texture(&self, texture: Option<&Self::Texture>){
+        self.record(GlCommand::DeleteTexture{texture: id_of(texture)});
+        self.inner.delete_texture(inner_of(texture));
+    }
+
+    fn has_multiview(&self)->bool{
+        let result = self.inner.has_multiview();
+        self.record(GlCommand::HasMultiview{result});
+        result
+    }
+
+    fn framebuffer_texture_multiview_ovr(&self, target: u32, attachment: u32, texture: Option<&Self::Texture>, level: i32, base_view_index: i32, num_views: i32){
+        self.record(GlCommand::FramebufferTextureMultiviewOvr{target, attachment, texture: id_of(texture), level, base_view_index, num_views});
+        self.inner.framebuffer_texture_multiview_ovr(target, attachment, inner_of(texture), level, base_view_index, num_views);
+    }
+
+    fn enable(&self, capability: u32){
+        self.record(GlCommand::Enable{capability});
+        self.inner.enable(capability);
+    }
+
+    fn viewport(&self, x: i32, y: i32, width: i32, height: i32){
+        self.record(GlCommand::Viewport{x, y, width, height});
+        self.inner.viewport(x, y, width, height);
+    }
+
+    fn clear_color(&self, red: f32, green: f32, blue: f32, alpha: f32){
+        self.record(GlCommand::ClearColor{red, green, blue, alpha});
+        self.inner.clear_color(red, green, blue, alpha);
+    }
+
+    fn clear_depth(&self, depth: f32){
+        self.record(GlCommand::ClearDepth{depth});
+        self.inner.clear_depth(depth);
+    }
+
+    fn clear(&self, mask: u32){
+        self.record(GlCommand::Clear{mask});
+        self.inner.clear(mask);
+    }
+
+    fn draw_elements(&self, mode: u32, count: i32, index_type: u32, offset: i32){
+        self.record(GlCommand::DrawElements{mode, count, index_type, offset});
+        self.inner.draw_elements(mode, count, index_type, offset);
+    }
+}
+
+// ゴールデンファイルとの比較
+// 環境変数UPDATE_GOLDENを設定して実行すると、比べずに今の記録でファイルを書き換える
+#[cfg(not(target_arch = "wasm32"))]
+pub const UPDATE_GOLDEN_ENV: &str = "UPDATE_GOLDEN";
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug,Clone,PartialEq)]
+pub enum GoldenError{
+    Io{path: String, message: String},
+    Parse{path: String, message: String},
+    // 最初に食い違ったコマンドの位置。どちらかが短ければNone
+    // GlCommandは大きいので、エラーを小さく保つためにBoxに入れる
+    Mismatch{path: String, index: usize, expected: Option<Box<GlCommand>>, actual: Option<Box<GlCommand>>},
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl std::fmt::Display for GoldenError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            GoldenError::Io{path, message}=>write!(f, "{}: {}", path, message),
+            GoldenError::Parse{path, message}=>write!(f, "{}: invalid command log: {}", path, message),
+            GoldenError::Mismatch{path, index, expected, actual}=>write!(f,
+                "{}: command {} differs\n  expected: {:?}\n    actual: {:?}\n(set {}=1 to update the golden file)",
+                path, index, expected, actual, UPDATE_GOLDEN_ENV,
+            ),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl std::error::Error for GoldenError{}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn check_golden(path: impl AsRef<std::path::Path>, log: &CommandLog)->Result<(),GoldenError>{
+    let path = path.as_ref();
+    let display = path.display().to_string();
+    if std::env::var_os(UPDATE_GOLDEN_ENV).is_some(){
+        if let Some(parent) = path.parent(){
+            std::fs::create_dir_all(parent).map_err(|error| GoldenError::Io{path: display.clone(), message: error.to_string()})?;
+        }
+        return std::fs::write(path, log.to_json_lines()).map_err(|error| GoldenError::Io{path: display, message: error.to_string()});
+    }
+
+    let text = std::fs::read_to_string(path).map_err(|error| GoldenError::Io{path: display.clone(), message: error.to_string()})?;
+    let expected = CommandLog::from_json_lines(&text).map_err(|error| GoldenError::Parse{path: display.clone(), message: error.to_string()})?;
+    let length = expected.len().max(log.len());
+    for index in 0..length{
+        let expected = expected.commands.get(index);
+        let actual = log.commands.get(index);
+        if expected != actual{
+            return Err(GoldenError::Mismatch{path: display, index, expected: expected.cloned().map(Box::new), actual: actual.cloned().map(Box::new)});
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use web_sys::WebGl2RenderingContext as Gl;
+    use crate::platform::mock::MockDevice;
+    use crate::render_frame;
+    use crate::test_support::{demo_scene,stereo_views};
+
+    // シェーダのコンパイルとメッシュの作成から、1フレームの描画までをまとめて比べる
+    fn demo_frame_log(inner: MockDevice)->CommandLog{
+        let gl = RecordingDevice::new(inner);
+        let (mut renderer, scene) = demo_scene(&gl);
+        render_frame(&stereo_views(64, 64), None, &mut renderer, &scene, &[]);
+        gl.log()
+    }
+
+    #[test]
+    fn demo_frame_matches_golden(){
+        let log = demo_frame_log(MockDevice::new());
+        // リンクの成否を確かめ、uniformの位置はリンク直後に一度だけ調べる
+        assert_eq!(log.count(|command| matches!(command, GlCommand::GetProgramParameter{pname: Gl::LINK_STATUS, ..})), 1);
+        let lookups = log.count(|command| matches!(command, GlCommand::GetUniformLocation{..}));
+        let active = log.count(|command| matches!(command, GlCommand::GetActiveUniform{result: Some(_), ..}));
+        assert_eq!(lookups, active);
+        assert_eq!(log.count(|command| matches!(command, GlCommand::DrawElements{..})), 6);
+        check_golden("tests/golden/demo_frame.jsonl", &log).unwrap();
+    }
+
+    #[test]
+    fn multiview_demo_frame_matches_golden(){
+        let inner = MockDevice::new();
+        inner.set_multiview(true);
+        let log = demo_frame_log(inner);
+        // ビューはシェーダが選ぶので、view_indexは設定せずに1回で描く
+        assert_eq!(log.count(|command| matches!(command, GlCommand::Uniform1i{..})), 0);
+        assert_eq!(log.count(|command| matches!(command, GlCommand::DrawElements{..})), 3);
+        assert_eq!(log.count(|command| matches!(command, GlCommand::BlitFramebuffer{..})), 2);
+        check_golden("tests/golden/demo_frame_multiview.jsonl", &log).unwrap();
+    }
+
+    #[test]
+    fn recording_is_independent_of_inner_handles(){
+        let first = RecordingDevice::new(MockDevice::new());
+        let second = RecordingDevice::new(MockDevice::new());
+        // 中のデバイスが別のオブジェクトを先に作っていても、記録の番号は変わらない
+        second.inner().create_buffer();
+        for gl in [&first, &second]{
+            let buffer = gl.create_buffer();
+            gl.bind_buffer(Gl::ARRAY_BUFFER, buffer.as_ref());
+        }
+        assert_eq!(first.log(), second.log());
+        assert_eq!(first.log().commands[0], GlCommand::CreateBuffer{buffer: Some(1)});
+        assert_eq!(CommandLog::from_json_lines(&first.log().to_json_lines()).unwrap(), first.log());
+    }
+
+    #[test]
+    fn check_golden_reports_first_difference(){
+        // 書き換えモードでは比べないので確かめられない
+        if std::env::var_os(UPDATE_GOLDEN_ENV).is_some(){
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("wasm_xr_golden_{}.jsonl", std::process::id()));
+        let expected = CommandLog{commands: vec![GlCommand::Enable{capability: Gl::DEPTH_TEST}, GlCommand::Clear{mask: Gl::COLOR_BUFFER_BIT}]};
+        std::fs::write(&path, expected.to_json_lines()).unwrap();
+        assert_eq!(check_golden(&path, &expected), Ok(()));
+
+        let actual = CommandLog{commands: vec![GlCommand::Enable{capability: Gl::DEPTH_TEST}]};
+        match check_golden(&path, &actual){
+            Err(GoldenError::Mismatch{index, expected, actual, ..})=>{
+                assert_eq!(index, 1);
+                assert_eq!(expected.as_deref(), Some(&GlCommand::Clear{mask: Gl::COLOR_BUFFER_BIT}));
+                assert_eq!(actual, None);
+            },
+            other=>panic!("unexpected result {:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(check_golden(&path, &actual), Err(GoldenError::Io{..})));
+    }
+}
@@ -0,0 +1,652 @@
+use gl_matrix::common::Mat4;
+use gl_matrix::mat4;
+use super::{log,ViewData,Viewport,XrFrameInput,XrFrameSource};
+use crate::gamepad::GamepadSnapshot;
+use crate::input::{Handedness,InputEvent,InputEventKind,InputSourceState,Pose,TargetRayMode};
+use crate::lifecycle::Visibility;
+use crate::session::SessionMode;
+
+// ヘッドセット無しでアニメーションループを動かすための、台本どおりに動くXRランタイム
+// 時刻は実時間と関係なく、advanceを呼ぶたびにリフレッシュレートの1周期ずつ進むので、何度動かしても同じフレームが届く
+// 台本の姿勢は床を原点にした参照空間(local-floor)で書き、set_origin_offsetで移動した分はランタイムが反映する
+
+// 片目の視野。視線から上下左右それぞれの端までの角度(ラジアン)
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct FieldOfView{
+    pub left: f32,
+    pub right: f32,
+    pub up: f32,
+    pub down: f32,
+}
+
+impl FieldOfView{
+    // 上下左右に対称な視野。角度は端から端まで
+    pub fn symmetric(horizontal: f32, vertical: f32)->Self{
+        FieldOfView{left: horizontal * 0.5, right: horizontal * 0.5, up: vertical * 0.5, down: vertical * 0.5}
+    }
+
+    // 左右を入れ替える。左目の視野から右目の視野を作る
+    pub fn mirrored(&self)->Self{
+        FieldOfView{left: self.right, right: self.left, ..*self}
+    }
+
+    pub fn projection(&self, near: f32, far: f32)->Mat4{
+        let mut projection = mat4::create();
+        mat4::frustum(&mut projection, -self.left.tan() * near, self.right.tan() * near, -self.down.tan() * near, self.up.tan() * near, near, far);
+        projection
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct SimulatedDevice{
+    // 瞳孔間距離(メートル)
+    pub ipd: f32,
+    // 左目の視野。右目は左右を入れ替えて使う
+    pub fov: FieldOfView,
+    // 片目の描画サイズ(ピクセル)。両目はフレームバッファに左右に並べる
+    pub eye_width: i32,
+    pub eye_height: i32,
+    // リフレッシュレート(Hz)
+    pub refresh_rate: f64,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for SimulatedDevice{
+    fn default()->Self{
+        SimulatedDevice{
+            ipd: 0.063,
+            fov: FieldOfView::symmetric(90.0_f32.to_radians(), 90.0_f32.to_radians()),
+            eye_width: 1024,
+            eye_height: 1024,
+            refresh_rate: 72.0,
+            near: 0.1,
+            far: 1000.0,
+        }
+    }
+}
+
+impl SimulatedDevice{
+    // 1リフレッシュの長さ(ミリ秒)
+    pub fn frame_interval(&self)->f64{
+        1000.0 / self.refresh_rate
+    }
+}
+
+// 補間できる値
+pub trait Keyframe: Clone{
+    // tは0.0..=1.0
+    fn interpolate(&self, next: &Self, t: f32)->Self;
+}
+
+impl Keyframe for Pose{
+    fn interpolate(&self, next: &Self, t: f32)->Self{
+        self.lerp(next, t)
+    }
+}
+
+// ボタンとスティックは次のキーフレームまで同じ値のまま
+impl Keyframe for GamepadSnapshot{
+    fn interpolate(&self, _next: &Self, _t: f32)->Self{
+        self.clone()
+    }
+}
+
+// 時刻(ミリ秒)ごとのキーフレーム。間は補間し、最初より前と最後より後は端の値のまま
+#[derive(Debug,Clone,PartialEq)]
+pub struct Timeline<T>{
+    keys: Vec<(f64, T)>,
+}
+
+impl<T> Default for Timeline<T>{
+    fn default()->Self{
+        Timeline{keys: Vec::new()}
+    }
+}
+
+impl<T: Keyframe> Timeline<T>{
+    pub fn new()->Self{
+        Timeline::default()
+    }
+
+    // ずっと同じ値
+    pub fn constant(value: T)->Self{
+        Timeline::new().with_key(0.0, value)
+    }
+
+    // 同じ時刻のキーがあれば置き換える
+    pub fn with_key(mut self, time: f64, value: T)->Self{
+        match self.keys.binary_search_by(|(key_time, _)| key_time.total_cmp(&time)){
+            Ok(index)=>self.keys[index].1 = value,
+            Err(index)=>self.keys.insert(index, (time, value)),
+        }
+        self
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.keys.is_empty()
+    }
+
+    pub fn sample(&self, time: f64)->Option<T>{
+        let next = self.keys.partition_point(|(key_time, _)| *key_time <= time);
+        match (next.checked_sub(1).and_then(|index| self.keys.get(index)), self.keys.get(next)){
+            (Some((start_time, start)), Some((end_time, end)))=>{
+                let t = ((time - start_time) / (end_time - start_time)) as f32;
+                Some(start.interpolate(end, t))
+            },
+            (Some((_, value)), None) | (None, Some((_, value)))=>Some(value.clone()),
+            (None, None)=>None,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct SimulatedInputSource{
+    pub handedness: Handedness,
+    pub target_ray_mode: TargetRayMode,
+    pub profiles: Vec<String>,
+    // Noneのときはgrip_poseが届かない(視線や画面タップ)
+    pub grip: Option<Timeline<Pose>>,
+    pub target_ray: Timeline<Pose>,
+    pub gamepad: Option<Timeline<GamepadSnapshot>>,
+    // 接続されている間(ミリ秒)。終わりがNoneなら最後まで
+    pub connected: (f64, Option<f64>),
+}
+
+impl SimulatedInputSource{
+    // xr-standardのコントローラー。グリップとターゲットレイは同じ姿勢にする
+    pub fn controller(handedness: Handedness, poses: Timeline<Pose>)->Self{
+        SimulatedInputSource{
+            handedness,
+            target_ray_mode: TargetRayMode::TrackedPointer,
+            profiles: vec!["generic-trigger-squeeze-thumbstick".to_string()],
+            grip: Some(poses.clone()),
+            target_ray: poses,
+            gamepad: Some(Timeline::constant(GamepadSnapshot::xr_standard())),
+            connected: (0.0, None),
+        }
+    }
+
+    pub fn with_gamepad(mut self, gamepad: Timeline<GamepadSnapshot>)->Self{
+        self.gamepad = Some(gamepad);
+        self
+    }
+
+    pub fn connected_between(mut self, start: f64, end: Option<f64>)->Self{
+        self.connected = (start, end);
+        self
+    }
+
+    pub fn is_connected(&self, time: f64)->bool{
+        let (start, end) = self.connected;
+        time >= start && end.is_none_or(|end| time < end)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ScriptEvent{
+    // handednessの入力ソースのselect/squeeze。visible以外のときは届かない
+    Input{handedness: Handedness, kind: InputEventKind},
+    Visibility(Visibility),
+    // 描画が間に合わず、この後のリフレッシュをframes回分フレームを届けずに飛ばす
+    DropFrames(u32),
+    // ユーザーがシステムのメニューから抜けたなど、ランタイムの側からセッションを終える
+    End,
+}
+
+// 頭と入力ソースの動き、決まった時刻に起こすイベント
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct SessionScript{
+    pub viewer: Timeline<Pose>,
+    pub sources: Vec<SimulatedInputSource>,
+    // 時刻順。同じ時刻のイベントは追加した順
+    events: Vec<(f64, ScriptEvent)>,
+}
+
+impl SessionScript{
+    pub fn new(viewer: Timeline<Pose>)->Self{
+        SessionScript{viewer, ..SessionScript::default()}
+    }
+
+    pub fn with_source(mut self, source: SimulatedInputSource)->Self{
+        self.sources.push(source);
+        self
+    }
+
+    pub fn with_event(mut self, time: f64, event: ScriptEvent)->Self{
+        let index = self.events.partition_point(|(event_time, _)| *event_time <= time);
+        self.events.insert(index, (time, event));
+        self
+    }
+
+    pub fn events(&self)->&[(f64, ScriptEvent)]{
+        &self.events
+    }
+}
+
+// アニメーションフレームのコールバックに渡るもの
+#[derive(Debug,Clone)]
+pub struct SimulatedFrame{
+    // ミリ秒。最初のリフレッシュが0
+    pub time: f64,
+    // 届けたフレームの通し番号。最初のフレームが1
+    pub index: u64,
+    // 台本の頭の姿勢が無いときはNone。viewsも空になる
+    pub viewer_pose: Option<Pose>,
+    pub views: Vec<ViewData>,
+    pub input_sources: Vec<InputSourceState>,
+    // 前のフレームの後に起きたselect/squeeze
+    pub events: Vec<InputEvent>,
+}
+
+impl XrFrameSource for SimulatedFrame{
+    fn views(&self)->Option<Vec<ViewData>>{
+        self.viewer_pose.map(|_| self.views.clone())
+    }
+}
+
+// FrameLoop::stepに渡す、届いたフレームとそれを出したセッションの組
+pub struct SimulatedFrameInput<'a>{
+    pub session: &'a mut SimulatedSession,
+    pub frame: &'a mut SimulatedFrame,
+}
+
+impl XrFrameInput for SimulatedFrameInput<'_>{
+    fn update_input(&mut self){
+        self.session.update_poses(self.frame);
+    }
+
+    fn input_sources(&self)->&[InputSourceState]{
+        &self.frame.input_sources
+    }
+
+    fn viewer_pose(&self)->Option<Pose>{
+        self.frame.viewer_pose
+    }
+
+    fn set_origin_offset(&mut self, position: [f32; 3], orientation: [f32; 4])->bool{
+        self.session.set_origin_offset(position, orientation);
+        true
+    }
+
+    fn take_events(&mut self)->Vec<InputEvent>{
+        std::mem::take(&mut self.frame.events)
+    }
+}
+
+#[derive(Debug,Clone)]
+pub enum SimulatedStep{
+    Frame(SimulatedFrame),
+    // このリフレッシュではフレームが届かなかった。hiddenの間、フレームを要求していないとき、フレームを落としたとき
+    Skipped,
+    Ended,
+}
+
+// SessionLifecycleと同じ流れで、フレームの要求・visibilityの変化・終了を扱う
+pub struct SimulatedSession{
+    pub device: SimulatedDevice,
+    pub mode: SessionMode,
+    script: SessionScript,
+    // 次のリフレッシュの番号。時刻は番号×1周期
+    refresh: u64,
+    frame_index: u64,
+    next_event: usize,
+    dropping: u32,
+    // 参照空間のオフセットの逆行列。台本の姿勢にかけるとオフセットした空間での姿勢になる
+    offset_inverse: Mat4,
+    visibility: Visibility,
+    ended: bool,
+    frame_requested: bool,
+    pending_events: Vec<InputEvent>,
+}
+
+impl SimulatedSession{
+    pub fn new(device: SimulatedDevice, mode: SessionMode, script: SessionScript)->Self{
+        SimulatedSession{
+            device,
+            mode,
+            script,
+            refresh: 0,
+            frame_index: 0,
+            next_event: 0,
+            dropping: 0,
+            offset_inverse: mat4::create(),
+            visibility: Visibility::Visible,
+            ended: false,
+            frame_requested: false,
+            pending_events: Vec::new(),
+        }
+    }
+
+    pub fn visibility(&self)->Visibility{
+        self.visibility
+    }
+
+    pub fn is_ended(&self)->bool{
+        self.ended
+    }
+
+    // 次のリフレッシュの時刻(ミリ秒)
+    pub fn time(&self)->f64{
+        self.refresh as f64 * self.device.frame_interval()
+    }
+
+    // 終了したセッションには次のフレームを要求しない
+    pub fn request_animation_frame(&mut self){
+        if !self.ended{
+            self.frame_requested = true;
+        }
+    }
+
+    pub fn cancel_animation_frame(&mut self){
+        self.frame_requested = false;
+    }
+
+    // アプリ側からセッションを終える
+    pub fn end(&mut self){
+        if !self.ended{
+            log("Simulated session ended");
+        }
+        self.ended = true;
+        self.frame_requested = false;
+    }
+
+    // ReferenceSpace::set_origin_offsetと同じく、床の原点から見たワールドの原点を設定する
+    // 以降の姿勢はオフセットした空間で届く
+    pub fn set_origin_offset(&mut self, position: [f32; 3], orientation: [f32; 4]){
+        let offset = Pose{position, orientation}.matrix();
+        if mat4::invert(&mut self.offset_inverse, &offset).is_none(){
+            log("[Error] Could not offset simulated reference space");
+        }
+    }
+
+    // 1リフレッシュ進める。台本のイベントを起こしてから、要求されていればフレームを届ける
+    pub fn advance(&mut self)->SimulatedStep{
+        if self.ended{
+            return SimulatedStep::Ended;
+        }
+        let time = self.time();
+        self.refresh += 1;
+        while let Some((event_time, event)) = self.script.events.get(self.next_event).copied(){
+            if event_time > time{
+                break;
+            }
+            self.next_event += 1;
+            self.apply(event_time, event);
+        }
+        if self.ended{
+            return SimulatedStep::Ended;
+        }
+        if self.dropping > 0{
+            self.dropping -= 1;
+            return SimulatedStep::Skipped;
+        }
+        // hiddenの間はフレームが届かない。要求は残るので、visibleに戻ると再開する
+        if self.visibility == Visibility::Hidden || !self.frame_requested{
+            return SimulatedStep::Skipped;
+        }
+        self.frame_requested = false;
+        self.frame_index += 1;
+        let mut frame = self.frame(time, self.frame_index);
+        frame.events = std::mem::take(&mut self.pending_events);
+        SimulatedStep::Frame(frame)
+    }
+
+    fn apply(&mut self, time: f64, event: ScriptEvent){
+        match event{
+            ScriptEvent::Input{handedness, kind}=>{
+                if self.visibility != Visibility::Visible{
+                    return;
+                }
+                let Some(source) = self.script.sources.iter().find(|source| source.handedness == handedness && source.is_connected(time)) else{
+                    log(&format!("[Warning] No simulated {} input source is connected for {:?}", handedness, kind));
+                    return;
+                };
+                let target_ray_pose = source.target_ray.sample(time).map(|pose| self.to_reference_space(&pose));
+                self.pending_events.push(InputEvent{kind, handedness, target_ray_pose, source: None});
+            },
+            ScriptEvent::Visibility(visibility)=>{
+                log(&format!("Simulated session is {}", visibility));
+                self.visibility = visibility;
+                // 見えなくなったら、届いていない入力イベントも捨てる
+                if visibility != Visibility::Visible{
+                    self.pending_events.clear();
+                }
+            },
+            ScriptEvent::DropFrames(frames)=>self.dropping += frames,
+            ScriptEvent::End=>self.end(),
+        }
+    }
+
+    fn to_reference_space(&self, pose: &Pose)->Pose{
+        let mut matrix = mat4::create();
+        mat4::multiply(&mut matrix, &self.offset_inverse, &pose.matrix());
+        Pose::from_matrix(&matrix)
+    }
+
+    // 参照空間を動かした後に呼び、同じ時刻の姿勢を新しい空間で読み直す
+    pub fn update_poses(&self, frame: &mut SimulatedFrame){
+        let events = std::mem::take(&mut frame.events);
+        *frame = SimulatedFrame{events, ..self.frame(frame.time, frame.index)};
+    }
+
+    // time(ミリ秒)の時点の姿勢
+    fn frame(&self, time: f64, index: u64)->SimulatedFrame{
+        let viewer_pose = self.script.viewer.sample(time).map(|pose| self.to_reference_space(&pose));
+        let views = viewer_pose.map(|pose| self.views(&pose)).unwrap_or_default();
+        let input_sources = self.script.sources.iter()
+            .filter(|source| source.is_connected(time))
+            .filter_map(|source|{
+                let target_ray_pose = source.target_ray.sample(time)?;
+                Some(InputSourceState{
+                    handedness: source.handedness,
+                    target_ray_mode: source.target_ray_mode,
+                    profiles: source.profiles.clone(),
+                    grip_pose: source.grip.as_ref().and_then(|grip| grip.sample(time)).map(|pose| self.to_reference_space(&pose)),
+                    target_ray_pose: Some(self.to_reference_space(&target_ray_pose)),
+                    gamepad: source.gamepad.as_ref().and_then(|gamepad| gamepad.sample(time)),
+                    hand: None,
+                    source: None,
+                })
+            })
+            .collect();
+        SimulatedFrame{time, index, viewer_pose, views, input_sources, events: Vec::new()}
+    }
+
+    // immersiveでは両目を頭からIPDの半分ずつ左右にずらし、inlineでは頭の位置から1つだけ描く
+    fn views(&self, head: &Pose)->Vec<ViewData>{
+        let device = &self.device;
+        let eyes: &[(f32, FieldOfView)] = if self.mode.is_immersive(){
+            &[(-device.ipd * 0.5, device.fov), (device.ipd * 0.5, device.fov.mirrored())]
+        }
+        else{
+            &[(0.0, device.fov)]
+        };
+        eyes.iter().enumerate().map(|(index, (offset, fov))|{
+            let mut eye = mat4::create();
+            mat4::translate(&mut eye, &head.matrix(), &[*offset, 0.0, 0.0]);
+            let mut view = mat4::create();
+            mat4::invert(&mut view, &eye);
+            ViewData{
+                view,
+                projection: fov.projection(device.near, device.far),
+                viewport: Viewport{x: index as i32 * device.eye_width, y: 0, width: device.eye_width, height: device.eye_height},
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn head(position: [f32; 3])->Pose{
+        Pose{position, ..Pose::default()}
+    }
+
+    // 100Hzなので1周期は10ミリ秒
+    fn device()->SimulatedDevice{
+        SimulatedDevice{eye_width: 64, eye_height: 64, refresh_rate: 100.0, ..Default::default()}
+    }
+
+    // 毎回フレームを要求して、届いたフレームの時刻を返す。Skippedは-1、Endedで止める
+    fn frame_times(session: &mut SimulatedSession, refreshes: usize)->Vec<f64>{
+        let mut times = Vec::new();
+        for _ in 0..refreshes{
+            session.request_animation_frame();
+            match session.advance(){
+                SimulatedStep::Frame(frame)=>times.push(frame.time),
+                SimulatedStep::Skipped=>times.push(-1.0),
+                SimulatedStep::Ended=>break,
+            }
+        }
+        times
+    }
+
+    #[test]
+    fn timeline_interpolates_between_keys(){
+        let timeline = Timeline::new()
+            .with_key(1000.0, head([2.0, 1.0, 0.0]))
+            .with_key(0.0, head([0.0, 1.0, 0.0]))
+            .with_key(500.0, head([1.0, 1.0, 0.0]));
+        assert_eq!(timeline.sample(250.0).unwrap().position, [0.5, 1.0, 0.0]);
+        assert_eq!(timeline.sample(750.0).unwrap().position, [1.5, 1.0, 0.0]);
+        // 範囲の外は端の値のまま
+        assert_eq!(timeline.sample(-10.0).unwrap().position, [0.0, 1.0, 0.0]);
+        assert_eq!(timeline.sample(5000.0).unwrap().position, [2.0, 1.0, 0.0]);
+        // 同じ時刻のキーは置き換える
+        let timeline = timeline.with_key(1000.0, head([4.0, 1.0, 0.0]));
+        assert_eq!(timeline.sample(1000.0).unwrap().position, [4.0, 1.0, 0.0]);
+        assert_eq!(Timeline::<Pose>::new().sample(0.0), None);
+
+        // ボタンとスティックは補間せず、次のキーまで前の値のまま
+        let pushed = GamepadSnapshot::xr_standard().with_thumbstick([1.0, 0.0]);
+        let gamepad = Timeline::constant(GamepadSnapshot::xr_standard()).with_key(100.0, pushed.clone());
+        assert_eq!(gamepad.sample(99.0), Some(GamepadSnapshot::xr_standard()));
+        assert_eq!(gamepad.sample(100.0), Some(pushed));
+    }
+
+    #[test]
+    fn frames_follow_refresh_rate_and_requests(){
+        let script = SessionScript::new(Timeline::constant(head([0.0, 1.6, 0.0]))).with_event(20.0, ScriptEvent::DropFrames(2));
+        let mut session = SimulatedSession::new(device(), SessionMode::ImmersiveVr, script);
+        // 要求していないリフレッシュではフレームが届かない
+        assert!(matches!(session.advance(), SimulatedStep::Skipped));
+        assert_eq!(session.time(), 10.0);
+        assert_eq!(frame_times(&mut session, 6), vec![10.0, -1.0, -1.0, 40.0, 50.0, 60.0]);
+
+        let mut session = SimulatedSession::new(device(), SessionMode::ImmersiveVr, SessionScript::new(Timeline::constant(head([0.0; 3]))));
+        session.request_animation_frame();
+        session.cancel_animation_frame();
+        assert!(matches!(session.advance(), SimulatedStep::Skipped));
+        session.request_animation_frame();
+        let SimulatedStep::Frame(frame) = session.advance() else{
+            panic!("frame expected");
+        };
+        assert_eq!((frame.time, frame.index), (10.0, 1));
+    }
+
+    #[test]
+    fn views_are_offset_by_half_the_ipd(){
+        let script = SessionScript::new(Timeline::constant(head([0.0, 1.6, 0.0])));
+        let mut session = SimulatedSession::new(device(), SessionMode::ImmersiveVr, script.clone());
+        session.request_animation_frame();
+        let SimulatedStep::Frame(frame) = session.advance() else{
+            panic!("frame expected");
+        };
+        let views = frame.views().unwrap();
+        assert_eq!(views.len(), 2);
+        // ビュー行列は目の姿勢の逆行列なので、平行移動は -目の位置
+        assert!((views[0].view[12] - 0.0315).abs() < 1e-6);
+        assert!((views[1].view[12] + 0.0315).abs() < 1e-6);
+        assert!((views[0].view[13] + 1.6).abs() < 1e-6);
+        assert_eq!(views[1].viewport, Viewport{x: 64, y: 0, width: 64, height: 64});
+
+        let mut session = SimulatedSession::new(device(), SessionMode::Inline, script);
+        session.request_animation_frame();
+        let SimulatedStep::Frame(frame) = session.advance() else{
+            panic!("frame expected");
+        };
+        assert_eq!(frame.views().unwrap().len(), 1);
+
+        // 頭の姿勢が無ければビューも無い
+        let mut session = SimulatedSession::new(device(), SessionMode::ImmersiveVr, SessionScript::default());
+        session.request_animation_frame();
+        let SimulatedStep::Frame(frame) = session.advance() else{
+            panic!("frame expected");
+        };
+        assert!(frame.views().is_none());
+    }
+
+    #[test]
+    fn origin_offset_moves_poses_into_offset_space(){
+        let controller = SimulatedInputSource::controller(Handedness::Right, Timeline::constant(head([0.2, 1.0, -0.3])));
+        let script = SessionScript::new(Timeline::constant(head([0.0, 1.6, 0.0]))).with_source(controller);
+        let mut session = SimulatedSession::new(device(), SessionMode::ImmersiveVr, script);
+        session.request_animation_frame();
+        let SimulatedStep::Frame(mut frame) = session.advance() else{
+            panic!("frame expected");
+        };
+        // 原点を1メートル右に置くと、姿勢は1メートル左に見える
+        session.set_origin_offset([1.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0]);
+        session.update_poses(&mut frame);
+        assert_eq!(frame.viewer_pose.unwrap().position, [-1.0, 1.6, 0.0]);
+        let source = &frame.input_sources[0];
+        assert_eq!(source.grip_pose.unwrap().position, [-0.8, 1.0, -0.3]);
+        assert_eq!(source.target_ray_pose.unwrap().position, [-0.8, 1.0, -0.3]);
+    }
+
+    #[test]
+    fn input_sources_connect_and_disconnect(){
+        let left = SimulatedInputSource::controller(Handedness::Left, Timeline::constant(head([-0.2, 1.0, -0.3]))).connected_between(15.0, Some(35.0));
+        let script = SessionScript::new(Timeline::constant(head([0.0, 1.6, 0.0]))).with_source(left);
+        let mut session = SimulatedSession::new(device(), SessionMode::ImmersiveVr, script);
+        let mut counts = Vec::new();
+        for _ in 0..5{
+            session.request_animation_frame();
+            if let SimulatedStep::Frame(frame) = session.advance(){
+                counts.push(frame.input_sources.len());
+            }
+        }
+        assert_eq!(counts, vec![0, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn visibility_controls_frames_and_input_events(){
+        let select = ScriptEvent::Input{handedness: Handedness::Right, kind: InputEventKind::Select};
+        let controller = SimulatedInputSource::controller(Handedness::Right, Timeline::constant(head([0.2, 1.0, -0.3])));
+        let script = SessionScript::new(Timeline::constant(head([0.0, 1.6, 0.0])))
+            .with_source(controller)
+            .with_event(5.0, select)
+            .with_event(10.0, ScriptEvent::Visibility(Visibility::VisibleBlurred))
+            .with_event(15.0, select)
+            .with_event(20.0, ScriptEvent::Visibility(Visibility::Hidden))
+            .with_event(40.0, ScriptEvent::Visibility(Visibility::Visible))
+            .with_event(45.0, select)
+            // 左手のコントローラーは無いので届かない
+            .with_event(45.0, ScriptEvent::Input{handedness: Handedness::Left, kind: InputEventKind::Squeeze})
+            .with_event(60.0, ScriptEvent::End);
+        let mut session = SimulatedSession::new(device(), SessionMode::ImmersiveVr, script);
+        let mut frames = Vec::new();
+        for _ in 0..10{
+            session.request_animation_frame();
+            match session.advance(){
+                SimulatedStep::Frame(frame)=>frames.push((frame.time, session.visibility(), frame.events.len())),
+                SimulatedStep::Skipped=>frames.push((-1.0, session.visibility(), 0)),
+                SimulatedStep::Ended=>break,
+            }
+        }
+        // visible-blurredの間はフレームは届くが入力は届かず、hiddenの間はフレームも届かない
+        // 5ミリ秒のselectも、届く前にvisible-blurredになったので捨てられる
+        assert_eq!(frames, vec![
+            (0.0, Visibility::Visible, 0),
+            (10.0, Visibility::VisibleBlurred, 0),
+            (-1.0, Visibility::Hidden, 0),
+            (-1.0, Visibility::Hidden, 0),
+            (40.0, Visibility::Visible, 0),
+            (50.0, Visibility::Visible, 1),
+        ]);
+        assert!(session.is_ended());
+        assert!(matches!(session.advance(), SimulatedStep::Ended));
+    }
+}
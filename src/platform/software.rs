@@ -0,0 +1,628 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use web_sys::WebGl2RenderingContext as Gl;
+use super::{ActiveInfo,GraphicsDevice};
+use super::mock::{AttribPointer,MockDevice,MockHandle,UniformValue};
+use crate::image::{Image,ImageDiff,ImageError};
+use crate::mesh::VertexAttribute;
+use crate::uniform::{self,CAMERA_BINDING};
+
+// CPUで描画するGraphicsDevice。ネイティブのテストで、シーンを画像にするために使う
+// GLの状態の管理はMockDeviceに任せ、clearとdraw_elementsだけを自前のフレームバッファに描く
+// シェーダは実行せず、shader/のシェーダと同じ変換(projection * view * model)と頂点色の補間を行う
+// 対応しているのは、インデックス付きの三角形・深度テスト(LESS)・背面カリング(反時計回りが表)だけ
+// 描画先は1枚だけで、バインドしたフレームバッファに関係なくそこに描く。マルチビューには対応しない
+
+// 頂点属性が無効のときの値。GLの既定値と同じ
+const DEFAULT_ATTRIBUTE: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+const IDENTITY: [f32; 16] = [
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 1.0, 0.0,
+    0.0, 0.0, 0.0, 1.0,
+];
+
+struct Framebuffer{
+    color: Image,
+    depth: Vec<f32>,
+}
+
+// 変換後の頂点。clipはクリップ座標
+#[derive(Debug,Clone,Copy)]
+struct ClipVertex{
+    clip: [f32; 4],
+    color: [f32; 4],
+}
+
+#[derive(Clone)]
+pub struct SoftwareDevice{
+    device: MockDevice,
+    framebuffer: Rc<RefCell<Framebuffer>>,
+}
+
+impl SoftwareDevice{
+    pub fn new(width: u32, height: u32)->Self{
+        let device = MockDevice::new();
+        // 既定のビューポートはキャンバス全体
+        device.viewport(0, 0, width as i32, height as i32);
+        SoftwareDevice{
+            device,
+            framebuffer: Rc::new(RefCell::new(Framebuffer{
+                color: Image::new(width, height, [0, 0, 0, 0]),
+                depth: vec![1.0; width as usize * height as usize],
+            })),
+        }
+    }
+
+    // 状態や描画の記録を調べるときに使う
+    pub fn mock(&self)->&MockDevice{
+        &self.device
+    }
+
+    pub fn image(&self)->Image{
+        self.framebuffer.borrow().color.clone()
+    }
+
+    fn attribute(&self, program: &MockHandle, attribute: VertexAttribute)->Option<(AttribPointer, Vec<u8>)>{
+        let vertex_array = self.device.current_vertex_array()?;
+        let state = self.device.vertex_array_state(&vertex_array)?;
+        let location = self.device.get_attrib_location(program, attribute.shader_name());
+        if location < 0 || !state.enabled.contains(&(location as u32)){
+            return None;
+        }
+        let pointer = *state.pointers.get(&(location as u32))?;
+        let data = self.device.buffer_data(&pointer.buffer)?;
+        Some((pointer, data))
+    }
+
+    fn camera_matrices(&self, view_index: usize)->([f32; 16], [f32; 16]){
+        let Some(data) = self.device.uniform_buffer_data(CAMERA_BINDING) else{
+            return (IDENTITY, IDENTITY);
+        };
+        let layout = uniform::camera_layout();
+        let read = |name: &str|{
+            let Some(field) = layout.field(name) else{
+                return IDENTITY;
+            };
+            read_mat4(&data, field.offset + field.field_type.array_stride() * view_index).unwrap_or(IDENTITY)
+        };
+        (read("views"), read("projections"))
+    }
+
+    fn rasterize(&self, count: i32, offset: i32){
+        let Some(program) = self.device.current_program() else{
+            return;
+        };
+        let Some(vertex_array) = self.device.current_vertex_array() else{
+            return;
+        };
+        let Some(indices) = self.device.vertex_array_state(&vertex_array)
+            .and_then(|state| state.element_buffer)
+            .and_then(|buffer| self.device.buffer_data(&buffer)) else{
+            return;
+        };
+        let Some((position_pointer, position_data)) = self.attribute(&program, VertexAttribute::Position) else{
+            return;
+        };
+        let color = self.attribute(&program, VertexAttribute::Color);
+
+        let model = match self.device.uniform_value(&program, "model"){
+            Some(UniformValue::Matrix(value))=>value.try_into().unwrap_or(IDENTITY),
+            _=>IDENTITY,
+        };
+        let view_index = match self.device.uniform_value(&program, "view_index"){
+            Some(UniformValue::Int(index))=>index.max(0) as usize,
+            _=>0,
+        };
+        let (view, projection) = self.camera_matrices(view_index);
+        let transform = multiply(&projection, &multiply(&view, &model));
+
+        let viewport = self.device.current_viewport();
+        let depth_test = self.device.is_enabled(Gl::DEPTH_TEST);
+        let cull = self.device.is_enabled(Gl::CULL_FACE);
+        let mut framebuffer = self.framebuffer.borrow_mut();
+
+        let first = offset.max(0) as usize / 2;
+        let indices: Vec<u16> = indices.chunks_exact(2).skip(first).take(count.max(0) as usize)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        for triangle in indices.chunks_exact(3){
+            let vertices: Vec<ClipVertex> = triangle.iter().map(|index|{
+                let position = read_attribute(&position_pointer, &position_data, *index as usize);
+                let color = color.as_ref().map(|(pointer, data)| read_attribute(pointer, data, *index as usize)).unwrap_or(DEFAULT_ATTRIBUTE);
+                ClipVertex{clip: transform_point(&transform, [position[0], position[1], position[2], 1.0]), color}
+            }).collect();
+            // カメラの後ろに回った部分は近クリップ面で切り取り、扇形の三角形に分ける
+            let polygon = clip_near(&vertices);
+            for index in 1..polygon.len().saturating_sub(1){
+                draw_triangle(&mut framebuffer, [polygon[0], polygon[index], polygon[index + 1]], viewport, depth_test, cull);
+            }
+        }
+    }
+}
+
+fn read_mat4(data: &[u8], offset: usize)->Option<[f32; 16]>{
+    let bytes = data.get(offset..offset + 64)?;
+    let mut matrix = [0.0; 16];
+    for (value, bytes) in matrix.iter_mut().zip(bytes.chunks_exact(4)){
+        *value = f32::from_le_bytes(bytes.try_into().ok()?);
+    }
+    Some(matrix)
+}
+
+// FLOATの属性のindex番目の頂点を読む。足りない成分は既定値で埋める
+fn read_attribute(pointer: &AttribPointer, data: &[u8], index: usize)->[f32; 4]{
+    let mut value = DEFAULT_ATTRIBUTE;
+    if pointer.data_type != Gl::FLOAT{
+        return value;
+    }
+    let stride = if pointer.stride == 0{ pointer.size as usize * 4 } else{ pointer.stride as usize };
+    let start = pointer.offset as usize + stride * index;
+    for (component, value) in value.iter_mut().enumerate().take(pointer.size.clamp(0, 4) as usize){
+        let position = start + component * 4;
+        if let Some(bytes) = data.get(position..position + 4){
+            *value = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+    value
+}
+
+// 列優先の行列の積 a * b
+fn multiply(a: &[f32; 16], b: &[f32; 16])->[f32; 16]{
+    let mut result = [0.0; 16];
+    for column in 0..4{
+        for row in 0..4{
+            result[column * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[column * 4 + k]).sum();
+        }
+    }
+    result
+}
+
+fn transform_point(matrix: &[f32; 16], point: [f32; 4])->[f32; 4]{
+    let mut result = [0.0; 4];
+    for (row, value) in result.iter_mut().enumerate(){
+        *value = (0..4).map(|column| matrix[column * 4 + row] * point[column]).sum();
+    }
+    result
+}
+
+// z >= -w の側だけを残す(Sutherland-Hodgman)
+fn clip_near(vertices: &[ClipVertex])->Vec<ClipVertex>{
+    let distance = |vertex: &ClipVertex| vertex.clip[2] + vertex.clip[3];
+    let mut polygon = Vec::with_capacity(vertices.len() + 1);
+    for (index, current) in vertices.iter().enumerate(){
+        let next = &vertices[(index + 1) % vertices.len()];
+        let (current_distance, next_distance) = (distance(current), distance(next));
+        if current_distance >= 0.0{
+            polygon.push(*current);
+        }
+        if (current_distance >= 0.0) != (next_distance >= 0.0){
+            let t = current_distance / (current_distance - next_distance);
+            let lerp = |a: [f32; 4], b: [f32; 4]| [0, 1, 2, 3].map(|i| a[i] + (b[i] - a[i]) * t);
+            polygon.push(ClipVertex{clip: lerp(current.clip, next.clip), color: lerp(current.color, next.color)});
+        }
+    }
+    polygon
+}
+
+fn draw_triangle(framebuffer: &mut Framebuffer, vertices: [ClipVertex; 3], viewport: super::Viewport, depth_test: bool, cull: bool){
+    let (width, height) = (framebuffer.color.width() as i32, framebuffer.color.height() as i32);
+    // ウィンドウ座標(左下が原点)と、遠近補正に使う1/w
+    let window = vertices.map(|vertex|{
+        let inverse_w = 1.0 / vertex.clip[3];
+        let ndc = [vertex.clip[0] * inverse_w, vertex.clip[1] * inverse_w, vertex.clip[2] * inverse_w];
+        [
+            viewport.x as f32 + (ndc[0] + 1.0) * 0.5 * viewport.width as f32,
+            viewport.y as f32 + (ndc[1] + 1.0) * 0.5 * viewport.height as f32,
+            (ndc[2] + 1.0) * 0.5,
+            inverse_w,
+        ]
+    });
+    let edge = |a: [f32; 4], b: [f32; 4], x: f32, y: f32| (b[0] - a[0]) * (y - a[1]) - (b[1] - a[1]) * (x - a[0]);
+    let area = edge(window[0], window[1], window[2][0], window[2][1]);
+    if area == 0.0 || (cull && area < 0.0){
+        return;
+    }
+
+    // ビューポートとフレームバッファの内側だけを塗る
+    let min_x = window.iter().map(|v| v[0]).fold(f32::MAX, f32::min).floor().max(viewport.x as f32).max(0.0) as i32;
+    let max_x = window.iter().map(|v| v[0]).fold(f32::MIN, f32::max).ceil().min((viewport.x + viewport.width) as f32).min(width as f32) as i32;
+    let min_y = window.iter().map(|v| v[1]).fold(f32::MAX, f32::min).floor().max(viewport.y as f32).max(0.0) as i32;
+    let max_y = window.iter().map(|v| v[1]).fold(f32::MIN, f32::max).ceil().min((viewport.y + viewport.height) as f32).min(height as f32) as i32;
+    for y in min_y..max_y{
+        for x in min_x..max_x{
+            // ピクセルの中心でサンプルする
+            let (sample_x, sample_y) = (x as f32 + 0.5, y as f32 + 0.5);
+            let weights = [
+                edge(window[1], window[2], sample_x, sample_y) / area,
+                edge(window[2], window[0], sample_x, sample_y) / area,
+                edge(window[0], window[1], sample_x, sample_y) / area,
+            ];
+            if weights.iter().any(|weight| *weight < 0.0){
+                continue;
+            }
+            let depth: f32 = (0..3).map(|i| weights[i] * window[i][2]).sum();
+            if !(0.0..=1.0).contains(&depth){
+                continue;
+            }
+            let index = ((height - 1 - y) * width + x) as usize;
+            if depth_test && depth >= framebuffer.depth[index]{
+                continue;
+            }
+            let inverse_w: f32 = (0..3).map(|i| weights[i] * window[i][3]).sum();
+            let color = [0, 1, 2, 3].map(|channel|{
+                let value: f32 = (0..3).map(|i| weights[i] * window[i][3] * vertices[i].color[channel]).sum::<f32>() / inverse_w;
+                (value.clamp(0.0, 1.0) * 255.0).round() as u8
+            });
+            if depth_test{
+                framebuffer.depth[index] = depth;
+            }
+            framebuffer.color.set_pixel(x as u32, (height - 1 - y) as u32, color);
+        }
+    }
+}
+
+impl GraphicsDevice for SoftwareDevice{
+    type Buffer = MockHandle;
+    type VertexArray = MockHandle;
+    type Shader = MockHandle;
+    type Program = MockHandle;
+    type UniformLocation = MockHandle;
+    type Framebuffer = MockHandle;
+    type Texture = MockHandle;
+
+    fn create_buffer(&self)->Option<MockHandle>{
+        self.device.create_buffer()
+    }
+
+    fn bind_buffer(&self, target: u32, buffer: Option<&MockHandle>){
+        self.device.bind_buffer(target, buffer);
+    }
+
+    fn buffer_data_f32(&self, target: u32, data: &[f32], usage: u32){
+        self.device.buffer_data_f32(target, data, usage);
+    }
+
+    fn buffer_data_u16(&self, target: u32, data: &[u16], usage: u32){
+        self.device.buffer_data_u16(target, data, usage);
+    }
+
+    fn buffer_data_size(&self, target: u32, size: i32, usage: u32){
+        self.device.buffer_data_size(target, size, usage);
+    }
+
+    fn buffer_sub_data_u8(&self, target: u32, offset: i32, data: &[u8]){
+        self.device.buffer_sub_data_u8(target, offset, data);
+    }
+
+    fn bind_buffer_base(&self, target: u32, index: u32, buffer: Option<&MockHandle>){
+        self.device.bind_buffer_base(target, index, buffer);
+    }
+
+    fn delete_buffer(&self, buffer: Option<&MockHandle>){
+        self.device.delete_buffer(buffer);
+    }
+
+    fn create_vertex_array(&self)->Option<MockHandle>{
+        self.device.create_vertex_array()
+    }
+
+    fn bind_vertex_array(&self, vertex_array: Option<&MockHandle>){
+        self.device.bind_vertex_array(vertex_array);
+    }
+
+    fn delete_vertex_array(&self, vertex_array: Option<&MockHandle>){
+        self.device.delete_vertex_array(vertex_array);
+    }
+
+    fn enable_vertex_attrib_array(&self, index: u32){
+        self.device.enable_vertex_attrib_array(index);
+    }
+
+    fn vertex_attrib_pointer(&self, index: u32, size: i32, data_type: u32, normalized: bool, stride: i32, offset: i32){
+        self.device.vertex_attrib_pointer(index, size, data_type, normalized, stride, offset);
+    }
+
+    fn create_shader(&self, shader_type: u32)->Option<MockHandle>{
+        self.device.create_shader(shader_type)
+    }
+
+    fn shader_source(&self, shader: &MockHandle, source: &str){
+        self.device.shader_source(shader, source);
+    }
+
+    fn compile_shader(&self, shader: &MockHandle){
+        self.device.compile_shader(shader);
+    }
+
+    fn get_shader_parameter(&self, shader: &MockHandle, pname: u32)->i32{
+        self.device.get_shader_parameter(shader, pname)
+    }
+
+    fn get_shader_info_log(&self, shader: &MockHandle)->Option<String>{
+        self.device.get_shader_info_log(shader)
+    }
+
+    fn delete_shader(&self, shader: Option<&MockHandle>){
+        self.device.delete_shader(shader);
+    }
+
+    fn create_program(&self)->Option<MockHandle>{
+        self.device.create_program()
+    }
+
+    fn attach_shader(&self, program: &MockHandle, shader: &MockHandle){
+        self.device.attach_shader(program, shader);
+    }
+
+    fn detach_shader(&self, program: &MockHandle, shader: &MockHandle){
+        self.device.detach_shader(program, shader);
+    }
+
+    fn bind_attrib_location(&self, program: &MockHandle, index: u32, name: &str){
+        self.device.bind_attrib_location(program, index, name);
+    }
+
+    fn link_program(&self, program: &MockHandle){
+        self.device.link_program(program);
+    }
+
+    fn get_program_parameter(&self, program: &MockHandle, pname: u32)->i32{
+        self.device.get_program_parameter(program, pname)
+    }
+
+    fn get_program_info_log(&self, program: &MockHandle)->Option<String>{
+        self.device.get_program_info_log(program)
+    }
+
+    fn use_program(&self, program: Option<&MockHandle>){
+        self.device.use_program(program);
+    }
+
+    fn delete_program(&self, program: Option<&MockHandle>){
+        self.device.delete_program(program);
+    }
+
+    fn get_attrib_location(&self, program: &MockHandle, name: &str)->i32{
+        self.device.get_attrib_location(program, name)
+    }
+
+    fn get_active_uniform(&self, program: &MockHandle, index: u32)->Option<ActiveInfo>{
+        self.device.get_active_uniform(program, index)
+    }
+
+    fn get_uniform_location(&self, program: &MockHandle, name: &str)->Option<MockHandle>{
+        self.device.get_uniform_location(program, name)
+    }
+
+    fn get_uniform_block_index(&self, program: &MockHandle, name: &str)->u32{
+        self.device.get_uniform_block_index(program, name)
+    }
+
+    fn uniform_block_binding(&self, program: &MockHandle, index: u32, binding: u32){
+        self.device.uniform_block_binding(program, index, binding);
+    }
+
+    fn uniform1f(&self, location: Option<&MockHandle>, value: f32){
+        self.device.uniform1f(location, value);
+    }
+
+    fn uniform1i(&self, location: Option<&MockHandle>, value: i32){
+        self.device.uniform1i(location, value);
+    }
+
+    fn uniform2fv(&self, location: Option<&MockHandle>, value: &[f32]){
+        self.device.uniform2fv(location, value);
+    }
+
+    fn uniform3fv(&self, location: Option<&MockHandle>, value: &[f32]){
+        self.device.uniform3fv(location, value);
+    }
+
+    fn uniform4fv(&self, location: Option<&MockHandle>, value: &[f32]){
+        self.device.uniform4fv(location, value);
+    }
+
+    fn uniform_matrix3fv(&self, location: Option<&MockHandle>, transpose: bool, value: &[f32]){
+        self.device.uniform_matrix3fv(location, transpose, value);
+    }
+
+    fn uniform_matrix4fv(&self, location: Option<&MockHandle>, transpose: bool, value: &[f32]){
+        self.device.uniform_matrix4fv(location, transpose, value);
+    }
+
+    fn create_framebuffer(&self)->Option<MockHandle>{
+        self.device.create_framebuffer()
+    }
+
+    fn bind_framebuffer(&self, target: u32, framebuffer: Option<&MockHandle>){
+        self.device.bind_framebuffer(target, framebuffer);
+    }
+
+    fn check_framebuffer_status(&self, target: u32)->u32{
+        self.device.check_framebuffer_status(target)
+    }
+
+    fn framebuffer_texture_layer(&self, target: u32, attachment: u32, texture: Option<&MockHandle>, level: i32, layer: i32){
+        self.device.framebuffer_texture_layer(target, attachment, texture, level, layer);
+    }
+
+    fn blit_framebuffer(&self, source: [i32; 4], destination: [i32; 4], mask: u32, filter: u32){
+        self.device.blit_framebuffer(source, destination, mask, filter);
+    }
+
+    fn delete_framebuffer(&self, framebuffer: Option<&MockHandle>){
+        self.device.delete_framebuffer(framebuffer);
+    }
+
+    fn create_texture(&self)->Option<MockHandle>{
+        self.device.create_texture()
+    }
+
+    fn bind_texture(&self, target: u32, texture: Option<&MockHandle>){
+        self.device.bind_texture(target, texture);
+    }
+
+    fn tex_storage_3d(&self, target: u32, levels: i32, internal_format: u32, width: i32, height: i32, depth: i32){
+        self.device.tex_storage_3d(target, levels, internal_format, width, height, depth);
+    }
+
+    fn delete_texture(&self, texture: Option<&MockHandle>){
+        self.device.delete_texture(texture);
+    }
+
+    // レイヤーごとに描き分けられないので、常にビューごとに描かせる
+    fn has_multiview(&self)->bool{
+        false
+    }
+
+    fn framebuffer_texture_multiview_ovr(&self, _target: u32, _attachment: u32, _texture: Option<&MockHandle>, _level: i32, _base_view_index: i32, _num_views: i32){}
+
+    fn enable(&self, capability: u32){
+        self.device.enable(capability);
+    }
+
+    fn viewport(&self, x: i32, y: i32, width: i32, height: i32){
+        self.device.viewport(x, y, width, height);
+    }
+
+    fn clear_color(&self, red: f32, green: f32, blue: f32, alpha: f32){
+        self.device.clear_color(red, green, blue, alpha);
+    }
+
+    fn clear_depth(&self, depth: f32){
+        self.device.clear_depth(depth);
+    }
+
+    // ビューポートに関係なく、フレームバッファ全体をクリアする
+    fn clear(&self, mask: u32){
+        self.device.clear(mask);
+        let (color, depth) = self.device.clear_values();
+        let mut framebuffer = self.framebuffer.borrow_mut();
+        if mask & Gl::COLOR_BUFFER_BIT != 0{
+            let color = color.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+            let (width, height) = (framebuffer.color.width(), framebuffer.color.height());
+            framebuffer.color = Image::new(width, height, color);
+        }
+        if mask & Gl::DEPTH_BUFFER_BIT != 0{
+            framebuffer.depth.fill(depth.clamp(0.0, 1.0));
+        }
+    }
+
+    fn draw_elements(&self, mode: u32, count: i32, index_type: u32, offset: i32){
+        self.device.draw_elements(mode, count, index_type, offset);
+        if mode != Gl::TRIANGLES || index_type != Gl::UNSIGNED_SHORT{
+            super::log(&format!("[Warning] Software renderer only draws indexed triangles (mode 0x{:04x})", mode));
+            return;
+        }
+        self.rasterize(count, offset);
+    }
+}
+
+// 参照画像との比較
+// 環境変数UPDATE_GOLDENを設定して実行すると、比べずに参照画像を書き換える
+// 食い違った場合は、同じ場所に .actual.png を書き出す
+#[derive(Debug,Clone,PartialEq)]
+pub enum ReferenceImageError{
+    Io{path: String, message: String},
+    Image{path: String, error: ImageError},
+    Mismatch{path: String, diff: ImageDiff},
+}
+
+impl std::fmt::Display for ReferenceImageError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            ReferenceImageError::Io{path, message}=>write!(f, "{}: {}", path, message),
+            ReferenceImageError::Image{path, error}=>write!(f, "{}: {}", path, error),
+            ReferenceImageError::Mismatch{path, diff}=>write!(f,
+                "{}: {} pixels differ (max difference {})\n(set {}=1 to update the reference image)",
+                path, diff.differing_pixels, diff.max_difference, super::recording::UPDATE_GOLDEN_ENV,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReferenceImageError{}
+
+// toleranceはチャンネルごとに許す差、max_differing_pixelsはそれを超えてもよいピクセル数
+pub fn check_reference_image(path: impl AsRef<std::path::Path>, image: &Image, tolerance: u8, max_differing_pixels: usize)->Result<ImageDiff,ReferenceImageError>{
+    let path = path.as_ref();
+    let display = path.display().to_string();
+    let io_error = |error: std::io::Error| ReferenceImageError::Io{path: display.clone(), message: error.to_string()};
+    if std::env::var_os(super::recording::UPDATE_GOLDEN_ENV).is_some(){
+        if let Some(parent) = path.parent(){
+            std::fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        std::fs::write(path, image.to_png()).map_err(io_error)?;
+        return Ok(ImageDiff::default());
+    }
+
+    let bytes = std::fs::read(path).map_err(io_error)?;
+    let reference = Image::from_png(&bytes).map_err(|error| ReferenceImageError::Image{path: display.clone(), error})?;
+    let diff = reference.compare(image, tolerance).map_err(|error| ReferenceImageError::Image{path: display.clone(), error})?;
+    if diff.differing_pixels > max_differing_pixels{
+        std::fs::write(path.with_extension("actual.png"), image.to_png()).map_err(io_error)?;
+        return Err(ReferenceImageError::Mismatch{path: display, diff});
+    }
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::render_frame;
+    use crate::test_support::{demo_scene,stereo_views};
+
+    // 不透明のセッションのクリア色
+    const BACKGROUND: [u8; 4] = [0, 0, 0, 255];
+
+    // デモのシーンを両目分描く。cullがfalseなら、create_rendererで有効にする深度テストと背面カリングを外す
+    fn render_demo(eye_width: i32, eye_height: i32, cull: bool)->Image{
+        let gl = SoftwareDevice::new(eye_width as u32 * 2, eye_height as u32);
+        let (mut renderer, scene) = demo_scene(&gl);
+        if !cull{
+            gl.mock().disable(Gl::DEPTH_TEST);
+            gl.mock().disable(Gl::CULL_FACE);
+        }
+        render_frame(&stereo_views(eye_width, eye_height), None, &mut renderer, &scene, &[]);
+        gl.image()
+    }
+
+    // x_rangeの列のうち、背景以外のピクセル
+    fn covered(image: &Image, x_range: std::ops::Range<u32>)->Vec<(u32, u32)>{
+        x_range.flat_map(|x| (0..image.height()).map(move |y| (x, y)))
+            .filter(|(x, y)| image.pixel(*x, *y) != Some(BACKGROUND))
+            .collect()
+    }
+
+    #[test]
+    fn demo_scene_matches_reference_image(){
+        let image = render_demo(64, 64, true);
+        // 両目とも立方体が写っていて、視差で横にずれている
+        let left = covered(&image, 0..64);
+        let right: Vec<(u32, u32)> = covered(&image, 64..128).into_iter().map(|(x, y)| (x - 64, y)).collect();
+        assert!(left.len() > 50 && right.len() > 50, "left {} right {}", left.len(), right.len());
+        assert_ne!(left, right);
+        // 丸め方の違いで輪郭の数ピクセルがずれるのは許す
+        check_reference_image("tests/golden/demo_stereo.png", &image, 2, 8).unwrap();
+    }
+
+    #[test]
+    fn culling_hides_back_faces(){
+        // 深度テストもカリングも無いと、後から描いた奥の面が手前の面を上書きする
+        let culled = render_demo(32, 32, true);
+        let unculled = render_demo(32, 32, false);
+        assert_eq!(covered(&culled, 0..64), covered(&unculled, 0..64));
+        assert_ne!(culled, unculled);
+    }
+
+    #[test]
+    fn clear_fills_whole_framebuffer(){
+        let gl = SoftwareDevice::new(8, 4);
+        assert_eq!(gl.image().pixel(0, 0), Some([0, 0, 0, 0]));
+        gl.viewport(0, 0, 2, 2);
+        gl.clear_color(1.0, 0.5, 0.0, 1.0);
+        gl.clear(Gl::COLOR_BUFFER_BIT);
+        assert!(gl.image().pixels().iter().all(|pixel| *pixel == [255, 128, 0, 255]));
+    }
+}
@@ -0,0 +1,397 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+use futures::channel::mpsc;
+use super::{ActiveInfo,AssetError,AssetFetcher,GraphicsDevice,ViewData,Viewport,XrBackend,XrFrameInput,XrFrameSource};
+use crate::input::{Input,InputEvent,InputSourceState,Pose};
+use crate::reference_space::ReferenceSpace;
+use crate::session::SessionMode;
+use crate::multiview::MULTIVIEW_EXTENSION;
+use crate::uniform::MAX_VIEWS;
+
+// web-sysによる実装。そのままWebGL2・WebXR・fetchを呼ぶ
+
+// JsValueのパラメータを、真偽値は0か1、数値はそのまま整数にする
+fn parameter_to_i32(value: JsValue)->i32{
+    match value.as_bool(){
+        Some(flag)=>i32::from(flag),
+        None=>value.as_f64().unwrap_or(0.0) as i32,
+    }
+}
+
+// 同じコンテキストでは、getExtensionは何度呼んでも同じオブジェクトを返す
+fn multiview_extension(gl: &WebGl2RenderingContext)->Option<OvrMultiview2>{
+    let extension = gl.get_extension(MULTIVIEW_EXTENSION).ok()??;
+    Some(extension.unchecked_into::<OvrMultiview2>())
+}
+
+impl GraphicsDevice for WebGl2RenderingContext{
+    type Buffer = WebGlBuffer;
+    type VertexArray = WebGlVertexArrayObject;
+    type Shader = WebGlShader;
+    type Program = WebGlProgram;
+    type UniformLocation = WebGlUniformLocation;
+    type Framebuffer = WebGlFramebuffer;
+    type Texture = WebGlTexture;
+
+    fn create_buffer(&self)->Option<WebGlBuffer>{
+        WebGl2RenderingContext::create_buffer(self)
+    }
+
+    fn bind_buffer(&self, target: u32, buffer: Option<&WebGlBuffer>){
+        WebGl2RenderingContext::bind_buffer(self, target, buffer);
+    }
+
+    fn buffer_data_f32(&self, target: u32, data: &[f32], usage: u32){
+        let array = js_sys::Float32Array::from(data);
+        self.buffer_data_with_array_buffer_view(target, &array, usage);
+    }
+
+    fn buffer_data_u16(&self, target: u32, data: &[u16], usage: u32){
+        let array = js_sys::Uint16Array::from(data);
+        self.buffer_data_with_array_buffer_view(target, &array, usage);
+    }
+
+    fn buffer_data_size(&self, target: u32, size: i32, usage: u32){
+        self.buffer_data_with_i32(target, size, usage);
+    }
+
+    fn buffer_sub_data_u8(&self, target: u32, offset: i32, data: &[u8]){
+        self.buffer_sub_data_with_i32_and_u8_array(target, offset, data);
+    }
+
+    fn bind_buffer_base(&self, target: u32, index: u32, buffer: Option<&WebGlBuffer>){
+        WebGl2RenderingContext::bind_buffer_base(self, target, index, buffer);
+    }
+
+    fn delete_buffer(&self, buffer: Option<&WebGlBuffer>){
+        WebGl2RenderingContext::delete_buffer(self, buffer);
+    }
+
+    fn create_vertex_array(&self)->Option<WebGlVertexArrayObject>{
+        WebGl2RenderingContext::create_vertex_array(self)
+    }
+
+    fn bind_vertex_array(&self, vertex_array: Option<&WebGlVertexArrayObject>){
+        WebGl2RenderingContext::bind_vertex_array(self, vertex_array);
+    }
+
+    fn delete_vertex_array(&self, vertex_array: Option<&WebGlVertexArrayObject>){
+        WebGl2RenderingContext::delete_vertex_array(self, vertex_array);
+    }
+
+    fn enable_vertex_attrib_array(&self, index: u32){
+        WebGl2RenderingContext::enable_vertex_attrib_array(self, index);
+    }
+
+    fn vertex_attrib_pointer(&self, index: u32, size: i32, data_type: u32, normalized: bool, stride: i32, offset: i32){
+        self.vertex_attrib_pointer_with_i32(index, size, data_type, normalized, stride, offset);
+    }
+
+    fn create_shader(&self, shader_type: u32)->Option<WebGlShader>{
+        WebGl2RenderingContext::create_shader(self, shader_type)
+    }
+
+    fn shader_source(&self, shader: &WebGlShader, source: &str){
+        WebGl2RenderingContext::shader_source(self, shader, source);
+    }
+
+    fn compile_shader(&self, shader: &WebGlShader){
+        WebGl2RenderingContext::compile_shader(self, shader);
+    }
+
+    fn get_shader_parameter(&self, shader: &WebGlShader, pname: u32)->i32{
+        parameter_to_i32(WebGl2RenderingContext::get_shader_parameter(self, shader, pname))
+    }
+
+    fn get_shader_info_log(&self, shader: &WebGlShader)->Option<String>{
+        WebGl2RenderingContext::get_shader_info_log(self, shader)
+    }
+
+    fn delete_shader(&self, shader: Option<&WebGlShader>){
+        WebGl2RenderingContext::delete_shader(self, shader);
+    }
+
+    fn create_program(&self)->Option<WebGlProgram>{
+        WebGl2RenderingContext::create_program(self)
+    }
+
+    fn attach_shader(&self, program: &WebGlProgram, shader: &WebGlShader){
+        WebGl2RenderingContext::attach_shader(self, program, shader);
+    }
+
+    fn detach_shader(&self, program: &WebGlProgram, shader: &WebGlShader){
+        WebGl2RenderingContext::detach_shader(self, program, shader);
+    }
+
+    fn bind_attrib_location(&self, program: &WebGlProgram, index: u32, name: &str){
+        WebGl2RenderingContext::bind_attrib_location(self, program, index, name);
+    }
+
+    fn link_program(&self, program: &WebGlProgram){
+        WebGl2RenderingContext::link_program(self, program);
+    }
+
+    fn get_program_parameter(&self, program: &WebGlProgram, pname: u32)->i32{
+        parameter_to_i32(WebGl2RenderingContext::get_program_parameter(self, program, pname))
+    }
+
+    fn get_program_info_log(&self, program: &WebGlProgram)->Option<String>{
+        WebGl2RenderingContext::get_program_info_log(self, program)
+    }
+
+    fn use_program(&self, program: Option<&WebGlProgram>){
+        WebGl2RenderingContext::use_program(self, program);
+    }
+
+    fn delete_program(&self, program: Option<&WebGlProgram>){
+        WebGl2RenderingContext::delete_program(self, program);
+    }
+
+    fn get_attrib_location(&self, program: &WebGlProgram, name: &str)->i32{
+        WebGl2RenderingContext::get_attrib_location(self, program, name)
+    }
+
+    fn get_active_uniform(&self, program: &WebGlProgram, index: u32)->Option<ActiveInfo>{
+        WebGl2RenderingContext::get_active_uniform(self, program, index).map(|info| ActiveInfo{
+            name: info.name(),
+            gl_type: info.type_(),
+            size: info.size(),
+        })
+    }
+
+    fn get_uniform_location(&self, program: &WebGlProgram, name: &str)->Option<WebGlUniformLocation>{
+        WebGl2RenderingContext::get_uniform_location(self, program, name)
+    }
+
+    fn get_uniform_block_index(&self, program: &WebGlProgram, name: &str)->u32{
+        WebGl2RenderingContext::get_uniform_block_index(self, program, name)
+    }
+
+    fn uniform_block_binding(&self, program: &WebGlProgram, index: u32, binding: u32){
+        WebGl2RenderingContext::uniform_block_binding(self, program, index, binding);
+    }
+
+    fn uniform1f(&self, location: Option<&WebGlUniformLocation>, value: f32){
+        WebGl2RenderingContext::uniform1f(self, location, value);
+    }
+
+    fn uniform1i(&self, location: Option<&WebGlUniformLocation>, value: i32){
+        WebGl2RenderingContext::uniform1i(self, location, value);
+    }
+
+    fn uniform2fv(&self, location: Option<&WebGlUniformLocation>, value: &[f32]){
+        self.uniform2fv_with_f32_array(location, value);
+    }
+
+    fn uniform3fv(&self, location: Option<&WebGlUniformLocation>, value: &[f32]){
+        self.uniform3fv_with_f32_array(location, value);
+    }
+
+    fn uniform4fv(&self, location: Option<&WebGlUniformLocation>, value: &[f32]){
+        self.uniform4fv_with_f32_array(location, value);
+    }
+
+    fn uniform_matrix3fv(&self, location: Option<&WebGlUniformLocation>, transpose: bool, value: &[f32]){
+        self.uniform_matrix3fv_with_f32_array(location, transpose, value);
+    }
+
+    fn uniform_matrix4fv(&self, location: Option<&WebGlUniformLocation>, transpose: bool, value: &[f32]){
+        self.uniform_matrix4fv_with_f32_array(location, transpose, value);
+    }
+
+    fn create_framebuffer(&self)->Option<WebGlFramebuffer>{
+        WebGl2RenderingContext::create_framebuffer(self)
+    }
+
+    fn bind_framebuffer(&self, target: u32, framebuffer: Option<&WebGlFramebuffer>){
+        WebGl2RenderingContext::bind_framebuffer(self, target, framebuffer);
+    }
+
+    fn check_framebuffer_status(&self, target: u32)->u32{
+        WebGl2RenderingContext::check_framebuffer_status(self, target)
+    }
+
+    fn framebuffer_texture_layer(&self, target: u32, attachment: u32, texture: Option<&WebGlTexture>, level: i32, layer: i32){
+        WebGl2RenderingContext::framebuffer_texture_layer(self, target, attachment, texture, level, layer);
+    }
+
+    fn blit_framebuffer(&self, source: [i32; 4], destination: [i32; 4], mask: u32, filter: u32){
+        let [src_x0, src_y0, src_x1, src_y1] = source;
+        let [dst_x0, dst_y0, dst_x1, dst_y1] = destination;
+        WebGl2RenderingContext::blit_framebuffer(self, src_x0, src_y0, src_x1, src_y1, dst_x0, dst_y0, dst_x1, dst_y1, mask, filter);
+    }
+
+    fn delete_framebuffer(&self, framebuffer: Option<&WebGlFramebuffer>){
+        WebGl2RenderingContext::delete_framebuffer(self, framebuffer);
+    }
+
+    fn create_texture(&self)->Option<WebGlTexture>{
+        WebGl2RenderingContext::create_texture(self)
+    }
+
+    fn bind_texture(&self, target: u32, texture: Option<&WebGlTexture>){
+        WebGl2RenderingContext::bind_texture(self, target, texture);
+    }
+
+    fn tex_storage_3d(&self, target: u32, levels: i32, internal_format: u32, width: i32, height: i32, depth: i32){
+        WebGl2RenderingContext::tex_storage_3d(self, target, levels, internal_format, width, height, depth);
+    }
+
+    fn delete_texture(&self, texture: Option<&WebGlTexture>){
+        WebGl2RenderingContext::delete_texture(self, texture);
+    }
+
+    fn has_multiview(&self)->bool{
+        multiview_extension(self).is_some()
+    }
+
+    fn framebuffer_texture_multiview_ovr(&self, target: u32, attachment: u32, texture: Option<&WebGlTexture>, level: i32, base_view_index: i32, num_views: i32){
+        if let Some(extension) = multiview_extension(self){
+            extension.framebuffer_texture_multiview_ovr(target, attachment, texture, level, base_view_index, num_views);
+        }
+    }
+
+    fn enable(&self, capability: u32){
+        WebGl2RenderingContext::enable(self, capability);
+    }
+
+    fn viewport(&self, x: i32, y: i32, width: i32, height: i32){
+        WebGl2RenderingContext::viewport(self, x, y, width, height);
+    }
+
+    fn clear_color(&self, red: f32, green: f32, blue: f32, alpha: f32){
+        WebGl2RenderingContext::clear_color(self, red, green, blue, alpha);
+    }
+
+    fn clear_depth(&self, depth: f32){
+        WebGl2RenderingContext::clear_depth(self, depth);
+    }
+
+    fn clear(&self, mask: u32){
+        WebGl2RenderingContext::clear(self, mask);
+    }
+
+    fn draw_elements(&self, mode: u32, count: i32, index_type: u32, offset: i32){
+        self.draw_elements_with_i32(mode, count, index_type, offset);
+    }
+}
+
+pub struct WebXrSystem{
+    pub system: XrSystem,
+}
+
+impl XrBackend for WebXrSystem{
+    async fn is_session_supported(&self, mode: SessionMode)->bool{
+        match JsFuture::from(self.system.is_session_supported(mode.to_xr())).await{
+            Ok(supported)=>supported.as_bool().unwrap_or(false),
+            Err(_)=>{
+                console::log_1(&format!("[Error] Could not check support for {}", mode).into());
+                false
+            },
+        }
+    }
+}
+
+// アニメーションフレームのコールバックで受け取ったXrFrame
+pub struct WebXrFrame<'a>{
+    pub frame: &'a XrFrame,
+    pub reference_space: &'a XrReferenceSpace,
+    pub layer: &'a XrWebGlLayer,
+}
+
+impl XrFrameSource for WebXrFrame<'_>{
+    fn views(&self)->Option<Vec<ViewData>>{
+        let pose = self.frame.get_viewer_pose(self.reference_space)?;
+        let views = pose.views().iter()
+            .filter_map(|view| view.dyn_into::<XrView>().ok())
+            .take(MAX_VIEWS)
+            .filter_map(|view|{
+                let viewport = self.layer.get_viewport(&view)?;
+                Some(ViewData{
+                    // XrViewの姿勢の逆行列がview行列になる
+                    view: view.transform().inverse().matrix().try_into().ok()?,
+                    projection: view.projection_matrix().try_into().ok()?,
+                    viewport: Viewport{x: viewport.x(), y: viewport.y(), width: viewport.width(), height: viewport.height()},
+                })
+            })
+            .collect();
+        Some(views)
+    }
+}
+
+// FrameLoop::stepに渡す、アニメーションフレームのXrFrameと入力
+pub struct WebXrInput<'a>{
+    pub frame: &'a XrFrame,
+    pub reference_space: &'a mut ReferenceSpace,
+    pub input: &'a mut Input,
+    // Inputのリスナーが送ったselect/squeeze
+    pub events: &'a mut mpsc::UnboundedReceiver<InputEvent>,
+}
+
+impl XrFrameInput for WebXrInput<'_>{
+    fn update_input(&mut self){
+        self.input.update(self.frame, self.reference_space.space());
+    }
+
+    fn input_sources(&self)->&[InputSourceState]{
+        self.input.sources()
+    }
+
+    fn viewer_pose(&self)->Option<Pose>{
+        let viewer_pose = self.frame.get_viewer_pose(self.reference_space.space())?;
+        Some(Pose::from_transform(&viewer_pose.transform()))
+    }
+
+    fn set_origin_offset(&mut self, position: [f32; 3], orientation: [f32; 4])->bool{
+        if self.reference_space.set_origin_offset(position, orientation).is_err(){
+            console::log_1(&"[Error] Could not offset reference space".into());
+            return false;
+        }
+        true
+    }
+
+    fn take_events(&mut self)->Vec<InputEvent>{
+        let mut events = Vec::new();
+        while let Ok(event) = self.events.try_recv(){
+            events.push(event);
+        }
+        events
+    }
+}
+
+// window.fetchで読み込む
+pub struct WebFetcher{
+    pub window: Window,
+}
+
+impl WebFetcher{
+    async fn fetch(&self, path: &str)->Result<Response,AssetError>{
+        let error = |message: String| AssetError{path: path.to_string(), message};
+        let response = JsFuture::from(self.window.fetch_with_str(path)).await
+            .map_err(|_| error("fetch failed".to_string()))?;
+        let response = response.dyn_into::<Response>()
+            .map_err(|_| error("fetch did not return a Response".to_string()))?;
+        if !response.ok(){
+            return Err(error(format!("HTTP {}", response.status())));
+        }
+        Ok(response)
+    }
+}
+
+impl AssetFetcher for WebFetcher{
+    async fn fetch_bytes(&self, path: &str)->Result<Vec<u8>,AssetError>{
+        let response = self.fetch(path).await?;
+        let error = || AssetError{path: path.to_string(), message: "could not read the response body".to_string()};
+        let buffer = JsFuture::from(response.array_buffer().map_err(|_| error())?).await.map_err(|_| error())?;
+        Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+    }
+
+    async fn fetch_text(&self, path: &str)->Result<String,AssetError>{
+        let response = self.fetch(path).await?;
+        let error = || AssetError{path: path.to_string(), message: "could not read the response body".to_string()};
+        let text = JsFuture::from(response.text().map_err(|_| error())?).await.map_err(|_| error())?;
+        text.as_string().ok_or_else(error)
+    }
+}
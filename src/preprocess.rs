@@ -0,0 +1,365 @@
+use std::collections::{BTreeMap,HashMap};
+use wasm_bindgen::prelude::*;
+
+// GLSLのプリプロセッサ
+// #include "file.glsl" の展開と、Rust側からの#defineの注入を行う
+// 展開後の各行が元のどのファイルの何行目かを覚えておき、エラー表示に使う
+// fetchは呼び出し側で行うので、web-sysには依存しない
+
+// 展開後の行の元の位置
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SourceLocation{
+    pub file: String,
+    pub line: u32,
+}
+
+impl std::fmt::Display for SourceLocation{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum PreprocessError{
+    // インクルードが循環している。循環しているファイルを順に並べる
+    IncludeCycle(Vec<String>),
+    MissingFile{path: String, included_from: Option<SourceLocation>},
+    MalformedInclude(SourceLocation),
+    InvalidDefine(String),
+}
+
+impl std::fmt::Display for PreprocessError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            PreprocessError::IncludeCycle(files)=>write!(f, "include cycle: {}", files.join(" -> ")),
+            PreprocessError::MissingFile{path, included_from: Some(location)}=>write!(f, "{}: could not load `{}`", location, path),
+            PreprocessError::MissingFile{path, included_from: None}=>write!(f, "could not load `{}`", path),
+            PreprocessError::MalformedInclude(location)=>write!(f, "{}: malformed #include, expected #include \"file.glsl\"", location),
+            PreprocessError::InvalidDefine(name)=>write!(f, "`{}` is not a valid macro name", name),
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError{}
+
+impl From<PreprocessError> for JsValue{
+    fn from(error: PreprocessError)->Self{
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+// シェーダに注入する#defineの集合
+// 順序を固定するためにBTreeMapで持つ
+#[derive(Debug,Clone,Default,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct Defines{
+    values: BTreeMap<String,String>,
+}
+
+impl Defines{
+    pub fn new()->Self{
+        Defines::default()
+    }
+
+    // 値付きのマクロ (例: MAX_LIGHTS 4)
+    pub fn with_value(mut self, name: &str, value: impl ToString)->Self{
+        self.set(name, value);
+        self
+    }
+
+    // 値なしのフラグ (例: USE_NORMAL_MAP)
+    pub fn with_flag(mut self, name: &str)->Self{
+        self.set(name, "");
+        self
+    }
+
+    pub fn set(&mut self, name: &str, value: impl ToString){
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, name: &str){
+        self.values.remove(name);
+    }
+
+    pub fn contains(&self, name: &str)->bool{
+        self.values.contains_key(name)
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self)->impl Iterator<Item=(&str,&str)>{
+        self.values.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    fn validate(&self)->Result<(),PreprocessError>{
+        for name in self.values.keys(){
+            let mut chars = name.chars();
+            let valid_start = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+            if !valid_start || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_'){
+                return Err(PreprocessError::InvalidDefine(name.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// 前処理済みのシェーダソース
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct PreprocessedSource{
+    pub code: String,
+    // 展開後の行ごとの元の位置。注入した#defineの行はNone
+    origins: Vec<Option<SourceLocation>>,
+}
+
+impl PreprocessedSource{
+    // 展開後の行番号(1始まり)から元の位置を求める
+    pub fn origin(&self, line: u32)->Option<&SourceLocation>{
+        let index = (line as usize).checked_sub(1)?;
+        self.origins.get(index)?.as_ref()
+    }
+
+    pub fn line_count(&self)->usize{
+        self.origins.len()
+    }
+}
+
+// `#include "path"` の行ならパスを返す。#includeだが書式が違う場合はErr
+pub fn parse_include(line: &str)->Option<Result<String,()>>{
+    let rest = line.trim_start().strip_prefix('#')?.trim_start().strip_prefix("include")?;
+    if !rest.starts_with(char::is_whitespace) && !rest.starts_with('"'){
+        // #included などの別の指令
+        return None;
+    }
+    let rest = rest.trim();
+    let path = rest.strip_prefix('"').and_then(|rest| rest.strip_suffix('"'));
+    match path{
+        Some(path) if !path.is_empty() && !path.contains('"')=>Some(Ok(path.to_string())),
+        _=>Some(Err(())),
+    }
+}
+
+// インクルードするファイルのパスは、インクルードしている側のファイルからの相対パス
+pub fn resolve_path(including_file: &str, include: &str)->String{
+    if include.starts_with('/') || include.contains("://"){
+        return include.to_string();
+    }
+    let mut segments: Vec<&str> = match including_file.rfind('/'){
+        Some(index)=>including_file[..index].split('/').collect(),
+        None=>Vec::new(),
+    };
+    for segment in include.split('/'){
+        match segment{
+            "." | ""=>{},
+            // 先頭が".."のままのものは、ページからの相対パスとして残す
+            ".." if segments.last().is_some_and(|last| *last != ".." && !last.is_empty())=>{
+                segments.pop();
+            },
+            _=>segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+// ソース中の#includeを、解決済みのパスにして列挙する
+pub fn include_paths(path: &str, source: &str)->Vec<String>{
+    source.lines()
+        .filter_map(parse_include)
+        .filter_map(|include| include.ok())
+        .map(|include| resolve_path(path, &include))
+        .collect()
+}
+
+// 読み込み済みのファイルを使って#includeを再帰的に展開する
+pub fn resolve_includes(entry: &str, files: &HashMap<String,String>)->Result<PreprocessedSource,PreprocessError>{
+    let mut lines = Vec::new();
+    let mut stack = Vec::new();
+    expand(entry, None, files, &mut stack, &mut lines)?;
+
+    let mut code = String::new();
+    let mut origins = Vec::with_capacity(lines.len());
+    for (text, origin) in lines{
+        code.push_str(&text);
+        code.push('\n');
+        origins.push(Some(origin));
+    }
+    Ok(PreprocessedSource{code, origins})
+}
+
+fn expand(path: &str, included_from: Option<SourceLocation>, files: &HashMap<String,String>, stack: &mut Vec<String>, lines: &mut Vec<(String,SourceLocation)>)->Result<(),PreprocessError>{
+    if let Some(start) = stack.iter().position(|file| file == path){
+        let mut cycle = stack[start..].to_vec();
+        cycle.push(path.to_string());
+        return Err(PreprocessError::IncludeCycle(cycle));
+    }
+    let Some(source) = files.get(path) else{
+        return Err(PreprocessError::MissingFile{path: path.to_string(), included_from});
+    };
+
+    stack.push(path.to_string());
+    for (index, text) in source.lines().enumerate(){
+        let location = SourceLocation{file: path.to_string(), line: index as u32 + 1};
+        match parse_include(text){
+            Some(Ok(include))=>{
+                let include = resolve_path(path, &include);
+                expand(&include, Some(location), files, stack, lines)?;
+            },
+            Some(Err(()))=>return Err(PreprocessError::MalformedInclude(location)),
+            None=>lines.push((text.to_string(), location)),
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+// #versionの直後に#defineを差し込む。#versionは必ず先頭行でなければならないため
+pub fn inject_defines(source: &PreprocessedSource, defines: &Defines)->Result<PreprocessedSource,PreprocessError>{
+    defines.validate()?;
+    if defines.is_empty(){
+        return Ok(source.clone());
+    }
+
+    let source_lines: Vec<&str> = source.code.lines().collect();
+    let version_line = source_lines.iter().position(|line| line.trim_start().starts_with("#version"));
+    let insert_at = version_line.map(|line| line + 1).unwrap_or(0);
+
+    let mut code = String::new();
+    let mut origins = Vec::with_capacity(source_lines.len() + defines.values.len());
+    for (index, line) in source_lines.iter().enumerate(){
+        if index == insert_at{
+            push_defines(&mut code, &mut origins, defines);
+        }
+        code.push_str(line);
+        code.push('\n');
+        origins.push(source.origins.get(index).cloned().flatten());
+    }
+    if insert_at >= source_lines.len(){
+        push_defines(&mut code, &mut origins, defines);
+    }
+    Ok(PreprocessedSource{code, origins})
+}
+
+fn push_defines(code: &mut String, origins: &mut Vec<Option<SourceLocation>>, defines: &Defines){
+    for (name, value) in defines.iter(){
+        if value.is_empty(){
+            code.push_str(&format!("#define {}\n", name));
+        }
+        else{
+            code.push_str(&format!("#define {} {}\n", name, value));
+        }
+        origins.push(None);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::shader::{ShaderError,ShaderStage};
+
+    fn files(entries: &[(&str, &str)])->HashMap<String,String>{
+        entries.iter().map(|(path, source)| (path.to_string(), source.to_string())).collect()
+    }
+
+    fn location(file: &str, line: u32)->Option<SourceLocation>{
+        Some(SourceLocation{file: file.to_string(), line})
+    }
+
+    // shader/main.glsl -> shader/lib/common.glsl -> shader/lib/constants.glsl
+    fn shader_files()->HashMap<String,String>{
+        files(&[
+            ("shader/main.glsl", "#version 300 es\n#include \"lib/common.glsl\"\nvoid main(){\n    gl_Position = transform(position);\n}"),
+            ("shader/lib/common.glsl", "#include \"constants.glsl\"\nvec4 transform(vec3 p){ return vec4(p * SCALE, 1.0); }"),
+            ("shader/lib/constants.glsl", "const float SCALE = 2.0;"),
+        ])
+    }
+
+    #[test]
+    fn parses_include_lines(){
+        assert_eq!(parse_include("#include \"common.glsl\""), Some(Ok("common.glsl".to_string())));
+        assert_eq!(parse_include("  #  include   \"a/b.glsl\"  "), Some(Ok("a/b.glsl".to_string())));
+        assert_eq!(parse_include("#include <common.glsl>"), Some(Err(())));
+        assert_eq!(parse_include("#include \"\""), Some(Err(())));
+        assert_eq!(parse_include("#included \"x\""), None);
+        assert_eq!(parse_include("// #include \"x\""), None);
+    }
+
+    #[test]
+    fn resolves_paths_relative_to_including_file(){
+        assert_eq!(resolve_path("shader/main.glsl", "lib/common.glsl"), "shader/lib/common.glsl");
+        assert_eq!(resolve_path("shader/lib/common.glsl", "../util.glsl"), "shader/util.glsl");
+        assert_eq!(resolve_path("../shader/main.glsl", "./common.glsl"), "../shader/common.glsl");
+        assert_eq!(resolve_path("../shader/main.glsl", "../../common.glsl"), "../../common.glsl");
+        assert_eq!(resolve_path("main.glsl", "/shared/common.glsl"), "/shared/common.glsl");
+        assert_eq!(include_paths("shader/main.glsl", &shader_files()["shader/main.glsl"]), vec!["shader/lib/common.glsl".to_string()]);
+    }
+
+    #[test]
+    fn expands_nested_includes_and_maps_lines(){
+        let source = resolve_includes("shader/main.glsl", &shader_files()).unwrap();
+        assert_eq!(source.code, "#version 300 es\nconst float SCALE = 2.0;\nvec4 transform(vec3 p){ return vec4(p * SCALE, 1.0); }\nvoid main(){\n    gl_Position = transform(position);\n}\n");
+        assert_eq!(source.line_count(), 6);
+        assert_eq!(source.origin(1).cloned(), location("shader/main.glsl", 1));
+        assert_eq!(source.origin(2).cloned(), location("shader/lib/constants.glsl", 1));
+        assert_eq!(source.origin(3).cloned(), location("shader/lib/common.glsl", 2));
+        assert_eq!(source.origin(5).cloned(), location("shader/main.glsl", 4));
+        assert_eq!(source.origin(0), None);
+        assert_eq!(source.origin(7), None);
+    }
+
+    #[test]
+    fn rejects_include_cycles_and_missing_files(){
+        let cyclic = files(&[
+            ("a.glsl", "#include \"b.glsl\""),
+            ("b.glsl", "// b\n#include \"c.glsl\""),
+            ("c.glsl", "#include \"b.glsl\""),
+        ]);
+        assert_eq!(resolve_includes("a.glsl", &cyclic), Err(PreprocessError::IncludeCycle(vec!["b.glsl".to_string(), "c.glsl".to_string(), "b.glsl".to_string()])));
+        let itself = files(&[("a.glsl", "#include \"a.glsl\"")]);
+        assert_eq!(resolve_includes("a.glsl", &itself), Err(PreprocessError::IncludeCycle(vec!["a.glsl".to_string(), "a.glsl".to_string()])));
+
+        // 同じファイルを2回インクルードするのは循環ではない
+        let twice = files(&[("a.glsl", "#include \"b.glsl\"\n#include \"b.glsl\""), ("b.glsl", "float b;")]);
+        assert_eq!(resolve_includes("a.glsl", &twice).unwrap().code, "float b;\nfloat b;\n");
+
+        let missing = files(&[("a.glsl", "void f();\n#include \"gone.glsl\"")]);
+        assert_eq!(resolve_includes("a.glsl", &missing), Err(PreprocessError::MissingFile{path: "gone.glsl".to_string(), included_from: location("a.glsl", 2)}));
+        let malformed = files(&[("a.glsl", "#include gone.glsl")]);
+        assert_eq!(resolve_includes("a.glsl", &malformed), Err(PreprocessError::MalformedInclude(SourceLocation{file: "a.glsl".to_string(), line: 1})));
+    }
+
+    #[test]
+    fn injects_defines_after_version(){
+        let source = resolve_includes("shader/main.glsl", &shader_files()).unwrap();
+        let defines = Defines::new().with_flag("USE_MULTIVIEW").with_value("MAX_VIEWS", 2);
+        let injected = inject_defines(&source, &defines).unwrap();
+        let lines: Vec<&str> = injected.code.lines().collect();
+        // BTreeMapの順に並ぶ
+        assert_eq!(&lines[..4], &["#version 300 es", "#define MAX_VIEWS 2", "#define USE_MULTIVIEW", "const float SCALE = 2.0;"]);
+        assert_eq!(injected.origin(1).cloned(), location("shader/main.glsl", 1));
+        assert_eq!(injected.origin(2), None);
+        assert_eq!(injected.origin(3), None);
+        assert_eq!(injected.origin(4).cloned(), location("shader/lib/constants.glsl", 1));
+        assert_eq!(injected.origin(7).cloned(), location("shader/main.glsl", 4));
+
+        // #versionが無ければ先頭に入れる
+        let plain = resolve_includes("a.glsl", &files(&[("a.glsl", "float a;")])).unwrap();
+        assert_eq!(inject_defines(&plain, &defines).unwrap().code, "#define MAX_VIEWS 2\n#define USE_MULTIVIEW\nfloat a;\n");
+        assert_eq!(inject_defines(&source, &Defines::new()).unwrap(), source);
+        assert_eq!(inject_defines(&source, &Defines::new().with_flag("2D")), Err(PreprocessError::InvalidDefine("2D".to_string())));
+        assert_eq!(inject_defines(&source, &Defines::new().with_flag("A-B")), Err(PreprocessError::InvalidDefine("A-B".to_string())));
+    }
+
+    #[test]
+    fn maps_shader_errors_back_to_included_files(){
+        let source = resolve_includes("shader/main.glsl", &shader_files()).unwrap();
+        let source = inject_defines(&source, &Defines::new().with_flag("USE_MULTIVIEW")).unwrap();
+        // 展開後の3行目は、constants.glslの1行目
+        let error = ShaderError::new(ShaderStage::Vertex, "ERROR: 0:3: 'SCALE' : redefinition", Some(&source.code)).with_source_map(&source);
+        assert_eq!(error.diagnostics[0].origin, location("shader/lib/constants.glsl", 1));
+        assert_eq!(error.summary(), "vertex shader failed at shader/lib/constants.glsl:1: 'SCALE' : redefinition");
+        // 注入した#defineの行は展開後の行番号のまま表示する
+        let labels: Vec<Option<SourceLocation>> = error.annotated_source.iter().map(|line| line.origin.clone()).collect();
+        assert_eq!(labels[1], None);
+        assert!(error.to_string().contains(">shader/lib/constants.glsl:1 | const float SCALE = 2.0;"));
+    }
+}
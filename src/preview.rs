@@ -0,0 +1,281 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use futures::channel::mpsc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::*;
+use crate::{Renderer,WALKABLE_FLOOR_SIZE,fit_canvas_to_views,render_frame,render_scene,reload_program,write_camera_matrices};
+use crate::camera::{CameraInput,PreviewCamera,projection_matrix};
+use crate::frame_loop::{FrameClock,FrameLoop,Interaction};
+use crate::hand::HAND_TRACKING_FEATURE;
+use crate::hot_reload::ShaderSources;
+use crate::input::InputEventSubscribers;
+use crate::locomotion::WalkableArea;
+use crate::platform::XrFrameSource;
+use crate::scene::Scene;
+use crate::session::{EnabledFeatures,SessionMode};
+use crate::session_recording::{ReplayFrameInput,ReplaySession,SessionRecording};
+
+// WebXRが使えない環境でのデスクトッププレビュー
+// requestAnimationFrameで、XRと同じrender_sceneを片目分だけ描画する
+// 操作: 左ドラッグで視点回転、WASDで移動、Q/Eで上下、ホイールで距離、Fでフライ/オービット切り替え
+// 記録したXRセッションの再生もここで行う
+
+const FIELD_OF_VIEW: f32 = 60.0 * std::f32::consts::PI / 180.0;
+const NEAR: f32 = 0.05;
+const FAR: f32 = 100.0;
+
+// フレームが長く止まったときにカメラが飛ばないようにする(秒)
+const MAX_DELTA_TIME: f32 = 0.1;
+
+// イベントから集めた、次のフレームで使う入力
+#[derive(Debug,Default)]
+struct InputState{
+    // 押されているキーのKeyboardEvent.code
+    keys: HashSet<String>,
+    dragging: bool,
+    look: [f32; 2],
+    zoom: f32,
+    toggle_mode: bool,
+}
+
+impl InputState{
+    fn axis(&self, positive: &str, negative: &str)->f32{
+        let mut value = 0.0;
+        if self.keys.contains(positive){
+            value += 1.0;
+        }
+        if self.keys.contains(negative){
+            value -= 1.0;
+        }
+        value
+    }
+
+    // 溜まったマウスの移動量を取り出して0に戻す
+    fn take(&mut self)->CameraInput{
+        let input = CameraInput{
+            movement: [self.axis("KeyD", "KeyA"), self.axis("KeyE", "KeyQ"), self.axis("KeyW", "KeyS")],
+            look: self.look,
+            zoom: self.zoom,
+        };
+        self.look = [0.0, 0.0];
+        self.zoom = 0.0;
+        input
+    }
+}
+
+type EventListener = Closure<dyn FnMut(Event)>;
+
+// 登録したイベントリスナー。プレビューの描画ループが持ち続ける
+struct Listeners{
+    listeners: Vec<(EventTarget, &'static str, EventListener)>,
+}
+
+impl Listeners{
+    fn add(&mut self, target: &EventTarget, event_type: &'static str, listener: impl FnMut(Event) + 'static){
+        let listener = Closure::wrap(Box::new(listener) as Box<dyn FnMut(Event)>);
+        if target.add_event_listener_with_callback(event_type, listener.as_ref().unchecked_ref()).is_err(){
+            console::log_1(&format!("[Error] Could not listen to {} events", event_type).into());
+        }
+        self.listeners.push((target.clone(), event_type, listener));
+    }
+}
+
+impl Drop for Listeners{
+    fn drop(&mut self){
+        for (target, event_type, listener) in self.listeners.iter(){
+            let _ = target.remove_event_listener_with_callback(event_type, listener.as_ref().unchecked_ref());
+        }
+    }
+}
+
+fn listen_input(window: &Window, canvas: &HtmlCanvasElement, state: &Rc<RefCell<InputState>>)->Listeners{
+    let mut listeners = Listeners{listeners: Vec::new()};
+
+    let keydown_state = Rc::clone(state);
+    listeners.add(window, "keydown", move |event|{
+        let Some(event) = event.dyn_ref::<KeyboardEvent>() else{
+            return;
+        };
+        let mut state = keydown_state.borrow_mut();
+        if event.code() == "KeyF" && !event.repeat(){
+            state.toggle_mode = true;
+        }
+        state.keys.insert(event.code());
+    });
+    let keyup_state = Rc::clone(state);
+    listeners.add(window, "keyup", move |event|{
+        if let Some(event) = event.dyn_ref::<KeyboardEvent>(){
+            keyup_state.borrow_mut().keys.remove(&event.code());
+        }
+    });
+    // ウィンドウからフォーカスが外れるとkeyupが届かないので、全て離したことにする
+    let blur_state = Rc::clone(state);
+    listeners.add(window, "blur", move |_|{
+        let mut state = blur_state.borrow_mut();
+        state.keys.clear();
+        state.dragging = false;
+    });
+
+    let mousedown_state = Rc::clone(state);
+    listeners.add(canvas, "mousedown", move |event|{
+        if event.dyn_ref::<MouseEvent>().is_some_and(|event| event.button() == 0){
+            mousedown_state.borrow_mut().dragging = true;
+        }
+    });
+    let mouseup_state = Rc::clone(state);
+    listeners.add(window, "mouseup", move |_|{
+        mouseup_state.borrow_mut().dragging = false;
+    });
+    // canvasの外までドラッグしても回転を続けられるように、移動はwindowで受け取る
+    let mousemove_state = Rc::clone(state);
+    listeners.add(window, "mousemove", move |event|{
+        let Some(event) = event.dyn_ref::<MouseEvent>() else{
+            return;
+        };
+        let mut state = mousemove_state.borrow_mut();
+        if state.dragging{
+            state.look[0] += event.movement_x() as f32;
+            state.look[1] += event.movement_y() as f32;
+        }
+    });
+    let wheel_state = Rc::clone(state);
+    listeners.add(canvas, "wheel", move |event|{
+        let Some(wheel) = event.dyn_ref::<WheelEvent>() else{
+            return;
+        };
+        // ページのスクロールを止める
+        event.prevent_default();
+        wheel_state.borrow_mut().zoom += wheel.delta_y() as f32;
+    });
+    listeners
+}
+
+// canvasの表示サイズに描画バッファの大きさを合わせる
+fn resize_canvas(window: &Window, canvas: &HtmlCanvasElement)->(i32, i32){
+    let ratio = window.device_pixel_ratio();
+    let width = ((canvas.client_width() as f64 * ratio) as u32).max(1);
+    let height = ((canvas.client_height() as f64 * ratio) as u32).max(1);
+    if canvas.width() != width || canvas.height() != height{
+        canvas.set_width(width);
+        canvas.set_height(height);
+    }
+    (width as i32, height as i32)
+}
+
+pub fn start_desktop_preview(window: &Window, mut renderer: Renderer<WebGl2RenderingContext>, scene: Scene, mut shader_reload_rx: Option<mpsc::Receiver<ShaderSources>>)->Result<(),JsValue>{
+    let canvas = renderer.gl().canvas()
+        .and_then(|canvas| canvas.dyn_into::<HtmlCanvasElement>().ok())
+        .ok_or_else(|| JsValue::from_str("WebGL2 context has no canvas"))?;
+    let state = Rc::new(RefCell::new(InputState::default()));
+    let listeners = listen_input(window, &canvas, &state);
+    let mut camera = PreviewCamera::new();
+    let mut last_time: Option<f64> = None;
+    console::log_1(&"Starting desktop preview".into());
+
+    let animation_loop = Rc::new(RefCell::new(None::<Closure<dyn FnMut(f64)>>));
+    let animation_loop_clone = Rc::clone(&animation_loop);
+    let window_clone = window.clone();
+    *animation_loop.borrow_mut() = Some(Closure::wrap(Box::new(move |time: f64|{
+        // リスナーは描画ループと同じだけ生きていればよい
+        let _ = &listeners;
+        let delta_time = last_time.map(|last_time| ((time - last_time) / 1000.0) as f32).unwrap_or(0.0).min(MAX_DELTA_TIME);
+        last_time = Some(time);
+
+        if let Some(Ok(sources)) = shader_reload_rx.as_mut().map(|receiver| receiver.try_recv()){
+            reload_program(&mut renderer, &sources);
+        }
+
+        let input = {
+            let mut state = state.borrow_mut();
+            if std::mem::take(&mut state.toggle_mode){
+                camera.toggle_mode();
+            }
+            state.take()
+        };
+        camera.update(&input, delta_time);
+
+        let gl = renderer.gl().clone();
+        let (width, height) = resize_canvas(&window_clone, &canvas);
+        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+        gl.viewport(0, 0, width, height);
+        let [red, green, blue, alpha] = renderer.blend_mode.clear_color();
+        gl.clear_color(red, green, blue, alpha);
+        gl.clear_depth(1.0);
+        gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
+
+        let projection = projection_matrix(FIELD_OF_VIEW, width as f32 / height as f32, NEAR, FAR);
+        if let Err(error) = write_camera_matrices(&mut renderer.camera, 0, &camera.view_matrix(), &projection){
+            console::log_1(&format!("[Error] {}", error).into());
+        }
+        renderer.camera.upload(&gl);
+        render_scene(&renderer.program, Some(0), &scene, &renderer.meshes, &[]);
+
+        if let Some(animation_loop) = animation_loop_clone.borrow().as_ref(){
+            let _ = window_clone.request_animation_frame(animation_loop.as_ref().unchecked_ref());
+        }
+    }) as Box<dyn FnMut(f64)>));
+
+    if let Some(animation_loop) = animation_loop.borrow().as_ref(){
+        window.request_animation_frame(animation_loop.as_ref().unchecked_ref())?;
+    }
+    Ok(())
+}
+
+// 記録したセッションを、記録したときと同じ速さで繰り返し再生する
+// 入力はXRと同じFrameLoopに通して購読者に配り、ビューは記録したものをそのまま使ってXRと同じrender_frameで両目を並べて描く
+pub fn start_replay_preview(window: &Window, mut renderer: Renderer<WebGl2RenderingContext>, scene: Scene, recording: SessionRecording, mut shader_reload_rx: Option<mpsc::Receiver<ShaderSources>>, input_events: InputEventSubscribers)->Result<(),JsValue>{
+    if recording.frames.is_empty(){
+        return Err(JsValue::from_str("Session recording has no frames"));
+    }
+    let mut replay = ReplaySession::new(recording);
+    let mut start_time: Option<f64> = None;
+    // 記録には許可された機能が残らないので、手のジェスチャーも判定する
+    let features = EnabledFeatures::new([HAND_TRACKING_FEATURE]);
+    let interaction = Interaction::new(SessionMode::ImmersiveVr, &features, vec![WalkableArea::floor(WALKABLE_FLOOR_SIZE)]);
+    let mut frame_loop = FrameLoop::new(FrameClock::new(MAX_DELTA_TIME), interaction, input_events);
+    console::log_1(&"Starting session replay".into());
+
+    let animation_loop = Rc::new(RefCell::new(None::<Closure<dyn FnMut(f64)>>));
+    let animation_loop_clone = Rc::clone(&animation_loop);
+    let window_clone = window.clone();
+    *animation_loop.borrow_mut() = Some(Closure::wrap(Box::new(move |time: f64|{
+        if let Some(Ok(sources)) = shader_reload_rx.as_mut().map(|receiver| receiver.try_recv()){
+            reload_program(&mut renderer, &sources);
+        }
+
+        // 最後まで再生したら最初に戻る。記録の時刻も戻るので経過時間も測り直す
+        if replay.is_finished(){
+            replay.rewind();
+            start_time = None;
+            frame_loop.clock = FrameClock::new(MAX_DELTA_TIME);
+        }
+        let start = *start_time.get_or_insert(time);
+        // 描画が追いつかず飛ばしたフレームも、記録した順に処理してイベントを配る
+        for frame in replay.advance_to(time - start){
+            frame_loop.step(&mut ReplayFrameInput::new(frame), frame.time, frame.visibility);
+        }
+        if let Some(frame) = replay.current(){
+            renderer.input_models.receive(&renderer.program, &mut renderer.meshes);
+            renderer.input_models.request_profiles(&window_clone, &frame.input_sources);
+            let mut input_draws = renderer.input_models.draws(&frame.input_sources);
+            if let Some(arc) = frame_loop.interaction.locomotion.as_ref().and_then(|locomotion| locomotion.arc()){
+                input_draws.extend(renderer.input_models.arc_draws(arc));
+            }
+            if let Some(views) = frame.views(){
+                fit_canvas_to_views(renderer.gl(), &views);
+                render_frame(&views, None, &mut renderer, &scene, &input_draws);
+            }
+        }
+
+        if let Some(animation_loop) = animation_loop_clone.borrow().as_ref(){
+            let _ = window_clone.request_animation_frame(animation_loop.as_ref().unchecked_ref());
+        }
+    }) as Box<dyn FnMut(f64)>));
+
+    if let Some(animation_loop) = animation_loop.borrow().as_ref(){
+        window.request_animation_frame(animation_loop.as_ref().unchecked_ref())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use crate::{GlProgram,compile_preprocessed};
+use crate::platform::GraphicsDevice;
+use crate::preprocess::{self,Defines,PreprocessedSource,PreprocessError};
+use crate::shader::ShaderError;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ProgramError{
+    Preprocess(PreprocessError),
+    Shader(ShaderError),
+}
+
+impl std::fmt::Display for ProgramError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            ProgramError::Preprocess(error)=>write!(f, "{}", error),
+            ProgramError::Shader(error)=>write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ProgramError{}
+
+impl From<PreprocessError> for ProgramError{
+    fn from(error: PreprocessError)->Self{
+        ProgramError::Preprocess(error)
+    }
+}
+
+impl From<ShaderError> for ProgramError{
+    fn from(error: ShaderError)->Self{
+        ProgramError::Shader(error)
+    }
+}
+
+impl From<ProgramError> for JsValue{
+    fn from(error: ProgramError)->Self{
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+// キャッシュのキー。#include展開後のソースと、注入する#defineの組み合わせ
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct ProgramKey{
+    vertex: String,
+    fragment: String,
+    defines: Defines,
+}
+
+impl ProgramKey{
+    pub fn new(vertex: &PreprocessedSource, fragment: &PreprocessedSource, defines: &Defines)->Self{
+        ProgramKey{
+            vertex: vertex.code.clone(),
+            fragment: fragment.code.clone(),
+            defines: defines.clone(),
+        }
+    }
+}
+
+// コンパイル済みのプログラムを、ソースと#defineの組み合わせごとに使い回す
+pub struct ProgramCache<G: GraphicsDevice>{
+    gl: G,
+    programs: HashMap<ProgramKey,GlProgram<G>>,
+}
+
+impl<G: GraphicsDevice> ProgramCache<G>{
+    pub fn new(gl: &G)->Self{
+        ProgramCache{
+            gl: gl.clone(),
+            programs: HashMap::new(),
+        }
+    }
+
+    pub fn gl(&self)->&G{
+        &self.gl
+    }
+
+    pub fn len(&self)->usize{
+        self.programs.len()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.programs.is_empty()
+    }
+
+    // 同じ組み合わせがあればそれを返し、無ければ#defineを注入してコンパイルする
+    pub fn get_or_compile(&mut self, vertex: &PreprocessedSource, fragment: &PreprocessedSource, defines: &Defines)->Result<GlProgram<G>,ProgramError>{
+        let key = ProgramKey::new(vertex, fragment, defines);
+        if let Some(program) = self.programs.get(&key){
+            return Ok(program.clone());
+        }
+
+        let vertex = preprocess::inject_defines(vertex, defines)?;
+        let fragment = preprocess::inject_defines(fragment, defines)?;
+        let program = compile_preprocessed(&self.gl, &vertex, &fragment)?;
+        let gl_program = GlProgram::new(self.gl.clone(), program);
+        self.programs.insert(key, gl_program.clone());
+        Ok(gl_program)
+    }
+
+    // 使わなくなったプログラムをキャッシュから外して削除する
+    pub fn evict(&mut self, gl_program: &GlProgram<G>){
+        let before = self.programs.len();
+        self.programs.retain(|_, cached| !cached.same_program(gl_program));
+        if self.programs.len() != before{
+            self.gl.delete_program(Some(gl_program.program()));
+        }
+    }
+
+    // キャッシュしている全てのプログラムを削除する
+    pub fn clear(&mut self){
+        for (_, gl_program) in self.programs.drain(){
+            self.gl.delete_program(Some(gl_program.program()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::collections::HashMap;
+    use crate::compile_program;
+    use crate::mesh::VertexAttribute;
+    use crate::platform::mock::MockDevice;
+    use crate::preprocess::resolve_includes;
+    use crate::shader::ShaderStage;
+
+    const VERTEX: &str = "#version 300 es\nin vec3 vertex_position;\nin vec4 color;\nuniform mat4 model;\nout vec4 v_color;\nvoid main(){\n    v_color = color;\n    gl_Position = model * vec4(vertex_position, 1.0);\n}";
+    const FRAGMENT: &str = "#version 300 es\nprecision highp float;\nin vec4 v_color;\nout vec4 fragment_color;\nvoid main(){\n    fragment_color = v_color;\n}";
+
+    fn source(code: &str)->PreprocessedSource{
+        let files = HashMap::from([("shader.glsl".to_string(), code.to_string())]);
+        resolve_includes("shader.glsl", &files).unwrap()
+    }
+
+    #[test]
+    fn compiles_program_with_fixed_attribute_locations(){
+        let gl = MockDevice::new();
+        let program = compile_program(&gl, VERTEX, FRAGMENT).unwrap();
+        assert_eq!(gl.current_program(), Some(program));
+        // リンク後はシェーダが削除され、プログラムだけが残る
+        assert_eq!(gl.live_objects(), 1);
+        assert_eq!(gl.get_attrib_location(&program, "vertex_position"), 0);
+        let color = VertexAttribute::ALL.iter().position(|attribute| *attribute == VertexAttribute::Color).unwrap();
+        assert_eq!(gl.get_attrib_location(&program, "color"), color as i32);
+    }
+
+    #[test]
+    fn compile_errors_report_stage_and_free_shaders(){
+        let gl = MockDevice::new();
+        let broken = VERTEX.replace("out vec4 v_color;", "#error missing output");
+        let error = compile_program(&gl, &broken, FRAGMENT).unwrap_err();
+        assert_eq!(error.stage, ShaderStage::Vertex);
+        assert_eq!(error.diagnostics[0].line, Some(5));
+        assert_eq!(gl.live_objects(), 0);
+
+        // フラグメントシェーダで失敗したら、コンパイル済みの頂点シェーダも削除する
+        let broken = FRAGMENT.replace("precision highp float;", "#error no precision");
+        let error = compile_program(&gl, VERTEX, &broken).unwrap_err();
+        assert_eq!(error.stage, ShaderStage::Fragment);
+        assert_eq!(error.diagnostics[0].line, Some(2));
+        assert_eq!(gl.live_objects(), 0);
+        assert_eq!(gl.current_program(), None);
+    }
+
+    #[test]
+    fn link_errors_delete_program(){
+        let gl = MockDevice::new();
+        let fragment = FRAGMENT.replace("in vec4 v_color;", "in vec4 v_color;\nin vec3 v_normal;");
+        let error = compile_program(&gl, VERTEX, &fragment).unwrap_err();
+        assert_eq!(error.stage, ShaderStage::Link);
+        assert!(error.info_log.contains("v_normal"), "{}", error.info_log);
+        assert_eq!(gl.live_objects(), 0);
+
+        // プログラムを作れなかった場合もシェーダを残さない
+        gl.set_object_limit(Some(2));
+        let error = compile_program(&gl, VERTEX, FRAGMENT).unwrap_err();
+        assert_eq!(error.stage, ShaderStage::Link);
+        assert_eq!(gl.live_objects(), 0);
+    }
+
+    #[test]
+    fn cache_reuses_programs_per_source_and_defines(){
+        let gl = MockDevice::new();
+        let mut cache = ProgramCache::new(&gl);
+        let (vertex, fragment) = (source(VERTEX), source(FRAGMENT));
+        let first = cache.get_or_compile(&vertex, &fragment, &Defines::new()).unwrap();
+        let again = cache.get_or_compile(&vertex, &fragment, &Defines::new()).unwrap();
+        assert!(first.same_program(&again));
+        assert_eq!(cache.len(), 1);
+
+        let defines = Defines::new().with_flag("MULTIVIEW");
+        let variant = cache.get_or_compile(&vertex, &fragment, &defines).unwrap();
+        assert!(!variant.same_program(&first));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(gl.live_objects(), 2);
+        assert!(gl.shader_sources().is_empty());
+
+        cache.evict(&first);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(gl.live_objects(), 1);
+        // キャッシュに無いプログラムは削除しない
+        cache.evict(&first);
+        assert_eq!(gl.live_objects(), 1);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(gl.live_objects(), 0);
+    }
+
+    #[test]
+    fn cache_reports_errors_without_caching(){
+        let gl = MockDevice::new();
+        let mut cache = ProgramCache::new(&gl);
+        let files = HashMap::from([
+            ("shader/main.glsl".to_string(), "#version 300 es\n#include \"common.glsl\"\nvoid main(){}".to_string()),
+            ("shader/common.glsl".to_string(), "uniform mat4 model;\n#error broken include".to_string()),
+        ]);
+        let vertex = resolve_includes("shader/main.glsl", &files).unwrap();
+        let Err(ProgramError::Shader(error)) = cache.get_or_compile(&vertex, &source(FRAGMENT), &Defines::new().with_flag("DEBUG")) else{
+            panic!("compile error expected");
+        };
+        // #defineを注入した行の分もずらして、インクルード元の行に戻す
+        assert_eq!(error.stage, ShaderStage::Vertex);
+        assert_eq!(error.diagnostics[0].line, Some(4));
+        let origin = error.diagnostics[0].origin.as_ref().unwrap();
+        assert_eq!((origin.file.as_str(), origin.line), ("shader/common.glsl", 2));
+
+        let invalid = Defines::new().with_flag("NOT VALID");
+        assert!(matches!(cache.get_or_compile(&source(VERTEX), &source(FRAGMENT), &invalid), Err(ProgramError::Preprocess(_))));
+        assert!(cache.is_empty());
+        assert_eq!(gl.live_objects(), 0);
+    }
+}
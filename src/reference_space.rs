@@ -0,0 +1,299 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+use crate::session::{BOUNDED_FLOOR_FEATURE,LOCAL_FLOOR_FEATURE,SessionMode};
+
+// 参照空間の選択
+// 床を原点にできる空間から順に試し、どれも使えなければlocalを目の高さ分だけ下げて床の代わりにする
+
+// localしか使えないときに仮定する、床から目までの高さ(メートル)
+pub const DEFAULT_EYE_HEIGHT: f32 = 1.6;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ReferenceSpaceKind{
+    // 床が原点で、歩ける範囲の境界がある
+    BoundedFloor,
+    // 床が原点
+    LocalFloor,
+    // 開始時の頭の位置が原点
+    Local,
+    // 常に頭の位置が原点。inlineセッションで使う
+    Viewer,
+}
+
+impl ReferenceSpaceKind{
+    pub fn to_xr(&self)->XrReferenceSpaceType{
+        match self{
+            ReferenceSpaceKind::BoundedFloor=>XrReferenceSpaceType::BoundedFloor,
+            ReferenceSpaceKind::LocalFloor=>XrReferenceSpaceType::LocalFloor,
+            ReferenceSpaceKind::Local=>XrReferenceSpaceType::Local,
+            ReferenceSpaceKind::Viewer=>XrReferenceSpaceType::Viewer,
+        }
+    }
+
+    // セッションの開始時に要求しておく必要がある機能
+    pub fn feature(&self)->Option<&'static str>{
+        match self{
+            ReferenceSpaceKind::BoundedFloor=>Some(BOUNDED_FLOOR_FEATURE),
+            ReferenceSpaceKind::LocalFloor=>Some(LOCAL_FLOOR_FEATURE),
+            ReferenceSpaceKind::Local | ReferenceSpaceKind::Viewer=>None,
+        }
+    }
+
+    pub fn is_floor_level(&self)->bool{
+        matches!(self, ReferenceSpaceKind::BoundedFloor | ReferenceSpaceKind::LocalFloor)
+    }
+}
+
+impl std::fmt::Display for ReferenceSpaceKind{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            ReferenceSpaceKind::BoundedFloor=>write!(f, "bounded-floor"),
+            ReferenceSpaceKind::LocalFloor=>write!(f, "local-floor"),
+            ReferenceSpaceKind::Local=>write!(f, "local"),
+            ReferenceSpaceKind::Viewer=>write!(f, "viewer"),
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct ReferenceSpaceConfig{
+    // 試す参照空間。先頭ほど優先する
+    pub kinds: Vec<ReferenceSpaceKind>,
+    // 床の無い空間で、原点を下げる高さ。0.0なら下げない
+    pub emulated_eye_height: f32,
+}
+
+impl Default for ReferenceSpaceConfig{
+    fn default()->Self{
+        ReferenceSpaceConfig{
+            kinds: vec![ReferenceSpaceKind::BoundedFloor, ReferenceSpaceKind::LocalFloor, ReferenceSpaceKind::Local],
+            emulated_eye_height: DEFAULT_EYE_HEIGHT,
+        }
+    }
+}
+
+impl ReferenceSpaceConfig{
+    // inlineセッションではviewerしか使えるとは限らない
+    pub fn for_mode(mode: SessionMode)->Self{
+        if mode.is_immersive(){
+            ReferenceSpaceConfig::default()
+        }
+        else{
+            ReferenceSpaceConfig{
+                kinds: vec![ReferenceSpaceKind::Viewer],
+                emulated_eye_height: 0.0,
+            }
+        }
+    }
+}
+
+// bounded-floorの歩ける範囲。床の上のXZ平面の多角形
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Bounds{
+    pub points: Vec<[f32; 2]>,
+}
+
+impl Bounds{
+    pub fn from_xr(space: &XrBoundedReferenceSpace)->Self{
+        let points = space.bounds_geometry().iter()
+            .filter_map(|point| point.dyn_into::<DomPointReadOnly>().ok())
+            .map(|point| [point.x() as f32, point.z() as f32])
+            .collect();
+        Bounds{points}
+    }
+
+    // 境界が分からない(点が3つ未満)ときはfalse
+    pub fn contains(&self, x: f32, z: f32)->bool{
+        if self.points.len() < 3{
+            return false;
+        }
+        let mut inside = false;
+        let mut previous = self.points[self.points.len() - 1];
+        for point in self.points.iter(){
+            if (point[1] > z) != (previous[1] > z){
+                let crossing = point[0] + (z - point[1]) / (previous[1] - point[1]) * (previous[0] - point[0]);
+                if x < crossing{
+                    inside = !inside;
+                }
+            }
+            previous = *point;
+        }
+        inside
+    }
+
+    // 境界を囲む長方形の大きさ(幅, 奥行き)
+    pub fn size(&self)->[f32; 2]{
+        let mut min = [f32::MAX, f32::MAX];
+        let mut max = [f32::MIN, f32::MIN];
+        for point in self.points.iter(){
+            for axis in 0..2{
+                min[axis] = min[axis].min(point[axis]);
+                max[axis] = max[axis].max(point[axis]);
+            }
+        }
+        if self.points.is_empty(){
+            return [0.0, 0.0];
+        }
+        [max[0] - min[0], max[1] - min[1]]
+    }
+}
+
+// 選ばれた参照空間
+// 描画や入力にはspace()を使う。床を真似ている場合はbaseを下げたfloorを使い、
+// 移動した場合はさらにfloorをオフセットした空間になっている
+pub struct ReferenceSpace{
+    kind: ReferenceSpaceKind,
+    base: XrReferenceSpace,
+    floor: XrReferenceSpace,
+    space: XrReferenceSpace,
+    bounds: Option<Bounds>,
+    reset: Rc<Cell<bool>>,
+    reset_listener: Closure<dyn FnMut(Event)>,
+}
+
+impl ReferenceSpace{
+    // 設定の順に参照空間を要求し、最初に取得できたものを使う
+    pub async fn request(session: &XrSession, config: &ReferenceSpaceConfig)->Result<Self,JsValue>{
+        for kind in config.kinds.iter().copied(){
+            let Ok(space) = JsFuture::from(session.request_reference_space(kind.to_xr())).await else{
+                console::log_1(&format!("[Warning] {} reference space is not available", kind).into());
+                continue;
+            };
+            let Ok(base) = space.dyn_into::<XrReferenceSpace>() else{
+                continue;
+            };
+            console::log_1(&format!("Using {} reference space", kind).into());
+            return ReferenceSpace::new(kind, base, config);
+        }
+        Err(JsValue::from_str("No reference space is available"))
+    }
+
+    fn new(kind: ReferenceSpaceKind, base: XrReferenceSpace, config: &ReferenceSpaceConfig)->Result<Self,JsValue>{
+        // 床の高さが分からないので、頭が目の高さに来るように原点を下げる
+        let floor = if kind.is_floor_level() || config.emulated_eye_height == 0.0{
+            base.clone()
+        }
+        else{
+            let position = DomPointInit::new();
+            position.set_y(-config.emulated_eye_height as f64);
+            base.get_offset_reference_space(&XrRigidTransform::new_with_position(&position)?)
+        };
+        let bounds = base.dyn_ref::<XrBoundedReferenceSpace>().map(Bounds::from_xr);
+
+        // ユーザーが向きをリセットしたときに、境界を読み直すための印を立てる
+        let reset = Rc::new(Cell::new(false));
+        let reset_clone = Rc::clone(&reset);
+        let reset_listener = Closure::wrap(Box::new(move |_: Event|{
+            reset_clone.set(true);
+        }) as Box<dyn FnMut(Event)>);
+        if base.add_event_listener_with_callback("reset", reset_listener.as_ref().unchecked_ref()).is_err(){
+            console::log_1(&"[Error] Could not listen to reset events".into());
+        }
+        Ok(ReferenceSpace{kind, base, space: floor.clone(), floor, bounds, reset, reset_listener})
+    }
+
+    pub fn kind(&self)->ReferenceSpaceKind{
+        self.kind
+    }
+
+    pub fn space(&self)->&XrReferenceSpace{
+        &self.space
+    }
+
+    // 床の原点から見たワールドの原点の(位置, クォータニオン)を設定する
+    // 以降の姿勢はオフセットした空間、つまりワールド座標で届く
+    pub fn set_origin_offset(&mut self, position: [f32; 3], orientation: [f32; 4])->Result<(),JsValue>{
+        let point = DomPointInit::new();
+        point.set_x(position[0] as f64);
+        point.set_y(position[1] as f64);
+        point.set_z(position[2] as f64);
+        let rotation = DomPointInit::new();
+        rotation.set_x(orientation[0] as f64);
+        rotation.set_y(orientation[1] as f64);
+        rotation.set_z(orientation[2] as f64);
+        rotation.set_w(orientation[3] as f64);
+        let transform = XrRigidTransform::new_with_position_and_orientation(&point, &rotation)?;
+        self.space = self.floor.get_offset_reference_space(&transform);
+        Ok(())
+    }
+
+    pub fn bounds(&self)->Option<&Bounds>{
+        self.bounds.as_ref()
+    }
+
+    // 前回の呼び出しからresetイベントが来ていればtrue
+    // 姿勢は新しい原点基準で届くので、ここでは境界だけを読み直す
+    pub fn take_reset(&mut self)->bool{
+        if !self.reset.replace(false){
+            return false;
+        }
+        if let Some(bounded) = self.base.dyn_ref::<XrBoundedReferenceSpace>(){
+            self.bounds = Some(Bounds::from_xr(bounded));
+        }
+        console::log_1(&format!("{} reference space was reset", self.kind).into());
+        true
+    }
+}
+
+impl Drop for ReferenceSpace{
+    fn drop(&mut self){
+        let _ = self.base.remove_event_listener_with_callback("reset", self.reset_listener.as_ref().unchecked_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn square()->Bounds{
+        Bounds{points: vec![[0.0, 0.0], [2.0, 0.0], [2.0, 2.0], [0.0, 2.0]]}
+    }
+
+    #[test]
+    fn contains_points_inside_the_polygon(){
+        let bounds = square();
+        assert!(bounds.contains(1.0, 1.0));
+        assert!(!bounds.contains(3.0, 1.0));
+        assert!(!bounds.contains(1.0, -0.5));
+
+        // へこんだL字の、へこみの部分は外側
+        let l_shape = Bounds{points: vec![[0.0, 0.0], [2.0, 0.0], [2.0, 1.0], [1.0, 1.0], [1.0, 2.0], [0.0, 2.0]]};
+        assert!(l_shape.contains(0.5, 1.5));
+        assert!(l_shape.contains(1.5, 0.5));
+        assert!(!l_shape.contains(1.5, 1.5));
+    }
+
+    #[test]
+    fn contains_treats_edges_as_half_open(){
+        let bounds = square();
+        // 左と手前の辺は内側、右と奥の辺は外側になるので、隣り合う多角形で二重に数えない
+        assert!(bounds.contains(0.0, 1.0));
+        assert!(bounds.contains(1.0, 0.0));
+        assert!(!bounds.contains(2.0, 1.0));
+        assert!(!bounds.contains(1.0, 2.0));
+        // 頂点と同じ高さを通る水平線でも、交差を二重に数えない
+        let diamond = Bounds{points: vec![[1.0, 0.0], [2.0, 1.0], [1.0, 2.0], [0.0, 1.0]]};
+        assert!(diamond.contains(1.0, 1.0));
+        assert!(!diamond.contains(-0.5, 1.0));
+        assert!(!diamond.contains(2.5, 1.0));
+    }
+
+    #[test]
+    fn contains_is_false_without_a_polygon(){
+        assert!(!Bounds::default().contains(0.0, 0.0));
+        assert!(!Bounds{points: vec![[0.0, 0.0], [1.0, 1.0]]}.contains(0.5, 0.5));
+    }
+
+    #[test]
+    fn size_is_the_bounding_rectangle(){
+        assert_eq!(square().size(), [2.0, 2.0]);
+        let offset = Bounds{points: vec![[-1.5, -3.0], [0.5, -1.0], [-0.5, 1.0]]};
+        assert_eq!(offset.size(), [2.0, 4.0]);
+        assert_eq!(Bounds::default().size(), [0.0, 0.0]);
+        assert_eq!(Bounds{points: vec![[1.0, 1.0]]}.size(), [0.0, 0.0]);
+    }
+}
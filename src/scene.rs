@@ -0,0 +1,386 @@
+use gl_matrix::common::*;
+use gl_matrix::mat4;
+
+// シーン内のノードを指すハンドル
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct NodeId(pub usize);
+
+// レンダラ側で管理しているメッシュを指すハンドル
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct MeshHandle(pub usize);
+
+// レンダラ側で管理しているマテリアルを指すハンドル
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct MaterialHandle(pub usize);
+
+// 親ノードに対するローカルな変換(平行移動・回転・拡大縮小)
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Transform{
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform{
+    fn default()->Self{
+        Transform{
+            translation: [0.0,0.0,0.0],
+            rotation: [0.0,0.0,0.0,1.0],
+            scale: [1.0,1.0,1.0],
+        }
+    }
+}
+
+impl Transform{
+    pub fn from_translation(translation: Vec3)->Self{
+        Transform{
+            translation,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: Quat)->Self{
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: Vec3)->Self{
+        self.scale = scale;
+        self
+    }
+
+    // T * R * S の順で合成したローカル行列
+    pub fn matrix(&self)->Mat4{
+        let mut matrix = mat4::create();
+        mat4::from_rotation_translation_scale(&mut matrix, &self.rotation, &self.translation, &self.scale);
+        matrix
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Node{
+    pub name: String,
+    pub transform: Transform,
+    pub mesh: Option<MeshHandle>,
+    pub material: Option<MaterialHandle>,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+}
+
+impl Node{
+    pub fn new(name: &str)->Self{
+        Node{
+            name: name.to_string(),
+            transform: Transform::default(),
+            mesh: None,
+            material: None,
+            parent: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_transform(mut self, transform: Transform)->Self{
+        self.transform = transform;
+        self
+    }
+
+    pub fn with_mesh(mut self, mesh: MeshHandle)->Self{
+        self.mesh = Some(mesh);
+        self
+    }
+
+    pub fn with_material(mut self, material: MaterialHandle)->Self{
+        self.material = Some(material);
+        self
+    }
+
+    pub fn parent(&self)->Option<NodeId>{
+        self.parent
+    }
+
+    pub fn children(&self)->&[NodeId]{
+        &self.children
+    }
+}
+
+// 描画対象となるノード1つ分の情報
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct DrawItem{
+    pub node: NodeId,
+    pub world: Mat4,
+    pub mesh: MeshHandle,
+    pub material: Option<MaterialHandle>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum SceneError{
+    UnknownNode(NodeId),
+    // 親子関係を設定すると循環してしまう場合
+    Cycle{node: NodeId, parent: NodeId},
+}
+
+impl std::fmt::Display for SceneError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            SceneError::UnknownNode(id)=>write!(f, "unknown node {}", id.0),
+            SceneError::Cycle{node, parent}=>write!(f, "making node {} a child of node {} would create a cycle", node.0, parent.0),
+        }
+    }
+}
+
+impl std::error::Error for SceneError{}
+
+// ノードの親子関係を持つシーングラフ
+// web-sysに依存しないので、ネイティブ環境でも行列計算を確認できる
+// 削除したノードの場所は空けたままにして、他のノードのNodeIdが変わらないようにする
+#[derive(Debug,Clone,Default)]
+pub struct Scene{
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>,
+}
+
+impl Scene{
+    pub fn new()->Self{
+        Scene::default()
+    }
+
+    // 削除されていないノードの数
+    pub fn len(&self)->usize{
+        self.nodes.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.len() == 0
+    }
+
+    pub fn roots(&self)->&[NodeId]{
+        &self.roots
+    }
+
+    pub fn node(&self, id: NodeId)->Option<&Node>{
+        self.nodes.get(id.0).and_then(|node| node.as_ref())
+    }
+
+    pub fn node_mut(&mut self, id: NodeId)->Option<&mut Node>{
+        self.nodes.get_mut(id.0).and_then(|node| node.as_mut())
+    }
+
+    pub fn find(&self, name: &str)->Option<NodeId>{
+        self.nodes.iter().position(|node| node.as_ref().is_some_and(|node| node.name == name)).map(NodeId)
+    }
+
+    // 存在するノードだけを受け付ける
+    fn existing(&self, id: NodeId)->Result<&Node,SceneError>{
+        self.node(id).ok_or(SceneError::UnknownNode(id))
+    }
+
+    // ノードを追加する。parentがNoneならルートノードになる
+    pub fn add_node(&mut self, parent: Option<NodeId>, mut node: Node)->Result<NodeId,SceneError>{
+        if let Some(parent) = parent{
+            self.existing(parent)?;
+        }
+        let id = NodeId(self.nodes.len());
+        node.parent = parent;
+        node.children.clear();
+        self.nodes.push(Some(node));
+        self.attach(id, parent);
+        Ok(id)
+    }
+
+    // ノードの親を付け替える。循環する場合はエラー
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>)->Result<(),SceneError>{
+        self.existing(id)?;
+        if let Some(parent) = parent{
+            self.existing(parent)?;
+            // 新しい親から根に向かって辿り、自分自身が見つかれば循環
+            let mut current = Some(parent);
+            while let Some(ancestor) = current{
+                if ancestor == id{
+                    return Err(SceneError::Cycle{node: id, parent});
+                }
+                current = self.node(ancestor).and_then(|node| node.parent);
+            }
+        }
+
+        self.detach(id);
+        if let Some(node) = self.node_mut(id){
+            node.parent = parent;
+        }
+        self.attach(id, parent);
+        Ok(())
+    }
+
+    // ノードを子孫ごと削除し、削除したノードを深さ優先の順に返す
+    pub fn remove_node(&mut self, id: NodeId)->Result<Vec<NodeId>,SceneError>{
+        self.existing(id)?;
+        self.detach(id);
+        let mut removed = Vec::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop(){
+            if let Some(node) = self.nodes[id.0].take(){
+                stack.extend(node.children.iter().rev());
+                removed.push(id);
+            }
+        }
+        Ok(removed)
+    }
+
+    fn attach(&mut self, id: NodeId, parent: Option<NodeId>){
+        match parent.and_then(|parent| self.node_mut(parent)){
+            Some(parent)=>parent.children.push(id),
+            None=>self.roots.push(id),
+        }
+    }
+
+    // 今の親の子供、またはルートの一覧から外す
+    fn detach(&mut self, id: NodeId){
+        match self.node(id).and_then(|node| node.parent){
+            Some(old_parent)=>{
+                if let Some(old_parent) = self.node_mut(old_parent){
+                    old_parent.children.retain(|child| *child != id);
+                }
+            },
+            None=>self.roots.retain(|root| *root != id),
+        }
+    }
+
+    // 全ノードのワールド行列をNodeIdの順に計算する。削除したノードは単位行列
+    pub fn world_matrices(&self)->Vec<Mat4>{
+        let mut worlds = vec![mat4::create(); self.nodes.len()];
+        let mut stack: Vec<(NodeId,Mat4)> = self.roots.iter().rev().map(|root| (*root, mat4::create())).collect();
+        while let Some((id,parent_world)) = stack.pop(){
+            let Some(node) = self.node(id) else{
+                continue;
+            };
+            let local = node.transform.matrix();
+            mat4::multiply(&mut worlds[id.0], &parent_world, &local);
+            for child in node.children.iter().rev(){
+                stack.push((*child, worlds[id.0]));
+            }
+        }
+        worlds
+    }
+
+    // 指定したノードのワールド行列
+    pub fn world_matrix(&self, id: NodeId)->Option<Mat4>{
+        let mut node = self.node(id)?;
+        let mut world = node.transform.matrix();
+        while let Some(parent) = node.parent{
+            node = self.node(parent)?;
+            let world_clone = world;
+            mat4::multiply(&mut world, &node.transform.matrix(), &world_clone);
+        }
+        Some(world)
+    }
+
+    // メッシュを持つノードを、ワールド行列付きで深さ優先の順に列挙する
+    pub fn draw_items(&self)->Vec<DrawItem>{
+        let worlds = self.world_matrices();
+        let mut items = Vec::new();
+        let mut stack: Vec<NodeId> = self.roots.iter().rev().copied().collect();
+        while let Some(id) = stack.pop(){
+            let Some(node) = self.node(id) else{
+                continue;
+            };
+            if let Some(mesh) = node.mesh{
+                items.push(DrawItem{
+                    node: id,
+                    world: worlds[id.0],
+                    mesh,
+                    material: node.material,
+                });
+            }
+            stack.extend(node.children.iter().rev());
+        }
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn assert_translation(matrix: &Mat4, expected: Vec3){
+        for (axis, value) in expected.iter().enumerate(){
+            assert!((matrix[12 + axis] - value).abs() < 1e-5, "{:?} != {:?}", &matrix[12..15], expected);
+        }
+    }
+
+    // root(1,0,0, Y軸90度回転) -> child(0,0,-1, 2倍) -> grandchild(1,0,0)
+    fn hierarchy()->(Scene, NodeId, NodeId, NodeId){
+        let quarter = std::f32::consts::FRAC_PI_4;
+        let mut scene = Scene::new();
+        let root = scene.add_node(None, Node::new("root")
+            .with_transform(Transform::from_translation([1.0,0.0,0.0]).with_rotation([0.0,quarter.sin(),0.0,quarter.cos()]))).unwrap();
+        let child = scene.add_node(Some(root), Node::new("child")
+            .with_transform(Transform::from_translation([0.0,0.0,-1.0]).with_scale([2.0,2.0,2.0]))
+            .with_mesh(MeshHandle(0))).unwrap();
+        let grandchild = scene.add_node(Some(child), Node::new("grandchild")
+            .with_transform(Transform::from_translation([1.0,0.0,0.0]))
+            .with_mesh(MeshHandle(1))
+            .with_material(MaterialHandle(3))).unwrap();
+        (scene, root, child, grandchild)
+    }
+
+    #[test]
+    fn world_matrices_compose_parent_transforms(){
+        let (scene, root, child, grandchild) = hierarchy();
+        let worlds = scene.world_matrices();
+        assert_translation(&worlds[root.0], [1.0,0.0,0.0]);
+        // Y軸90度回転で-Zは-Xになる
+        assert_translation(&worlds[child.0], [0.0,0.0,0.0]);
+        // 子の2倍の拡大も掛かり、+Xは-Zになる
+        assert_translation(&worlds[grandchild.0], [0.0,0.0,-2.0]);
+        for id in [root, child, grandchild]{
+            assert_eq!(scene.world_matrix(id).map(|matrix| matrix.map(|value| (value * 1e4).round())), Some(worlds[id.0].map(|value| (value * 1e4).round())));
+        }
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles(){
+        let (mut scene, root, child, grandchild) = hierarchy();
+        assert_eq!(scene.set_parent(root, Some(grandchild)), Err(SceneError::Cycle{node: root, parent: grandchild}));
+        assert_eq!(scene.set_parent(child, Some(child)), Err(SceneError::Cycle{node: child, parent: child}));
+        assert_eq!(scene.set_parent(child, Some(NodeId(10))), Err(SceneError::UnknownNode(NodeId(10))));
+        // 失敗しても関係は変わらない
+        assert_eq!(scene.node(grandchild).unwrap().parent(), Some(child));
+
+        scene.set_parent(grandchild, None).unwrap();
+        assert_eq!(scene.roots(), &[root, grandchild]);
+        assert!(scene.node(child).unwrap().children().is_empty());
+        assert_translation(&scene.world_matrices()[grandchild.0], [1.0,0.0,0.0]);
+    }
+
+    #[test]
+    fn remove_node_removes_subtree(){
+        let (mut scene, root, child, grandchild) = hierarchy();
+        let other = scene.add_node(None, Node::new("other").with_mesh(MeshHandle(2))).unwrap();
+        assert_eq!(scene.remove_node(child), Ok(vec![child, grandchild]));
+        assert_eq!(scene.len(), 2);
+        assert!(scene.node(grandchild).is_none());
+        assert!(scene.node(root).unwrap().children().is_empty());
+        assert_eq!(scene.find("grandchild"), None);
+        // 残ったノードのNodeIdは変わらない
+        assert_eq!(scene.find("other"), Some(other));
+        assert_eq!(scene.remove_node(child), Err(SceneError::UnknownNode(child)));
+        assert_eq!(scene.set_parent(other, Some(child)), Err(SceneError::UnknownNode(child)));
+
+        scene.remove_node(root).unwrap();
+        assert_eq!(scene.roots(), &[other]);
+        let added = scene.add_node(None, Node::new("added")).unwrap();
+        assert_eq!(added, NodeId(4));
+    }
+
+    #[test]
+    fn draw_items_lists_meshes_depth_first(){
+        let (mut scene, root, child, grandchild) = hierarchy();
+        let sibling = scene.add_node(Some(root), Node::new("sibling").with_mesh(MeshHandle(2))).unwrap();
+        let items = scene.draw_items();
+        // メッシュを持たないrootは含まない
+        assert_eq!(items.iter().map(|item| item.node).collect::<Vec<_>>(), vec![child, grandchild, sibling]);
+        assert_eq!(items[1].mesh, MeshHandle(1));
+        assert_eq!(items[1].material, Some(MaterialHandle(3)));
+        assert_eq!(items[0].material, None);
+        assert_translation(&items[1].world, [0.0,0.0,-2.0]);
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeSet;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+use crate::hand::HAND_TRACKING_FEATURE;
+use crate::platform::XrBackend;
+#[cfg(target_arch = "wasm32")]
+use crate::platform::web::WebXrSystem;
+
+// XRセッションのモードと機能の選択
+// 呼び出し側が優先順にモードを並べ、is_session_supportedで使える最初のものを選ぶ
+// 機能はrequired(無ければ開始しない)とoptional(無くても開始する)に分けて要求する
+
+// XRSessionInitに渡す機能名。hand-trackingはhand.rsのHAND_TRACKING_FEATURE
+pub const LOCAL_FLOOR_FEATURE: &str = "local-floor";
+pub const BOUNDED_FLOOR_FEATURE: &str = "bounded-floor";
+pub const HIT_TEST_FEATURE: &str = "hit-test";
+pub const ANCHORS_FEATURE: &str = "anchors";
+pub const LAYERS_FEATURE: &str = "layers";
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum SessionMode{
+    ImmersiveVr,
+    ImmersiveAr,
+    // ページのcanvasに描画する、ヘッドセット無しのセッション
+    Inline,
+}
+
+impl SessionMode{
+    pub fn to_xr(&self)->XrSessionMode{
+        match self{
+            SessionMode::ImmersiveVr=>XrSessionMode::ImmersiveVr,
+            SessionMode::ImmersiveAr=>XrSessionMode::ImmersiveAr,
+            SessionMode::Inline=>XrSessionMode::Inline,
+        }
+    }
+
+    pub fn is_immersive(&self)->bool{
+        !matches!(self, SessionMode::Inline)
+    }
+}
+
+impl std::fmt::Display for SessionMode{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            SessionMode::ImmersiveVr=>write!(f, "immersive-vr"),
+            SessionMode::ImmersiveAr=>write!(f, "immersive-ar"),
+            SessionMode::Inline=>write!(f, "inline"),
+        }
+    }
+}
+
+// 表示装置が描画結果と現実の景色をどう合成するか
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BlendMode{
+    // VRヘッドセット。描画したものだけが見える
+    Opaque,
+    // 光学シースルー。黒は透明になる
+    Additive,
+    // パススルー。アルファ値で合成される
+    AlphaBlend,
+}
+
+impl BlendMode{
+    pub fn from_name(name: &str)->Option<Self>{
+        match name{
+            "opaque"=>Some(BlendMode::Opaque),
+            "additive"=>Some(BlendMode::Additive),
+            "alpha-blend"=>Some(BlendMode::AlphaBlend),
+            _=>None,
+        }
+    }
+
+    // web-sysにはXRSession.environmentBlendModeが無いので、プロパティを直接読む
+    pub fn of_session(session: &XrSession)->Self{
+        js_sys::Reflect::get(session, &"environmentBlendMode".into()).ok()
+            .and_then(|mode| mode.as_string())
+            .and_then(|mode| BlendMode::from_name(&mode))
+            .unwrap_or(BlendMode::Opaque)
+    }
+
+    // 現実の景色が見えるモードでは、背景を透明にクリアする
+    pub fn clear_color(&self)->[f32; 4]{
+        match self{
+            BlendMode::Opaque=>[0.0, 0.0, 0.0, 1.0],
+            BlendMode::Additive | BlendMode::AlphaBlend=>[0.0, 0.0, 0.0, 0.0],
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SessionConfig{
+    // 試すモード。先頭ほど優先する
+    pub modes: Vec<SessionMode>,
+    // 使えなければセッションの開始に失敗する機能
+    pub required_features: Vec<String>,
+    // 使えなくてもセッションを開始する機能
+    pub optional_features: Vec<String>,
+}
+
+impl Default for SessionConfig{
+    // VRを優先し、ARも使えなければデスクトッププレビューにする
+    // inlineはデスクトップのブラウザでも使えてしまい、プレビューに進めなくなるので既定では試さない
+    // 床を原点にする参照空間は、セッションの開始時に要求しておかないと取得できない
+    fn default()->Self{
+        SessionConfig{
+            modes: vec![SessionMode::ImmersiveVr, SessionMode::ImmersiveAr],
+            required_features: Vec::new(),
+            optional_features: vec![
+                BOUNDED_FLOOR_FEATURE.to_string(),
+                LOCAL_FLOOR_FEATURE.to_string(),
+                HAND_TRACKING_FEATURE.to_string(),
+            ],
+        }
+    }
+}
+
+impl SessionConfig{
+    pub fn new(modes: &[SessionMode])->Self{
+        SessionConfig{
+            modes: modes.to_vec(),
+            ..SessionConfig::default()
+        }
+    }
+
+    // requiredに入れた機能はoptionalからは外す
+    pub fn with_required_feature(mut self, feature: &str)->Self{
+        self.optional_features.retain(|optional| optional != feature);
+        if !self.required_features.iter().any(|required| required == feature){
+            self.required_features.push(feature.to_string());
+        }
+        self
+    }
+
+    // 既にrequiredに入っている機能は何もしない
+    pub fn with_optional_feature(mut self, feature: &str)->Self{
+        if self.required_features.iter().any(|required| required == feature){
+            return self;
+        }
+        if !self.optional_features.iter().any(|optional| optional == feature){
+            self.optional_features.push(feature.to_string());
+        }
+        self
+    }
+
+    // 対応しているモードのうち、最も優先度の高いもの
+    pub fn negotiate(&self, is_supported: impl Fn(SessionMode)->bool)->Option<SessionMode>{
+        self.modes.iter().copied().find(|mode| is_supported(*mode))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn session_init(&self)->XrSessionInit{
+        let session_init = XrSessionInit::new();
+        let required_features: js_sys::Array = self.required_features.iter().map(|feature| JsValue::from_str(feature)).collect();
+        let optional_features: js_sys::Array = self.optional_features.iter().map(|feature| JsValue::from_str(feature)).collect();
+        session_init.set_required_features(&required_features);
+        session_init.set_optional_features(&optional_features);
+        session_init
+    }
+}
+
+// セッションで実際に有効になった機能
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct EnabledFeatures{
+    features: BTreeSet<String>,
+}
+
+impl EnabledFeatures{
+    pub fn new<'a>(features: impl IntoIterator<Item = &'a str>)->Self{
+        EnabledFeatures{
+            features: features.into_iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    // web-sysにはXRSession.enabledFeaturesが無いので、プロパティを直接読む
+    // enabledFeaturesを持たない古いブラウザでは、requiredの機能だけが有効だとみなす
+    pub fn of_session(session: &XrSession, config: &SessionConfig)->Self{
+        let enabled = js_sys::Reflect::get(session, &"enabledFeatures".into()).ok()
+            .filter(js_sys::Array::is_array)
+            .map(|features| js_sys::Array::from(&features));
+        match enabled{
+            Some(features)=>EnabledFeatures{
+                features: features.iter().filter_map(|feature| feature.as_string()).collect(),
+            },
+            None=>EnabledFeatures::new(config.required_features.iter().map(|feature| feature.as_str())),
+        }
+    }
+
+    pub fn contains(&self, feature: &str)->bool{
+        self.features.contains(feature)
+    }
+
+    pub fn iter(&self)->impl Iterator<Item = &str>{
+        self.features.iter().map(|feature| feature.as_str())
+    }
+
+    // 要求したoptionalの機能のうち、有効にならなかったもの
+    pub fn missing_optional<'a>(&self, config: &'a SessionConfig)->Vec<&'a str>{
+        config.optional_features.iter()
+            .map(|feature| feature.as_str())
+            .filter(|feature| !self.contains(feature))
+            .collect()
+    }
+}
+
+// 開始したセッションと、選ばれたモード・有効になった機能
+#[derive(Debug,Clone)]
+pub struct ActiveSession{
+    pub session: XrSession,
+    pub mode: SessionMode,
+    pub features: EnabledFeatures,
+}
+
+// 設定の優先順にモードを確かめ、最初に使えるモードを返す
+// negotiateは同期の判定関数を取るので、先に全てのモードの対応状況を調べておく
+pub async fn negotiate_mode<B: XrBackend>(backend: &B, config: &SessionConfig)->Option<SessionMode>{
+    let mut supported = Vec::new();
+    for mode in config.modes.iter(){
+        if backend.is_session_supported(*mode).await{
+            supported.push(*mode);
+        }
+    }
+    config.negotiate(|mode| supported.contains(&mode))
+}
+
+// 設定の優先順にモードを確かめ、最初に使えたモードでセッションを開始する
+// どのモードも使えなければNone。requiredの機能が使えない場合はErr
+#[cfg(target_arch = "wasm32")]
+pub async fn request_session(xrsystem: &XrSystem, config: &SessionConfig)->Result<Option<ActiveSession>,JsValue>{
+    let backend = WebXrSystem{system: xrsystem.clone()};
+    let Some(mode) = negotiate_mode(&backend, config).await else{
+        return Ok(None);
+    };
+    console::log_1(&format!("WebXR {} is Available!", mode).into());
+
+    let session_jsval = JsFuture::from(xrsystem.request_session_with_options(mode.to_xr(), &config.session_init())).await?;
+    let xrsession = session_jsval.dyn_into::<XrSession>()?;
+    let features = EnabledFeatures::of_session(&xrsession, config);
+    console::log_1(&format!("Enabled features: {}", features.iter().collect::<Vec<_>>().join(", ")).into());
+    let missing = features.missing_optional(config);
+    if !missing.is_empty(){
+        console::log_1(&format!("[Warning] Optional features were not granted: {}", missing.join(", ")).into());
+    }
+    Ok(Some(ActiveSession{session: xrsession, mode, features}))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn negotiate_picks_first_supported_mode_in_priority_order(){
+        let config = SessionConfig::new(&[SessionMode::ImmersiveAr, SessionMode::ImmersiveVr, SessionMode::Inline]);
+        assert_eq!(config.negotiate(|_| true), Some(SessionMode::ImmersiveAr));
+        assert_eq!(config.negotiate(|mode| mode != SessionMode::ImmersiveAr), Some(SessionMode::ImmersiveVr));
+        assert_eq!(config.negotiate(|mode| mode == SessionMode::Inline), Some(SessionMode::Inline));
+        assert_eq!(config.negotiate(|_| false), None);
+        // 設定に無いモードは、対応していても選ばない
+        let vr_only = SessionConfig::new(&[SessionMode::ImmersiveVr]);
+        assert_eq!(vr_only.negotiate(|mode| mode == SessionMode::ImmersiveAr), None);
+    }
+
+    #[test]
+    fn default_config_only_negotiates_immersive_modes(){
+        let config = SessionConfig::default();
+        // inlineしか使えない環境(デスクトップのChromeなど)ではセッションを開始せず、プレビューにする
+        assert_eq!(config.negotiate(|mode| mode == SessionMode::Inline), None);
+        assert_eq!(config.negotiate(|_| true), Some(SessionMode::ImmersiveVr));
+        assert_eq!(config.negotiate(|mode| mode != SessionMode::ImmersiveVr), Some(SessionMode::ImmersiveAr));
+    }
+
+    #[test]
+    fn optional_features_are_not_duplicated(){
+        let config = SessionConfig::new(&[SessionMode::ImmersiveVr])
+            .with_optional_feature("local-floor")
+            .with_optional_feature("local-floor")
+            .with_optional_feature(HAND_TRACKING_FEATURE)
+            .with_optional_feature("layers");
+        assert_eq!(config.optional_features, [BOUNDED_FLOOR_FEATURE, LOCAL_FLOOR_FEATURE, HAND_TRACKING_FEATURE, "layers"].map(String::from).to_vec());
+    }
+
+    #[test]
+    fn required_feature_moves_out_of_optional(){
+        let config = SessionConfig::new(&[SessionMode::ImmersiveVr])
+            .with_required_feature(HAND_TRACKING_FEATURE)
+            .with_required_feature(HAND_TRACKING_FEATURE)
+            // requiredに入っている機能はoptionalに戻らない
+            .with_optional_feature(HAND_TRACKING_FEATURE)
+            .with_optional_feature("layers");
+        assert_eq!(config.required_features, vec![HAND_TRACKING_FEATURE.to_string()]);
+        assert_eq!(config.optional_features, [BOUNDED_FLOOR_FEATURE, LOCAL_FLOOR_FEATURE, "layers"].map(String::from).to_vec());
+
+        // 後からrequiredにすれば、optionalからは外れる
+        let config = config.with_required_feature("layers");
+        assert_eq!(config.required_features, vec![HAND_TRACKING_FEATURE.to_string(), "layers".to_string()]);
+        assert_eq!(config.optional_features, [BOUNDED_FLOOR_FEATURE, LOCAL_FLOOR_FEATURE].map(String::from).to_vec());
+    }
+
+    #[test]
+    fn missing_optional_lists_features_that_were_not_granted(){
+        let config = SessionConfig::new(&[SessionMode::ImmersiveVr])
+            .with_optional_feature("local-floor")
+            .with_required_feature("layers");
+        let features = EnabledFeatures::new(["local-floor", "layers", "viewer"]);
+        assert_eq!(features.missing_optional(&config), vec![BOUNDED_FLOOR_FEATURE, HAND_TRACKING_FEATURE]);
+        assert!(features.contains("viewer"));
+        // requiredの機能は、有効になっていなくてもoptionalとしては数えない
+        let features = EnabledFeatures::new([HAND_TRACKING_FEATURE]);
+        assert_eq!(features.missing_optional(&config), vec![BOUNDED_FLOOR_FEATURE, LOCAL_FLOOR_FEATURE]);
+        assert_eq!(EnabledFeatures::default().missing_optional(&config), vec![BOUNDED_FLOOR_FEATURE, LOCAL_FLOOR_FEATURE, HAND_TRACKING_FEATURE]);
+    }
+
+    #[test]
+    fn blend_mode_clears_transparent_only_for_see_through_displays(){
+        assert_eq!(BlendMode::from_name("alpha-blend"), Some(BlendMode::AlphaBlend));
+        assert_eq!(BlendMode::from_name("unknown"), None);
+        assert_eq!(BlendMode::Opaque.clear_color()[3], 1.0);
+        assert_eq!(BlendMode::Additive.clear_color()[3], 0.0);
+        assert_eq!(BlendMode::AlphaBlend.clear_color()[3], 0.0);
+    }
+}
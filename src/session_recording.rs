@@ -0,0 +1,835 @@
+use gl_matrix::mat4;
+use wasm_bindgen::prelude::*;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::JsCast;
+#[cfg(target_arch = "wasm32")]
+use web_sys::*;
+use crate::gamepad::{ButtonSnapshot,GamepadSnapshot};
+use crate::hand::{HandSkeleton,JointPose,JOINT_COUNT};
+use crate::input::{Handedness,InputEvent,InputEventKind,InputSourceState,Pose,TargetRayMode};
+use crate::lifecycle::Visibility;
+use crate::platform::{self,AssetFetcher,ViewData,Viewport,XrFrameInput,XrFrameSource};
+
+// XRセッションの記録と再生
+// ヘッドセットで起きた不具合を再現するため、フレームごとの時刻・頭の姿勢・ビュー・入力をバイナリで残す
+// 再生はReplaySessionから記録したフレームを順に取り出し、WebXRと同じFrameLoopに通してからプレビューやネイティブのテストで描画する
+//
+// 形式(リトルエンディアン): MAGIC, VERSION(u16), 続いてフレームがファイルの終わりまで並ぶ
+// ビュー行列は剛体変換なので、逆行列(目の姿勢)を位置とクォータニオンの7つの値で持つ
+
+pub const MAGIC: [u8; 4] = *b"WXRR";
+pub const VERSION: u16 = 1;
+
+// ページのURLにこのクエリが含まれているときだけ記録する
+pub const RECORD_QUERY: &str = "record";
+// ?replay=<パス>で記録を読み込み、XRの代わりにプレビューで再生する
+pub const REPLAY_QUERY: &str = "replay";
+pub const RECORDING_FILE_NAME: &str = "session.wxr";
+// ダウンロードリンクの要素のid。次のセッションの記録で置き換える
+#[cfg(target_arch = "wasm32")]
+const DOWNLOAD_LINK_ID: &str = "session-recording";
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum RecordingError{
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    // 途中でデータが終わった
+    UnexpectedEnd,
+    InvalidValue(&'static str),
+}
+
+impl std::fmt::Display for RecordingError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            RecordingError::InvalidMagic=>write!(f, "not a session recording"),
+            RecordingError::UnsupportedVersion(version)=>write!(f, "unsupported session recording version {}", version),
+            RecordingError::UnexpectedEnd=>write!(f, "session recording ended in the middle of a frame"),
+            RecordingError::InvalidValue(name)=>write!(f, "invalid {} in session recording", name),
+        }
+    }
+}
+
+impl std::error::Error for RecordingError{}
+
+impl From<RecordingError> for JsValue{
+    fn from(error: RecordingError)->Self{
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+// 1フレーム分の記録。姿勢は全てオフセットした参照空間(アプリが見ていた空間)
+#[derive(Debug,Clone)]
+pub struct RecordedFrame{
+    // アニメーションフレームの時刻(ミリ秒)
+    pub time: f64,
+    pub visibility: Visibility,
+    pub viewer_pose: Option<Pose>,
+    pub views: Vec<ViewData>,
+    // 再生した入力ソースとイベントのsourceはNone
+    pub input_sources: Vec<InputSourceState>,
+    // このフレームで処理したselect/squeeze
+    pub events: Vec<InputEvent>,
+}
+
+impl XrFrameSource for RecordedFrame{
+    fn views(&self)->Option<Vec<ViewData>>{
+        self.viewer_pose.map(|_| self.views.clone())
+    }
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct SessionRecording{
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl SessionRecording{
+    pub fn to_bytes(&self)->Vec<u8>{
+        let mut writer = Writer::header();
+        for frame in self.frames.iter(){
+            writer.frame(frame);
+        }
+        writer.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8])->Result<Self,RecordingError>{
+        let mut reader = Reader{bytes, position: 0};
+        if reader.take(MAGIC.len())? != MAGIC{
+            return Err(RecordingError::InvalidMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION{
+            return Err(RecordingError::UnsupportedVersion(version));
+        }
+        let mut frames = Vec::new();
+        while !reader.is_at_end(){
+            frames.push(reader.frame()?);
+        }
+        Ok(SessionRecording{frames})
+    }
+
+    // 最初のフレームから最後のフレームまでの長さ(ミリ秒)
+    pub fn duration(&self)->f64{
+        match (self.frames.first(), self.frames.last()){
+            (Some(first), Some(last))=>last.time - first.time,
+            _=>0.0,
+        }
+    }
+}
+
+// アニメーションループの中で、フレームを記録したそばからバイト列にする
+pub struct SessionRecorder{
+    writer: Writer,
+    frames: usize,
+    // メモリを使い切らないように、これを超えたら記録をやめる
+    max_frames: usize,
+}
+
+impl SessionRecorder{
+    pub fn new(max_frames: usize)->Self{
+        SessionRecorder{writer: Writer::header(), frames: 0, max_frames}
+    }
+
+    // 上限に達していて記録しなかった場合はfalse
+    pub fn record(&mut self, frame: &RecordedFrame)->bool{
+        if self.frames >= self.max_frames{
+            return false;
+        }
+        self.writer.frame(frame);
+        self.frames += 1;
+        if self.frames == self.max_frames{
+            platform::log(&format!("[Warning] Session recording reached {} frames, later frames are not recorded", self.max_frames));
+        }
+        true
+    }
+
+    pub fn frame_count(&self)->usize{
+        self.frames
+    }
+
+    pub fn into_bytes(self)->Vec<u8>{
+        self.writer.bytes
+    }
+}
+
+// 記録したフレームを順に返す再生用のバックエンド
+pub struct ReplaySession{
+    recording: SessionRecording,
+    next: usize,
+}
+
+impl ReplaySession{
+    pub fn new(recording: SessionRecording)->Self{
+        ReplaySession{recording, next: 0}
+    }
+
+    pub fn recording(&self)->&SessionRecording{
+        &self.recording
+    }
+
+    pub fn is_finished(&self)->bool{
+        self.next >= self.recording.frames.len()
+    }
+
+    pub fn rewind(&mut self){
+        self.next = 0;
+    }
+
+    // 記録した順に1フレームずつ返す。ネイティブのテストではこちらで全てのフレームを描く
+    pub fn next_frame(&mut self)->Option<&RecordedFrame>{
+        let frame = self.recording.frames.get(self.next)?;
+        self.next += 1;
+        Some(frame)
+    }
+
+    // 最後に返したフレーム
+    pub fn current(&self)->Option<&RecordedFrame>{
+        self.next.checked_sub(1).and_then(|index| self.recording.frames.get(index))
+    }
+
+    // 最初のフレームからelapsed(ミリ秒)経った時点まで進め、前の呼び出しから通り過ぎたフレームを返す
+    // 表示のリフレッシュレートが記録と違っても、記録したときと同じ速さで再生できる
+    pub fn advance_to(&mut self, elapsed: f64)->&[RecordedFrame]{
+        let Some(start) = self.recording.frames.first().map(|frame| frame.time) else{
+            return &[];
+        };
+        let start_index = self.next;
+        let end = self.recording.frames.partition_point(|frame| frame.time - start <= elapsed).max(start_index);
+        self.next = end;
+        &self.recording.frames[start_index..end]
+    }
+}
+
+// 記録したフレームをFrameLoop::stepに渡す
+// 姿勢は移動した後の参照空間で記録してあるので、読み直さずにそのまま使い、参照空間も動かさない
+pub struct ReplayFrameInput<'a>{
+    frame: &'a RecordedFrame,
+    events: Vec<InputEvent>,
+}
+
+impl<'a> ReplayFrameInput<'a>{
+    pub fn new(frame: &'a RecordedFrame)->Self{
+        ReplayFrameInput{frame, events: frame.events.clone()}
+    }
+}
+
+impl XrFrameInput for ReplayFrameInput<'_>{
+    fn update_input(&mut self){}
+
+    fn input_sources(&self)->&[InputSourceState]{
+        &self.frame.input_sources
+    }
+
+    fn viewer_pose(&self)->Option<Pose>{
+        self.frame.viewer_pose
+    }
+
+    fn set_origin_offset(&mut self, _position: [f32; 3], _orientation: [f32; 4])->bool{
+        false
+    }
+
+    fn take_events(&mut self)->Vec<InputEvent>{
+        std::mem::take(&mut self.events)
+    }
+}
+
+struct Writer{
+    bytes: Vec<u8>,
+}
+
+impl Writer{
+    fn header()->Self{
+        let mut writer = Writer{bytes: MAGIC.to_vec()};
+        writer.u16(VERSION);
+        writer
+    }
+
+    fn u8(&mut self, value: u8){
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16){
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32){
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32){
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64){
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool){
+        self.u8(u8::from(value));
+    }
+
+    // 要素数はu8で書くので、255を超える分は捨てる
+    fn count(&mut self, len: usize)->usize{
+        let len = len.min(u8::MAX as usize);
+        self.u8(len as u8);
+        len
+    }
+
+    fn string(&mut self, value: &str){
+        let bytes = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+        self.u16(bytes.len() as u16);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn pose(&mut self, pose: &Pose){
+        for value in pose.position.iter().chain(pose.orientation.iter()){
+            self.f32(*value);
+        }
+    }
+
+    fn optional_pose(&mut self, pose: Option<&Pose>){
+        self.bool(pose.is_some());
+        if let Some(pose) = pose{
+            self.pose(pose);
+        }
+    }
+
+    fn frame(&mut self, frame: &RecordedFrame){
+        self.f64(frame.time);
+        self.u8(visibility_to_u8(frame.visibility));
+        self.optional_pose(frame.viewer_pose.as_ref());
+        let views = self.count(frame.views.len());
+        for view in frame.views.iter().take(views){
+            let mut eye = mat4::create();
+            mat4::invert(&mut eye, &view.view);
+            self.pose(&Pose::from_matrix(&eye));
+            for value in view.projection.iter(){
+                self.f32(*value);
+            }
+            for value in [view.viewport.x, view.viewport.y, view.viewport.width, view.viewport.height]{
+                self.i32(value);
+            }
+        }
+        let sources = self.count(frame.input_sources.len());
+        for source in frame.input_sources.iter().take(sources){
+            self.input_source(source);
+        }
+        let events = self.count(frame.events.len());
+        for event in frame.events.iter().take(events){
+            self.u8(InputEventKind::ALL.iter().position(|kind| *kind == event.kind).unwrap_or(0) as u8);
+            self.u8(handedness_to_u8(event.handedness));
+            self.optional_pose(event.target_ray_pose.as_ref());
+        }
+    }
+
+    fn input_source(&mut self, source: &InputSourceState){
+        self.u8(handedness_to_u8(source.handedness));
+        self.u8(target_ray_mode_to_u8(source.target_ray_mode));
+        let profiles = self.count(source.profiles.len());
+        for profile in source.profiles.iter().take(profiles){
+            self.string(profile);
+        }
+        self.optional_pose(source.grip_pose.as_ref());
+        self.optional_pose(source.target_ray_pose.as_ref());
+        self.bool(source.gamepad.is_some());
+        if let Some(gamepad) = source.gamepad.as_ref(){
+            self.string(&gamepad.mapping);
+            let buttons = self.count(gamepad.buttons.len());
+            for button in gamepad.buttons.iter().take(buttons){
+                self.u8(u8::from(button.pressed) | u8::from(button.touched) << 1);
+                self.f32(button.value);
+            }
+            let axes = self.count(gamepad.axes.len());
+            for axis in gamepad.axes.iter().take(axes){
+                self.f32(*axis);
+            }
+        }
+        self.bool(source.hand.is_some());
+        if let Some(hand) = source.hand.as_ref(){
+            for joint in hand.joints().iter(){
+                self.pose(&joint.pose);
+                self.f32(joint.radius);
+            }
+        }
+    }
+}
+
+struct Reader<'a>{
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_>{
+    fn is_at_end(&self)->bool{
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize)->Result<&[u8],RecordingError>{
+        let end = self.position.checked_add(len).filter(|end| *end <= self.bytes.len()).ok_or(RecordingError::UnexpectedEnd)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self)->Result<[u8; N],RecordingError>{
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self)->Result<u8,RecordingError>{
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self)->Result<u16,RecordingError>{
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self)->Result<i32,RecordingError>{
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self)->Result<f32,RecordingError>{
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn f64(&mut self)->Result<f64,RecordingError>{
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    fn bool(&mut self)->Result<bool,RecordingError>{
+        match self.u8()?{
+            0=>Ok(false),
+            1=>Ok(true),
+            _=>Err(RecordingError::InvalidValue("flag")),
+        }
+    }
+
+    fn string(&mut self)->Result<String,RecordingError>{
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| RecordingError::InvalidValue("string"))
+    }
+
+    fn pose(&mut self)->Result<Pose,RecordingError>{
+        let mut values = [0.0; 7];
+        for value in values.iter_mut(){
+            *value = self.f32()?;
+        }
+        Ok(Pose{
+            position: [values[0], values[1], values[2]],
+            orientation: [values[3], values[4], values[5], values[6]],
+        })
+    }
+
+    fn optional_pose(&mut self)->Result<Option<Pose>,RecordingError>{
+        if self.bool()?{
+            Ok(Some(self.pose()?))
+        }
+        else{
+            Ok(None)
+        }
+    }
+
+    fn frame(&mut self)->Result<RecordedFrame,RecordingError>{
+        let time = self.f64()?;
+        let visibility = visibility_from_u8(self.u8()?)?;
+        let viewer_pose = self.optional_pose()?;
+        let mut views = Vec::new();
+        for _ in 0..self.u8()?{
+            let eye = self.pose()?;
+            let mut view = mat4::create();
+            mat4::invert(&mut view, &eye.matrix());
+            let mut projection = mat4::create();
+            for value in projection.iter_mut(){
+                *value = self.f32()?;
+            }
+            let viewport = Viewport{x: self.i32()?, y: self.i32()?, width: self.i32()?, height: self.i32()?};
+            views.push(ViewData{view, projection, viewport});
+        }
+        let mut input_sources = Vec::new();
+        for _ in 0..self.u8()?{
+            input_sources.push(self.input_source()?);
+        }
+        let mut events = Vec::new();
+        for _ in 0..self.u8()?{
+            let kind = *InputEventKind::ALL.get(self.u8()? as usize).ok_or(RecordingError::InvalidValue("input event"))?;
+            let handedness = handedness_from_u8(self.u8()?)?;
+            let target_ray_pose = self.optional_pose()?;
+            events.push(InputEvent{kind, handedness, target_ray_pose, source: None});
+        }
+        Ok(RecordedFrame{time, visibility, viewer_pose, views, input_sources, events})
+    }
+
+    fn input_source(&mut self)->Result<InputSourceState,RecordingError>{
+        let handedness = handedness_from_u8(self.u8()?)?;
+        let target_ray_mode = target_ray_mode_from_u8(self.u8()?)?;
+        let mut profiles = Vec::new();
+        for _ in 0..self.u8()?{
+            profiles.push(self.string()?);
+        }
+        let grip_pose = self.optional_pose()?;
+        let target_ray_pose = self.optional_pose()?;
+        let gamepad = if self.bool()?{
+            let mapping = self.string()?;
+            let mut buttons = Vec::new();
+            for _ in 0..self.u8()?{
+                let flags = self.u8()?;
+                buttons.push(ButtonSnapshot{pressed: flags & 1 != 0, touched: flags & 2 != 0, value: self.f32()?});
+            }
+            let mut axes = Vec::new();
+            for _ in 0..self.u8()?{
+                axes.push(self.f32()?);
+            }
+            Some(GamepadSnapshot{mapping, buttons, axes})
+        }
+        else{
+            None
+        };
+        let hand = if self.bool()?{
+            let mut joints = Vec::with_capacity(JOINT_COUNT);
+            for _ in 0..JOINT_COUNT{
+                joints.push(JointPose{pose: self.pose()?, radius: self.f32()?});
+            }
+            let joints = joints.try_into().map_err(|_| RecordingError::InvalidValue("hand"))?;
+            Some(HandSkeleton::new(handedness, joints))
+        }
+        else{
+            None
+        };
+        Ok(InputSourceState{handedness, target_ray_mode, profiles, grip_pose, target_ray_pose, gamepad, hand, source: None})
+    }
+}
+
+fn visibility_to_u8(visibility: Visibility)->u8{
+    match visibility{
+        Visibility::Visible=>0,
+        Visibility::VisibleBlurred=>1,
+        Visibility::Hidden=>2,
+    }
+}
+
+fn visibility_from_u8(value: u8)->Result<Visibility,RecordingError>{
+    match value{
+        0=>Ok(Visibility::Visible),
+        1=>Ok(Visibility::VisibleBlurred),
+        2=>Ok(Visibility::Hidden),
+        _=>Err(RecordingError::InvalidValue("visibility")),
+    }
+}
+
+fn handedness_to_u8(handedness: Handedness)->u8{
+    match handedness{
+        Handedness::None=>0,
+        Handedness::Left=>1,
+        Handedness::Right=>2,
+    }
+}
+
+fn handedness_from_u8(value: u8)->Result<Handedness,RecordingError>{
+    match value{
+        0=>Ok(Handedness::None),
+        1=>Ok(Handedness::Left),
+        2=>Ok(Handedness::Right),
+        _=>Err(RecordingError::InvalidValue("handedness")),
+    }
+}
+
+fn target_ray_mode_to_u8(mode: TargetRayMode)->u8{
+    match mode{
+        TargetRayMode::Gaze=>0,
+        TargetRayMode::TrackedPointer=>1,
+        TargetRayMode::Screen=>2,
+    }
+}
+
+fn target_ray_mode_from_u8(value: u8)->Result<TargetRayMode,RecordingError>{
+    match value{
+        0=>Ok(TargetRayMode::Gaze),
+        1=>Ok(TargetRayMode::TrackedPointer),
+        2=>Ok(TargetRayMode::Screen),
+        _=>Err(RecordingError::InvalidValue("target ray mode")),
+    }
+}
+
+// ページのURLのクエリを探す。値の無いクエリは空文字列
+#[cfg(target_arch = "wasm32")]
+fn query_parameter(window: &Window, name: &str)->Option<String>{
+    let search = window.location().search().ok()?;
+    search.trim_start_matches('?').split('&').find_map(|parameter|{
+        let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        (key == name).then(|| js_sys::decode_uri_component(value).ok().and_then(|value| value.as_string()).unwrap_or_else(|| value.to_string()))
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn recording_enabled(window: &Window)->bool{
+    query_parameter(window, RECORD_QUERY).is_some()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn replay_path(window: &Window)->Option<String>{
+    query_parameter(window, REPLAY_QUERY).filter(|path| !path.is_empty())
+}
+
+pub async fn load_recording<A: AssetFetcher>(fetcher: &A, path: &str)->Result<SessionRecording,JsValue>{
+    let bytes = fetcher.fetch_bytes(path).await?;
+    let recording = SessionRecording::from_bytes(&bytes)?;
+    platform::log(&format!("Loaded session recording {} ({} frames, {:.1}s)", path, recording.frames.len(), recording.duration() / 1000.0));
+    Ok(recording)
+}
+
+// 記録をBlobにして、ダウンロードリンクをページに置く
+#[cfg(target_arch = "wasm32")]
+pub fn offer_download(document: &Document, bytes: &[u8])->Result<(),JsValue>{
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+    let options = BlobPropertyBag::new();
+    options.set_type("application/octet-stream");
+    let blob = Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+
+    // 前のセッションのリンクは、URLを解放してから置き換える
+    if let Some(previous) = document.get_element_by_id(DOWNLOAD_LINK_ID){
+        if let Some(href) = previous.get_attribute("href"){
+            let _ = Url::revoke_object_url(&href);
+        }
+        previous.remove();
+    }
+    let link = document.create_element("a")?.dyn_into::<HtmlAnchorElement>()?;
+    link.set_id(DOWNLOAD_LINK_ID);
+    link.set_href(&url);
+    link.set_download(RECORDING_FILE_NAME);
+    link.set_inner_text(&format!("Download session recording ({} KB)", bytes.len().div_ceil(1024)));
+    document.body().ok_or_else(|| JsValue::from_str("document has no body"))?.append_child(&link)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::frame_loop::{FrameClock,FrameLoop,FrameStep,Interaction};
+    use crate::gamepad::ControllerButton;
+    use crate::input::InputEventSubscribers;
+    use crate::locomotion::WalkableArea;
+    use crate::session::{EnabledFeatures,SessionMode};
+    use crate::platform::simulated::{ScriptEvent,SessionScript,SimulatedDevice,SimulatedFrameInput,SimulatedInputSource,SimulatedSession,SimulatedStep,Timeline};
+
+    fn pose(position: [f32; 3], yaw: f32)->Pose{
+        Pose{position, orientation: [0.0, (yaw * 0.5).sin(), 0.0, (yaw * 0.5).cos()]}
+    }
+
+    fn session(script: SessionScript)->SimulatedSession{
+        let device = SimulatedDevice{eye_width: 64, eye_height: 64, refresh_rate: 100.0, ..Default::default()};
+        SimulatedSession::new(device, SessionMode::ImmersiveVr, script)
+    }
+
+    fn frame_loop()->FrameLoop{
+        let interaction = Interaction::new(SessionMode::ImmersiveVr, &EnabledFeatures::new([]), vec![WalkableArea::floor(20.0)]);
+        FrameLoop::new(FrameClock::new(0.1), interaction, InputEventSubscribers::new())
+    }
+
+    // フレームの時刻・経過時間・右手のトリガー・左手がつながっているか
+    type FrameState = (f64, f32, bool, bool);
+
+    fn frame_state(time: f64, step: &FrameStep, frame_loop: &FrameLoop)->FrameState{
+        let controllers = &frame_loop.interaction.controllers;
+        (time, step.delta_time, controllers.right.pressed(ControllerButton::Trigger), controllers.left.connected)
+    }
+
+    // 頭と右手が動き、左手は途中だけつながる。select・トリガー・visibilityの変化・フレーム落ちを含む
+    fn script()->SessionScript{
+        let idle = GamepadSnapshot::xr_standard();
+        let trigger = Timeline::constant(idle.clone())
+            .with_key(80.0, idle.clone().with_button(ControllerButton::Trigger, true))
+            .with_key(130.0, idle.clone());
+        let right = Timeline::new()
+            .with_key(0.0, pose([0.2, 1.0, -0.3], 0.0))
+            .with_key(250.0, pose([0.4, 1.2, -0.5], 0.8));
+        let left = SimulatedInputSource::controller(Handedness::Left, Timeline::constant(pose([-0.2, 1.0, -0.3], 0.0))).connected_between(50.0, Some(150.0));
+        let viewer = Timeline::new()
+            .with_key(0.0, pose([0.0, 1.6, 0.0], 0.0))
+            .with_key(250.0, pose([0.5, 1.6, -1.0], 1.0));
+        SessionScript::new(viewer)
+            .with_source(SimulatedInputSource::controller(Handedness::Right, right).with_gamepad(trigger))
+            .with_source(left)
+            .with_event(60.0, ScriptEvent::Input{handedness: Handedness::Right, kind: InputEventKind::Select})
+            .with_event(70.0, ScriptEvent::Input{handedness: Handedness::Left, kind: InputEventKind::Squeeze})
+            .with_event(100.0, ScriptEvent::Visibility(Visibility::VisibleBlurred))
+            .with_event(120.0, ScriptEvent::Visibility(Visibility::Visible))
+            .with_event(160.0, ScriptEvent::Visibility(Visibility::Hidden))
+            .with_event(180.0, ScriptEvent::Visibility(Visibility::Visible))
+            .with_event(200.0, ScriptEvent::DropFrames(2))
+            .with_event(250.0, ScriptEvent::End)
+    }
+
+    // アニメーションループと同じく、FrameLoopで処理したフレームと配ったイベントを記録する
+    fn record(mut session: SimulatedSession, frame_loop: &mut FrameLoop)->(Vec<u8>, Vec<FrameState>){
+        let mut recorder = SessionRecorder::new(1000);
+        let mut states = Vec::new();
+        loop{
+            session.request_animation_frame();
+            let mut frame = match session.advance(){
+                SimulatedStep::Frame(frame)=>frame,
+                SimulatedStep::Skipped=>continue,
+                SimulatedStep::Ended=>break,
+            };
+            let (time, visibility) = (frame.time, session.visibility());
+            let step = frame_loop.step(&mut SimulatedFrameInput{session: &mut session, frame: &mut frame}, time, visibility);
+            states.push(frame_state(time, &step, frame_loop));
+            assert!(recorder.record(&RecordedFrame{
+                time,
+                visibility,
+                viewer_pose: frame.viewer_pose,
+                views: frame.views.clone(),
+                input_sources: frame.input_sources.clone(),
+                events: step.events,
+            }));
+        }
+        (recorder.into_bytes(), states)
+    }
+
+    fn received(events: &mut futures::channel::mpsc::UnboundedReceiver<InputEvent>)->Vec<InputEvent>{
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv(){
+            received.push(event);
+        }
+        received
+    }
+
+    fn assert_pose_near(actual: Option<Pose>, expected: Option<Pose>, epsilon: f32){
+        let (Some(actual), Some(expected)) = (actual, expected) else{
+            assert_eq!(actual.is_some(), expected.is_some());
+            return;
+        };
+        for (a, b) in actual.position.iter().chain(actual.orientation.iter()).zip(expected.position.iter().chain(expected.orientation.iter())){
+            assert!((a - b).abs() <= epsilon, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    fn assert_sources_eq(actual: &[InputSourceState], expected: &[InputSourceState], epsilon: f32){
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected.iter()){
+            assert_eq!(actual.handedness, expected.handedness);
+            assert_eq!(actual.target_ray_mode, expected.target_ray_mode);
+            assert_eq!(actual.profiles, expected.profiles);
+            assert_eq!(actual.gamepad, expected.gamepad);
+            assert_eq!(actual.hand.is_some(), expected.hand.is_some());
+            assert_pose_near(actual.grip_pose, expected.grip_pose, epsilon);
+            assert_pose_near(actual.target_ray_pose, expected.target_ray_pose, epsilon);
+        }
+    }
+
+    fn assert_events_eq(actual: &[InputEvent], expected: &[InputEvent], epsilon: f32){
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected.iter()){
+            assert_eq!((actual.kind, actual.handedness), (expected.kind, expected.handedness));
+            assert_pose_near(actual.target_ray_pose, expected.target_ray_pose, epsilon);
+        }
+    }
+
+    #[test]
+    fn round_trip_replays_frames_in_order(){
+        let mut session = session(script());
+        let mut expected = Vec::new();
+        loop{
+            session.request_animation_frame();
+            match session.advance(){
+                SimulatedStep::Frame(frame)=>expected.push((frame, session.visibility())),
+                SimulatedStep::Skipped=>continue,
+                SimulatedStep::Ended=>break,
+            }
+        }
+        let (bytes, _) = record(self::session(script()), &mut frame_loop());
+        let mut replay = ReplaySession::new(SessionRecording::from_bytes(&bytes).unwrap());
+        assert_eq!(replay.recording().frames.len(), expected.len());
+        assert_eq!(replay.recording().duration(), 240.0);
+
+        for (frame, visibility) in expected.iter(){
+            let recorded = replay.next_frame().unwrap();
+            assert_eq!(recorded.time, frame.time);
+            assert_eq!(recorded.visibility, *visibility);
+            // 姿勢と入力はf32のまま書くので、そのまま戻る
+            assert_eq!(recorded.viewer_pose, frame.viewer_pose);
+            assert_sources_eq(&recorded.input_sources, &frame.input_sources, 0.0);
+            assert_events_eq(&recorded.events, &frame.events, 0.0);
+            // ビュー行列は目の姿勢にしてから逆行列に戻すので、丸め誤差だけずれる
+            let views = recorded.views().unwrap();
+            assert_eq!(views.len(), frame.views.len());
+            for (view, expected) in views.iter().zip(frame.views.iter()){
+                assert!(view.view.iter().zip(expected.view.iter()).all(|(a, b)| (a - b).abs() < 1e-5));
+                assert_eq!(view.projection, expected.projection);
+                assert_eq!(view.viewport, expected.viewport);
+            }
+        }
+        assert!(replay.is_finished());
+        assert!(replay.next_frame().is_none());
+
+        // 記録した時刻の通りに進める
+        replay.rewind();
+        assert_eq!(replay.advance_to(0.0).len(), 1);
+        assert_eq!(replay.current().unwrap().time, 0.0);
+        let passed: Vec<f64> = replay.advance_to(55.0).iter().map(|frame| frame.time).collect();
+        assert_eq!(passed, vec![10.0, 20.0, 30.0, 40.0, 50.0]);
+        assert!(replay.advance_to(55.0).is_empty());
+        // hiddenの間とフレーム落ちの間は記録がないので飛ばす
+        let passed: Vec<f64> = replay.advance_to(1000.0).iter().map(|frame| frame.time).collect();
+        assert_eq!(passed, vec![60.0, 70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 130.0, 140.0, 150.0, 180.0, 190.0, 220.0, 230.0, 240.0]);
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn rejects_invalid_recordings(){
+        let (bytes, _) = record(session(script()), &mut frame_loop());
+        assert_eq!(SessionRecording::from_bytes(b"WXRX\x01\x00").unwrap_err(), RecordingError::InvalidMagic);
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert_eq!(SessionRecording::from_bytes(&version).unwrap_err(), RecordingError::UnsupportedVersion(2));
+        assert_eq!(SessionRecording::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), RecordingError::UnexpectedEnd);
+        assert_eq!(SessionRecording::from_bytes(&bytes[..3]).unwrap_err(), RecordingError::UnexpectedEnd);
+        // 最初のフレームのvisibility
+        let mut visibility = bytes.clone();
+        visibility[MAGIC.len() + 2 + 8] = 9;
+        assert!(matches!(SessionRecording::from_bytes(&visibility), Err(RecordingError::InvalidValue(_))));
+        // ヘッダーだけなら空の記録
+        let empty = SessionRecording::from_bytes(&bytes[..MAGIC.len() + 2]).unwrap();
+        assert!(empty.frames.is_empty());
+        assert_eq!(empty.duration(), 0.0);
+        assert!(ReplaySession::new(empty).advance_to(100.0).is_empty());
+    }
+
+    #[test]
+    fn recorder_stops_at_max_frames(){
+        let mut recorder = SessionRecorder::new(2);
+        let frame = RecordedFrame{time: 0.0, visibility: Visibility::Visible, viewer_pose: None, views: Vec::new(), input_sources: Vec::new(), events: Vec::new()};
+        assert!(recorder.record(&frame));
+        assert!(recorder.record(&RecordedFrame{time: 10.0, ..frame.clone()}));
+        assert!(!recorder.record(&RecordedFrame{time: 20.0, ..frame}));
+        assert_eq!(recorder.frame_count(), 2);
+        let recording = SessionRecording::from_bytes(&recorder.into_bytes()).unwrap();
+        assert_eq!(recording.frames.len(), 2);
+        assert_eq!(recording.duration(), 10.0);
+    }
+
+    #[test]
+    fn replayed_recording_drives_frame_loop(){
+        // 元の台本で動かしたときのフレームと入力の状態
+        let mut original = frame_loop();
+        let mut original_events = original.input_events.subscribe();
+        let (bytes, expected) = record(session(script()), &mut original);
+
+        let mut replayed = frame_loop();
+        let mut replayed_events = replayed.input_events.subscribe();
+        let mut replay = ReplaySession::new(SessionRecording::from_bytes(&bytes).unwrap());
+        let mut states = Vec::new();
+        while let Some(frame) = replay.next_frame(){
+            let step = replayed.step(&mut ReplayFrameInput::new(frame), frame.time, frame.visibility);
+            states.push(frame_state(frame.time, &step, &replayed));
+        }
+        // 記録から再生しても、フレームの間隔と入力の状態は元のセッションと同じになる
+        assert_eq!(states, expected);
+        assert!(expected.iter().any(|(_, _, trigger, _)| *trigger));
+        assert!(expected.iter().any(|(_, _, _, left)| *left));
+        // 購読者にも同じイベントが同じ順番で届く
+        let events = received(&mut original_events);
+        assert_eq!(events.len(), 2);
+        assert_events_eq(&received(&mut replayed_events), &events, 0.0);
+    }
+}
@@ -0,0 +1,360 @@
+use wasm_bindgen::prelude::*;
+use crate::preprocess::{PreprocessedSource,SourceLocation};
+
+// シェーダのコンパイル・リンクエラーの解析
+// ドライバごとに異なるinfo logの書式を読み取り、ソースの該当行に注釈を付ける
+
+// 注釈付きソースで、エラー行の前後に表示する行数
+const CONTEXT_LINES: u32 = 2;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ShaderStage{
+    Vertex,
+    Fragment,
+    Link,
+}
+
+impl std::fmt::Display for ShaderStage{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            ShaderStage::Vertex=>write!(f, "vertex shader"),
+            ShaderStage::Fragment=>write!(f, "fragment shader"),
+            ShaderStage::Link=>write!(f, "program link"),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Severity{
+    Error,
+    Warning,
+}
+
+// info logの1行分を解析した結果
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ShaderDiagnostic{
+    pub severity: Severity,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub message: String,
+    // #include展開前の位置。with_source_mapで設定される
+    pub origin: Option<SourceLocation>,
+}
+
+// 行番号付きのソース1行と、その行に対するメッセージ
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct AnnotatedLine{
+    pub number: u32,
+    pub text: String,
+    pub messages: Vec<String>,
+    pub origin: Option<SourceLocation>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ShaderError{
+    pub stage: ShaderStage,
+    pub info_log: String,
+    pub diagnostics: Vec<ShaderDiagnostic>,
+    pub annotated_source: Vec<AnnotatedLine>,
+}
+
+impl ShaderError{
+    // info logを解析し、ソースがあればエラー行の前後に注釈を付ける
+    pub fn new(stage: ShaderStage, info_log: &str, source: Option<&str>)->Self{
+        let diagnostics = parse_info_log(info_log);
+        let annotated_source = match source{
+            Some(source)=>annotate_source(source, &diagnostics),
+            None=>Vec::new(),
+        };
+        ShaderError{
+            stage,
+            info_log: info_log.trim().to_string(),
+            diagnostics,
+            annotated_source,
+        }
+    }
+
+    // プリプロセッサの行対応を使って、展開前のファイルと行番号を付ける
+    pub fn with_source_map(mut self, source: &PreprocessedSource)->Self{
+        for diagnostic in self.diagnostics.iter_mut(){
+            diagnostic.origin = diagnostic.line.and_then(|line| source.origin(line)).cloned();
+        }
+        for line in self.annotated_source.iter_mut(){
+            line.origin = source.origin(line.number).cloned();
+        }
+        self
+    }
+
+    // エラーページの見出しに使う1行の要約
+    pub fn summary(&self)->String{
+        let first = self.diagnostics.iter().find(|diagnostic| diagnostic.severity == Severity::Error);
+        match first{
+            Some(ShaderDiagnostic{origin: Some(origin), message, ..})=>format!("{} failed at {}: {}", self.stage, origin, message),
+            Some(ShaderDiagnostic{line: Some(line), message, ..})=>format!("{} failed at line {}: {}", self.stage, line, message),
+            Some(ShaderDiagnostic{message, ..})=>format!("{} failed: {}", self.stage, message),
+            None=>format!("{} failed", self.stage),
+        }
+    }
+}
+
+impl std::fmt::Display for ShaderError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        writeln!(f, "{}", self.summary())?;
+        if self.annotated_source.is_empty(){
+            return write!(f, "{}", self.info_log);
+        }
+        // 展開前の位置が分かる行は "file:line" で表示する
+        let label = |line: &AnnotatedLine| match &line.origin{
+            Some(origin)=>origin.to_string(),
+            None=>line.number.to_string(),
+        };
+        let width = self.annotated_source.iter().map(|line| label(line).len()).max().unwrap_or(1);
+        let mut previous = None;
+        for line in self.annotated_source.iter(){
+            // 行が飛んでいるところは省略記号を入れる
+            if previous.is_some_and(|previous| line.number > previous + 1){
+                writeln!(f, "{:>width$} |", "...", width = width)?;
+            }
+            let marker = if line.messages.is_empty(){" "} else{">"};
+            writeln!(f, "{}{:>width$} | {}", marker, label(line), line.text, width = width)?;
+            for message in line.messages.iter(){
+                writeln!(f, " {:>width$} | ^ {}", "", message, width = width)?;
+            }
+            previous = Some(line.number);
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ShaderError{}
+
+impl From<ShaderError> for JsValue{
+    fn from(error: ShaderError)->Self{
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+// 以下の書式に対応する
+//   ANGLE / Chrome / Firefox / Safari: "ERROR: 0:12: 'foo' : undeclared identifier"
+//   Mesa:                              "0:12(5): error: `foo' undeclared"
+//   NVIDIA:                            "0(12) : error C1008: undefined variable \"foo\""
+// 行番号が読み取れない行は、行番号なしのメッセージとして扱う
+pub fn parse_info_log(info_log: &str)->Vec<ShaderDiagnostic>{
+    info_log.lines()
+        .map(|line| line.trim_matches(|c: char| c.is_whitespace() || c == '\0'))
+        .filter(|line| !line.is_empty())
+        .filter(|line| !is_summary_line(line))
+        .map(parse_line)
+        .collect()
+}
+
+fn parse_line(line: &str)->ShaderDiagnostic{
+    if let Some(diagnostic) = parse_angle(line).or_else(|| parse_mesa(line)).or_else(|| parse_nvidia(line)){
+        return diagnostic;
+    }
+    // "error: ..." のような位置情報を持たない行
+    let (severity, message) = split_severity(line).unwrap_or((Severity::Error, line));
+    ShaderDiagnostic{
+        severity,
+        line: None,
+        column: None,
+        message: message.trim().to_string(),
+        origin: None,
+    }
+}
+
+// "ERROR: 0:12: message"
+fn parse_angle(line: &str)->Option<ShaderDiagnostic>{
+    let (severity, rest) = split_severity(line)?;
+    let mut parts = rest.splitn(3, ':');
+    parts.next()?.trim().parse::<u32>().ok()?;
+    let line_number = parts.next()?.trim().parse::<u32>().ok()?;
+    let message = parts.next()?.trim();
+    Some(ShaderDiagnostic{
+        severity,
+        line: Some(line_number),
+        column: None,
+        message: message.to_string(),
+        origin: None,
+    })
+}
+
+// "0:12(5): error: message"
+fn parse_mesa(line: &str)->Option<ShaderDiagnostic>{
+    let (location, rest) = line.split_once(": ")?;
+    let (source, position) = location.split_once(':')?;
+    source.parse::<u32>().ok()?;
+    let (line_number, column) = match position.split_once('('){
+        Some((line_number, column))=>(line_number, Some(column.strip_suffix(')')?.parse::<u32>().ok()?)),
+        None=>(position, None),
+    };
+    let line_number = line_number.parse::<u32>().ok()?;
+    let (severity, message) = split_severity(rest).unwrap_or((Severity::Error, rest));
+    Some(ShaderDiagnostic{
+        severity,
+        line: Some(line_number),
+        column,
+        message: message.trim().to_string(),
+        origin: None,
+    })
+}
+
+// "0(12) : error C1008: message"
+fn parse_nvidia(line: &str)->Option<ShaderDiagnostic>{
+    let (location, rest) = line.split_once(" : ")?;
+    let (source, line_number) = location.split_once('(')?;
+    source.parse::<u32>().ok()?;
+    let line_number = line_number.strip_suffix(')')?.parse::<u32>().ok()?;
+    let (severity, message) = match rest.split_once(':'){
+        Some((kind, message)) if kind.starts_with("error")=>(Severity::Error, message),
+        Some((kind, message)) if kind.starts_with("warning")=>(Severity::Warning, message),
+        _=>(Severity::Error, rest),
+    };
+    Some(ShaderDiagnostic{
+        severity,
+        line: Some(line_number),
+        column: None,
+        message: message.trim().to_string(),
+        origin: None,
+    })
+}
+
+// 先頭の "ERROR:" / "error:" / "WARNING:" を取り除く
+fn split_severity(line: &str)->Option<(Severity,&str)>{
+    let (kind, rest) = line.split_once(':')?;
+    match kind.trim().to_ascii_lowercase().as_str(){
+        "error"=>Some((Severity::Error, rest)),
+        "warning"=>Some((Severity::Warning, rest)),
+        _=>None,
+    }
+}
+
+// "ERROR: 2 compilation errors.  No code generated." のような集計行
+fn is_summary_line(line: &str)->bool{
+    let lower = line.to_ascii_lowercase();
+    lower.contains("compilation error") && lower.contains("no code generated")
+}
+
+// 診断のある行とその前後の行を、行番号付きで取り出す
+pub fn annotate_source(source: &str, diagnostics: &[ShaderDiagnostic])->Vec<AnnotatedLine>{
+    let lines: Vec<&str> = source.lines().collect();
+    let mut annotated: Vec<AnnotatedLine> = Vec::new();
+    let mut error_lines: Vec<u32> = diagnostics.iter()
+        .filter_map(|diagnostic| diagnostic.line)
+        .filter(|line| *line >= 1 && *line as usize <= lines.len())
+        .collect();
+    error_lines.sort_unstable();
+    error_lines.dedup();
+
+    for error_line in error_lines{
+        let first = error_line.saturating_sub(CONTEXT_LINES).max(1);
+        let last = (error_line + CONTEXT_LINES).min(lines.len() as u32);
+        for number in first..=last{
+            if annotated.last().is_some_and(|line| line.number >= number){
+                continue;
+            }
+            annotated.push(AnnotatedLine{
+                number,
+                text: lines[number as usize - 1].to_string(),
+                messages: Vec::new(),
+                origin: None,
+            });
+        }
+    }
+
+    for diagnostic in diagnostics.iter(){
+        let Some(line) = diagnostic.line else{
+            continue;
+        };
+        if let Some(annotated_line) = annotated.iter_mut().find(|annotated_line| annotated_line.number == line){
+            let severity = match diagnostic.severity{
+                Severity::Error=>"error",
+                Severity::Warning=>"warning",
+            };
+            let message = match diagnostic.column{
+                Some(column)=>format!("{} (column {}): {}", severity, column, diagnostic.message),
+                None=>format!("{}: {}", severity, diagnostic.message),
+            };
+            annotated_line.messages.push(message);
+        }
+    }
+    annotated
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const SOURCE: &str = "#version 300 es\nprecision mediump float;\nin vec3 color;\nout vec4 fragment_color;\nvoid main(){\n    fragment_color = vec4(colr, 1.0);\n    float unused;\n}\n";
+
+    fn diagnostic(severity: Severity, line: Option<u32>, column: Option<u32>, message: &str)->ShaderDiagnostic{
+        ShaderDiagnostic{severity, line, column, message: message.to_string(), origin: None}
+    }
+
+    #[test]
+    fn parses_angle_log(){
+        let log = "ERROR: 0:6: 'colr' : undeclared identifier\nERROR: 0:6: 'constructor' : not enough data provided for construction\nWARNING: 0:7: 'unused' : variable is never used\nERROR: 2 compilation errors.  No code generated.\n\0";
+        assert_eq!(parse_info_log(log), vec![
+            diagnostic(Severity::Error, Some(6), None, "'colr' : undeclared identifier"),
+            diagnostic(Severity::Error, Some(6), None, "'constructor' : not enough data provided for construction"),
+            diagnostic(Severity::Warning, Some(7), None, "'unused' : variable is never used"),
+        ]);
+    }
+
+    #[test]
+    fn parses_mesa_log(){
+        let log = "0:6(27): error: `colr' undeclared\n0:6(22): error: too few components to construct `vec4'\n0:7(8): warning: `unused' declared but not used\n";
+        assert_eq!(parse_info_log(log), vec![
+            diagnostic(Severity::Error, Some(6), Some(27), "`colr' undeclared"),
+            diagnostic(Severity::Error, Some(6), Some(22), "too few components to construct `vec4'"),
+            diagnostic(Severity::Warning, Some(7), Some(8), "`unused' declared but not used"),
+        ]);
+    }
+
+    #[test]
+    fn parses_nvidia_log(){
+        let log = "0(6) : error C1008: undefined variable \"colr\"\n0(7) : warning C7050: \"unused\" might be used before being initialized\n";
+        assert_eq!(parse_info_log(log), vec![
+            diagnostic(Severity::Error, Some(6), None, "undefined variable \"colr\""),
+            diagnostic(Severity::Warning, Some(7), None, "\"unused\" might be used before being initialized"),
+        ]);
+    }
+
+    #[test]
+    fn keeps_unknown_lines_without_location(){
+        let log = "error: Fragment shader is not compiled.\nLink failed because of missing vertex shader\n";
+        assert_eq!(parse_info_log(log), vec![
+            diagnostic(Severity::Error, None, None, "Fragment shader is not compiled."),
+            diagnostic(Severity::Error, None, None, "Link failed because of missing vertex shader"),
+        ]);
+        let error = ShaderError::new(ShaderStage::Link, log, None);
+        assert_eq!(error.summary(), "program link failed: Fragment shader is not compiled.");
+        // ソースが無いときはinfo logをそのまま表示する
+        assert!(error.to_string().ends_with("Link failed because of missing vertex shader"));
+        assert!(parse_info_log("  \n\0").is_empty());
+    }
+
+    #[test]
+    fn annotates_error_lines_with_context(){
+        let error = ShaderError::new(ShaderStage::Fragment, "ERROR: 0:6: 'colr' : undeclared identifier\nWARNING: 0:7: 'unused' : variable is never used\n", Some(SOURCE));
+        assert_eq!(error.summary(), "fragment shader failed at line 6: 'colr' : undeclared identifier");
+        // 6行目と7行目の前後2行ずつを、重ならないように並べる
+        let numbers: Vec<u32> = error.annotated_source.iter().map(|line| line.number).collect();
+        assert_eq!(numbers, vec![4, 5, 6, 7, 8]);
+        assert_eq!(error.annotated_source[2].messages, vec!["error: 'colr' : undeclared identifier".to_string()]);
+        assert_eq!(error.annotated_source[3].messages, vec!["warning: 'unused' : variable is never used".to_string()]);
+        assert!(error.to_string().contains(">6 |     fragment_color = vec4(colr, 1.0);"));
+    }
+
+    #[test]
+    fn annotates_distant_lines_separately(){
+        let diagnostics = parse_info_log("0:1(1): error: first\n0:8(3): error: last\n0:99(1): error: out of range");
+        let annotated = annotate_source(SOURCE, &diagnostics);
+        let numbers: Vec<u32> = annotated.iter().map(|line| line.number).collect();
+        assert_eq!(numbers, vec![1, 2, 3, 6, 7, 8]);
+        assert_eq!(annotated[5].messages, vec!["error (column 3): last".to_string()]);
+        let error = ShaderError::new(ShaderStage::Vertex, "0:1(1): error: first\n0:8(3): error: last", Some(SOURCE));
+        // 飛んでいる行の間には省略記号が入る
+        assert!(error.to_string().contains("... |"));
+    }
+}
@@ -0,0 +1,349 @@
+// uniformブロックのstd140レイアウト
+// GLSL ES 3.00 仕様 (OpenGL ES 3.0 仕様 2.12.6.4) の規則でオフセットを計算し、
+// GPUに送るバイト列を組み立てる。web-sysには依存しない
+
+// vec4の大きさ。配列や行列の要素はこの境界に揃えられる
+const VEC4_SIZE: usize = 16;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Std140Type{
+    Float,
+    Int,
+    Vec2,
+    Vec3,
+    Vec4,
+    Mat3,
+    Mat4,
+    // 要素の型と要素数
+    Array(Std140Scalar, usize),
+}
+
+// 配列の要素になれる型
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Std140Scalar{
+    Float,
+    Int,
+    Vec2,
+    Vec3,
+    Vec4,
+    Mat3,
+    Mat4,
+}
+
+impl Std140Scalar{
+    fn as_type(&self)->Std140Type{
+        match self{
+            Std140Scalar::Float=>Std140Type::Float,
+            Std140Scalar::Int=>Std140Type::Int,
+            Std140Scalar::Vec2=>Std140Type::Vec2,
+            Std140Scalar::Vec3=>Std140Type::Vec3,
+            Std140Scalar::Vec4=>Std140Type::Vec4,
+            Std140Scalar::Mat3=>Std140Type::Mat3,
+            Std140Scalar::Mat4=>Std140Type::Mat4,
+        }
+    }
+}
+
+impl Std140Type{
+    // 基本アラインメント(バイト)
+    pub fn alignment(&self)->usize{
+        match self{
+            Std140Type::Float | Std140Type::Int=>4,
+            Std140Type::Vec2=>8,
+            // vec3はvec4と同じ境界に揃える
+            Std140Type::Vec3 | Std140Type::Vec4=>16,
+            // 行列は列ベクトルの配列として扱うので、vec4の境界に揃える
+            Std140Type::Mat3 | Std140Type::Mat4=>VEC4_SIZE,
+            // 配列の要素はvec4の境界に切り上げられる
+            Std140Type::Array(element, _)=>round_up(element.as_type().alignment(), VEC4_SIZE),
+        }
+    }
+
+    // メンバーとして占める大きさ(バイト)。後続のメンバーはこの後ろから始まる
+    pub fn size(&self)->usize{
+        match self{
+            Std140Type::Float | Std140Type::Int=>4,
+            Std140Type::Vec2=>8,
+            Std140Type::Vec3=>12,
+            Std140Type::Vec4=>16,
+            // 3列それぞれがvec4分の場所を取る
+            Std140Type::Mat3=>3 * VEC4_SIZE,
+            Std140Type::Mat4=>4 * VEC4_SIZE,
+            Std140Type::Array(_, count)=>self.array_stride() * count,
+        }
+    }
+
+    // 配列の要素間の間隔。配列でなければ大きさと同じ
+    pub fn array_stride(&self)->usize{
+        match self{
+            Std140Type::Array(element, _)=>round_up(element.as_type().size(), VEC4_SIZE),
+            _=>self.size(),
+        }
+    }
+}
+
+fn round_up(value: usize, alignment: usize)->usize{
+    value.div_ceil(alignment) * alignment
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Std140Field{
+    pub name: String,
+    pub field_type: Std140Type,
+    pub offset: usize,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Std140Error{
+    UnknownField(String),
+    TypeMismatch{name: String, expected: Std140Type, actual: Std140Type},
+    IndexOutOfRange{name: String, index: usize, count: usize},
+}
+
+impl std::fmt::Display for Std140Error{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            Std140Error::UnknownField(name)=>write!(f, "uniform block has no member `{}`", name),
+            Std140Error::TypeMismatch{name, expected, actual}=>write!(f, "`{}` is {:?}, but was written as {:?}", name, actual, expected),
+            Std140Error::IndexOutOfRange{name, index, count}=>write!(f, "index {} is out of range for `{}` ({} elements)", index, name, count),
+        }
+    }
+}
+
+impl std::error::Error for Std140Error{}
+
+// メンバーを宣言順に並べたレイアウト
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Std140Layout{
+    fields: Vec<Std140Field>,
+    end: usize,
+}
+
+impl Std140Layout{
+    pub fn new()->Self{
+        Std140Layout::default()
+    }
+
+    // GLSLでの宣言と同じ順番で追加する
+    pub fn with_field(mut self, name: &str, field_type: Std140Type)->Self{
+        let offset = round_up(self.end, field_type.alignment());
+        self.fields.push(Std140Field{
+            name: name.to_string(),
+            field_type,
+            offset,
+        });
+        self.end = offset + field_type.size();
+        self
+    }
+
+    pub fn fields(&self)->&[Std140Field]{
+        &self.fields
+    }
+
+    pub fn field(&self, name: &str)->Option<&Std140Field>{
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    pub fn offset(&self, name: &str)->Option<usize>{
+        self.field(name).map(|field| field.offset)
+    }
+
+    // ブロック全体の大きさ。末尾はvec4の境界まで埋める
+    pub fn size(&self)->usize{
+        round_up(self.end, VEC4_SIZE)
+    }
+}
+
+// レイアウトに従ってGPUに送るバイト列
+#[derive(Debug,Clone,PartialEq)]
+pub struct Std140Buffer{
+    layout: Std140Layout,
+    data: Vec<u8>,
+}
+
+impl Std140Buffer{
+    pub fn new(layout: Std140Layout)->Self{
+        let data = vec![0; layout.size()];
+        Std140Buffer{layout, data}
+    }
+
+    pub fn layout(&self)->&Std140Layout{
+        &self.layout
+    }
+
+    pub fn as_bytes(&self)->&[u8]{
+        &self.data
+    }
+
+    pub fn set_float(&mut self, name: &str, value: f32)->Result<(),Std140Error>{
+        let offset = self.element_offset(name, 0, Std140Scalar::Float)?;
+        self.write_floats(offset, &[value]);
+        Ok(())
+    }
+
+    pub fn set_int(&mut self, name: &str, value: i32)->Result<(),Std140Error>{
+        let offset = self.element_offset(name, 0, Std140Scalar::Int)?;
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn set_vec2(&mut self, name: &str, value: &[f32; 2])->Result<(),Std140Error>{
+        let offset = self.element_offset(name, 0, Std140Scalar::Vec2)?;
+        self.write_floats(offset, value);
+        Ok(())
+    }
+
+    pub fn set_vec3(&mut self, name: &str, value: &[f32; 3])->Result<(),Std140Error>{
+        let offset = self.element_offset(name, 0, Std140Scalar::Vec3)?;
+        self.write_floats(offset, value);
+        Ok(())
+    }
+
+    pub fn set_vec4(&mut self, name: &str, value: &[f32; 4])->Result<(),Std140Error>{
+        let offset = self.element_offset(name, 0, Std140Scalar::Vec4)?;
+        self.write_floats(offset, value);
+        Ok(())
+    }
+
+    pub fn set_mat4(&mut self, name: &str, value: &[f32; 16])->Result<(),Std140Error>{
+        self.set_mat4_at(name, 0, value)
+    }
+
+    // mat4の配列のindex番目に書き込む。配列でないメンバーはindex 0だけ
+    pub fn set_mat4_at(&mut self, name: &str, index: usize, value: &[f32; 16])->Result<(),Std140Error>{
+        let offset = self.element_offset(name, index, Std140Scalar::Mat4)?;
+        self.write_floats(offset, value);
+        Ok(())
+    }
+
+    // 列ごとにvec4の境界へ詰め直して書き込む
+    pub fn set_mat3(&mut self, name: &str, value: &[f32; 9])->Result<(),Std140Error>{
+        let offset = self.element_offset(name, 0, Std140Scalar::Mat3)?;
+        for (column, values) in value.chunks(3).enumerate(){
+            self.write_floats(offset + column * VEC4_SIZE, values);
+        }
+        Ok(())
+    }
+
+    fn element_offset(&self, name: &str, index: usize, expected: Std140Scalar)->Result<usize,Std140Error>{
+        let Some(field) = self.layout.field(name) else{
+            return Err(Std140Error::UnknownField(name.to_string()));
+        };
+        let (element, count) = match field.field_type{
+            Std140Type::Array(element, count)=>(element.as_type(), count),
+            field_type=>(field_type, 1),
+        };
+        if element != expected.as_type(){
+            return Err(Std140Error::TypeMismatch{name: name.to_string(), expected: expected.as_type(), actual: field.field_type});
+        }
+        if index >= count{
+            return Err(Std140Error::IndexOutOfRange{name: name.to_string(), index, count});
+        }
+        Ok(field.offset + index * field.field_type.array_stride())
+    }
+
+    fn write_floats(&mut self, offset: usize, values: &[f32]){
+        for (index, value) in values.iter().enumerate(){
+            let start = offset + index * 4;
+            self.data[start..start + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::uniform::{camera_layout,MAX_VIEWS};
+
+    fn offsets(layout: &Std140Layout)->Vec<usize>{
+        layout.fields().iter().map(|field| field.offset).collect()
+    }
+
+    fn read_f32(buffer: &Std140Buffer, offset: usize)->f32{
+        let bytes = &buffer.as_bytes()[offset..offset + 4];
+        f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    #[test]
+    fn float_fills_vec3_padding(){
+        // vec3の後ろの4バイトにfloatが入る
+        let layout = Std140Layout::new()
+            .with_field("position", Std140Type::Vec3)
+            .with_field("radius", Std140Type::Float)
+            .with_field("direction", Std140Type::Vec3)
+            .with_field("uv", Std140Type::Vec2);
+        assert_eq!(offsets(&layout), vec![0, 12, 16, 32]);
+        assert_eq!(layout.size(), 48);
+
+        // vec2の後のvec3は次のvec4境界まで飛ぶ
+        let layout = Std140Layout::new()
+            .with_field("scale", Std140Type::Float)
+            .with_field("uv", Std140Type::Vec2)
+            .with_field("color", Std140Type::Vec3)
+            .with_field("index", Std140Type::Int);
+        assert_eq!(offsets(&layout), vec![0, 8, 16, 28]);
+        assert_eq!(layout.size(), 32);
+    }
+
+    #[test]
+    fn float_arrays_use_vec4_stride(){
+        let weights = Std140Type::Array(Std140Scalar::Float, 3);
+        assert_eq!(weights.alignment(), 16);
+        assert_eq!(weights.array_stride(), 16);
+        assert_eq!(weights.size(), 48);
+        let layout = Std140Layout::new()
+            .with_field("count", Std140Type::Int)
+            .with_field("weights", weights)
+            .with_field("bias", Std140Type::Float);
+        assert_eq!(offsets(&layout), vec![0, 16, 64]);
+        assert_eq!(layout.size(), 80);
+
+        let mut buffer = Std140Buffer::new(layout);
+        buffer.set_float("bias", 0.5).unwrap();
+        assert_eq!(read_f32(&buffer, 64), 0.5);
+        assert_eq!(buffer.set_mat4_at("weights", 0, &[0.0; 16]), Err(Std140Error::TypeMismatch{name: "weights".to_string(), expected: Std140Type::Mat4, actual: weights}));
+    }
+
+    #[test]
+    fn mat4_arrays_and_mat3_columns(){
+        let layout = Std140Layout::new()
+            .with_field("time", Std140Type::Float)
+            .with_field("models", Std140Type::Array(Std140Scalar::Mat4, 3))
+            .with_field("normal", Std140Type::Mat3)
+            .with_field("tint", Std140Type::Vec4);
+        assert_eq!(offsets(&layout), vec![0, 16, 208, 256]);
+        assert_eq!(layout.size(), 272);
+
+        let mut buffer = Std140Buffer::new(layout);
+        let matrix: [f32; 16] = std::array::from_fn(|index| index as f32 + 1.0);
+        buffer.set_mat4_at("models", 2, &matrix).unwrap();
+        assert_eq!(read_f32(&buffer, 16 + 2 * 64), 1.0);
+        assert_eq!(read_f32(&buffer, 16 + 2 * 64 + 60), 16.0);
+        assert_eq!(buffer.set_mat4_at("models", 3, &matrix), Err(Std140Error::IndexOutOfRange{name: "models".to_string(), index: 3, count: 3}));
+
+        // mat3の各列はvec4の境界から始まる
+        buffer.set_mat3("normal", &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]).unwrap();
+        assert_eq!(read_f32(&buffer, 208 + 8), 3.0);
+        assert_eq!(read_f32(&buffer, 208 + 12), 0.0);
+        assert_eq!(read_f32(&buffer, 208 + 16), 4.0);
+        assert_eq!(read_f32(&buffer, 208 + 32), 7.0);
+        assert_eq!(buffer.set_vec4("missing", &[0.0; 4]), Err(Std140Error::UnknownField("missing".to_string())));
+    }
+
+    #[test]
+    fn camera_block_matches_shader(){
+        // shader/common.glslのCameraブロック: mat4 views[2]; mat4 projections[2];
+        let layout = camera_layout();
+        assert_eq!(MAX_VIEWS, 2);
+        assert_eq!(layout.offset("views"), Some(0));
+        assert_eq!(layout.offset("projections"), Some(128));
+        assert_eq!(layout.size(), 256);
+
+        let mut buffer = Std140Buffer::new(layout);
+        let mut projection = [0.0; 16];
+        projection[0] = 2.0;
+        buffer.set_mat4_at("projections", 1, &projection).unwrap();
+        assert_eq!(read_f32(&buffer, 192), 2.0);
+        assert_eq!(buffer.as_bytes().len(), 256);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use web_sys::*;
+use crate::platform::{GraphicsDevice,GraphicsError};
+use crate::std140::{Std140Buffer,Std140Layout,Std140Scalar,Std140Type};
+
+// リンク済みプログラムのアクティブなuniformの情報
+// getActiveUniformで一度だけ調べ、毎フレームのgetUniformLocationを避ける
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum UniformType{
+    Float,
+    Vec2,
+    Vec3,
+    Vec4,
+    Int,
+    Bool,
+    Mat3,
+    Mat4,
+    Sampler,
+    // 型付きのセッターを用意していない型。GLの型定数をそのまま持つ
+    Other(u32),
+}
+
+impl UniformType{
+    pub fn from_gl(gl_type: u32)->Self{
+        match gl_type{
+            WebGl2RenderingContext::FLOAT=>UniformType::Float,
+            WebGl2RenderingContext::FLOAT_VEC2=>UniformType::Vec2,
+            WebGl2RenderingContext::FLOAT_VEC3=>UniformType::Vec3,
+            WebGl2RenderingContext::FLOAT_VEC4=>UniformType::Vec4,
+            WebGl2RenderingContext::INT=>UniformType::Int,
+            WebGl2RenderingContext::BOOL=>UniformType::Bool,
+            WebGl2RenderingContext::FLOAT_MAT3=>UniformType::Mat3,
+            WebGl2RenderingContext::FLOAT_MAT4=>UniformType::Mat4,
+            WebGl2RenderingContext::SAMPLER_2D
+            | WebGl2RenderingContext::SAMPLER_3D
+            | WebGl2RenderingContext::SAMPLER_CUBE
+            | WebGl2RenderingContext::SAMPLER_2D_ARRAY=>UniformType::Sampler,
+            other=>UniformType::Other(other),
+        }
+    }
+
+    // uniform1iで設定できる型か
+    pub fn accepts_int(&self)->bool{
+        matches!(self, UniformType::Int | UniformType::Bool | UniformType::Sampler)
+    }
+}
+
+impl std::fmt::Display for UniformType{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            UniformType::Float=>write!(f, "float"),
+            UniformType::Vec2=>write!(f, "vec2"),
+            UniformType::Vec3=>write!(f, "vec3"),
+            UniformType::Vec4=>write!(f, "vec4"),
+            UniformType::Int=>write!(f, "int"),
+            UniformType::Bool=>write!(f, "bool"),
+            UniformType::Mat3=>write!(f, "mat3"),
+            UniformType::Mat4=>write!(f, "mat4"),
+            UniformType::Sampler=>write!(f, "sampler"),
+            UniformType::Other(gl_type)=>write!(f, "0x{:04x}", gl_type),
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct UniformInfo<L = WebGlUniformLocation>{
+    pub location: L,
+    pub uniform_type: UniformType,
+    // 配列の要素数。配列でなければ1
+    pub size: i32,
+}
+
+// 配列のuniformは "lights[0]" のように報告されるので、"[0]"を取り除いた名前で引けるようにする
+pub fn uniform_base_name(name: &str)->&str{
+    name.strip_suffix("[0]").unwrap_or(name)
+}
+
+// リンク済みのプログラムのアクティブなuniformを列挙する
+// uniformブロック内のメンバーは位置を持たないので含まれない
+pub fn introspect_uniforms<G: GraphicsDevice>(gl: &G, program: &G::Program)->HashMap<String,UniformInfo<G::UniformLocation>>{
+    let count = gl.get_program_parameter(program, WebGl2RenderingContext::ACTIVE_UNIFORMS).max(0) as u32;
+    let mut uniforms = HashMap::new();
+    for index in 0..count{
+        let Some(info) = gl.get_active_uniform(program, index) else{
+            continue;
+        };
+        let Some(location) = gl.get_uniform_location(program, &info.name) else{
+            continue;
+        };
+        uniforms.insert(uniform_base_name(&info.name).to_string(), UniformInfo{
+            location,
+            uniform_type: UniformType::from_gl(info.gl_type),
+            size: info.size,
+        });
+    }
+    uniforms
+}
+
+// カメラのuniformブロック。common.glslの宣言と合わせること
+pub const CAMERA_BLOCK: &str = "Camera";
+pub const CAMERA_BINDING: u32 = 0;
+// ブロックに入れられるビューの最大数(両目)
+pub const MAX_VIEWS: usize = 2;
+
+// プログラムをリンクしたときに、ブロック名ごとに割り当てるバインディングポイント
+pub const BLOCK_BINDINGS: [(&str, u32); 1] = [(CAMERA_BLOCK, CAMERA_BINDING)];
+
+// layout(std140) uniform Camera{ mat4 views[2]; mat4 projections[2]; };
+pub fn camera_layout()->Std140Layout{
+    Std140Layout::new()
+        .with_field("views", Std140Type::Array(Std140Scalar::Mat4, MAX_VIEWS))
+        .with_field("projections", Std140Type::Array(Std140Scalar::Mat4, MAX_VIEWS))
+}
+
+// プログラムが使っているuniformブロックを、決まったバインディングポイントに結び付ける
+pub fn bind_uniform_blocks<G: GraphicsDevice>(gl: &G, program: &G::Program){
+    for (name, binding) in BLOCK_BINDINGS{
+        let index = gl.get_uniform_block_index(program, name);
+        if index != WebGl2RenderingContext::INVALID_INDEX{
+            gl.uniform_block_binding(program, index, binding);
+        }
+    }
+}
+
+// std140のデータを持つuniformバッファ
+pub struct UniformBuffer<G: GraphicsDevice>{
+    buffer: G::Buffer,
+    binding: u32,
+    pub data: Std140Buffer,
+}
+
+impl<G: GraphicsDevice> UniformBuffer<G>{
+    pub fn new(gl: &G, layout: Std140Layout, binding: u32)->Result<Self,GraphicsError>{
+        let Some(buffer) = gl.create_buffer() else{
+            return Err(GraphicsError("Could not create uniform buffer".to_string()));
+        };
+        let data = Std140Buffer::new(layout);
+        gl.bind_buffer(WebGl2RenderingContext::UNIFORM_BUFFER, Some(&buffer));
+        gl.buffer_data_size(WebGl2RenderingContext::UNIFORM_BUFFER, data.as_bytes().len() as i32, WebGl2RenderingContext::DYNAMIC_DRAW);
+        gl.bind_buffer(WebGl2RenderingContext::UNIFORM_BUFFER, None);
+        Ok(UniformBuffer{buffer, binding, data})
+    }
+
+    // 書き込んだ内容をGPUに送り、バインディングポイントに結び付ける
+    pub fn upload(&self, gl: &G){
+        gl.bind_buffer(WebGl2RenderingContext::UNIFORM_BUFFER, Some(&self.buffer));
+        gl.buffer_sub_data_u8(WebGl2RenderingContext::UNIFORM_BUFFER, 0, self.data.as_bytes());
+        gl.bind_buffer(WebGl2RenderingContext::UNIFORM_BUFFER, None);
+        gl.bind_buffer_base(WebGl2RenderingContext::UNIFORM_BUFFER, self.binding, Some(&self.buffer));
+    }
+
+    pub fn delete(&self, gl: &G){
+        gl.delete_buffer(Some(&self.buffer));
+    }
+}